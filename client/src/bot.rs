@@ -0,0 +1,197 @@
+//! Where a bot's inputs come from: a script that says which frame to add each body on, or a
+//! seeded random stream.
+
+use rand::{Rng, SeedableRng};
+use std::path::Path;
+
+pub trait Inputs: Send {
+    /// The bodies to add now that we've simulated `frame` frames since joining.
+    fn poll(&mut self, frame: shared::FrameIndex) -> Vec<shared::AddBodyEvent>;
+}
+
+/// Bodies to add at fixed frames, counted from when the bot joined.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Script {
+    /// Sorted by frame.
+    inputs: Vec<(shared::FrameIndex, shared::AddBodyEvent)>,
+    next: usize,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        text.parse()
+    }
+}
+
+/// One input per line, as `frame x y mass [vx vy]`. Blank lines and anything after a `#` are
+/// ignored.
+impl std::str::FromStr for Script {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut inputs = Vec::new();
+        for (index, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |msg: &str| format!("line {}: {}", index + 1, msg);
+
+            let mut fields = line.split_whitespace();
+            let frame = fields
+                .next()
+                .unwrap_or_default()
+                .parse::<shared::FrameIndex>()
+                .map_err(|e| error(&format!("invalid frame: {}", e)))?;
+            let values = fields
+                .map(str::parse::<f32>)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| error(&e.to_string()))?;
+            let event = match values.as_slice() {
+                [x, y, mass] => shared::AddBodyEvent::new(*x, *y, *mass),
+                [x, y, mass, vx, vy] => {
+                    shared::AddBodyEvent::new_with_velocity(*x, *y, *mass, *vx, *vy)
+                }
+                _ => return Err(error("expected frame x y mass [vx vy]")),
+            };
+            inputs.push((frame, event));
+        }
+        // stable, so inputs on the same frame keep their order
+        inputs.sort_by_key(|(frame, _)| *frame);
+        Ok(Self { inputs, next: 0 })
+    }
+}
+
+impl Inputs for Script {
+    fn poll(&mut self, frame: shared::FrameIndex) -> Vec<shared::AddBodyEvent> {
+        let due = self.inputs[self.next..]
+            .iter()
+            .take_while(|(at, _)| *at <= frame)
+            .map(|(_, event)| *event)
+            .collect::<Vec<_>>();
+        self.next += due.len();
+        due
+    }
+}
+
+/// Adds bodies at random places and times, the same ones for the same seed.
+pub struct Random {
+    rng: rand::rngs::StdRng,
+    /// The chance of adding a body on each frame.
+    per_frame: f64,
+    /// How far from the origin bodies are added.
+    spread: f32,
+    last_frame: Option<shared::FrameIndex>,
+}
+
+impl Random {
+    pub fn new(seed: u64, inputs_per_sec: f64, tick_rate: u32, spread: f32) -> Self {
+        Self {
+            rng: rand::rngs::StdRng::seed_from_u64(seed),
+            per_frame: (inputs_per_sec / tick_rate as f64).min(1.),
+            spread,
+            last_frame: None,
+        }
+    }
+}
+
+impl Inputs for Random {
+    fn poll(&mut self, frame: shared::FrameIndex) -> Vec<shared::AddBodyEvent> {
+        let frames = match self.last_frame {
+            Some(last_frame) => frame.saturating_sub(last_frame),
+            None => 1,
+        };
+        self.last_frame = Some(frame);
+
+        let mut events = Vec::new();
+        for _ in 0..frames {
+            if !self.rng.gen_bool(self.per_frame) {
+                continue;
+            }
+            let angle = self.rng.gen::<f32>() * 2. * std::f32::consts::PI;
+            let distance = self.rng.gen::<f32>().sqrt() * self.spread;
+            let mass = self.rng.gen_range(1., 100.);
+            let vx = self.rng.gen_range(-1., 1.);
+            let vy = self.rng.gen_range(-1., 1.);
+            events.push(shared::AddBodyEvent::new_with_velocity(
+                distance * angle.cos(),
+                distance * angle.sin(),
+                mass,
+                vx,
+                vy,
+            ));
+        }
+        events
+    }
+}
+
+/// Doesn't add anything, for bots that only watch.
+pub struct Idle;
+
+impl Inputs for Idle {
+    fn poll(&mut self, _frame: shared::FrameIndex) -> Vec<shared::AddBodyEvent> {
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_runs_inputs_in_frame_order() {
+        let mut script = "
+            # frame x y mass [vx vy]
+            10 1 2 3
+            5 4 5 6 0.5 -0.5 # with velocity
+
+            10 7 8 9
+        "
+        .parse::<Script>()
+        .unwrap();
+
+        assert!(script.poll(4).is_empty());
+        assert_eq!(
+            script.poll(5),
+            vec![shared::AddBodyEvent::new_with_velocity(
+                4., 5., 6., 0.5, -0.5
+            )]
+        );
+        assert!(script.poll(9).is_empty());
+        assert_eq!(
+            script.poll(12),
+            vec![
+                shared::AddBodyEvent::new(1., 2., 3.),
+                shared::AddBodyEvent::new(7., 8., 9.)
+            ]
+        );
+        assert!(script.poll(100).is_empty());
+    }
+
+    #[test]
+    fn script_reports_bad_lines() {
+        assert_eq!(
+            "1 2 3 4\n2 3 4".parse::<Script>(),
+            Err("line 2: expected frame x y mass [vx vy]".to_owned())
+        );
+        assert!("x 1 2 3"
+            .parse::<Script>()
+            .unwrap_err()
+            .starts_with("line 1"));
+    }
+
+    #[test]
+    fn random_is_seeded() {
+        let run = |seed| {
+            let mut random = Random::new(seed, 30., 60, 1000.);
+            (0..600)
+                .flat_map(|frame| random.poll(frame))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(2));
+        assert!(!run(1).is_empty());
+    }
+}
@@ -19,22 +19,181 @@ impl Into<&'static str> for Callback {
 
 type RcCell<T> = Rc<RefCell<T>>;
 type WeakCell<T> = std::rc::Weak<RefCell<T>>;
+type Wakers = RcCell<Vec<WeakCell<Option<std::task::Waker>>>>;
+type MessageCallback = Closure<dyn FnMut(web_sys::MessageEvent)>;
+
+/// How long the WebRTC data channel gets to open before giving up on it.
+const RTC_TIMEOUT_MS: i32 = 5000;
+
+enum Channel {
+    /// Unordered and unreliable, like UDP.
+    Rtc {
+        peer: web_sys::RtcPeerConnection,
+        data_channel: web_sys::RtcDataChannel,
+        on_ice_candidate_callback: Closure<dyn FnMut(web_sys::RtcPeerConnectionIceEvent)>,
+    },
+    /// For networks where the data channel never opens. Each message is a datagram, so the
+    /// same packets work unchanged even though they now arrive reliably and in order.
+    WebSocket(web_sys::WebSocket),
+}
 
 #[wasm_bindgen]
 pub struct Connection {
-    peer: web_sys::RtcPeerConnection,
-    data_channel: web_sys::RtcDataChannel,
-    on_message_callback: Closure<dyn FnMut(web_sys::MessageEvent)>,
-    on_ice_candidate_callback: Closure<dyn FnMut(web_sys::RtcPeerConnectionIceEvent)>,
-    wakers: RcCell<Vec<WeakCell<Option<std::task::Waker>>>>,
+    channel: Channel,
+    on_message_callback: MessageCallback,
+    wakers: Wakers,
     receiver: crossbeam_channel::Receiver<Box<[u8]>>,
 }
 
+/// Buffers every message and wakes anything waiting on one.
+fn on_message_callback(
+    wakers: &Wakers,
+) -> (MessageCallback, crossbeam_channel::Receiver<Box<[u8]>>) {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let wakers = Rc::clone(wakers);
+    let on_message_callback = Closure::wrap(Box::new(move |ev: web_sys::MessageEvent| {
+        let data = js_sys::Uint8Array::new(&ev.data());
+        let _r = sender.send(data.to_vec().into_boxed_slice());
+        let mut wakers = wakers.borrow_mut();
+        wakers.retain(|waker| match waker.upgrade() {
+            None => false,
+            Some(waker) => {
+                if let Some(waker) = waker.borrow_mut().take() {
+                    waker.wake();
+                }
+                true
+            }
+        });
+    }) as Box<dyn FnMut(web_sys::MessageEvent)>);
+    (on_message_callback, receiver)
+}
+
+/// A promise that rejects after `ms` milliseconds.
+fn timeout(ms: i32) -> js_sys::Promise {
+    js_sys::Promise::new(&mut move |_, reject| {
+        let on_timeout = Closure::once_into_js(move || {
+            let _ = reject.call1(&JsValue::undefined(), &JsValue::from_str("timed out"));
+        });
+        if let Some(window) = web_sys::window() {
+            let _ = window.set_timeout_with_callback_and_timeout_and_arguments_0(
+                on_timeout.unchecked_ref(),
+                ms,
+            );
+        }
+    })
+}
+
+/// The server's WebSocket endpoint, on the same host as the page.
+fn websocket_url() -> Result<String, JsValue> {
+    let location = web_sys::window().unwrap().location();
+    let scheme = match location.protocol()?.as_str() {
+        "https:" => "wss:",
+        _ => "ws:",
+    };
+    Ok(format!("{}//{}/ws", scheme, location.host()?))
+}
+
 #[allow(clippy::type_complexity)]
 #[wasm_bindgen]
 impl Connection {
+    /// Connects over WebRTC, or over a WebSocket if the data channel fails or doesn't open in
+    /// time.
+    #[wasm_bindgen]
+    pub async fn open(peer: web_sys::RtcPeerConnection) -> Result<Connection, JsValue> {
+        match Self::connect(peer).await {
+            Ok(connection) => Ok(connection),
+            Err(err) => {
+                log::warn!(
+                    "could not open a WebRTC data channel, falling back to a WebSocket: {:?}",
+                    err
+                );
+                Self::connect_websocket().await
+            }
+        }
+    }
+
+    #[wasm_bindgen]
+    pub async fn connect_websocket() -> Result<Connection, JsValue> {
+        let socket = web_sys::WebSocket::new(&websocket_url()?)?;
+        socket.set_binary_type(web_sys::BinaryType::Arraybuffer);
+
+        let wakers = Wakers::default();
+        let (on_message_callback, receiver) = on_message_callback(&wakers);
+        socket.add_event_listener_with_callback(
+            Callback::OnMessage.into(),
+            on_message_callback.as_ref().unchecked_ref(),
+        )?;
+
+        let promise = js_sys::Promise::new(&mut |resolve, reject| {
+            let on_open_callback = Closure::once_into_js(move || {
+                let _ = resolve.call0(&JsValue::undefined());
+            });
+            socket.set_onopen(Some(on_open_callback.unchecked_ref()));
+            let on_error_callback = Closure::once_into_js(move |error: JsValue| {
+                let _ = reject.call1(&JsValue::undefined(), &error);
+            });
+            socket.set_onerror(Some(on_error_callback.unchecked_ref()));
+        });
+        wasm_bindgen_futures::JsFuture::from(promise).await?;
+        socket.set_onopen(None);
+        socket.set_onerror(None);
+        log::info!("connected over a WebSocket");
+
+        Ok(Self {
+            channel: Channel::WebSocket(socket),
+            on_message_callback,
+            wakers,
+            receiver,
+        })
+    }
+
+    /// Connects over a WebRTC data channel. The peer connection is closed if that fails.
     #[wasm_bindgen]
     pub async fn connect(peer: web_sys::RtcPeerConnection) -> Result<Connection, JsValue> {
+        let connected = Self::connect_rtc(peer.clone()).await;
+        if connected.is_err() {
+            peer.close();
+        }
+        connected
+    }
+
+    #[wasm_bindgen]
+    pub fn send_num(&mut self, num: i32) -> Result<(), JsValue> {
+        self.send(&num.to_le_bytes())
+    }
+
+    #[wasm_bindgen]
+    pub fn send_str(&mut self, s: &str) -> Result<(), JsValue> {
+        self.send(s.as_bytes())
+    }
+
+    #[wasm_bindgen]
+    pub fn send(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        match &self.channel {
+            Channel::Rtc { data_channel, .. } => data_channel.send_with_u8_array(data),
+            Channel::WebSocket(socket) => socket.send_with_u8_array(data),
+        }
+    }
+
+    #[wasm_bindgen]
+    pub fn recv(&mut self) -> Option<Box<[u8]>> {
+        self.receiver.try_recv().ok()
+    }
+
+    #[wasm_bindgen]
+    pub fn recv_fut(&mut self) -> RecvFuture {
+        let waker: Rc<RefCell<Option<std::task::Waker>>> = Default::default();
+        self.wakers.borrow_mut().push(Rc::downgrade(&waker));
+        RecvFuture {
+            receiver: self.receiver.clone(),
+            waker,
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+impl Connection {
+    async fn connect_rtc(peer: web_sys::RtcPeerConnection) -> Result<Connection, JsValue> {
         let data_channel = {
             let mut data_channel_config = web_sys::RtcDataChannelInit::new();
             data_channel_config.ordered(false);
@@ -42,32 +201,12 @@ impl Connection {
             peer.create_data_channel_with_data_channel_dict("webudp", &data_channel_config)
         };
 
-        let wakers: Rc<RefCell<Vec<std::rc::Weak<RefCell<Option<std::task::Waker>>>>>> =
-            Default::default();
-        let (sender, receiver) = crossbeam_channel::unbounded();
-        let on_message_callback = {
-            let wakers = Rc::clone(&wakers);
-            let on_message_callback = Closure::wrap(Box::new(move |ev: web_sys::MessageEvent| {
-                let data = js_sys::Uint8Array::new(&ev.data());
-                let _r = sender.send(data.to_vec().into_boxed_slice());
-                let mut wakers = wakers.borrow_mut();
-                wakers.retain(|waker| match waker.upgrade() {
-                    None => false,
-                    Some(waker) => {
-                        if let Some(waker) = waker.borrow_mut().take() {
-                            waker.wake();
-                        }
-                        true
-                    }
-                });
-            })
-                as Box<dyn FnMut(web_sys::MessageEvent)>);
-            data_channel.add_event_listener_with_callback(
-                Callback::OnMessage.into(),
-                on_message_callback.as_ref().unchecked_ref(),
-            )?;
-            on_message_callback
-        };
+        let wakers = Wakers::default();
+        let (on_message_callback, receiver) = on_message_callback(&wakers);
+        data_channel.add_event_listener_with_callback(
+            Callback::OnMessage.into(),
+            on_message_callback.as_ref().unchecked_ref(),
+        )?;
 
         let on_ice_candidate_callback = {
             let on_ice_candidate_callback =
@@ -162,68 +301,59 @@ impl Connection {
             }
         }
 
-        let data_channel: web_sys::RtcDataChannel = wasm_bindgen_futures::JsFuture::from(promise)
+        // some networks never let the data channel open and don't say so
+        let opened = js_sys::Promise::race(&js_sys::Array::of2(&promise, &timeout(RTC_TIMEOUT_MS)));
+        let data_channel: web_sys::RtcDataChannel = wasm_bindgen_futures::JsFuture::from(opened)
             .await?
             .dyn_into()?;
 
         Ok(Self {
-            peer,
-            data_channel,
+            channel: Channel::Rtc {
+                peer,
+                data_channel,
+                on_ice_candidate_callback,
+            },
             on_message_callback,
-            on_ice_candidate_callback,
             receiver,
             wakers,
         })
     }
-
-    #[wasm_bindgen]
-    pub fn send_num(&mut self, num: i32) -> Result<(), JsValue> {
-        self.send(&num.to_le_bytes())
-    }
-
-    #[wasm_bindgen]
-    pub fn send_str(&mut self, s: &str) -> Result<(), JsValue> {
-        self.send(s.as_bytes())
-    }
-
-    #[wasm_bindgen]
-    pub fn send(&mut self, data: &[u8]) -> Result<(), JsValue> {
-        self.data_channel.send_with_u8_array(data)
-    }
-
-    #[wasm_bindgen]
-    pub fn recv(&mut self) -> Option<Box<[u8]>> {
-        self.receiver.try_recv().ok()
-    }
-
-    #[wasm_bindgen]
-    pub fn recv_fut(&mut self) -> RecvFuture {
-        let waker: Rc<RefCell<Option<std::task::Waker>>> = Default::default();
-        self.wakers.borrow_mut().push(Rc::downgrade(&waker));
-        RecvFuture {
-            receiver: self.receiver.clone(),
-            waker,
-        }
-    }
 }
 
 impl Drop for Connection {
     fn drop(&mut self) {
         log::debug!("Dropping data channel.");
 
-        self.data_channel
-            .remove_event_listener_with_callback(
-                Callback::OnMessage.into(),
-                self.on_message_callback.as_ref().unchecked_ref(),
-            )
-            .expect("failed to remove message event listener");
+        let on_message_callback = self.on_message_callback.as_ref().unchecked_ref();
+        match &self.channel {
+            Channel::Rtc {
+                peer,
+                data_channel,
+                on_ice_candidate_callback,
+            } => {
+                data_channel
+                    .remove_event_listener_with_callback(
+                        Callback::OnMessage.into(),
+                        on_message_callback,
+                    )
+                    .expect("failed to remove message event listener");
 
-        self.peer
-            .remove_event_listener_with_callback(
-                Callback::OnIceCandidate.into(),
-                self.on_ice_candidate_callback.as_ref().unchecked_ref(),
-            )
-            .expect("failed to remove icecandidate event listener");
+                peer.remove_event_listener_with_callback(
+                    Callback::OnIceCandidate.into(),
+                    on_ice_candidate_callback.as_ref().unchecked_ref(),
+                )
+                .expect("failed to remove icecandidate event listener");
+            }
+            Channel::WebSocket(socket) => {
+                socket
+                    .remove_event_listener_with_callback(
+                        Callback::OnMessage.into(),
+                        on_message_callback,
+                    )
+                    .expect("failed to remove message event listener");
+                let _ = socket.close();
+            }
+        }
     }
 }
 
@@ -1,6 +1,7 @@
 #[derive(Clone, Debug)]
 pub struct LatencyBuffer {
-    buffer: Vec<(shared::FrameIndex, instant::Instant)>,
+    /// Outstanding pings by their `client_time`, with when they were sent.
+    buffer: Vec<(u64, std::time::Duration)>,
     timings: std::collections::VecDeque<std::time::Duration>,
     timeout: std::time::Duration,
     lost_packet_count: usize,
@@ -18,21 +19,26 @@ impl LatencyBuffer {
         }
     }
 
-    pub fn send(&mut self, index: shared::FrameIndex) {
-        self.buffer.push((index, instant::Instant::now()))
+    /// `now` is measured from the same, arbitrary, epoch as in `recv`.
+    pub fn send(&mut self, client_time: u64, now: std::time::Duration) {
+        self.buffer.push((client_time, now))
     }
 
-    pub fn recv(&mut self, frame: shared::FrameIndex) -> Option<std::time::Duration> {
+    pub fn recv(
+        &mut self,
+        client_time: u64,
+        now: std::time::Duration,
+    ) -> Option<std::time::Duration> {
         let len = self.buffer.len();
         self.buffer.retain({
             let timeout = self.timeout;
-            move |(_index, time)| time.elapsed() < timeout
+            move |(_index, sent)| now.saturating_sub(*sent) < timeout
         });
         self.lost_packet_count += len - self.buffer.len();
 
-        for (index, (other, instant)) in self.buffer.iter().enumerate() {
-            if frame == *other {
-                let d = instant.elapsed();
+        for (index, (other, sent)) in self.buffer.iter().enumerate() {
+            if client_time == *other {
+                let d = now.saturating_sub(*sent);
                 if self.timings.capacity() == self.timings.len() {
                     self.timings.pop_front();
                 }
@@ -3,12 +3,15 @@ use wasm_bindgen::prelude::*;
 
 mod connection;
 mod latency_buffer;
+pub mod session;
 
 #[cfg(feature = "renderer")]
 mod renderer;
 
 use connection::Connection;
-use latency_buffer::LatencyBuffer;
+use session::Session;
+use shared::codec::{Bincode, Codec};
+use shared::control::Control;
 
 #[wasm_bindgen(start)]
 pub fn main() {
@@ -18,14 +21,10 @@ pub fn main() {
 
 #[wasm_bindgen]
 pub struct State {
-    inner: shared::State,
+    session: Session,
     connection: Connection,
-    hash_buffer: HashBuffer,
-    latency_buffer: LatencyBuffer,
-    /// The most recent frame index that we've received from the server.
-    server_frame: shared::FrameIndex,
-    hash_successes: u32,
-    hash_failures: u32,
+    /// The session's times are measured from here.
+    epoch: instant::Instant,
 }
 
 #[wasm_bindgen]
@@ -37,97 +36,112 @@ impl State {
 
     #[wasm_bindgen]
     pub fn from_raw(data: &[u8], connection: Connection) -> Result<State, JsValue> {
-        let (hash, inner) = bincode::deserialize::<(u64, shared::State)>(data)
+        let ((hash, inner), _) = Codec::<(u64, shared::State)>::decode(&Bincode, data)
             .map_err(|e| JsValue::from_str(&format!("{}", e)))?;
-        assert_eq!(hash, inner.hash());
+        if hash != inner.hash() {
+            return Err(JsValue::from_str("initial state doesn't match its hash"));
+        }
         Ok(Self::new_with_state(inner, connection))
     }
 
     fn new_with_state(inner: shared::State, connection: Connection) -> State {
-        let server_frame = inner.frame_index;
         Self {
-            inner,
+            session: Session::new(inner),
             connection,
-            hash_buffer: Default::default(),
-            latency_buffer: LatencyBuffer::with_timeout(std::time::Duration::from_secs(1)),
-            server_frame,
-            hash_successes: 0,
-            hash_failures: 0,
+            epoch: instant::Instant::now(),
         }
     }
 
     #[wasm_bindgen]
     pub fn step(&mut self) -> Result<(), JsValue> {
         while let Some(buf) = self.connection.recv() {
-            if let Ok(input) = bincode::deserialize::<shared::Recv>(&buf) {
-                match input {
-                    shared::Recv::Pong(frame_index) => {
-                        self.latency_buffer.recv(frame_index);
-                    }
-                    shared::Recv::StateHash(shared::IndexedState {
-                        frame_index,
-                        state: hash,
-                    }) => {
-                        self.server_frame = self.server_frame.max(frame_index);
-                        self.hash_buffer.insert(frame_index, hash);
-                    }
-                    shared::Recv::FullState(_) => unimplemented!(),
-                    shared::Recv::InputState(input) => self.inner.input_buffer.push(input),
-                }
-            }
+            self.session
+                .receive(&buf, self.epoch.elapsed())
+                .map_err(|e| JsValue::from_str(&e.to_string()))?;
         }
+        self.session.update(self.epoch.elapsed());
+        self.flush()
+    }
 
-        match bincode::serialize(&shared::Send::Ping(self.inner.frame_index)) {
-            Ok(buf) => self.connection.send(&buf)?,
-            Err(err) => log::error!("serialization error: {}", err),
-        }
-        self.latency_buffer.send(self.inner.frame_index);
+    /// Sets the name sent to the server. Only takes effect before the handshake completes.
+    #[wasm_bindgen]
+    pub fn set_client_name(&mut self, client_name: String) {
+        self.session.set_client_name(client_name)
+    }
 
-        if self.inner.frame_index > self.target_frame() {
-            return Ok(());
-        }
+    /// Joins as a spectator instead of a player, playing `delay` frames behind the server.
+    /// Only takes effect before the handshake completes.
+    #[wasm_bindgen]
+    pub fn spectate(&mut self, delay: shared::FrameIndex) {
+        self.session.spectate(delay)
+    }
 
-        while self.inner.frame_index < self.server_frame {
-            self.inner.step();
-            let frame = self.inner.frame_index;
-            let hash = self.inner.hash();
-            log::trace!("{}, {:?}", hash, self.inner);
-            match self.hash_buffer.take(frame, hash) {
-                None => self.hash_failures += 1,
-                Some(_) => self.hash_successes += 1,
-            }
+    /// Enters the room the ticket was issued for, given in hex as the lobby returns it. Only
+    /// takes effect before the handshake completes.
+    #[wasm_bindgen]
+    pub fn set_ticket(&mut self, ticket: &str) -> Result<(), JsValue> {
+        let ticket = shared::RoomTicket::from_str_radix(ticket, 16)
+            .map_err(|e| JsValue::from_str(&format!("invalid room ticket: {}", e)))?;
+        self.session.set_ticket(ticket);
+        Ok(())
+    }
+
+    /// Sends every datagram that the session has flushed.
+    fn flush(&mut self) -> Result<(), JsValue> {
+        for datagram in self.session.take_datagrams() {
+            self.connection.send(&datagram)?;
         }
-        // TODO: if client is behind the server, run multiple steps
         Ok(())
     }
 
+    fn flush_or_log(&mut self) {
+        if let Err(err) = self.flush() {
+            log::error!("failed send: {}", err.as_string().unwrap());
+        }
+    }
+
     #[wasm_bindgen]
     pub fn mouse_click_event(&mut self, down_x: f32, down_y: f32, mass: f32, up_x: f32, up_y: f32) {
         // TODO: it this magic number is reasonable but it should really be tied to the simulation
         const VEL_SCALE: f32 = 0.01;
         let dx = (up_x - down_x) * VEL_SCALE;
         let dy = (up_y - down_y) * VEL_SCALE;
-        // TODO: should this frame index be based off our guess of the server's frame index?
         let event = shared::AddBodyEvent::new_with_velocity(down_x, down_y, mass, dx, dy);
-        let input_event = shared::IndexedState {
-            frame_index: self.inner.frame_index + shared::INPUT_BUFFER_FRAMES,
-            state: event,
-        };
-        self.inner.input_buffer.push(input_event);
-        match bincode::serialize(&shared::Send::InputState(input_event)) {
-            Ok(state) => {
-                if let Err(err) = self.connection.send(&state) {
-                    log::error!("failed send: {}", err.as_string().unwrap());
-                }
-            }
-            Err(err) => log::error!("serialization error: {}", err),
-        };
+        self.session.add_body(event);
+        self.flush_or_log();
+    }
+
+    fn send_control(&mut self, control: Control) {
+        self.session.send_control(control);
+        self.flush_or_log();
+    }
+
+    #[wasm_bindgen]
+    pub fn pause(&mut self) {
+        self.send_control(Control::Pause)
+    }
+
+    #[wasm_bindgen]
+    pub fn resume(&mut self) {
+        self.send_control(Control::Resume)
+    }
+
+    /// Runs `steps` simulation steps while paused.
+    #[wasm_bindgen]
+    pub fn step_frames(&mut self, steps: u32) {
+        self.send_control(Control::Step(steps))
+    }
+
+    #[wasm_bindgen]
+    pub fn set_timescale(&mut self, timescale: u32) {
+        self.send_control(Control::Timescale(timescale))
     }
 
     #[wasm_bindgen]
     pub fn to_json(&self) -> Result<JsValue, JsValue> {
         let render_data = self
-            .inner
+            .session
+            .state()
             .simulation
             .bodies
             .iter()
@@ -143,99 +157,84 @@ impl State {
 
     #[wasm_bindgen]
     pub fn latency_secs(&self) -> f32 {
-        self.latency_buffer.average_latency().as_secs_f32()
+        self.session.latency().as_secs_f32()
     }
 
     #[wasm_bindgen]
     pub fn packet_loss(&self) -> f32 {
-        self.latency_buffer.packet_loss()
+        self.session.packet_loss()
     }
 
     #[wasm_bindgen]
     pub fn current_frame(&self) -> shared::FrameIndex {
-        self.inner.frame_index
+        self.session.state().frame_index
     }
 
+    /// Our best guess at the frame the server is on right now.
     #[wasm_bindgen]
     pub fn target_frame(&self) -> shared::FrameIndex {
-        self.server_frame + self.latency_buffer.average_latency().as_millis() as u32 / 60
+        self.session.target_frame(self.epoch.elapsed())
     }
 
+    /// Half the width of the interval that the server's frame is expected to be in.
     #[wasm_bindgen]
-    pub fn hash_successes(&self) -> u32 {
-        self.hash_successes
+    pub fn target_frame_error(&self) -> shared::FrameIndex {
+        self.session.target_frame_error(self.epoch.elapsed())
     }
 
     #[wasm_bindgen]
-    pub fn hash_failures(&self) -> u32 {
-        self.hash_failures
+    pub fn paused(&self) -> bool {
+        self.session.state().playback.paused
     }
-}
 
-#[derive(Serialize, Deserialize)]
-struct RenderDataBody {
-    x: f32,
-    y: f32,
-    radius: f32,
-    mass: f32,
-}
-
-struct HashBufferEntry(shared::FrameIndex, u64);
+    #[wasm_bindgen]
+    pub fn timescale(&self) -> u32 {
+        self.session.state().playback.timescale
+    }
 
-#[derive(Default)]
-struct HashBuffer(Vec<HashBufferEntry>);
+    /// Why our most recent input was refused, if it was.
+    #[wasm_bindgen]
+    pub fn last_rejection(&self) -> Option<String> {
+        self.session
+            .last_rejection()
+            .map(|reason| reason.to_string())
+    }
 
-impl HashBuffer {
-    pub fn insert(&mut self, frame_index: shared::FrameIndex, hash: u64) {
-        self.0.push(HashBufferEntry(frame_index, hash))
+    #[wasm_bindgen]
+    pub fn spectating(&self) -> bool {
+        self.session.spectating()
     }
 
-    pub fn contains(&self, frame_index: shared::FrameIndex, hash: u64) -> bool {
-        self.0
-            .iter()
-            .find(|HashBufferEntry(i, h)| frame_index == *i && hash == *h)
-            .is_some()
+    #[wasm_bindgen]
+    pub fn player_id(&self) -> Option<shared::PlayerId> {
+        self.session.player_id()
     }
 
-    pub fn by_frame(&self, frame_index: shared::FrameIndex) -> impl Iterator<Item = &u64> {
-        self.0
-            .iter()
-            .filter_map(move |HashBufferEntry(i, h)| if frame_index == *i { Some(h) } else { None })
+    #[wasm_bindgen]
+    pub fn hash_successes(&self) -> u32 {
+        self.session.hash_successes()
     }
 
-    pub fn take(
-        &mut self,
-        frame_index: shared::FrameIndex,
-        hash: u64,
-    ) -> Option<(shared::FrameIndex, u64)> {
-        match self
-            .0
-            .iter()
-            .position(|HashBufferEntry(i, h)| frame_index == *i && hash == *h)
-        {
-            None => None,
-            Some(index) => {
-                let entry = self.0.swap_remove(index);
-                Some((entry.0, entry.1))
-            }
-        }
+    #[wasm_bindgen]
+    pub fn hash_failures(&self) -> u32 {
+        self.session.hash_failures()
     }
 
-    /// exclusive search
-    pub fn unmatched_hashed(&self, cutoff: shared::FrameIndex) -> usize {
-        self.0.iter().fold(
-            0,
-            |acc, HashBufferEntry(index, _)| {
-                if *index < cutoff {
-                    acc + 1
-                } else {
-                    acc
-                }
-            },
-        )
+    /// The most recent desync reports, oldest first.
+    #[wasm_bindgen]
+    pub fn desync_reports(&self) -> Result<JsValue, JsValue> {
+        serde_wasm_bindgen::to_value(self.session.desync_reports()).map_err(Into::into)
     }
 }
 
+#[derive(Serialize, Deserialize)]
+struct RenderDataBody {
+    x: f32,
+    y: f32,
+    radius: f32,
+    mass: f32,
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -1,33 +1,438 @@
-use std::net::UdpSocket;
-
-/// webrtc-unreliable is using a raw UDP socket under the hood
-/// but because of the way that it does client tracking it won't work seamlessly without
-/// an sdp
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let remote_addr = std::net::SocketAddr::new([127, 0, 0, 1].into(), 3031);
-    let local_addr = if remote_addr.is_ipv4() {
-        "0.0.0.0:0"
-    } else {
-        "[::]:0"
+//! A headless native client. It speaks the same protocol as the browser, over the server's
+//! plain UDP transport (`--udp` on the server), and runs the same `Session`. Run many at once
+//! as bots to load test the server and to check that every peer's state hashes agree. The
+//! `sim-` flags put each bot behind a simulated bad network.
+
+mod bot;
+
+use bot::Inputs;
+use client::session::Session;
+use shared::netsim::{Link, NetworkConditions};
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "client",
+    about = "Connects headless clients to the server over UDP."
+)]
+struct Args {
+    /// The server's UDP transport.
+    #[structopt(long, default_value = "127.0.0.1:3031")]
+    server: SocketAddr,
+    /// How many clients to run.
+    #[structopt(long, default_value = "1")]
+    bots: usize,
+    /// The threads that the bots are shared between. Defaults to one per core.
+    #[structopt(long)]
+    threads: Option<usize>,
+    /// How many times a second each bot receives, simulates and sends.
+    #[structopt(long, default_value = "60")]
+    fps: u32,
+    /// Stop after this many seconds instead of running until killed.
+    #[structopt(long)]
+    duration: Option<f64>,
+    /// A file of inputs to send, one `frame x y mass [vx vy]` per line, where the frame is
+    /// counted from when the bot joined. Every bot runs the same script.
+    #[structopt(long, parse(from_os_str))]
+    script: Option<PathBuf>,
+    /// Add random bodies at about this rate per bot instead of following a script.
+    #[structopt(long, default_value = "0")]
+    inputs_per_sec: f64,
+    /// Bot `n` uses `seed + n` for its random inputs.
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+    /// How far from the origin random bodies are added.
+    #[structopt(long, default_value = "1000")]
+    spread: f32,
+    /// The server's tick rate, to turn `inputs-per-sec` into a chance per frame.
+    #[structopt(long, default_value = "60")]
+    tick_rate: u32,
+    /// Join as spectators that stay this many frames behind the server.
+    #[structopt(long)]
+    spectate: Option<shared::FrameIndex>,
+    /// A room ticket from `POST /rooms/{id}/join`, in hex.
+    #[structopt(long, parse(try_from_str = parse_ticket))]
+    ticket: Option<shared::RoomTicket>,
+    /// Bots are named this followed by their number.
+    #[structopt(long, default_value = "bot")]
+    name: String,
+    /// Compare every bot's hash of each frame that's a multiple of this.
+    #[structopt(long, default_value = "60")]
+    checkpoint_interval: shared::FrameIndex,
+    /// Simulated one way latency in seconds, added each way on top of the real network.
+    #[structopt(long, default_value = "0")]
+    sim_latency: f64,
+    /// Up to this many more simulated seconds for each datagram.
+    #[structopt(long, default_value = "0")]
+    sim_jitter: f64,
+    /// The chance of dropping each datagram, each way.
+    #[structopt(long, default_value = "0")]
+    sim_loss: f64,
+    /// The chance of delivering a datagram twice.
+    #[structopt(long, default_value = "0")]
+    sim_duplicate: f64,
+    /// The chance of holding a datagram back so that later ones overtake it.
+    #[structopt(long, default_value = "0")]
+    sim_reorder: f64,
+    /// Simulated bandwidth in bytes per second each way. Unlimited if 0.
+    #[structopt(long, default_value = "0")]
+    sim_bandwidth: u64,
+}
+
+impl Args {
+    /// The simulated network between bot `index` and the server, inbound then outbound.
+    fn network(&self, index: usize) -> (NetworkConditions, NetworkConditions) {
+        let conditions = NetworkConditions {
+            latency: self.sim_latency,
+            jitter: self.sim_jitter,
+            loss: self.sim_loss,
+            duplicate: self.sim_duplicate,
+            reorder: self.sim_reorder,
+            bandwidth: self.sim_bandwidth,
+            ..Default::default()
+        };
+        let seed = self.seed.wrapping_add(index as u64).wrapping_mul(2);
+        (conditions.with_seed(seed), conditions.with_seed(seed + 1))
+    }
+}
+
+fn parse_ticket(s: &str) -> Result<shared::RoomTicket, std::num::ParseIntError> {
+    shared::RoomTicket::from_str_radix(s, 16)
+}
+
+/// What bots tell the main thread.
+enum Report {
+    Checkpoint {
+        bot: usize,
+        frame: shared::FrameIndex,
+        hash: u64,
+    },
+    Finished(Summary),
+}
+
+#[derive(Debug)]
+struct Summary {
+    bot: usize,
+    player_id: Option<shared::PlayerId>,
+    frame: shared::FrameIndex,
+    hash_successes: u32,
+    hash_failures: u32,
+    latency: Duration,
+    packet_loss: f32,
+    /// Why the bot stopped early, if it did.
+    error: Option<String>,
+}
+
+struct Bot {
+    index: usize,
+    socket: UdpSocket,
+    session: Session,
+    inputs: Box<dyn Inputs>,
+    /// Perfect unless a network is simulated with the `sim-` flags.
+    inbound: Link<Vec<u8>>,
+    outbound: Link<Vec<u8>>,
+    /// The frame we were on when we first had a state, which script frames count from.
+    joined_frame: Option<shared::FrameIndex>,
+    error: Option<String>,
+}
+
+impl Bot {
+    fn new(index: usize, args: &Args, script: Option<&bot::Script>) -> std::io::Result<Self> {
+        let local_addr = if args.server.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local_addr)?;
+        socket.connect(args.server)?;
+        socket.set_nonblocking(true)?;
+
+        let mut session = Session::without_state();
+        session.set_client_name(format!("{}{}", args.name, index));
+        session.set_checkpoint_interval(args.checkpoint_interval);
+        if let Some(delay) = args.spectate {
+            session.spectate(delay);
+        }
+        if let Some(ticket) = args.ticket {
+            session.set_ticket(ticket);
+        }
+        let inputs: Box<dyn Inputs> = match script {
+            _ if args.spectate.is_some() => Box::new(bot::Idle),
+            Some(script) => Box::new(script.clone()),
+            None if args.inputs_per_sec > 0. => Box::new(bot::Random::new(
+                args.seed.wrapping_add(index as u64),
+                args.inputs_per_sec,
+                args.tick_rate,
+                args.spread,
+            )),
+            None => Box::new(bot::Idle),
+        };
+        let (inbound, outbound) = args.network(index);
+        Ok(Self {
+            index,
+            socket,
+            session,
+            inputs,
+            inbound: Link::new(inbound),
+            outbound: Link::new(outbound),
+            joined_frame: None,
+            error: None,
+        })
+    }
+
+    /// Receives everything that has arrived, adds any inputs that are due, simulates and
+    /// sends. Returns false once the bot has stopped.
+    fn tick(&mut self, now: Duration, reports: &mpsc::Sender<Report>) -> bool {
+        if self.error.is_some() {
+            return false;
+        }
+        if let Err(err) = self.try_tick(now) {
+            log::error!("bot {} stopped: {}", self.index, err);
+            self.error = Some(err);
+            return false;
+        }
+        for (frame, hash) in self.session.take_checkpoints() {
+            let _ = reports.send(Report::Checkpoint {
+                bot: self.index,
+                frame,
+                hash,
+            });
+        }
+        true
+    }
+
+    fn try_tick(&mut self, now: Duration) -> Result<(), String> {
+        // big enough for the largest UDP datagram
+        let mut buf = [0u8; 65536];
+        loop {
+            match self.socket.recv(&mut buf) {
+                Ok(len) => self.inbound.send(now, len, buf[..len].to_vec()),
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                // the server isn't up yet, or went away. keep trying until it answers
+                Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => break,
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+        while let Some(datagram) = self.inbound.poll(now) {
+            self.session
+                .receive(&datagram, now)
+                .map_err(|e| e.to_string())?;
+        }
+
+        if self.session.has_state() && self.session.player_id().is_some() {
+            let frame = self.session.state().frame_index;
+            let joined_frame = *self.joined_frame.get_or_insert(frame);
+            for event in self.inputs.poll(frame.saturating_sub(joined_frame)) {
+                self.session.add_body(event);
+            }
+        }
+        self.session.update(now);
+
+        for datagram in self.session.take_datagrams() {
+            self.outbound.send(now, datagram.len(), datagram);
+        }
+        while let Some(datagram) = self.outbound.poll(now) {
+            match self.socket.send(&datagram) {
+                Ok(_) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {}
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    fn summary(&self) -> Summary {
+        Summary {
+            bot: self.index,
+            player_id: self.session.player_id(),
+            frame: self.session.state().frame_index,
+            hash_successes: self.session.hash_successes(),
+            hash_failures: self.session.hash_failures(),
+            latency: self.session.latency(),
+            packet_loss: self.session.packet_loss(),
+            error: self.error.clone(),
+        }
+    }
+}
+
+/// Runs the bots until `deadline`, ticking each of them `fps` times a second.
+fn run_bots(
+    mut bots: Vec<Bot>,
+    fps: u32,
+    deadline: Option<Instant>,
+    reports: mpsc::Sender<Report>,
+) {
+    let epoch = Instant::now();
+    let period = Duration::from_secs(1) / fps;
+    let mut next = epoch;
+    while deadline.map_or(true, |deadline| next < deadline) {
+        let now = Instant::now();
+        if next > now {
+            std::thread::sleep(next - now);
+        }
+        let mut running = 0;
+        for bot in bots.iter_mut() {
+            if bot.tick(epoch.elapsed(), &reports) {
+                running += 1;
+            }
+        }
+        if running == 0 {
+            break;
+        }
+        // skip ticks rather than bunching them up if the bots can't keep up
+        next = (next + period).max(Instant::now() - period);
+    }
+    for bot in bots {
+        let _ = reports.send(Report::Finished(bot.summary()));
+    }
+}
+
+/// Every bot's hash of each checkpoint frame, to check that they all agree.
+#[derive(Default)]
+struct Checkpoints {
+    hashes: HashMap<shared::FrameIndex, HashMap<u64, Vec<usize>>>,
+    disagreements: usize,
+}
+
+impl Checkpoints {
+    fn insert(&mut self, bot: usize, frame: shared::FrameIndex, hash: u64) {
+        let by_hash = self.hashes.entry(frame).or_default();
+        by_hash.entry(hash).or_default().push(bot);
+        // only report a frame the first time it diverges
+        if by_hash.len() == 2 && by_hash[&hash].len() == 1 {
+            self.disagreements += 1;
+            log::error!("frame {} diverged: {:?}", frame, by_hash);
+        }
+    }
+
+    fn frames(&self) -> usize {
+        self.hashes.len()
+    }
+}
+
+fn main() {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    let args = Args::from_args();
+    let script = match &args.script {
+        Some(path) => match bot::Script::load(path) {
+            Ok(script) => Some(script),
+            Err(err) => {
+                eprintln!("invalid script: {}", err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
     };
-    let socket = UdpSocket::bind(local_addr)?;
-    const MAX_DATAGRAM_SIZE: usize = 65_507;
-    socket.connect(&remote_addr)?;
+    if args.fps == 0 || args.checkpoint_interval == 0 {
+        eprintln!("fps and checkpoint-interval must be greater than 0");
+        std::process::exit(1);
+    }
+    if let Err(reason) = args.network(0).0.validate() {
+        eprintln!("invalid simulated network: {}", reason);
+        std::process::exit(1);
+    }
+
+    let mut bots = Vec::with_capacity(args.bots);
+    for index in 0..args.bots {
+        match Bot::new(index, &args, script.as_ref()) {
+            Ok(bot) => bots.push(bot),
+            Err(err) => {
+                eprintln!("could not create bot {}: {}", index, err);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let mut count = 0;
+    let threads = args
+        .threads
+        .or_else(|| std::thread::available_parallelism().ok().map(Into::into))
+        .unwrap_or(1)
+        .clamp(1, args.bots.max(1));
+    let deadline = args
+        .duration
+        .map(|secs| Instant::now() + Duration::from_secs_f64(secs));
+    let (reports, recver) = mpsc::channel();
+    let per_thread = args.bots.div_ceil(threads).max(1);
+    let mut handles = Vec::new();
+    while !bots.is_empty() {
+        let rest = bots.split_off(bots.len().min(per_thread));
+        let chunk = std::mem::replace(&mut bots, rest);
+        let reports = reports.clone();
+        let fps = args.fps;
+        handles.push(std::thread::spawn(move || {
+            run_bots(chunk, fps, deadline, reports)
+        }));
+    }
+    drop(reports);
+    log::info!(
+        "running {} bots on {} threads against {}",
+        args.bots,
+        handles.len(),
+        args.server
+    );
+
+    let mut checkpoints = Checkpoints::default();
+    let mut summaries = Vec::new();
+    let mut last_status = Instant::now();
     loop {
-        let input = format!("hello from udp {}", count);
-        let size = socket.send(input.as_bytes())?;
-        println!("sent {} bytes", size);
-        assert_eq!(size, input.len());
-        let mut data = vec![0u8; MAX_DATAGRAM_SIZE];
-        let len = socket.recv(&mut data)?;
-        let s = String::from_utf8_lossy(&data[..len]);
-        println!("Received {} bytes: {:?}", len, s);
-        assert_eq!(input, s);
-
-        count += 1;
-        std::thread::sleep(std::time::Duration::from_secs_f32(0.2));
+        match recver.recv_timeout(Duration::from_secs(1)) {
+            Ok(Report::Checkpoint { bot, frame, hash }) => checkpoints.insert(bot, frame, hash),
+            Ok(Report::Finished(summary)) => summaries.push(summary),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+        if last_status.elapsed() >= Duration::from_secs(5) {
+            log::info!(
+                "{} checkpoint frames compared, {} diverged",
+                checkpoints.frames(),
+                checkpoints.disagreements
+            );
+            last_status = Instant::now();
+        }
+    }
+    for handle in handles {
+        let _ = handle.join();
+    }
+
+    summaries.sort_by_key(|summary| summary.bot);
+    for summary in &summaries {
+        log::debug!("{:?}", summary);
+    }
+    let stopped = summaries.iter().filter(|s| s.error.is_some()).count();
+    let hash_failures = summaries.iter().map(|s| s.hash_failures).sum::<u32>();
+    let hash_successes = summaries.iter().map(|s| s.hash_successes).sum::<u32>();
+    let latency =
+        summaries.iter().map(|s| s.latency).sum::<Duration>() / summaries.len().max(1) as u32;
+    let packet_loss =
+        summaries.iter().map(|s| s.packet_loss).sum::<f32>() / summaries.len().max(1) as f32;
+    let joined = summaries.iter().filter(|s| s.player_id.is_some()).count();
+    let frames = summaries.iter().map(|s| s.frame);
+    log::info!(
+        "{} bots, {} joined as players, {} stopped early. frames {}..={}",
+        summaries.len(),
+        joined,
+        stopped,
+        frames.clone().min().unwrap_or(0),
+        frames.max().unwrap_or(0)
+    );
+    log::info!(
+        "server hashes: {} matched, {} failed. average latency: {:?}, packet loss: {:.1}%",
+        hash_successes,
+        hash_failures,
+        latency,
+        packet_loss * 100.
+    );
+    log::info!(
+        "{} checkpoint frames compared between bots, {} diverged",
+        checkpoints.frames(),
+        checkpoints.disagreements
+    );
+    if checkpoints.disagreements > 0 || stopped > 0 {
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,820 @@
+//! The client's side of the protocol, without any I/O of its own. Datagrams from the server
+//! go into `receive`, the ones for it come out of `take_datagrams`, and every call is told the
+//! time, so the same logic runs in the browser, in the native client and in tests.
+
+use crate::latency_buffer::LatencyBuffer;
+use shared::codec::WireCodec;
+use shared::control::Control;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionError {
+    Rejected(shared::ConnectionRejected),
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::Rejected(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+pub struct Session {
+    inner: shared::State,
+    /// False until the server has sent us a state to start from.
+    has_state: bool,
+    endpoint: shared::packet::PacketEndpoint,
+    /// Messages waiting for the next flush.
+    outbox: Vec<shared::Send>,
+    /// Flushed datagrams waiting for `take_datagrams`.
+    datagrams: Vec<Vec<u8>>,
+    /// Messages repeated in every flush until the server acknowledges them.
+    redundant: shared::redundant::RedundantQueue<shared::Send>,
+    /// Inputs from other players that we've already buffered.
+    inputs_seen: shared::redundant::Deduplicator,
+    hash_buffer: HashBuffer,
+    latency_buffer: LatencyBuffer,
+    /// The time given to the most recent `receive` or `update`. Local times sent in pings are
+    /// measured from the same epoch.
+    now: Duration,
+    clock: shared::clock::ClockSync,
+    /// The most recent frame index that we've received from the server.
+    server_frame: shared::FrameIndex,
+    hash_successes: u32,
+    hash_failures: u32,
+    client_name: String,
+    /// Assigned by the server once our `Hello` has been accepted.
+    player_id: Option<shared::PlayerId>,
+    /// Set to join as a spectator that stays this many frames behind the server.
+    spectator_delay: Option<shared::FrameIndex>,
+    spectating: bool,
+    /// From `POST /rooms/{id}/join`, to play in that room instead of the default one.
+    room_ticket: Option<shared::RoomTicket>,
+    input_delay: shared::FrameIndex,
+    sync_mode: shared::lockstep::SyncMode,
+    last_hello: Option<Duration>,
+    /// The most recent state whose hash matched the server's. Used as a delta baseline.
+    verified: Option<shared::State>,
+    resync_requested: Option<Duration>,
+    /// From `Load`. We don't simulate this frame, and wait for a resync to one after it.
+    load_frame: Option<shared::FrameIndex>,
+    next_input_seq: shared::InputSequence,
+    next_control_seq: shared::InputSequence,
+    /// Inputs that we've applied locally but whose frame hasn't been confirmed by the server.
+    unacked_inputs: Vec<(
+        shared::InputSequence,
+        shared::IndexedState<shared::AddBodyEvent>,
+    )>,
+    /// The server's, from `Welcome`, checked before sending so that inputs it would reject
+    /// never enter our prediction.
+    input_rules: shared::validation::InputRules,
+    last_rejection: Option<shared::InputRejected>,
+    /// How many of our controls the server has refused to schedule, and the latest one.
+    /// Controls are numbered in the order they're sent, so repeated rejections are never later.
+    rejected_controls: u32,
+    last_rejected_control: Option<shared::InputSequence>,
+    /// Drill down into the first frame that failed to match the server's hash.
+    diagnosis: Option<shared::hash_tree::Diagnosis>,
+    diagnosis_requested: Option<Duration>,
+    desync_reports: std::collections::VecDeque<shared::hash_tree::DesyncReport>,
+    /// Every frame that's a multiple of this has its hash kept, for comparing with other peers.
+    checkpoint_interval: Option<shared::FrameIndex>,
+    checkpoints: Vec<(shared::FrameIndex, u64)>,
+}
+
+impl Session {
+    /// Starts from a state that we already have, such as the one served over HTTP.
+    pub fn new(inner: shared::State) -> Self {
+        let server_frame = inner.frame_index;
+        Self {
+            inner,
+            has_state: true,
+            endpoint: Default::default(),
+            outbox: Vec::new(),
+            datagrams: Vec::new(),
+            redundant: Default::default(),
+            inputs_seen: Default::default(),
+            hash_buffer: Default::default(),
+            latency_buffer: LatencyBuffer::with_timeout(Duration::from_secs(1)),
+            now: Duration::default(),
+            clock: shared::clock::ClockSync::new(60),
+            server_frame,
+            hash_successes: 0,
+            hash_failures: 0,
+            client_name: "anonymous".to_owned(),
+            player_id: None,
+            spectator_delay: None,
+            spectating: false,
+            room_ticket: None,
+            input_delay: shared::INPUT_BUFFER_FRAMES,
+            sync_mode: Default::default(),
+            last_hello: None,
+            verified: None,
+            resync_requested: None,
+            load_frame: None,
+            next_input_seq: 0,
+            next_control_seq: 0,
+            unacked_inputs: Vec::new(),
+            input_rules: Default::default(),
+            last_rejection: None,
+            rejected_controls: 0,
+            last_rejected_control: None,
+            diagnosis: None,
+            diagnosis_requested: None,
+            desync_reports: Default::default(),
+            checkpoint_interval: None,
+            checkpoints: Vec::new(),
+        }
+    }
+
+    /// Asks the server for its whole state once the handshake completes, and doesn't simulate
+    /// until it arrives.
+    pub fn without_state() -> Self {
+        Self {
+            has_state: false,
+            ..Self::new(shared::State::new())
+        }
+    }
+
+    /// Handles a datagram from the server. `now` is measured from an epoch of the caller's
+    /// choosing, which must stay the same for the whole session.
+    pub fn receive(&mut self, datagram: &[u8], now: Duration) -> Result<(), SessionError> {
+        self.now = now;
+        match self
+            .endpoint
+            .unpack::<shared::Recv, _>(&WireCodec::default(), datagram)
+        {
+            Ok(Some((messages, acks))) => {
+                self.redundant.acknowledge(&acks);
+                for message in messages {
+                    self.on_message(message)?;
+                }
+            }
+            Ok(None) => log::trace!("dropping duplicate or stale packet"),
+            Err(err) => log::error!("deserialize error: {}", err),
+        }
+        Ok(())
+    }
+
+    /// Queues the handshake or pings and simulates up to the server's frame. Called once per
+    /// rendered frame, after everything that has arrived has been received.
+    pub fn update(&mut self, now: Duration) {
+        self.now = now;
+        if self.player_id.is_none() && !self.spectating {
+            self.queue_hello();
+            self.flush();
+            return;
+        }
+
+        let client_time = now.as_micros() as u64;
+        self.outbox.push(shared::Send::Ping { client_time });
+        self.latency_buffer.send(client_time, now);
+        if self.sync_mode == shared::lockstep::SyncMode::Strict && self.player_id.is_some() {
+            // inputs from now on are for later frames
+            self.outbox.push(shared::Send::InputsComplete {
+                frame_index: self.inner.frame_index + self.input_delay - 1,
+            });
+        }
+        self.queue_diagnosis_request();
+        if !self.has_state {
+            self.request_resync();
+            self.flush();
+            return;
+        }
+        self.flush();
+
+        if self.inner.frame_index > self.target_frame(now) {
+            return;
+        }
+
+        let mut delay = self.spectator_delay.unwrap_or(0);
+        if let Some(load_frame) = self.load_frame {
+            // the loaded simulation is only in states after the load
+            delay = delay.min(self.server_frame.saturating_sub(load_frame + 1));
+        }
+        while self.inner.frame_index < self.server_frame.saturating_sub(delay) {
+            if self
+                .load_frame
+                .is_some_and(|frame| self.inner.frame_index >= frame)
+            {
+                // only the server has the new simulation
+                self.request_resync();
+                break;
+            }
+            self.inner.step();
+            if let Some(tick_rate) = self.inner.playback.tick_rate {
+                // the server changes its rate on the same frame
+                self.clock.set_tick_rate(tick_rate);
+            }
+            let frame = self.inner.frame_index;
+            let hash = self.inner.hash();
+            log::trace!("{}, {:?}", hash, self.inner);
+            if let Some(interval) = self.checkpoint_interval {
+                if frame % interval == 0 {
+                    self.checkpoints.push((frame, hash));
+                }
+            }
+            match self.hash_buffer.take(frame, hash) {
+                Some(_) => {
+                    self.hash_successes += 1;
+                    self.verified = Some(self.inner.clone());
+                }
+                // the server's hash for this frame may have been lost
+                None if self.hash_buffer.by_frame(frame).next().is_none() => {}
+                None => {
+                    self.hash_failures += 1;
+                    if self.diagnosis.is_none() {
+                        self.diagnosis =
+                            Some(shared::hash_tree::Diagnosis::new(self.inner.clone()));
+                        self.diagnosis_requested = None;
+                    }
+                    self.request_resync();
+                }
+            }
+        }
+    }
+
+    /// The datagrams flushed since the last call, in the order they should be sent.
+    pub fn take_datagrams(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.datagrams)
+    }
+
+    /// Keeps the hash of every frame that's a multiple of `interval` that we simulate, to be
+    /// collected with `take_checkpoints`. Frames skipped over by a resync aren't kept.
+    pub fn set_checkpoint_interval(&mut self, interval: shared::FrameIndex) {
+        assert!(interval > 0);
+        self.checkpoint_interval = Some(interval);
+    }
+
+    /// The frames and hashes kept since the last call, oldest first.
+    pub fn take_checkpoints(&mut self) -> Vec<(shared::FrameIndex, u64)> {
+        std::mem::take(&mut self.checkpoints)
+    }
+
+    /// Sets the name sent to the server. Only takes effect before the handshake completes.
+    pub fn set_client_name(&mut self, mut client_name: String) {
+        while client_name.len() > shared::MAX_CLIENT_NAME_LEN {
+            client_name.pop();
+        }
+        self.client_name = client_name;
+    }
+
+    /// Joins as a spectator instead of a player, playing `delay` frames behind the server.
+    /// Only takes effect before the handshake completes.
+    pub fn spectate(&mut self, delay: shared::FrameIndex) {
+        self.spectator_delay = Some(delay);
+    }
+
+    /// Enters the room the ticket was issued for. Only takes effect before the handshake
+    /// completes.
+    pub fn set_ticket(&mut self, ticket: shared::RoomTicket) {
+        self.room_ticket = Some(ticket);
+    }
+
+    fn on_message(&mut self, message: shared::Recv) -> Result<(), SessionError> {
+        match message {
+            shared::Recv::Welcome {
+                player_id,
+                tick_rate,
+                input_delay,
+                server_frame,
+                sync_mode,
+                input_rules,
+            } => {
+                log::info!(
+                    "joined as player {} at frame {}. tick rate: {}, input delay: {}, sync mode: {:?}",
+                    player_id,
+                    server_frame,
+                    tick_rate,
+                    input_delay,
+                    sync_mode
+                );
+                self.player_id = Some(player_id);
+                self.input_delay = input_delay;
+                self.sync_mode = sync_mode;
+                self.input_rules = input_rules;
+                self.clock.set_tick_rate(tick_rate);
+                self.server_frame = self.server_frame.max(server_frame);
+            }
+            shared::Recv::Spectating {
+                tick_rate,
+                server_frame,
+            } => {
+                log::info!(
+                    "spectating at frame {}. tick rate: {}",
+                    server_frame,
+                    tick_rate
+                );
+                self.spectating = true;
+                self.clock.set_tick_rate(tick_rate);
+                self.server_frame = self.server_frame.max(server_frame);
+            }
+            shared::Recv::Rejected(reason) => {
+                return Err(SessionError::Rejected(reason));
+            }
+            shared::Recv::Pong {
+                client_time,
+                server_time,
+                server_frame,
+            } => {
+                self.latency_buffer.recv(client_time, self.now);
+                self.clock.on_pong(
+                    Duration::from_micros(client_time),
+                    Duration::from_micros(server_time),
+                    server_frame,
+                    self.now,
+                );
+            }
+            shared::Recv::StateHash(shared::IndexedState {
+                frame_index,
+                state: hash,
+            }) => {
+                self.server_frame = self.server_frame.max(frame_index);
+                self.hash_buffer.insert(frame_index, hash);
+            }
+            shared::Recv::FullState(state) => self.resync(state),
+            shared::Recv::StateDelta(delta) => {
+                let applied = match &self.verified {
+                    Some(baseline) => delta.apply(baseline),
+                    None => Err(shared::snapshot::DeltaError::WrongBaseline {
+                        expected: delta.baseline_frame,
+                        actual: self.inner.frame_index,
+                    }),
+                };
+                match applied {
+                    Ok(state) => self.resync(state),
+                    Err(err) => {
+                        log::warn!("could not apply state delta: {}", err);
+                        self.verified = None;
+                        self.resync_requested = None;
+                        self.request_resync();
+                    }
+                }
+            }
+            shared::Recv::InputState {
+                player_id,
+                client_seq,
+                input,
+            } => {
+                // a resync can bring an input before its first copy gets here
+                let buffered = self.inner.input_buffer.iter().any(|other| *other == input);
+                if self.inputs_seen.insert(player_id, client_seq) && !buffered {
+                    self.inner.input_buffer.push(input);
+                }
+            }
+            shared::Recv::InputAck {
+                client_seq,
+                applied_frame,
+            } => self.on_input_ack(client_seq, applied_frame),
+            shared::Recv::HashTree(_)
+            | shared::Recv::BodyHashes { .. }
+            | shared::Recv::Bodies { .. }
+            | shared::Recv::FrameUnavailable(_) => self.on_diagnosis_message(&message),
+            shared::Recv::InputRejected { client_seq, reason } => {
+                self.on_input_rejected(client_seq, reason)
+            }
+            shared::Recv::ControlRejected { client_seq, reason } => {
+                // nothing to undo, controls only apply once the server schedules them
+                if self.last_rejected_control >= Some(client_seq) {
+                    log::trace!("ignoring repeated rejection of control {}", client_seq);
+                } else {
+                    log::warn!("control {} was rejected: {}", client_seq, reason);
+                    self.last_rejected_control = Some(client_seq);
+                    self.rejected_controls += 1;
+                }
+            }
+            shared::Recv::Control(control) => {
+                // a control that arrives too late shows up as a hash mismatch and a resync
+                if !self.inner.schedule_control(control) {
+                    log::trace!("ignoring repeated or late control {:?}", control);
+                }
+            }
+            shared::Recv::Load { frame_index } => {
+                // repeats can still arrive once we've matched a hash after the load
+                let verified = self.verified.as_ref().map(|state| state.frame_index);
+                if verified.is_some_and(|verified| verified > frame_index) {
+                    log::trace!("ignoring repeated load for frame {}", frame_index);
+                } else {
+                    log::debug!("the server loads a new simulation on frame {}", frame_index);
+                    self.load_frame = self.load_frame.max(Some(frame_index));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn on_diagnosis_message(&mut self, message: &shared::Recv) {
+        const MAX_REPORTS: usize = 10;
+        let diagnosis = match &mut self.diagnosis {
+            Some(diagnosis) => diagnosis,
+            None => return,
+        };
+        if !diagnosis.on_message(message) {
+            return;
+        }
+        // the next request can go out straight away
+        self.diagnosis_requested = None;
+        if let Some(report) = diagnosis.report() {
+            log::warn!("{}", report);
+            if self.desync_reports.len() == MAX_REPORTS {
+                self.desync_reports.pop_front();
+            }
+            self.desync_reports.push_back(report.clone());
+            self.diagnosis = None;
+        }
+    }
+
+    /// Sends the pending diagnosis request, repeating it if the response seems to be lost.
+    fn queue_diagnosis_request(&mut self) {
+        const DIAGNOSIS_RETRY: Duration = Duration::from_secs(1);
+        if let Some(requested) = self.diagnosis_requested {
+            if self.now.saturating_sub(requested) < DIAGNOSIS_RETRY {
+                return;
+            }
+        }
+        if let Some(request) = self.diagnosis.as_ref().and_then(|d| d.request()) {
+            self.outbox.push(request);
+            self.diagnosis_requested = Some(self.now);
+        }
+    }
+
+    /// Asks the server for its current state, or the one a spectator's delay behind it, delta
+    /// encoded against the last frame we know matched. Repeated requests are rate limited
+    /// since the response may be lost.
+    fn request_resync(&mut self) {
+        const RESYNC_RETRY: Duration = Duration::from_secs(1);
+        if let Some(requested) = self.resync_requested {
+            if self.now.saturating_sub(requested) < RESYNC_RETRY {
+                return;
+            }
+        }
+
+        let baseline = self.verified.as_ref().map(|state| state.frame_index);
+        let mut delay = self.spectator_delay.unwrap_or(0);
+        if let Some(load_frame) = self.load_frame {
+            // the loaded simulation is only in states after the load
+            delay = delay.min(self.server_frame.saturating_sub(load_frame + 1));
+        }
+        log::info!("requesting resync from baseline {:?}", baseline);
+        self.outbox
+            .push(shared::Send::RequestState { baseline, delay });
+        self.resync_requested = Some(self.now);
+    }
+
+    fn resync(&mut self, state: shared::State) {
+        if self.resync_requested.is_none() {
+            log::debug!(
+                "ignoring unrequested resync for frame {}",
+                state.frame_index
+            );
+            return;
+        }
+        log::info!(
+            "resynced from frame {} to {}",
+            self.inner.frame_index,
+            state.frame_index
+        );
+        self.server_frame = self.server_frame.max(state.frame_index);
+        if self
+            .load_frame
+            .is_some_and(|frame| state.frame_index > frame)
+        {
+            self.load_frame = None;
+        }
+        self.hash_buffer.remove_through(state.frame_index);
+        self.verified = Some(state.clone());
+        self.inner = state;
+        self.has_state = true;
+        self.resync_requested = None;
+    }
+
+    /// Packs everything in the outbox into as few datagrams as possible.
+    fn flush(&mut self) {
+        let messages = std::mem::take(&mut self.outbox);
+        let datagrams = self
+            .redundant
+            .pack(&WireCodec::default(), &mut self.endpoint, &messages);
+        self.datagrams.extend(datagrams);
+    }
+
+    fn queue_hello(&mut self) {
+        const HELLO_RESEND: Duration = Duration::from_millis(500);
+        if let Some(last_hello) = self.last_hello {
+            if self.now.saturating_sub(last_hello) < HELLO_RESEND {
+                return;
+            }
+        }
+
+        // ahead of the handshake so the server knows which room it's for
+        if let Some(ticket) = self.room_ticket {
+            self.outbox.push(shared::Send::Enter { ticket });
+        }
+        let protocol_version = shared::PROTOCOL_VERSION;
+        let client_name = self.client_name.clone();
+        self.outbox.push(match self.spectator_delay {
+            Some(_) => shared::Send::Spectate {
+                protocol_version,
+                client_name,
+            },
+            None => shared::Send::Hello {
+                protocol_version,
+                client_name,
+            },
+        });
+        self.last_hello = Some(self.now);
+    }
+
+    /// Adds a body, predicting it locally and sending it to the server. Inputs that the
+    /// server would reject are refused here instead, with the reason in `last_rejection`.
+    pub fn add_body(&mut self, event: shared::AddBodyEvent) {
+        if self.player_id.is_none() {
+            log::warn!("ignoring input before the server has accepted us as a player");
+            return;
+        }
+        if let Err(reason) = self.input_rules.validate(&event, &self.inner) {
+            log::warn!("not sending invalid input: {}", reason);
+            self.last_rejection = Some(reason);
+            return;
+        }
+        self.last_rejection = None;
+        // TODO: should this frame index be based off our guess of the server's frame index?
+        let input_event = shared::IndexedState {
+            frame_index: self.inner.frame_index + self.input_delay,
+            state: event,
+        };
+        let client_seq = self.next_input_seq;
+        self.next_input_seq = self.next_input_seq.wrapping_add(1);
+        self.inner.input_buffer.push(input_event);
+        self.unacked_inputs.push((client_seq, input_event));
+        self.redundant.push(shared::Send::InputState {
+            client_seq,
+            input: input_event,
+        });
+        self.flush();
+    }
+
+    /// Asks the server to schedule a control for everyone.
+    pub fn send_control(&mut self, control: Control) {
+        if self.player_id.is_none() {
+            log::warn!("ignoring control before the server has accepted us as a player");
+            return;
+        }
+        let client_seq = self.next_control_seq;
+        self.next_control_seq = self.next_control_seq.wrapping_add(1);
+        self.redundant.push(shared::Send::Control {
+            client_seq,
+            control,
+        });
+        self.flush();
+    }
+
+    fn on_input_ack(
+        &mut self,
+        client_seq: shared::InputSequence,
+        applied_frame: shared::FrameIndex,
+    ) {
+        let index = match self
+            .unacked_inputs
+            .iter()
+            .position(|(seq, _)| *seq == client_seq)
+        {
+            Some(index) => index,
+            None => return,
+        };
+        let (_, input) = self.unacked_inputs.swap_remove(index);
+        if input.frame_index == applied_frame {
+            return;
+        }
+
+        let rescheduled = self.inner.input_buffer.reschedule(&input, applied_frame);
+        if !rescheduled || applied_frame < self.inner.frame_index {
+            // we've applied it on the wrong frame, or simulated its frame without it. the
+            // server's state has it on the right one
+            log::warn!(
+                "input {} was applied on frame {} which we've already simulated. current frame: {}",
+                client_seq,
+                applied_frame,
+                self.inner.frame_index
+            );
+            self.request_resync();
+        }
+    }
+
+    fn on_input_rejected(
+        &mut self,
+        client_seq: shared::InputSequence,
+        reason: shared::InputRejected,
+    ) {
+        let index = match self
+            .unacked_inputs
+            .iter()
+            .position(|(seq, _)| *seq == client_seq)
+        {
+            Some(index) => index,
+            None => return,
+        };
+        let (_, input) = self.unacked_inputs.swap_remove(index);
+        log::warn!("input {} was rejected: {}", client_seq, reason);
+        self.last_rejection = Some(reason);
+
+        if !self.inner.input_buffer.remove(&input) {
+            // we've already added the body so our state is wrong until we resync
+            self.request_resync();
+        }
+    }
+
+    pub fn state(&self) -> &shared::State {
+        &self.inner
+    }
+
+    /// False until the server has sent a state to start from.
+    pub fn has_state(&self) -> bool {
+        self.has_state
+    }
+
+    pub fn latency(&self) -> Duration {
+        self.latency_buffer.average_latency()
+    }
+
+    pub fn packet_loss(&self) -> f32 {
+        self.latency_buffer.packet_loss()
+    }
+
+    /// Our best guess at the frame the server is on at `now`.
+    pub fn target_frame(&self, now: Duration) -> shared::FrameIndex {
+        self.clock
+            .predict_frame(now)
+            .map_or(self.server_frame, |prediction| prediction.frame)
+            .max(self.server_frame)
+    }
+
+    /// Half the width of the interval that the server's frame is expected to be in at `now`.
+    pub fn target_frame_error(&self, now: Duration) -> shared::FrameIndex {
+        self.clock.predict_frame(now).map_or(0, |prediction| {
+            (prediction.latest - prediction.earliest).div_ceil(2)
+        })
+    }
+
+    /// Why our most recent input was refused, if it was.
+    pub fn last_rejection(&self) -> Option<shared::InputRejected> {
+        self.last_rejection
+    }
+
+    pub fn rejected_controls(&self) -> u32 {
+        self.rejected_controls
+    }
+
+    pub fn input_rules(&self) -> &shared::validation::InputRules {
+        &self.input_rules
+    }
+
+    pub fn spectating(&self) -> bool {
+        self.spectating
+    }
+
+    pub fn player_id(&self) -> Option<shared::PlayerId> {
+        self.player_id
+    }
+
+    pub fn hash_successes(&self) -> u32 {
+        self.hash_successes
+    }
+
+    pub fn hash_failures(&self) -> u32 {
+        self.hash_failures
+    }
+
+    /// The most recent desync reports, oldest first.
+    pub fn desync_reports(&self) -> &std::collections::VecDeque<shared::hash_tree::DesyncReport> {
+        &self.desync_reports
+    }
+}
+
+struct HashBufferEntry(shared::FrameIndex, u64);
+
+#[derive(Default)]
+struct HashBuffer(Vec<HashBufferEntry>);
+
+impl HashBuffer {
+    pub fn insert(&mut self, frame_index: shared::FrameIndex, hash: u64) {
+        self.0.push(HashBufferEntry(frame_index, hash))
+    }
+
+    pub fn contains(&self, frame_index: shared::FrameIndex, hash: u64) -> bool {
+        self.0
+            .iter()
+            .find(|HashBufferEntry(i, h)| frame_index == *i && hash == *h)
+            .is_some()
+    }
+
+    pub fn by_frame(&self, frame_index: shared::FrameIndex) -> impl Iterator<Item = &u64> {
+        self.0
+            .iter()
+            .filter_map(move |HashBufferEntry(i, h)| if frame_index == *i { Some(h) } else { None })
+    }
+
+    pub fn take(
+        &mut self,
+        frame_index: shared::FrameIndex,
+        hash: u64,
+    ) -> Option<(shared::FrameIndex, u64)> {
+        match self
+            .0
+            .iter()
+            .position(|HashBufferEntry(i, h)| frame_index == *i && hash == *h)
+        {
+            None => None,
+            Some(index) => {
+                let entry = self.0.swap_remove(index);
+                Some((entry.0, entry.1))
+            }
+        }
+    }
+
+    /// Drops every entry up to and including `frame_index`.
+    pub fn remove_through(&mut self, frame_index: shared::FrameIndex) {
+        self.0.retain(|HashBufferEntry(i, _)| *i > frame_index)
+    }
+
+    /// exclusive search
+    pub fn unmatched_hashed(&self, cutoff: shared::FrameIndex) -> usize {
+        self.0.iter().fold(
+            0,
+            |acc, HashBufferEntry(index, _)| {
+                if *index < cutoff {
+                    acc + 1
+                } else {
+                    acc
+                }
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joined() -> Session {
+        let mut session = Session::new(shared::State::new());
+        let welcome = shared::Recv::Welcome {
+            player_id: 0,
+            tick_rate: 60,
+            input_delay: 2,
+            server_frame: 0,
+            sync_mode: Default::default(),
+            input_rules: Default::default(),
+        };
+        session.on_message(welcome).unwrap();
+        session
+    }
+
+    fn run_to(session: &mut Session, frame_index: shared::FrameIndex) {
+        session.server_frame = frame_index;
+        session.update(Duration::default());
+        assert_eq!(session.inner.frame_index, frame_index);
+    }
+
+    #[test]
+    fn acks_move_inputs_to_the_servers_frame() {
+        let mut session = joined();
+        session.add_body(shared::AddBodyEvent::new(800., 0., 20.));
+        session
+            .on_message(shared::Recv::InputAck {
+                client_seq: 0,
+                applied_frame: 4,
+            })
+            .unwrap();
+
+        run_to(&mut session, 3);
+        assert!(session.inner.simulation.bodies.is_empty());
+        run_to(&mut session, 5);
+        assert_eq!(session.inner.simulation.bodies.len(), 1);
+        assert_eq!(session.resync_requested, None);
+    }
+
+    #[test]
+    fn late_acks_resync_instead_of_applying_the_input_again() {
+        let mut session = joined();
+        session.add_body(shared::AddBodyEvent::new(800., 0., 20.));
+        run_to(&mut session, 5);
+        assert_eq!(session.inner.simulation.bodies.len(), 1);
+
+        session
+            .on_message(shared::Recv::InputAck {
+                client_seq: 0,
+                applied_frame: 3,
+            })
+            .unwrap();
+        assert!(session.resync_requested.is_some());
+        assert!(session.outbox.contains(&shared::Send::RequestState {
+            baseline: None,
+            delay: 0
+        }));
+        run_to(&mut session, 8);
+        assert_eq!(session.inner.simulation.bodies.len(), 1);
+    }
+}
@@ -0,0 +1,27 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use shared::codec::{Bincode, BitPacked, Budgeted, Codec, Postcard, WireCodec};
+use shared::{Recv, State};
+
+fn check<C: Codec<Recv>>(codec: &C, data: &[u8]) {
+    if let Ok((message, _)) = codec.decode(data) {
+        let mut buf = Vec::new();
+        let _ = codec.encode(&message, &mut buf);
+        match message {
+            Recv::StateDelta(delta) => {
+                let _ = delta.apply(&State::new());
+            }
+            Recv::FullState(state) => {
+                let _ = State::decode(&state.encode());
+            }
+            _ => {}
+        }
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    check(&WireCodec::default(), data);
+    check(&BitPacked, data);
+    check(&Budgeted(Bincode), data);
+    check(&Budgeted(Postcard), data);
+});
@@ -0,0 +1,18 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use shared::codec::{Bincode, BitPacked, Budgeted, Codec, Postcard, WireCodec};
+use shared::Send;
+
+fn check<C: Codec<Send>>(codec: &C, data: &[u8]) {
+    if let Ok((message, _)) = codec.decode(data) {
+        let mut buf = Vec::new();
+        let _ = codec.encode(&message, &mut buf);
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    check(&WireCodec::default(), data);
+    check(&BitPacked, data);
+    check(&Budgeted(Bincode), data);
+    check(&Budgeted(Postcard), data);
+});
@@ -0,0 +1,15 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use shared::codec::{Bincode, Codec};
+use shared::State;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(state) = State::decode(data) {
+        let decoded = State::decode(&state.encode()).expect("re-encoded state should decode");
+        assert_eq!(decoded.hash(), state.hash());
+    }
+    // the initial state clients fetch over http
+    if let Ok(((_, state), _)) = Codec::<(u64, State)>::decode(&Bincode, data) {
+        let _ = state.hash();
+    }
+});
@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use shared::codec::WireCodec;
+use shared::packet::{PacketEndpoint, PacketHeader};
+use shared::{Recv, Send};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = PacketHeader::read(data);
+    let _ = PacketEndpoint::new().unpack::<Send, _>(&WireCodec::default(), data);
+    let _ = PacketEndpoint::new().unpack::<Recv, _>(&WireCodec::default(), data);
+});
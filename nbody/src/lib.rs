@@ -3,25 +3,28 @@ use serde::{Deserialize, Serialize};
 
 pub type Point2D = nalgebra::Point2<Float>;
 pub type Vector2D = nalgebra::Vector2<Float>;
+pub type BodyId = u64;
 
 fixed::const_fixed_from_int! {
-    const DENSITY: Float = 1;
-    const TICK: Float = 1;
+    pub const DENSITY: Float = 1;
+    pub const TICK: Float = 1;
 }
-static ID_COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+/// 0.1, rounded to the nearest representable value
+pub const GRAVITY: Float = Float::from_bits(429_496_730);
+static ID_COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
 
 fn zero_vec() -> Vector2D {
     Vector2D::new(Float::from_bits(0), Float::from_bits(0))
 }
-fn new_id() -> usize {
+fn new_id() -> BodyId {
     ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
 }
 
 #[derive(Copy, Clone, Debug, Eq, Serialize, Deserialize)]
 pub struct Body {
-    /// used for internal comparisons only. unique on a single client but not globally.
-    #[serde(skip, default = "new_id")]
-    id: usize,
+    /// unique within a simulation. reassigned by `Simulation::add_body` so that the same body
+    /// has the same id on every peer.
+    id: BodyId,
     collided: bool,
     pub position: Point2D,
     pub velocity: Vector2D,
@@ -48,6 +51,34 @@ impl Body {
             Float::from_num(mass),
         )
     }
+
+    /// Rebuilds a body exactly, e.g. when decoding a snapshot.
+    pub fn from_parts(
+        id: BodyId,
+        collided: bool,
+        position: Point2D,
+        velocity: Vector2D,
+        acceleration: Vector2D,
+        mass: Float,
+    ) -> Self {
+        Self {
+            id,
+            collided,
+            position,
+            velocity,
+            acceleration,
+            mass,
+        }
+    }
+
+    pub fn id(&self) -> BodyId {
+        self.id
+    }
+
+    /// Set during a step for bodies that merged into a new body.
+    pub fn collided(&self) -> bool {
+        self.collided
+    }
 }
 
 impl std::cmp::PartialEq for Body {
@@ -58,6 +89,8 @@ impl std::cmp::PartialEq for Body {
 
 impl std::hash::Hash for Body {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+        self.collided.hash(state);
         self.position.x.hash(state);
         self.position.y.hash(state);
         self.velocity.x.hash(state);
@@ -110,8 +143,7 @@ impl Body {
         if r == Float::from_bits(0) {
             zero_vec()
         } else {
-            let gravity = Float::from_num(0.1);
-            diff * gravity * other.mass / r.saturating_mul(r).saturating_mul(r)
+            diff * GRAVITY * other.mass / r.saturating_mul(r).saturating_mul(r)
         }
     }
 }
@@ -119,6 +151,9 @@ impl Body {
 #[derive(Clone, Debug, Eq, Default, Serialize, Deserialize)]
 pub struct Simulation {
     pub bodies: Vec<Body>,
+    /// the id given to the next body added to the simulation
+    #[serde(default)]
+    next_id: BodyId,
 }
 
 impl std::cmp::PartialEq for Simulation {
@@ -140,10 +175,33 @@ impl Simulation {
         Default::default()
     }
 
-    pub fn add_body(&mut self, body: Body) {
+    pub fn add_body(&mut self, mut body: Body) {
+        body.id = self.take_id();
         self.bodies.push(body)
     }
 
+    pub fn next_id(&self) -> BodyId {
+        self.next_id
+    }
+
+    /// Used when rebuilding a simulation from a snapshot.
+    pub fn set_next_id(&mut self, next_id: BodyId) {
+        self.next_id = next_id;
+    }
+
+    /// Removes the body with the given id, keeping the others in order. Returns it if it
+    /// existed.
+    pub fn remove_body(&mut self, id: BodyId) -> Option<Body> {
+        let index = self.bodies.iter().position(|body| body.id == id)?;
+        Some(self.bodies.remove(index))
+    }
+
+    fn take_id(&mut self) -> BodyId {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
     pub fn step(&mut self) {
         let mut new_bodies = Vec::new();
         for i in 0..self.bodies.len() {
@@ -165,6 +223,7 @@ impl Simulation {
                             + (body2.velocity * body2.mass))
                             / sum_mass;
                         let mut new_body = Body::new(new_position.x, new_position.y, sum_mass);
+                        new_body.id = self.take_id();
                         new_body.velocity = new_velocity;
                         new_bodies.push(new_body);
 
@@ -172,8 +231,7 @@ impl Simulation {
                         self.bodies[j].collided = true;
                     }
 
-                    let gravity = Float::from_num(0.1);
-                    let accel = gravity * self.bodies[j].mass / (mag * mag);
+                    let accel = GRAVITY * self.bodies[j].mass / (mag * mag);
                     acc += (diff / mag) * accel;
                 }
             }
@@ -223,6 +281,11 @@ mod tests {
         assert_eq!(b1.force_from(&b2), zero_vec())
     }
 
+    #[test]
+    fn gravity_constant() {
+        assert_eq!(GRAVITY, Float::from_num(0.1));
+    }
+
     #[test]
     fn mass_volume_density_radius() {
         let b1 = Body::new_lossy(0., 0., 1.);
@@ -348,6 +411,24 @@ mod tests {
         assert!(b2.position.x > b3.position.x);
     }
 
+    #[test]
+    fn ids_are_deterministic() {
+        let build = || {
+            let mut sim = Simulation::new();
+            sim.add_body(Body::new_lossy(0., 0., 1.));
+            sim.add_body(Body::new_lossy(1., 0., 1.));
+            sim.add_body(Body::new_lossy(100., 0., 1.));
+            sim.step();
+            sim
+        };
+        let ids = |sim: &Simulation| sim.bodies.iter().map(Body::id).collect::<Vec<_>>();
+
+        let sim = build();
+        assert_eq!(ids(&sim), vec![2, 3]);
+        assert_eq!(ids(&sim), ids(&build()));
+        assert_eq!(sim.next_id(), 4);
+    }
+
     #[test]
     fn center_of_mass() {
         let b1 = Body::new_lossy(0., 0., 1.);
@@ -0,0 +1,290 @@
+//! Endpoints for whoever runs the server to change a room without restarting it.
+//!
+//! `POST /admin/rooms/{id}` takes an `AdminCommand` as JSON and `POST /admin/rooms/{id}/snapshot`
+//! takes a state saved with `State::encode` and loads its simulation. Everything that changes
+//! the simulation is sent to the room's tick task and scheduled like a player's control, so
+//! clients apply it on the same frame as the server and stay in sync. A loaded simulation is
+//! too large to send the same way, so clients resync to it once the server has loaded it.
+//!
+//! The endpoints are only served when `admin_token` is set, and every request must carry it in
+//! an `Authorization: Bearer` header. Creating a room with `POST /rooms` needs it too, see
+//! `lobby`.
+
+use crate::config::Scenario;
+use crate::lobby::{LobbyError, SharedLobby};
+use crate::room::{Request, RoomId};
+use serde::Deserialize;
+use shared::control::{Control, MAX_TICK_RATE, MIN_TICK_RATE};
+use shared::nbody::BodyId;
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "command", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AdminCommand {
+    Pause,
+    Resume,
+    /// Runs this many simulation steps while paused.
+    Step {
+        steps: u32,
+    },
+    /// Replaces the simulation with the scenario's. File scenarios can only be loaded from the
+    /// config, use the snapshot endpoint to load a saved state.
+    Reset {
+        scenario: Scenario,
+    },
+    RemoveBody {
+        body_id: BodyId,
+    },
+    /// Disconnects a player. It can rejoin.
+    Kick {
+        player_id: shared::PlayerId,
+    },
+    TickRate {
+        tick_rate: u32,
+    },
+}
+
+#[derive(Debug)]
+pub enum AdminError {
+    Lobby(LobbyError),
+    InvalidTickRate(u32),
+    InvalidSnapshot(shared::EncodingError),
+    /// Only players in the room can be kicked from it.
+    NoSuchPlayer(shared::PlayerId),
+    /// The room's tick task has stopped, which only happens as the server shuts down.
+    RoomStopped,
+}
+
+impl std::fmt::Display for AdminError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdminError::Lobby(err) => write!(f, "{}", err),
+            AdminError::InvalidTickRate(tick_rate) => write!(
+                f,
+                "tick rate {} is not between {} and {}",
+                tick_rate, MIN_TICK_RATE, MAX_TICK_RATE
+            ),
+            AdminError::InvalidSnapshot(err) => write!(f, "invalid snapshot: {}", err),
+            AdminError::NoSuchPlayer(player_id) => {
+                write!(f, "player {} is not in the room", player_id)
+            }
+            AdminError::RoomStopped => write!(f, "the room has stopped"),
+        }
+    }
+}
+
+impl AdminError {
+    fn status(&self) -> StatusCode {
+        match self {
+            AdminError::Lobby(err) => err.status(),
+            AdminError::InvalidTickRate(_) | AdminError::InvalidSnapshot(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            AdminError::NoSuchPlayer(_) => StatusCode::NOT_FOUND,
+            AdminError::RoomStopped => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl From<LobbyError> for AdminError {
+    fn from(err: LobbyError) -> Self {
+        AdminError::Lobby(err)
+    }
+}
+
+impl AdminCommand {
+    fn request(self) -> Result<Request, AdminError> {
+        Ok(match self {
+            AdminCommand::Pause => Request::Control(Control::Pause),
+            AdminCommand::Resume => Request::Control(Control::Resume),
+            AdminCommand::Step { steps } => Request::Control(Control::Step(steps)),
+            AdminCommand::Reset {
+                scenario: Scenario::File(_),
+            } => return Err(LobbyError::FileScenario.into()),
+            // only file scenarios can fail
+            AdminCommand::Reset { scenario } => {
+                Request::Load(scenario.state().unwrap_or_default().simulation)
+            }
+            AdminCommand::RemoveBody { body_id } => Request::Control(Control::RemoveBody(body_id)),
+            AdminCommand::Kick { player_id } => Request::Kick(player_id),
+            AdminCommand::TickRate { tick_rate } => {
+                if !(MIN_TICK_RATE..=MAX_TICK_RATE).contains(&tick_rate) {
+                    return Err(AdminError::InvalidTickRate(tick_rate));
+                }
+                Request::Control(Control::TickRate(tick_rate))
+            }
+        })
+    }
+}
+
+/// Compares in time that only depends on the lengths, so the token can't be guessed a byte at
+/// a time.
+fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Checks the `Authorization` header against the token. Without a token the endpoints don't
+/// exist.
+fn authorize(token: Option<&str>, header: Option<&str>) -> Result<(), StatusCode> {
+    let token = token.ok_or(StatusCode::NOT_FOUND)?;
+    let given = header
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .unwrap_or("");
+    if same_bytes(given.as_bytes(), token.as_bytes()) {
+        Ok(())
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+fn send(lobby: &SharedLobby, id: RoomId, request: Request) -> Result<(), AdminError> {
+    let requests = lobby.lock().unwrap().room(id)?.requests.clone();
+    requests.send(request).map_err(|_| AdminError::RoomStopped)
+}
+
+fn reply(result: Result<(), AdminError>) -> warp::reply::Response {
+    match result {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(err) => {
+            log::warn!("admin request failed: {}", err);
+            warp::reply::with_status(err.to_string(), err.status()).into_response()
+        }
+    }
+}
+
+fn command(lobby: &SharedLobby, id: RoomId, command: AdminCommand) -> Result<(), AdminError> {
+    log::info!("admin command for room {}: {:?}", id, command);
+    if let AdminCommand::Kick { player_id } = command {
+        if !lobby.lock().unwrap().has_player(id, player_id)? {
+            return Err(AdminError::NoSuchPlayer(player_id));
+        }
+    }
+    send(lobby, id, command.request()?)
+}
+
+fn snapshot(lobby: &SharedLobby, id: RoomId, bytes: &[u8]) -> Result<(), AdminError> {
+    let state = shared::State::decode(bytes).map_err(AdminError::InvalidSnapshot)?;
+    log::info!(
+        "admin loading a snapshot of frame {} with {} bodies into room {}",
+        state.frame_index,
+        state.simulation.bodies.len(),
+        id
+    );
+    send(lobby, id, Request::Load(state.simulation))
+}
+
+/// Rejects requests that don't carry the token, see `authorize`. Routes using it need
+/// `recover_unauthorized` to reply with the status.
+pub(crate) fn authorized(
+    token: Option<String>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    let token: Arc<Option<String>> = Arc::new(token);
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let token = token.clone();
+            async move {
+                authorize(token.as_deref(), header.as_deref())
+                    .map_err(|status| warp::reject::custom(Unauthorized(status)))
+            }
+        })
+        .untuple_one()
+}
+
+pub(crate) async fn recover_unauthorized(
+    rejection: warp::Rejection,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    match rejection.find::<Unauthorized>() {
+        Some(Unauthorized(status)) => Ok((*status).into_response()),
+        None => Err(rejection),
+    }
+}
+
+/// `POST /admin/rooms/{id}` and `POST /admin/rooms/{id}/snapshot`, which reply 202 Accepted
+/// once the room has the request, or 404 to kick a player who isn't in the room. They reply
+/// 404 unless `token` is set and 401 unless the request carries it.
+pub fn routes(
+    lobby: SharedLobby,
+    token: Option<String>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let with_lobby = warp::any().map(move || lobby.clone());
+    let authorized = authorized(token);
+    let room = warp::path("admin")
+        .and(warp::path("rooms"))
+        .and(warp::path::param::<RoomId>());
+
+    let command = warp::post()
+        .and(room)
+        .and(warp::path::end())
+        .and(authorized.clone())
+        .and(with_lobby.clone())
+        .and(warp::body::json())
+        .map(|id: RoomId, lobby: SharedLobby, c: AdminCommand| reply(command(&lobby, id, c)));
+    let snapshot = warp::post()
+        .and(room)
+        .and(warp::path("snapshot"))
+        .and(warp::path::end())
+        .and(authorized)
+        .and(with_lobby)
+        .and(warp::body::content_length_limit(
+            shared::codec::MAX_MESSAGE_SIZE as u64,
+        ))
+        .and(warp::body::bytes())
+        .map(|id: RoomId, lobby: SharedLobby, bytes: bytes::Bytes| {
+            reply(snapshot(&lobby, id, &bytes))
+        });
+
+    command
+        .or(snapshot)
+        .unify()
+        .recover(recover_unauthorized)
+        .unify()
+}
+
+#[derive(Debug)]
+struct Unauthorized(StatusCode);
+
+impl warp::reject::Reject for Unauthorized {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_need_the_token() {
+        assert_eq!(
+            authorize(None, Some("Bearer hunter2")),
+            Err(StatusCode::NOT_FOUND)
+        );
+        assert_eq!(authorize(Some("hunter2"), Some("Bearer hunter2")), Ok(()));
+        assert_eq!(
+            authorize(Some("hunter2"), Some("Bearer hunter3")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            authorize(Some("hunter2"), Some("hunter2")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            authorize(Some("hunter2"), None),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn invalid_commands_are_refused() {
+        let file = AdminCommand::Reset {
+            scenario: Scenario::File("saves/galaxy.state".into()),
+        };
+        assert!(matches!(
+            file.request(),
+            Err(AdminError::Lobby(LobbyError::FileScenario))
+        ));
+        let too_fast = AdminCommand::TickRate { tick_rate: 1000 };
+        assert!(matches!(
+            too_fast.request(),
+            Err(AdminError::InvalidTickRate(1000))
+        ));
+    }
+}
@@ -0,0 +1,629 @@
+//! The server's main loop. It routes datagrams from the transport to rooms and everything
+//! the rooms schedule back out to their peers.
+
+use crate::config::AppConfig;
+use crate::lobby::{Lobby, LobbyError, SharedLobby};
+use crate::metrics::{Counter, MessageMetrics, Metered, Registry, SharedRegistry};
+use crate::peer::{PeerCounts, Peers, RoomPeers};
+use crate::room::{ClientInput, Event, Request, RoomHandle, RoomId, RoomSettings, Scheduled};
+use crate::transport::{Transport, TransportEvent};
+use shared::codec::WireCodec;
+use shared::hash_tree;
+use shared::snapshot::{SnapshotHistory, StateDelta};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// How many recent frames are kept for delta encoding, about four seconds.
+const SNAPSHOT_HISTORY: usize = 256;
+
+/// The main loop's series in the metrics registry.
+struct AppMetrics {
+    registry: SharedRegistry,
+    received: MessageMetrics,
+    sent: MessageMetrics,
+    decode_errors: Counter,
+    stale_packets: Counter,
+}
+
+impl AppMetrics {
+    fn new(registry: SharedRegistry) -> Self {
+        Self {
+            received: MessageMetrics::new(registry.clone(), "in"),
+            sent: MessageMetrics::new(registry.clone(), "out"),
+            decode_errors: registry.counter(
+                "lockstep_decode_errors_total",
+                "Datagrams that couldn't be decoded.",
+                &[],
+            ),
+            stale_packets: registry.counter(
+                "lockstep_stale_packets_total",
+                "Duplicate or out of date packets that were ignored.",
+                &[],
+            ),
+            registry,
+        }
+    }
+
+    /// Counts an input that was never scheduled. `reason` is a label value, not a message.
+    fn input_dropped(&self, reason: &str) {
+        self.registry
+            .counter(
+                "lockstep_dropped_inputs_total",
+                "Inputs that were rejected or ignored, by why.",
+                &[("reason", reason)],
+            )
+            .inc();
+    }
+
+    fn set_peers(&self, room_id: RoomId, counts: PeerCounts) {
+        let room = room_id.to_string();
+        let help = "Connected peers that have joined a room, by role.";
+        for (role, count) in &[("player", counts.players), ("spectator", counts.spectators)] {
+            let labels = [("room", room.as_str()), ("role", *role)];
+            self.registry
+                .gauge("lockstep_peers", help, &labels)
+                .set(*count as f64);
+        }
+    }
+}
+
+/// The label `lockstep_dropped_inputs_total` uses for a rejected input.
+fn rejection_label(reason: shared::InputRejected) -> &'static str {
+    use shared::InputRejected;
+    match reason {
+        InputRejected::TooManyPerFrame => "too_many_per_frame",
+        InputRejected::RateLimited => "rate_limited",
+        InputRejected::MassBudgetExceeded => "mass_budget_exceeded",
+        InputRejected::MassOutOfRange => "mass_out_of_range",
+        InputRejected::TooFarFromCenter => "too_far_from_center",
+        InputRejected::TooFast => "too_fast",
+        InputRejected::TooClose => "too_close",
+    }
+}
+
+/// A room as the main loop sees it.
+struct ServedRoom {
+    handle: RoomHandle,
+    history: SnapshotHistory,
+}
+
+pub struct App {
+    config: AppConfig,
+    lobby: SharedLobby,
+    event_recver: mpsc::UnboundedReceiver<(RoomId, Event)>,
+    peers: Peers,
+    rooms: HashMap<RoomId, ServedRoom>,
+    room_peers: HashMap<RoomId, RoomPeers>,
+    metrics: AppMetrics,
+    /// Server times in pongs are measured from here, on tokio's clock so that tests can
+    /// control it.
+    started: tokio::time::Instant,
+}
+
+impl App {
+    /// Starts the default room with `initial`. Must be called from within the runtime.
+    pub fn new(config: AppConfig, initial: shared::State) -> Result<Self, LobbyError> {
+        let (event_sender, event_recver) = mpsc::unbounded_channel();
+        let registry = Arc::new(Registry::new());
+        let lobby = Arc::new(Mutex::new(Lobby::new(
+            &config,
+            event_sender,
+            registry.clone(),
+        )));
+        let default_room = RoomSettings {
+            name: "default".to_owned(),
+            scenario: config.scenario.clone(),
+            sync_mode: config.sync_mode,
+        };
+        lobby.lock().unwrap().create(default_room, initial)?;
+        Ok(Self {
+            peers: Peers::new(config.input_limits, config.input_rules),
+            config,
+            lobby,
+            event_recver,
+            rooms: HashMap::new(),
+            room_peers: HashMap::new(),
+            metrics: AppMetrics::new(registry),
+            started: tokio::time::Instant::now(),
+        })
+    }
+
+    /// For the HTTP endpoints.
+    pub fn lobby(&self) -> SharedLobby {
+        self.lobby.clone()
+    }
+
+    /// For `GET /metrics` and for transports to register with.
+    pub fn metrics(&self) -> SharedRegistry {
+        self.metrics.registry.clone()
+    }
+
+    /// Serves peers on the transport for as long as the server runs.
+    pub async fn run<T: Transport>(mut self, mut transport: T) {
+        loop {
+            tokio::select! {
+                Some((room_id, event)) = self.event_recver.recv() => {
+                    on_event(&mut self.peers, &mut self.rooms, &self.lobby, room_id, event);
+                },
+                event = transport.recv() => match event {
+                    Ok(event) => self.on_transport_event(event),
+                    Err(err) => log::warn!("could not receive message: {}", err),
+                }
+            }
+            let datagrams = self.peers.flush(&self.metrics.sent);
+            send_all(&mut transport, datagrams).await;
+            for remote_addr in self.peers.take_disconnected() {
+                if let Err(err) = transport.disconnect(&remote_addr).await {
+                    log::warn!("could not disconnect {}: {}", remote_addr, err);
+                }
+            }
+            self.publish_peers();
+        }
+    }
+
+    fn on_transport_event(&mut self, event: TransportEvent) {
+        match event {
+            TransportEvent::Connected(remote_addr) => {
+                log::debug!("{} connected", remote_addr);
+                self.peers.get_or_insert(remote_addr);
+            }
+            TransportEvent::Received(remote_addr, datagram) => {
+                self.on_external_datagram(&datagram, remote_addr)
+            }
+            TransportEvent::Disconnected(remote_addr) => {
+                log::debug!("{} disconnected", remote_addr);
+                let left = match self.peers.remove(&remote_addr) {
+                    Some(left) => left,
+                    None => return,
+                };
+                let (room_id, player_id) = left;
+                if let Some(room) = served_room(&mut self.rooms, &self.lobby, room_id) {
+                    if let Err(err) = room.handle.requests.send(Request::Leave(player_id)) {
+                        log::error!("leave send error: {}", err);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Updates what the lobby reports for any rooms whose peers have changed.
+    fn publish_peers(&mut self) {
+        let room_peers = self.peers.by_room();
+        if room_peers == self.room_peers {
+            return;
+        }
+        let mut lobby = self.lobby.lock().unwrap();
+        for (&room_id, peers) in &room_peers {
+            if self.room_peers.get(&room_id) != Some(peers) {
+                let counts = peers.counts;
+                log::info!(
+                    "{} players and {} spectators in room {}",
+                    counts.players,
+                    counts.spectators,
+                    room_id
+                );
+                lobby.set_peers(room_id, peers.clone());
+                self.metrics.set_peers(room_id, counts);
+            }
+        }
+        for room_id in self
+            .room_peers
+            .keys()
+            .filter(|id| !room_peers.contains_key(id))
+        {
+            log::info!("room {} is empty", room_id);
+            lobby.set_peers(*room_id, Default::default());
+            self.metrics.set_peers(*room_id, Default::default());
+        }
+        self.room_peers = room_peers;
+    }
+
+    fn on_external_datagram(&mut self, message_buf: &[u8], remote_addr: SocketAddr) {
+        let Self {
+            peers,
+            rooms,
+            lobby,
+            config,
+            metrics,
+            started,
+            ..
+        } = self;
+        let codec = Metered {
+            codec: WireCodec::default(),
+            metrics: &metrics.received,
+        };
+        let peer = peers.get_or_insert(remote_addr);
+        let messages = match peer.endpoint.unpack::<shared::Send, _>(&codec, message_buf) {
+            Ok(Some((messages, acks))) => {
+                peer.redundant.acknowledge(&acks);
+                messages
+            }
+            Ok(None) => {
+                log::trace!("dropping duplicate or stale packet from {}", remote_addr);
+                metrics.stale_packets.inc();
+                return;
+            }
+            Err(err) => {
+                log::error!("deserialize error: {}", err);
+                metrics.decode_errors.inc();
+                return;
+            }
+        };
+        for message in messages {
+            // the ticket comes first so the handshake after it goes to the right room
+            if let shared::Send::Enter { ticket } = message {
+                on_enter(peers, lobby, remote_addr, ticket);
+                continue;
+            }
+            let room = match served_room(rooms, lobby, peers.room_id(&remote_addr)) {
+                Some(room) => room,
+                None => continue,
+            };
+            on_external_message(peers, room, *started, config, metrics, remote_addr, message);
+        }
+    }
+}
+
+async fn send_all<T: Transport>(transport: &mut T, datagrams: Vec<(SocketAddr, Vec<u8>)>) {
+    for (remote_addr, datagram) in datagrams {
+        match transport.send(&remote_addr, &datagram).await {
+            Ok(()) => log::trace!("send buf success to {}: {:?}", remote_addr, datagram),
+            Err(err) => log::warn!("could not send message to {}: {}", remote_addr, err),
+        }
+    }
+}
+
+/// Returns the room, looking it up in the lobby the first time it's needed.
+fn served_room<'a>(
+    rooms: &'a mut HashMap<RoomId, ServedRoom>,
+    lobby: &SharedLobby,
+    room_id: RoomId,
+) -> Option<&'a mut ServedRoom> {
+    use std::collections::hash_map::Entry;
+    match rooms.entry(room_id) {
+        Entry::Occupied(entry) => Some(entry.into_mut()),
+        Entry::Vacant(entry) => match lobby.lock().unwrap().room(room_id) {
+            Ok(handle) => Some(entry.insert(ServedRoom {
+                handle: handle.clone(),
+                history: SnapshotHistory::with_capacity(SNAPSHOT_HISTORY),
+            })),
+            Err(err) => {
+                log::error!("{}", err);
+                None
+            }
+        },
+    }
+}
+
+fn on_event(
+    peers: &mut Peers,
+    rooms: &mut HashMap<RoomId, ServedRoom>,
+    lobby: &SharedLobby,
+    room_id: RoomId,
+    event: Event,
+) {
+    match event {
+        Event::Scheduled(scheduled) => {
+            // the latest state is from before anything scheduled now
+            if let Some(room) = served_room(rooms, lobby, room_id) {
+                match &scheduled {
+                    Scheduled::Input(input) => room.history.scheduled_input(input.input),
+                    Scheduled::Control(control) => room.history.scheduled_control(*control),
+                    _ => {}
+                }
+            }
+            on_scheduled(peers, room_id, scheduled)
+        }
+        Event::Stepped(state) => {
+            let hash = state.hash();
+            let msg = shared::Recv::StateHash(shared::IndexedState {
+                frame_index: state.frame_index,
+                state: hash,
+            });
+            log::trace!("room {}: {}, {:?}", room_id, hash, state);
+            peers.broadcast(room_id, &msg);
+            if let Some(room) = served_room(rooms, lobby, room_id) {
+                // the players' limits are per second, so they follow an admin's change
+                if let Some(tick_rate) = state.playback.tick_rate {
+                    let previous = room
+                        .history
+                        .latest()
+                        .and_then(|latest| latest.playback.tick_rate);
+                    if previous != Some(tick_rate) {
+                        peers.set_tick_rate(room_id, tick_rate);
+                    }
+                }
+                room.history.push(state);
+            }
+        }
+    }
+}
+
+fn on_scheduled(peers: &mut Peers, room_id: RoomId, scheduled: Scheduled) {
+    match scheduled {
+        Scheduled::Input(input) => on_scheduled_input(peers, room_id, input),
+        Scheduled::Control(control) => {
+            peers.broadcast_redundant_except(room_id, &shared::Recv::Control(control), None)
+        }
+        Scheduled::Load(frame_index) => {
+            let load = shared::Recv::Load { frame_index };
+            peers.broadcast_redundant_except(room_id, &load, None)
+        }
+        Scheduled::Kicked(player_id) => {
+            log::info!("kicking player {}", player_id);
+            peers.disconnect(room_id, player_id, shared::ConnectionRejected::Kicked)
+        }
+        Scheduled::TimedOut {
+            player_id,
+            frame_index,
+        } => peers.disconnect(
+            room_id,
+            player_id,
+            shared::ConnectionRejected::TimedOut { frame_index },
+        ),
+    }
+}
+
+/// Sends the state `delay` frames before the latest, or the latest if that one's too old.
+fn on_state_request(
+    history: &SnapshotHistory,
+    baseline: Option<shared::FrameIndex>,
+    delay: shared::FrameIndex,
+) -> Option<shared::Recv> {
+    let latest = history.latest()?;
+    let target = history
+        .caught_up(latest.frame_index.saturating_sub(delay))
+        .unwrap_or_else(|| latest.clone());
+    let baseline = baseline
+        .filter(|frame_index| *frame_index <= target.frame_index)
+        .and_then(|frame_index| history.get(frame_index));
+    match baseline {
+        Some(baseline) => Some(shared::Recv::StateDelta(StateDelta::encode(
+            baseline, &target,
+        ))),
+        None => Some(shared::Recv::FullState(target)),
+    }
+}
+
+/// Answers a request about a past frame, or says that the frame is no longer available.
+fn on_frame_request<F: FnOnce(&shared::State) -> shared::Recv>(
+    history: &SnapshotHistory,
+    frame_index: shared::FrameIndex,
+    respond: F,
+) -> Option<shared::Recv> {
+    match history.get(frame_index) {
+        Some(state) => Some(respond(state)),
+        None => Some(shared::Recv::FrameUnavailable(frame_index)),
+    }
+}
+
+fn on_scheduled_input(peers: &mut Peers, room_id: RoomId, scheduled: ClientInput) {
+    let ack = shared::Recv::InputAck {
+        client_seq: scheduled.client_seq,
+        applied_frame: scheduled.input.frame_index,
+    };
+    peers.send_redundant(&scheduled.remote_addr, ack);
+
+    let msg = shared::Recv::InputState {
+        player_id: scheduled.player_id,
+        client_seq: scheduled.client_seq,
+        input: scheduled.input,
+    };
+    peers.broadcast_redundant_except(room_id, &msg, Some(&scheduled.remote_addr));
+}
+
+/// Routes the session to the room its ticket was issued for.
+fn on_enter(
+    peers: &mut Peers,
+    lobby: &SharedLobby,
+    remote_addr: SocketAddr,
+    ticket: shared::RoomTicket,
+) {
+    if !peers.can_enter(&remote_addr) {
+        log::debug!("ignoring repeated room ticket from {}", remote_addr);
+        return;
+    }
+    let redeemed = lobby.lock().unwrap().redeem(ticket);
+    match redeemed {
+        Some(room_id) => peers.enter(remote_addr, room_id),
+        None => {
+            log::warn!("rejecting {}: invalid room ticket", remote_addr);
+            peers.get_or_insert(remote_addr);
+            let rejected = shared::ConnectionRejected::InvalidTicket;
+            peers.send(&remote_addr, shared::Recv::Rejected(rejected));
+        }
+    }
+}
+
+/// The room's tick rate as of its latest frame, which an admin may have changed.
+fn tick_rate(room: &ServedRoom, config: &AppConfig) -> u32 {
+    room.history
+        .latest()
+        .and_then(|state| state.playback.tick_rate)
+        .unwrap_or(config.tick_rate)
+}
+
+fn on_external_message(
+    peers: &mut Peers,
+    room: &ServedRoom,
+    started: tokio::time::Instant,
+    config: &AppConfig,
+    metrics: &AppMetrics,
+    remote_addr: SocketAddr,
+    message: shared::Send,
+) {
+    let response = match message {
+        shared::Send::Hello {
+            protocol_version,
+            client_name,
+        }
+        | shared::Send::Spectate {
+            protocol_version,
+            client_name,
+        } if protocol_version != shared::PROTOCOL_VERSION => {
+            log::warn!(
+                "rejecting {} ({}): protocol version {} is not {}",
+                remote_addr,
+                client_name,
+                protocol_version,
+                shared::PROTOCOL_VERSION
+            );
+            Some(shared::Recv::Rejected(
+                shared::ConnectionRejected::IncompatibleVersion {
+                    server_version: shared::PROTOCOL_VERSION,
+                },
+            ))
+        }
+        shared::Send::Hello { client_name, .. } => {
+            let player_id = peers.join(remote_addr, client_name, tick_rate(room, config));
+            if let Err(err) = room.handle.requests.send(Request::Join(player_id)) {
+                log::error!("join send error: {}", err);
+            }
+            Some(shared::Recv::Welcome {
+                player_id,
+                tick_rate: tick_rate(room, config),
+                input_delay: shared::INPUT_BUFFER_FRAMES,
+                server_frame: room.history.latest().map_or(0, |state| state.frame_index),
+                sync_mode: room.handle.sync_mode,
+                input_rules: config.input_rules,
+            })
+        }
+        shared::Send::Spectate { client_name, .. } => {
+            peers.spectate(remote_addr, client_name);
+            Some(shared::Recv::Spectating {
+                tick_rate: tick_rate(room, config),
+                server_frame: room.history.latest().map_or(0, |state| state.frame_index),
+            })
+        }
+        _ if !peers.joined(&remote_addr) => {
+            log::debug!("ignoring message from {} before handshake", remote_addr);
+            None
+        }
+        shared::Send::Ping { client_time } => Some(shared::Recv::Pong {
+            client_time,
+            server_time: started.elapsed().as_micros() as u64,
+            server_frame: room.history.latest().map_or(0, |state| state.frame_index),
+        }),
+        shared::Send::RequestState { baseline, delay } => {
+            // states take dozens of fragments, so they're sent reliably and a client retrying
+            // while one is on its way doesn't get another
+            if peers.sending_state(&remote_addr) {
+                log::debug!("already sending a state to {}", remote_addr);
+                return;
+            }
+            if let Some(state) = on_state_request(&room.history, baseline, delay) {
+                peers.send_redundant(&remote_addr, state);
+            }
+            None
+        }
+        shared::Send::RequestHashTree { frame_index } => {
+            on_frame_request(&room.history, frame_index, |state| {
+                shared::Recv::HashTree(hash_tree::HashTree::new(state))
+            })
+        }
+        shared::Send::RequestBodyHashes {
+            frame_index,
+            buckets,
+        } => on_frame_request(&room.history, frame_index, |state| {
+            shared::Recv::BodyHashes {
+                frame_index,
+                hashes: hash_tree::body_hashes(state, &buckets),
+                buckets,
+            }
+        }),
+        shared::Send::RequestBodies { frame_index, ids } => {
+            on_frame_request(&room.history, frame_index, |state| shared::Recv::Bodies {
+                frame_index,
+                bodies: hash_tree::bodies(state, &ids),
+                ids,
+            })
+        }
+        shared::Send::InputState { .. }
+        | shared::Send::Control { .. }
+        | shared::Send::InputsComplete { .. }
+            if peers.player(&remote_addr).is_none() =>
+        {
+            log::debug!("ignoring input from spectator {}", remote_addr);
+            if matches!(message, shared::Send::InputState { .. }) {
+                metrics.input_dropped("spectator");
+            }
+            None
+        }
+        shared::Send::InputState { client_seq, input } => {
+            let player_id = peers.player(&remote_addr).unwrap().player_id;
+            if !peers.is_new_input(player_id, client_seq) {
+                return;
+            }
+            let empty = shared::State::new();
+            let latest = room.history.latest().unwrap_or(&empty);
+            if let Err(reason) = peers.check_input(&remote_addr, latest, &input.state) {
+                log::debug!(
+                    "rejecting input {} from player {}: {}",
+                    client_seq,
+                    player_id,
+                    reason
+                );
+                metrics.input_dropped(rejection_label(reason));
+                let rejected = shared::Recv::InputRejected { client_seq, reason };
+                peers.send_redundant(&remote_addr, rejected);
+                return;
+            }
+            let client_input = ClientInput {
+                remote_addr,
+                player_id,
+                client_seq,
+                input,
+            };
+            if let Err(err) = room.handle.requests.send(Request::Input(client_input)) {
+                log::error!("input send error: {}", err);
+            }
+            None
+        }
+        shared::Send::Control {
+            client_seq,
+            control,
+        } => {
+            let player_id = peers.player(&remote_addr).unwrap().player_id;
+            if !peers.is_new_control(player_id, client_seq) {
+                return;
+            }
+            let frame_index = room.history.latest().map_or(0, |state| state.frame_index);
+            if let Err(reason) = peers.check_control(&remote_addr, frame_index, &control) {
+                log::warn!(
+                    "rejecting control {:?} from {}: {}",
+                    control,
+                    remote_addr,
+                    reason
+                );
+                let rejected = shared::Recv::ControlRejected { client_seq, reason };
+                peers.send_redundant(&remote_addr, rejected);
+                return;
+            }
+            log::info!("control from {}: {:?}", remote_addr, control);
+            if let Err(err) = room.handle.requests.send(Request::Control(control)) {
+                log::error!("control send error: {}", err);
+            }
+            None
+        }
+        shared::Send::Enter { .. } => {
+            log::debug!("ignoring room ticket from {} after handshake", remote_addr);
+            None
+        }
+        shared::Send::InputsComplete { frame_index } => {
+            let player_id = peers.player(&remote_addr).unwrap().player_id;
+            let complete = Request::InputsComplete {
+                player_id,
+                frame_index,
+            };
+            if let Err(err) = room.handle.requests.send(complete) {
+                log::error!("inputs complete send error: {}", err);
+            }
+            None
+        }
+    };
+    if let Some(response) = response {
+        peers.send(&remote_addr, response);
+    }
+}
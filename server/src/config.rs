@@ -0,0 +1,502 @@
+//! Server configuration. The defaults are overridden by a TOML file given with `--config`,
+//! then by the `PORT`, `SYNC_MODE` and `ADMIN_TOKEN` environment variables, then by command
+//! line flags. The result is validated before anything is started.
+
+use serde::Deserialize;
+use shared::limits::InputLimits;
+use shared::lockstep::{LockstepConfig, SyncMode};
+use shared::netsim::NetworkConditions;
+use shared::validation::InputRules;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+/// Anything faster leaves too little time to simulate and send each frame.
+const MAX_TICK_RATE: u32 = 240;
+/// Anything shorter could be guessed.
+const MIN_ADMIN_TOKEN_LEN: usize = 16;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "server",
+    about = "Runs the shared simulation and serves the client."
+)]
+struct Args {
+    /// A TOML file with any of the settings below, using underscores instead of dashes, as
+    /// well as `input_limits`, `input_rules` and `lockstep` tables.
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Where the HTTP server listens. It serves the client and the signalling endpoint.
+    #[structopt(long)]
+    http: Option<SocketAddr>,
+    /// Where the WebRTC UDP socket binds.
+    #[structopt(long)]
+    webrtc_data: Option<SocketAddr>,
+    /// The address clients send WebRTC data to, when it isn't `webrtc-data`. Behind NAT this
+    /// is the public address that forwards to it.
+    #[structopt(long)]
+    webrtc_public: Option<SocketAddr>,
+    /// Where to also listen for native clients over plain UDP.
+    #[structopt(long)]
+    udp: Option<SocketAddr>,
+    /// Simulation frames per second.
+    #[structopt(long)]
+    tick_rate: Option<u32>,
+    /// What the simulation starts with: empty, orbits or file:<path> for a saved state.
+    #[structopt(long)]
+    scenario: Option<Scenario>,
+    /// One of off, error, warn, info, debug or trace. `RUST_LOG` takes precedence.
+    #[structopt(long)]
+    log_level: Option<String>,
+    /// The directory the client is served from.
+    #[structopt(long, parse(from_os_str))]
+    static_dir: Option<PathBuf>,
+    /// free or strict. See `shared::lockstep`.
+    #[structopt(long)]
+    sync_mode: Option<SyncMode>,
+    /// The most rooms that can run at once, including the default room.
+    #[structopt(long)]
+    max_rooms: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scenario {
+    Empty,
+    /// A star with two moons.
+    #[default]
+    Orbits,
+    /// A state saved with `State::encode`.
+    File(PathBuf),
+}
+
+impl std::str::FromStr for Scenario {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "empty" => Ok(Scenario::Empty),
+            "orbits" => Ok(Scenario::Orbits),
+            _ if s.starts_with("file:") => Ok(Scenario::File(s["file:".len()..].into())),
+            _ => Err(format!(
+                "unknown scenario {:?}, expected empty, orbits or file:<path>",
+                s
+            )),
+        }
+    }
+}
+
+impl Scenario {
+    pub fn state(&self) -> Result<shared::State, ConfigError> {
+        use shared::nbody::{Body, Float};
+        let mut state = shared::State::new();
+        match self {
+            Scenario::Empty => {}
+            Scenario::Orbits => {
+                state.simulation.add_body(Body::new_lossy(0., 0., 10000.));
+                state.simulation.add_body({
+                    let mut body = Body::new_lossy(0., -100., 10.);
+                    body.velocity.x = Float::from_num(3);
+                    body
+                });
+                state.simulation.add_body({
+                    let mut body = Body::new_lossy(0., 100., 10.);
+                    body.velocity.x = Float::from_num(-3);
+                    body
+                });
+            }
+            Scenario::File(path) => {
+                let bytes =
+                    std::fs::read(path).map_err(|err| ConfigError::Read(path.clone(), err))?;
+                state = shared::State::decode(&bytes)
+                    .map_err(|err| ConfigError::Scenario(path.clone(), err))?;
+            }
+        }
+        Ok(state)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Scenario(PathBuf, shared::EncodingError),
+    Invalid(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "invalid {}: {}", path.display(), err),
+            ConfigError::Scenario(path, err) => {
+                write!(f, "invalid scenario {}: {}", path.display(), err)
+            }
+            ConfigError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    pub http: SocketAddr,
+    pub webrtc_data: SocketAddr,
+    /// Defaults to `webrtc_data`.
+    pub webrtc_public: Option<SocketAddr>,
+    /// Off unless set.
+    pub udp: Option<SocketAddr>,
+    pub tick_rate: u32,
+    pub scenario: Scenario,
+    pub log_level: String,
+    pub static_dir: PathBuf,
+    pub input_limits: InputLimits,
+    pub input_rules: InputRules,
+    pub sync_mode: SyncMode,
+    pub lockstep: LockstepConfig,
+    pub max_rooms: usize,
+    /// Puts every peer behind a simulated network, for testing. Perfect unless set.
+    pub network: NetworkConditions,
+    /// The bearer token for the admin endpoints, which are off unless set. There's no flag for
+    /// it since flags show up in the process list.
+    pub admin_token: Option<String>,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        let localhost = [127, 0, 0, 1];
+        AppConfig {
+            http: (localhost, 3030).into(),
+            webrtc_data: (localhost, 3030).into(),
+            webrtc_public: None,
+            udp: None,
+            tick_rate: 60,
+            scenario: Scenario::default(),
+            log_level: "debug".to_owned(),
+            static_dir: Path::new(env!("CARGO_MANIFEST_DIR")).join("public"),
+            input_limits: Default::default(),
+            input_rules: Default::default(),
+            sync_mode: Default::default(),
+            lockstep: Default::default(),
+            max_rooms: 16,
+            network: Default::default(),
+            admin_token: None,
+        }
+    }
+}
+
+impl AppConfig {
+    /// Reads the configuration from every source and validates it. Invalid flags exit with
+    /// a usage message.
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::from_sources(Args::from_args(), |name| std::env::var(name).ok())
+    }
+
+    /// Like `load` but with the flags and environment variables passed in, so tests don't have
+    /// to change the process's environment.
+    fn from_sources<E>(args: Args, env: E) -> Result<Self, ConfigError>
+    where
+        E: Fn(&str) -> Option<String>,
+    {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply_env(env)?;
+        config.apply_args(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text =
+            std::fs::read_to_string(path).map_err(|err| ConfigError::Read(path.into(), err))?;
+        toml::from_str(&text).map_err(|err| ConfigError::Parse(path.into(), err))
+    }
+
+    /// `PORT` is set by hosts that only forward a single port, so everything binds to it.
+    fn apply_env<E: Fn(&str) -> Option<String>>(&mut self, env: E) -> Result<(), ConfigError> {
+        if let Some(port) = env("PORT") {
+            let port = port
+                .parse::<u16>()
+                .map_err(|_| ConfigError::Invalid(format!("PORT {:?} is not a port", port)))?;
+            let binding = [0, 0, 0, 0];
+            self.http = (binding, port).into();
+            self.webrtc_data = (binding, port).into();
+        }
+        if let Some(sync_mode) = env("SYNC_MODE") {
+            self.sync_mode = sync_mode
+                .parse()
+                .map_err(|err| ConfigError::Invalid(format!("SYNC_MODE: {}", err)))?;
+        }
+        if let Some(admin_token) = env("ADMIN_TOKEN") {
+            self.admin_token = Some(admin_token);
+        }
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: Args) {
+        let Args {
+            config: _,
+            http,
+            webrtc_data,
+            webrtc_public,
+            udp,
+            tick_rate,
+            scenario,
+            log_level,
+            static_dir,
+            sync_mode,
+            max_rooms,
+        } = args;
+        self.http = http.unwrap_or(self.http);
+        self.webrtc_data = webrtc_data.unwrap_or(self.webrtc_data);
+        self.webrtc_public = webrtc_public.or(self.webrtc_public);
+        self.udp = udp.or(self.udp);
+        self.tick_rate = tick_rate.unwrap_or(self.tick_rate);
+        if let Some(scenario) = scenario {
+            self.scenario = scenario;
+        }
+        if let Some(log_level) = log_level {
+            self.log_level = log_level;
+        }
+        if let Some(static_dir) = static_dir {
+            self.static_dir = static_dir;
+        }
+        self.sync_mode = sync_mode.unwrap_or(self.sync_mode);
+        self.max_rooms = max_rooms.unwrap_or(self.max_rooms);
+    }
+
+    pub fn webrtc_public(&self) -> SocketAddr {
+        self.webrtc_public.unwrap_or(self.webrtc_data)
+    }
+
+    /// Settings that are allowed but probably not intended.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.webrtc_public().ip().is_unspecified() {
+            warnings.push(format!(
+                "remote clients can't reach webrtc_public {}, set it to the public address",
+                self.webrtc_public()
+            ));
+        }
+        // the client is served by webpack during development
+        if !self.static_dir.is_dir() {
+            warnings.push(format!(
+                "static_dir {} is not a directory so the client won't be served",
+                self.static_dir.display()
+            ));
+        }
+        if !self.network.is_perfect() {
+            warnings.push(format!("simulating a bad network: {:?}", self.network));
+        }
+        warnings
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        fn check(valid: bool, reason: &str) -> Result<(), ConfigError> {
+            if valid {
+                Ok(())
+            } else {
+                Err(ConfigError::Invalid(reason.to_owned()))
+            }
+        }
+
+        check(
+            (1..=MAX_TICK_RATE).contains(&self.tick_rate),
+            "tick_rate must be between 1 and 240",
+        )?;
+        check(
+            self.log_level.parse::<log::LevelFilter>().is_ok(),
+            "log_level must be one of off, error, warn, info, debug or trace",
+        )?;
+        check(self.max_rooms > 0, "max_rooms must be at least 1")?;
+        check(
+            self.admin_token
+                .as_ref()
+                .is_none_or(|token| token.len() >= MIN_ADMIN_TOKEN_LEN),
+            "admin_token must be at least 16 characters",
+        )?;
+        check(
+            self.udp != Some(self.webrtc_data),
+            "udp and webrtc_data need different ports",
+        )?;
+        check(
+            self.webrtc_public().port() != 0,
+            "webrtc_public needs a port that clients can reach",
+        )?;
+
+        let limits = &self.input_limits;
+        check(
+            limits.per_frame > 0 && limits.burst > 0,
+            "input_limits.per_frame and input_limits.burst must be at least 1",
+        )?;
+        check(
+            limits.per_second > 0. && limits.mass_budget > 0. && limits.mass_window > 0.,
+            "input_limits.per_second, mass_budget and mass_window must be positive",
+        )?;
+
+        let rules = &self.input_rules;
+        check(
+            0. < rules.min_mass && rules.min_mass <= rules.max_mass,
+            "input_rules.min_mass must be positive and no more than max_mass",
+        )?;
+        check(
+            rules.max_distance > 0. && rules.max_speed >= 0. && rules.min_spacing >= 0.,
+            "input_rules.max_distance must be positive and max_speed and min_spacing can't \
+             be negative",
+        )?;
+
+        check(
+            self.lockstep.stall_after > 0. && self.lockstep.player_timeout > 0.,
+            "lockstep.stall_after and lockstep.player_timeout must be positive",
+        )?;
+
+        self.network
+            .validate()
+            .map_err(|reason| ConfigError::Invalid(format!("network: {}", reason)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(flags: &[&str]) -> Args {
+        Args::from_iter(std::iter::once("server").chain(flags.iter().copied()))
+    }
+
+    fn invalid(config: AppConfig) -> String {
+        match config.validate() {
+            Err(ConfigError::Invalid(reason)) => reason,
+            other => panic!("expected an invalid config, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn the_defaults_are_valid() {
+        assert!(AppConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_settings_are_refused() {
+        let defaults = AppConfig::default;
+        let reason = invalid(AppConfig {
+            tick_rate: 0,
+            ..defaults()
+        });
+        assert!(reason.starts_with("tick_rate"), "{}", reason);
+        let reason = invalid(AppConfig {
+            log_level: "loud".to_owned(),
+            ..defaults()
+        });
+        assert!(reason.starts_with("log_level"), "{}", reason);
+        let reason = invalid(AppConfig {
+            admin_token: Some("hunter2".to_owned()),
+            ..defaults()
+        });
+        assert!(reason.starts_with("admin_token"), "{}", reason);
+        let reason = invalid(AppConfig {
+            udp: Some(defaults().webrtc_data),
+            ..defaults()
+        });
+        assert!(reason.starts_with("udp"), "{}", reason);
+        let reason = invalid(AppConfig {
+            input_rules: InputRules {
+                min_mass: 10.,
+                max_mass: 1.,
+                ..Default::default()
+            },
+            ..defaults()
+        });
+        assert!(reason.starts_with("input_rules"), "{}", reason);
+        let reason = invalid(AppConfig {
+            lockstep: LockstepConfig {
+                player_timeout: 0.,
+                ..Default::default()
+            },
+            ..defaults()
+        });
+        assert!(reason.starts_with("lockstep"), "{}", reason);
+    }
+
+    #[test]
+    fn files_only_override_what_they_set() {
+        let config: AppConfig = toml::from_str(
+            r#"
+                tick_rate = 30
+                scenario = "empty"
+
+                [input_rules]
+                max_speed = 5.0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.tick_rate, 30);
+        assert_eq!(config.scenario, Scenario::Empty);
+        assert_eq!(config.input_rules.max_speed, 5.);
+        assert_eq!(config.input_rules.max_mass, InputRules::default().max_mass);
+        assert_eq!(config.max_rooms, AppConfig::default().max_rooms);
+
+        assert!(toml::from_str::<AppConfig>("tick_rte = 30").is_err());
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let mut config = AppConfig {
+            tick_rate: 30,
+            max_rooms: 4,
+            ..AppConfig::default()
+        };
+        config.apply_args(args(&[
+            "--tick-rate",
+            "120",
+            "--scenario",
+            "file:saves/galaxy.state",
+            "--sync-mode",
+            "strict",
+        ]));
+        assert_eq!(config.tick_rate, 120);
+        assert_eq!(config.scenario, Scenario::File("saves/galaxy.state".into()));
+        assert_eq!(config.sync_mode, SyncMode::Strict);
+        // unset flags leave the file's settings alone
+        assert_eq!(config.max_rooms, 4);
+        assert_eq!(config.http, AppConfig::default().http);
+    }
+
+    #[test]
+    fn the_environment_is_between_the_file_and_the_flags() {
+        let env = |name: &str| match name {
+            "PORT" => Some("8080".to_string()),
+            "SYNC_MODE" => Some("free".to_string()),
+            "ADMIN_TOKEN" => Some("correct horse battery staple".to_string()),
+            _ => None,
+        };
+        let mut config = AppConfig {
+            sync_mode: SyncMode::Strict,
+            ..AppConfig::default()
+        };
+        config.apply_env(env).unwrap();
+        assert_eq!(config.sync_mode, SyncMode::Free);
+
+        let config = AppConfig::from_sources(args(&["--http", "127.0.0.1:9090"]), env).unwrap();
+        assert_eq!(config.http, ([127, 0, 0, 1], 9090).into());
+        assert_eq!(config.webrtc_data, ([0, 0, 0, 0], 8080).into());
+        assert_eq!(config.sync_mode, SyncMode::Free);
+        assert_eq!(
+            config.admin_token.as_deref(),
+            Some("correct horse battery staple")
+        );
+
+        let bad_port = |name: &str| match name {
+            "PORT" => Some("http".to_string()),
+            _ => None,
+        };
+        assert!(matches!(
+            AppConfig::from_sources(args(&[]), bad_port),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,10 @@
+//! The server's logic, separate from the binary so it can also be run in process.
+
+pub mod admin;
+pub mod app;
+pub mod config;
+pub mod lobby;
+pub mod metrics;
+pub mod peer;
+pub mod room;
+pub mod transport;
@@ -0,0 +1,331 @@
+//! The rooms hosted by this server and tickets for joining them.
+//!
+//! The HTTP endpoints create and list rooms and hand out tickets. A client sends its ticket in
+//! `Enter` at the start of its RTC session and the main loop redeems it to route the session
+//! to the room. Tickets can only be used once and expire quickly since they're the only thing
+//! tying an HTTP request to an RTC session.
+
+use crate::admin;
+use crate::config::{AppConfig, Scenario};
+use crate::metrics::SharedRegistry;
+use crate::peer::{PeerCounts, RoomPeers};
+use crate::room::{Event, RoomHandle, RoomId, RoomSettings, DEFAULT_ROOM};
+use serde::Serialize;
+use shared::lockstep::{LockstepConfig, SyncMode};
+use shared::timestep::TickStats;
+use shared::RoomTicket;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use warp::Filter;
+
+pub type SharedLobby = Arc<Mutex<Lobby>>;
+
+const TICKET_LIFETIME: Duration = Duration::from_secs(30);
+const MAX_ROOM_NAME_LEN: usize = 64;
+
+#[derive(Debug, Serialize)]
+pub struct RoomInfo {
+    pub id: RoomId,
+    pub name: String,
+    pub sync_mode: SyncMode,
+    pub frame_index: shared::FrameIndex,
+    pub players: usize,
+    pub spectators: usize,
+    pub ticks: TickStats,
+}
+
+/// What `POST /rooms/{id}/join` returns.
+#[derive(Debug, Serialize)]
+pub struct JoinTicket {
+    pub room_id: RoomId,
+    /// In hex since JSON numbers can't hold every `u64`.
+    pub ticket: String,
+}
+
+#[derive(Debug)]
+pub enum LobbyError {
+    NoSuchRoom(RoomId),
+    TooManyRooms {
+        max: usize,
+    },
+    InvalidName,
+    /// Rooms created over HTTP can't read files on the server.
+    FileScenario,
+    /// The room's state couldn't be serialized for `GET /rooms/{id}/state`.
+    Encode(bincode::Error),
+}
+
+impl std::fmt::Display for LobbyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LobbyError::NoSuchRoom(id) => write!(f, "there is no room {}", id),
+            LobbyError::TooManyRooms { max } => {
+                write!(f, "this server hosts at most {} rooms", max)
+            }
+            LobbyError::InvalidName => write!(
+                f,
+                "room names must have between 1 and {} bytes",
+                MAX_ROOM_NAME_LEN
+            ),
+            LobbyError::FileScenario => write!(f, "file scenarios can only be set in the config"),
+            LobbyError::Encode(err) => write!(f, "could not encode the room's state: {}", err),
+        }
+    }
+}
+
+impl LobbyError {
+    pub(crate) fn status(&self) -> warp::http::StatusCode {
+        use warp::http::StatusCode;
+        match self {
+            LobbyError::NoSuchRoom(_) => StatusCode::NOT_FOUND,
+            LobbyError::TooManyRooms { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            LobbyError::InvalidName | LobbyError::FileScenario => StatusCode::BAD_REQUEST,
+            LobbyError::Encode(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Lobby {
+    rooms: BTreeMap<RoomId, (RoomHandle, RoomPeers)>,
+    next_room_id: RoomId,
+    max_rooms: usize,
+    tick_rate: u32,
+    lockstep: LockstepConfig,
+    events: mpsc::UnboundedSender<(RoomId, Event)>,
+    metrics: SharedRegistry,
+    tickets: HashMap<RoomTicket, (RoomId, Instant)>,
+    /// Tickets are a keyed hash of a counter, so they can't be guessed from earlier ones.
+    ticket_keys: RandomState,
+    next_ticket_seq: u64,
+}
+
+impl Lobby {
+    pub fn new(
+        config: &AppConfig,
+        events: mpsc::UnboundedSender<(RoomId, Event)>,
+        metrics: SharedRegistry,
+    ) -> Self {
+        Self {
+            rooms: BTreeMap::new(),
+            next_room_id: DEFAULT_ROOM,
+            max_rooms: config.max_rooms,
+            tick_rate: config.tick_rate,
+            lockstep: config.lockstep,
+            events,
+            metrics,
+            tickets: HashMap::new(),
+            ticket_keys: RandomState::new(),
+            next_ticket_seq: 0,
+        }
+    }
+
+    /// Starts a room. The first room created is `DEFAULT_ROOM`.
+    pub fn create(
+        &mut self,
+        settings: RoomSettings,
+        initial: shared::State,
+    ) -> Result<RoomId, LobbyError> {
+        if self.rooms.len() >= self.max_rooms {
+            return Err(LobbyError::TooManyRooms {
+                max: self.max_rooms,
+            });
+        }
+        if settings.name.is_empty() || settings.name.len() > MAX_ROOM_NAME_LEN {
+            return Err(LobbyError::InvalidName);
+        }
+
+        let id = self.next_room_id;
+        self.next_room_id += 1;
+        log::info!(
+            "room {} ({}) created with {} bodies in {:?} sync",
+            id,
+            settings.name,
+            initial.simulation.bodies.len(),
+            settings.sync_mode
+        );
+        let room = RoomHandle::spawn(
+            id,
+            settings,
+            initial,
+            self.tick_rate,
+            self.lockstep,
+            self.events.clone(),
+            &self.metrics,
+        );
+        self.rooms.insert(id, (room, RoomPeers::default()));
+        Ok(id)
+    }
+
+    pub fn room(&self, id: RoomId) -> Result<&RoomHandle, LobbyError> {
+        self.rooms
+            .get(&id)
+            .map(|(room, _)| room)
+            .ok_or(LobbyError::NoSuchRoom(id))
+    }
+
+    pub fn list(&self) -> Vec<RoomInfo> {
+        self.rooms
+            .values()
+            .map(|(room, peers)| RoomInfo {
+                id: room.id,
+                name: room.name.clone(),
+                sync_mode: room.sync_mode,
+                frame_index: room.state.borrow().frame_index,
+                players: peers.counts.players,
+                spectators: peers.counts.spectators,
+                ticks: *room.tick_stats.borrow(),
+            })
+            .collect()
+    }
+
+    pub fn counts(&self, id: RoomId) -> Result<PeerCounts, LobbyError> {
+        self.rooms
+            .get(&id)
+            .map(|(_, peers)| peers.counts)
+            .ok_or(LobbyError::NoSuchRoom(id))
+    }
+
+    /// Whether the player has joined the room, as of the main loop's last update.
+    pub fn has_player(&self, id: RoomId, player_id: shared::PlayerId) -> Result<bool, LobbyError> {
+        self.rooms
+            .get(&id)
+            .map(|(_, peers)| peers.player_ids.contains(&player_id))
+            .ok_or(LobbyError::NoSuchRoom(id))
+    }
+
+    pub fn set_peers(&mut self, id: RoomId, peers: RoomPeers) {
+        if let Some((_, current)) = self.rooms.get_mut(&id) {
+            *current = peers;
+        }
+    }
+
+    pub fn issue_ticket(&mut self, id: RoomId) -> Result<RoomTicket, LobbyError> {
+        use std::hash::BuildHasher;
+        self.room(id)?;
+        self.expire_tickets();
+        let ticket = self.ticket_keys.hash_one(self.next_ticket_seq);
+        self.next_ticket_seq += 1;
+        self.tickets.insert(ticket, (id, Instant::now()));
+        Ok(ticket)
+    }
+
+    /// Returns the room the ticket was issued for, unless it's expired or already used.
+    pub fn redeem(&mut self, ticket: RoomTicket) -> Option<RoomId> {
+        self.expire_tickets();
+        self.tickets.remove(&ticket).map(|(id, _)| id)
+    }
+
+    fn expire_tickets(&mut self) {
+        self.tickets
+            .retain(|_, (_, issued)| issued.elapsed() < TICKET_LIFETIME);
+    }
+}
+
+fn error_reply(err: LobbyError) -> warp::reply::Response {
+    use warp::Reply;
+    warp::reply::with_status(err.to_string(), err.status()).into_response()
+}
+
+fn reply<T: Serialize>(result: Result<T, LobbyError>) -> warp::reply::Response {
+    use warp::Reply;
+    match result {
+        Ok(value) => warp::reply::json(&value).into_response(),
+        Err(err) => error_reply(err),
+    }
+}
+
+fn state(lobby: &SharedLobby, id: RoomId) -> warp::reply::Response {
+    use warp::Reply;
+    let state = match lobby.lock().unwrap().room(id) {
+        Ok(room) => room.state.clone(),
+        Err(err) => return error_reply(err),
+    };
+    let output = state.borrow();
+    let hash = output.hash();
+    match bincode::serialize(&(hash, &*output)) {
+        Ok(bytes) => bytes.into_response(),
+        Err(err) => {
+            log::error!("could not encode the state of room {}: {}", id, err);
+            error_reply(LobbyError::Encode(err))
+        }
+    }
+}
+
+fn create(lobby: &SharedLobby, settings: RoomSettings) -> Result<RoomInfo, LobbyError> {
+    if let Scenario::File(_) = settings.scenario {
+        return Err(LobbyError::FileScenario);
+    }
+    // only file scenarios can fail
+    let initial = settings.scenario.state().unwrap_or_default();
+    let mut lobby = lobby.lock().unwrap();
+    let id = lobby.create(settings, initial)?;
+    Ok(lobby.list().into_iter().find(|info| info.id == id).unwrap())
+}
+
+/// `GET /rooms` lists the rooms and `POST /rooms` creates one from `RoomSettings`, which needs
+/// the admin token like the `admin` endpoints since rooms run until the server stops. Each room
+/// has `POST /rooms/{id}/join` for a ticket, `GET /rooms/{id}/state` for the initial state,
+/// and `GET /rooms/{id}/status`. `/state` and `/status` are kept for the default room. The
+/// simulation can only be controlled by players in the room or through `admin`.
+pub fn routes(
+    lobby: SharedLobby,
+    admin_token: Option<String>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let with_lobby = warp::any().map(move || lobby.clone());
+    let room = warp::path("rooms").and(warp::path::param::<RoomId>());
+    let default_room = warp::any().map(|| DEFAULT_ROOM);
+
+    let list = warp::get()
+        .and(warp::path("rooms"))
+        .and(warp::path::end())
+        .and(with_lobby.clone())
+        .map(|lobby: SharedLobby| reply(Ok(lobby.lock().unwrap().list())));
+    let create = warp::post()
+        .and(warp::path("rooms"))
+        .and(warp::path::end())
+        .and(admin::authorized(admin_token))
+        .and(with_lobby.clone())
+        .and(warp::body::json())
+        .map(|lobby: SharedLobby, settings: RoomSettings| reply(create(&lobby, settings)))
+        .recover(admin::recover_unauthorized)
+        .unify();
+    let join = warp::post()
+        .and(room)
+        .and(warp::path("join"))
+        .and(with_lobby.clone())
+        .map(|id: RoomId, lobby: SharedLobby| {
+            let ticket = lobby.lock().unwrap().issue_ticket(id);
+            reply(ticket.map(|ticket| JoinTicket {
+                room_id: id,
+                ticket: format!("{:x}", ticket),
+            }))
+        });
+    let state = warp::get()
+        .and(
+            room.and(warp::path("state"))
+                .or(warp::path("state").and(default_room))
+                .unify(),
+        )
+        .and(with_lobby.clone())
+        .map(|id: RoomId, lobby: SharedLobby| state(&lobby, id));
+    let status = warp::get()
+        .and(
+            room.and(warp::path("status"))
+                .or(warp::path("status").and(default_room))
+                .unify(),
+        )
+        .and(with_lobby)
+        .map(|id: RoomId, lobby: SharedLobby| reply(lobby.lock().unwrap().counts(id)));
+    list.or(create)
+        .unify()
+        .or(join)
+        .unify()
+        .or(state)
+        .unify()
+        .or(status)
+        .unify()
+}
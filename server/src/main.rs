@@ -1,114 +1,73 @@
+use server::admin;
+use server::app::App;
+use server::config::AppConfig;
+use server::lobby;
+use server::metrics;
+use server::transport::{
+    MeteredTransport, Multiplex, SimulatedTransport, Transport, UdpTransport, WebRtcTransport,
+    WebSocketEndpoint, WebSocketTransport,
+};
 use std::net::SocketAddr;
-use tokio::sync::{mpsc, watch};
-use webrtc_unreliable::{Server as RtcServer, SessionEndpoint};
+use webrtc_unreliable::SessionEndpoint;
 
-#[derive(Debug)]
-struct AppConfig {
-    http: std::net::SocketAddr,
-    webrtc_data: std::net::SocketAddr,
-    webrtc_public: std::net::SocketAddr,
-}
-
-impl Default for AppConfig {
-    fn default() -> Self {
-        let localhost = [127, 0, 0, 1];
-        AppConfig {
-            http: (localhost, 3030).into(),
-            webrtc_data: (localhost, 3030).into(),
-            webrtc_public: (localhost, 3030).into(),
+#[tokio::main]
+async fn main() {
+    let config = match AppConfig::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("invalid configuration: {}", err);
+            std::process::exit(1);
         }
+    };
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or(&config.log_level));
+    log::info!("config: {:#?}", config);
+    for warning in config.warnings() {
+        log::warn!("{}", warning);
     }
-}
-
-impl AppConfig {
-    pub fn try_from_env() -> Option<Self> {
-        let port = match std::env::var("PORT").map(|s| s.parse::<u16>()) {
-            Ok(Ok(port)) => port,
-            _ => return None,
-        };
-
-        let binding = [0, 0, 0, 0];
-        Some(AppConfig {
-            http: (binding, port).into(),
-            webrtc_data: (binding, port).into(),
-            webrtc_public: (binding, port).into(),
-        })
-    }
-}
-
-struct AppState {
-    current: shared::State,
-    input_recver: mpsc::UnboundedReceiver<shared::IndexedState<shared::AddBodyEvent>>,
-    state_sender: watch::Sender<shared::State>,
-}
-
-impl AppState {
-    pub fn new(
-        input_recver: mpsc::UnboundedReceiver<shared::IndexedState<shared::AddBodyEvent>>,
-    ) -> (Self, watch::Receiver<shared::State>) {
-        let (state_sender, recver) = watch::channel(Default::default());
-
-        use shared::nbody::{Body, Float};
-        let mut current = shared::State::new();
-        current.simulation.add_body(Body::new_lossy(0., 0., 10000.));
-        current.simulation.add_body({
-            let mut body = Body::new_lossy(0., -100., 10.);
-            body.velocity.x = Float::from_num(3);
-            body
-        });
-        current.simulation.add_body({
-            let mut body = Body::new_lossy(0., 100., 10.);
-            body.velocity.x = Float::from_num(-3);
-            body
-        });
-
-        (
-            Self {
-                current,
-                input_recver,
-                state_sender,
-            },
-            recver,
-        )
-    }
-
-    pub fn step(&mut self) -> Result<(), watch::error::SendError<shared::State>> {
-        while let Ok(input) = self.input_recver.try_recv() {
-            self.current.input_buffer.push(input);
+    let initial = match config.scenario.state() {
+        Ok(initial) => initial,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
         }
-        self.current.step();
-        self.state_sender.broadcast(self.current.clone())
-    }
-}
-
-#[tokio::main]
-async fn main() {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("debug"));
+    };
 
-    let config = AppConfig::try_from_env().unwrap_or_default();
-    log::info!("config: {:#?}", config);
+    let app = match App::new(config.clone(), initial) {
+        Ok(app) => app,
+        Err(err) => {
+            log::error!("could not create the default room: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let lobby = app.lobby();
+    let registry = app.metrics();
 
-    let mut rtc_server = RtcServer::new(config.webrtc_data, config.webrtc_public)
+    let rtc_transport = WebRtcTransport::bind(config.webrtc_data, config.webrtc_public())
         .await
         .expect("could not start RTC server");
+    let session_endpoint = rtc_transport.session_endpoint();
+    // for browsers that can't open a data channel
+    let (ws_transport, ws_endpoint) = WebSocketTransport::new();
+    let mut transports: Vec<Box<dyn Transport>> = vec![
+        Box::new(MeteredTransport::new(rtc_transport, "webrtc", &registry)),
+        Box::new(MeteredTransport::new(ws_transport, "websocket", &registry)),
+    ];
+    if let Some(udp) = config.udp {
+        let udp_transport = UdpTransport::bind(udp, UdpTransport::DEFAULT_IDLE_TIMEOUT)
+            .await
+            .expect("could not bind UDP socket");
+        transports.push(Box::new(MeteredTransport::new(
+            udp_transport,
+            "udp",
+            &registry,
+        )));
+    }
 
-    let (input_sender, input_recver) = mpsc::unbounded_channel();
-    let (mut state, mut state_recver) = AppState::new(input_recver);
-    tokio::spawn({
-        let dur = std::time::Duration::from_secs_f64(1. / 60.);
-        async move {
-            loop {
-                if let Err(err) = state.step() {
-                    log::error!("{}", err);
-                }
-                tokio::time::delay_for(dur).await;
-            }
-        }
-    });
-
-    let session_endpoint = rtc_server.session_endpoint();
     tokio::spawn({
-        let state_recver = state_recver.clone();
+        let lobby = lobby.clone();
+        let registry = registry.clone();
+        let admin_token = config.admin_token.clone();
+        let (http, static_dir) = (config.http, config.static_dir.clone());
         async move {
             use warp::Filter;
 
@@ -145,28 +104,47 @@ async fn main() {
                 }
             }
 
-            let public =
-                warp::fs::dir(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("public"));
+            let public = warp::fs::dir(static_dir);
             let rtc = warp::post()
                 .and(warp::path("new_rtc_session"))
                 .and(warp::body::stream())
                 .and(warp::any().map(move || session_endpoint.clone()))
                 .and_then(rtc_callback);
-            let state_get = warp::get().and(warp::path("state")).map(move || {
-                let output = state_recver.borrow();
-                let hash = output.hash();
-                let ser = bincode::serialize(&(hash, &*output)).unwrap();
-                warp::Reply::into_response(ser)
-            });
-            warp::serve(public.or(rtc).or(state_get))
-                .run(config.http)
-                .await;
+            let ws = warp::path("ws")
+                .and(warp::ws())
+                .and(warp::addr::remote())
+                .and(warp::any().map(move || ws_endpoint.clone()))
+                .and_then(
+                    |ws: warp::ws::Ws,
+                     remote_addr: Option<SocketAddr>,
+                     endpoint: WebSocketEndpoint| async move {
+                        // always known for TCP connections
+                        let remote_addr = remote_addr.ok_or_else(warp::reject::not_found)?;
+                        let ws = ws.max_message_size(shared::packet::MAX_PACKET_SIZE);
+                        Ok::<_, warp::Rejection>(
+                            ws.on_upgrade(move |socket| endpoint.serve(socket, remote_addr)),
+                        )
+                    },
+                );
+            let routes = public
+                .or(rtc)
+                .or(ws)
+                .or(admin::routes(lobby.clone(), admin_token.clone()))
+                .or(lobby::routes(lobby, admin_token))
+                .or(metrics::routes(registry));
+            warp::serve(routes).run(http).await;
         }
     });
 
     #[cfg(feature = "viz")]
     std::thread::spawn({
-        let state_recver = state_recver.clone();
+        let state_recver = lobby
+            .lock()
+            .unwrap()
+            .room(server::room::DEFAULT_ROOM)
+            .unwrap()
+            .state
+            .clone();
         move || {
             use glutin::platform::windows::EventLoopExtWindows;
             use renderer::*;
@@ -228,111 +206,11 @@ async fn main() {
         }
     });
 
-    async fn broadcast_except(rtc_server: &mut RtcServer, message: &[u8], except: SocketAddr) {
-        let connected_clients = rtc_server.connected_clients().copied().collect::<Vec<_>>();
-        for connected_client in connected_clients {
-            if connected_client != except {
-                if let Err(err) = rtc_server
-                    .send(
-                        message,
-                        webrtc_unreliable::MessageType::Binary,
-                        &connected_client,
-                    )
-                    .await
-                {
-                    log::error!("{}", err);
-                }
-            }
-        }
-    }
-
-    async fn broadcast(rtc_server: &mut RtcServer, message: &[u8]) {
-        let connected_clients = rtc_server.connected_clients().copied().collect::<Vec<_>>();
-        for connected_client in connected_clients {
-            if let Err(err) = rtc_server
-                .send(
-                    message,
-                    webrtc_unreliable::MessageType::Binary,
-                    &connected_client,
-                )
-                .await
-            {
-                log::error!("{}", err);
-            }
-        }
-    }
-
-    async fn on_internal_message(rtc_server: &mut RtcServer, state: shared::State) {
-        let hash = state.hash();
-        let msg = shared::Recv::StateHash(shared::IndexedState {
-            frame_index: state.frame_index,
-            state: hash,
-        });
-        log::trace!("{}, {:?}", hash, state);
-        let msg = bincode::serialize(&msg).unwrap();
-        broadcast(rtc_server, &msg).await;
-    }
-
-    async fn on_external_message(
-        rtc_server: &mut RtcServer,
-        message_buf: &mut Vec<u8>,
-        input_sender: &mpsc::UnboundedSender<shared::IndexedState<shared::AddBodyEvent>>,
-        message: Option<(webrtc_unreliable::MessageType, std::net::SocketAddr)>,
-    ) {
-        if let Some((message_type, remote_addr)) = message {
-            let response = match bincode::deserialize::<shared::Send>(&message_buf) {
-                Err(err) => {
-                    log::error!("deserialize error: {}", err);
-                    None
-                }
-                Ok(shared::Send::Ping(frame_index)) => Some(shared::Recv::Pong(frame_index)),
-                Ok(shared::Send::InputState(input_state)) => {
-                    if let Err(err) = input_sender.send(input_state) {
-                        log::error!("input send error: {}", err);
-                    }
-                    broadcast_except(rtc_server, message_buf, remote_addr).await;
-                    None
-                }
-            };
-            if let Some(response) = response {
-                let message_buf = bincode::serialize(&response).unwrap();
-                match rtc_server
-                    .send(&message_buf, message_type, &remote_addr)
-                    .await
-                {
-                    Ok(_) => log::trace!("send buf success to {}: {:?}", remote_addr, message_buf),
-                    Err(err) => log::warn!("could not send message to {}: {}", remote_addr, err),
-                }
-            }
-        }
-    }
-
-    async fn try_external(
-        rtc_server: &mut RtcServer,
-        message_buf: &mut Vec<u8>,
-    ) -> Option<(webrtc_unreliable::MessageType, std::net::SocketAddr)> {
-        match rtc_server.recv().await {
-            Ok(received) => {
-                message_buf.clear();
-                message_buf.extend(received.message.as_ref());
-                Some((received.message_type, received.remote_addr))
-            }
-            Err(err) => {
-                log::warn!("could not receive RTC message: {}", err);
-                None
-            }
-        }
-    }
-
-    let mut message_buf = Vec::new();
-    loop {
-        tokio::select! {
-            message = state_recver.recv() => {
-                on_internal_message(&mut rtc_server, message.unwrap()).await;
-            },
-            message = try_external(&mut rtc_server, &mut message_buf) => {
-                on_external_message(&mut rtc_server, &mut message_buf, &input_sender, message).await;
-            }
-        }
+    let transport = Multiplex::new(transports);
+    if config.network.is_perfect() {
+        app.run(transport).await
+    } else {
+        app.run(SimulatedTransport::new(transport, config.network))
+            .await
     }
 }
@@ -0,0 +1,468 @@
+//! Metrics for `GET /metrics`, in Prometheus' text format.
+//!
+//! Rooms, transports and the main loop register the series they update with the `Registry`
+//! and keep the handles it returns. Updating a counter or gauge is a single atomic operation,
+//! so the registry is only locked to register a series and to render them all. Registering
+//! the same name and labels again returns the same series, which lets anything that doesn't
+//! know its labels up front, such as the type of a message, register lazily.
+
+use shared::codec::{Codec, CodecError, Message};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use warp::Filter;
+
+pub type SharedRegistry = Arc<Registry>;
+
+/// The upper bounds of each bucket of `lockstep_tick_seconds`. A tick at 60Hz has about
+/// 0.0167 seconds before it's late.
+pub const TICK_SECONDS_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.0167, 0.025, 0.05, 0.1,
+];
+
+/// A value that only goes up.
+#[derive(Clone, Debug, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that can go up and down. Stored as the bits of an `f64`.
+#[derive(Clone, Debug, Default)]
+pub struct Gauge(Arc<AtomicU64>);
+
+impl Gauge {
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    bounds: Vec<f64>,
+    /// Not cumulative, one more than `bounds` for `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+/// Counts observations in buckets by their upper bounds.
+#[derive(Clone, Debug)]
+pub struct Histogram(Arc<Mutex<Buckets>>);
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Self {
+        Self(Arc::new(Mutex::new(Buckets {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.,
+        })))
+    }
+
+    pub fn observe(&self, value: f64) {
+        let mut buckets = self.0.lock().unwrap();
+        let index = buckets
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(buckets.bounds.len());
+        buckets.counts[index] += 1;
+        buckets.sum += value;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.0.lock().unwrap().counts.iter().sum()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Series {
+    Counter(Counter),
+    Gauge(Gauge),
+    Histogram(Histogram),
+}
+
+#[derive(Debug)]
+struct Family {
+    help: String,
+    kind: Kind,
+    /// By their labels, already formatted as `name="value",...`.
+    series: BTreeMap<String, Series>,
+}
+
+#[derive(Debug, Default)]
+pub struct Registry {
+    families: Mutex<BTreeMap<String, Family>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn counter(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Counter {
+        match self.register(name, help, Kind::Counter, labels, || {
+            Series::Counter(Counter::default())
+        }) {
+            Series::Counter(counter) => counter,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn gauge(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Gauge {
+        match self.register(name, help, Kind::Gauge, labels, || {
+            Series::Gauge(Gauge::default())
+        }) {
+            Series::Gauge(gauge) => gauge,
+            _ => unreachable!(),
+        }
+    }
+
+    /// `buckets` are the upper bounds of each bucket in increasing order, not including
+    /// `+Inf`. They're ignored if the series is already registered.
+    pub fn histogram(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[(&str, &str)],
+        buckets: &[f64],
+    ) -> Histogram {
+        match self.register(name, help, Kind::Histogram, labels, || {
+            Series::Histogram(Histogram::new(buckets))
+        }) {
+            Series::Histogram(histogram) => histogram,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Panics if `name` is already registered as a different kind of metric, which is a bug
+    /// in whatever registered it.
+    fn register<F: FnOnce() -> Series>(
+        &self,
+        name: &str,
+        help: &str,
+        kind: Kind,
+        labels: &[(&str, &str)],
+        create: F,
+    ) -> Series {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(name.to_owned()).or_insert_with(|| Family {
+            help: help.to_owned(),
+            kind,
+            series: BTreeMap::new(),
+        });
+        assert_eq!(
+            family.kind,
+            kind,
+            "metric {} is already registered as a {}",
+            name,
+            family.kind.as_str()
+        );
+        family
+            .series
+            .entry(format_labels(labels))
+            .or_insert_with(create)
+            .clone()
+    }
+
+    /// Every series in the text exposition format, sorted by name then labels.
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+        let mut out = String::new();
+        for (name, family) in families.iter() {
+            writeln!(out, "# HELP {} {}", name, escape_help(&family.help)).unwrap();
+            writeln!(out, "# TYPE {} {}", name, family.kind.as_str()).unwrap();
+            for (labels, series) in &family.series {
+                match series {
+                    Series::Counter(counter) => {
+                        write_sample(&mut out, name, labels, counter.get() as f64)
+                    }
+                    Series::Gauge(gauge) => write_sample(&mut out, name, labels, gauge.get()),
+                    Series::Histogram(histogram) => {
+                        write_histogram(&mut out, name, labels, histogram)
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    let mut out = String::new();
+    for (index, (name, value)) in labels.iter().enumerate() {
+        if index > 0 {
+            out.push(',');
+        }
+        write!(out, "{}=\"{}\"", name, escape_label(value)).unwrap();
+    }
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_owned()
+    } else if value.is_infinite() {
+        if value > 0. { "+Inf" } else { "-Inf" }.to_owned()
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_sample(out: &mut String, name: &str, labels: &str, value: f64) {
+    if labels.is_empty() {
+        writeln!(out, "{} {}", name, format_value(value)).unwrap();
+    } else {
+        writeln!(out, "{}{{{}}} {}", name, labels, format_value(value)).unwrap();
+    }
+}
+
+fn write_histogram(out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    let buckets = histogram.0.lock().unwrap();
+    let bucket_name = format!("{}_bucket", name);
+    let separator = if labels.is_empty() { "" } else { "," };
+    let mut cumulative = 0;
+    let bounds = buckets.bounds.iter().copied().chain(Some(f64::INFINITY));
+    for (bound, count) in bounds.zip(&buckets.counts) {
+        cumulative += count;
+        let le = format!("{}{}le=\"{}\"", labels, separator, format_value(bound));
+        write_sample(out, &bucket_name, &le, cumulative as f64);
+    }
+    write_sample(out, &format!("{}_sum", name), labels, buckets.sum);
+    write_sample(out, &format!("{}_count", name), labels, cumulative as f64);
+}
+
+/// Counts messages and the bytes they took, by their `Message::kind`, in one direction.
+#[derive(Debug)]
+pub struct MessageMetrics {
+    registry: SharedRegistry,
+    direction: &'static str,
+    series: Mutex<HashMap<&'static str, (Counter, Counter)>>,
+}
+
+impl MessageMetrics {
+    /// `direction` is the value of the `direction` label, such as `"in"` or `"out"`.
+    pub fn new(registry: SharedRegistry, direction: &'static str) -> Self {
+        Self {
+            registry,
+            direction,
+            series: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, kind: &'static str, bytes: usize) {
+        let mut series = self.series.lock().unwrap();
+        let (messages, total) = series.entry(kind).or_insert_with(|| {
+            let labels = [("direction", self.direction), ("type", kind)];
+            (
+                self.registry.counter(
+                    "lockstep_messages_total",
+                    "Messages sent and received, by type.",
+                    &labels,
+                ),
+                self.registry.counter(
+                    "lockstep_message_bytes_total",
+                    "Encoded bytes of messages sent and received, by type, not counting \
+                     packet headers.",
+                    &labels,
+                ),
+            )
+        });
+        messages.inc();
+        total.inc_by(bytes as u64);
+    }
+
+    /// Counts a message that couldn't be encoded, which is dropped rather than sent.
+    pub fn encode_failed(&self, kind: &'static str) {
+        self.registry
+            .counter(
+                "lockstep_encode_errors_total",
+                "Messages that couldn't be encoded and were dropped, by type.",
+                &[("type", kind)],
+            )
+            .inc();
+    }
+}
+
+/// Wraps a codec to record every message it encodes or decodes.
+pub struct Metered<'a, C> {
+    pub codec: C,
+    pub metrics: &'a MessageMetrics,
+}
+
+impl<'a, T: Message, C: Codec<T>> Codec<T> for Metered<'a, C> {
+    fn encode(&self, message: &T, buf: &mut Vec<u8>) -> Result<(), CodecError> {
+        let start = buf.len();
+        if let Err(err) = self.codec.encode(message, buf) {
+            self.metrics.encode_failed(message.kind());
+            return Err(err);
+        }
+        self.metrics.record(message.kind(), buf.len() - start);
+        Ok(())
+    }
+
+    fn decode<'b>(&self, bytes: &'b [u8]) -> Result<(T, &'b [u8]), CodecError> {
+        let (message, rest) = self.codec.decode(bytes)?;
+        self.metrics
+            .record(message.kind(), bytes.len() - rest.len());
+        Ok((message, rest))
+    }
+}
+
+/// `GET /metrics` renders every series in the registry.
+pub fn routes(
+    registry: SharedRegistry,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .map(move || {
+            use warp::Reply;
+            warp::reply::with_header(
+                registry.render(),
+                warp::http::header::CONTENT_TYPE,
+                "text/plain; version=0.0.4",
+            )
+            .into_response()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registering_twice_returns_the_same_series() {
+        let registry = Registry::new();
+        let a = registry.counter("requests_total", "Requests.", &[("path", "/")]);
+        let b = registry.counter("requests_total", "Requests.", &[("path", "/")]);
+        let other = registry.counter("requests_total", "Requests.", &[("path", "/x")]);
+        a.inc();
+        b.inc_by(2);
+        assert_eq!(a.get(), 3);
+        assert_eq!(other.get(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn kinds_cant_change() {
+        let registry = Registry::new();
+        registry.counter("value", "A value.", &[]);
+        registry.gauge("value", "A value.", &[]);
+    }
+
+    #[test]
+    fn renders_the_text_format() {
+        let registry = Registry::new();
+        registry
+            .counter("lockstep_decode_errors_total", "Bad datagrams.", &[])
+            .inc_by(2);
+        registry
+            .gauge("lockstep_frame", "Frame.", &[("room", "0")])
+            .set(42.);
+        registry
+            .gauge("lockstep_frame", "Frame.", &[("room", "a \"b\"")])
+            .set(-1.5);
+        let histogram = registry.histogram("tick_seconds", "Ticks.", &[], &[0.25, 1.]);
+        histogram.observe(0.125);
+        histogram.observe(0.5);
+        histogram.observe(2.);
+
+        let expected = "\
+# HELP lockstep_decode_errors_total Bad datagrams.
+# TYPE lockstep_decode_errors_total counter
+lockstep_decode_errors_total 2
+# HELP lockstep_frame Frame.
+# TYPE lockstep_frame gauge
+lockstep_frame{room=\"0\"} 42
+lockstep_frame{room=\"a \\\"b\\\"\"} -1.5
+# HELP tick_seconds Ticks.
+# TYPE tick_seconds histogram
+tick_seconds_bucket{le=\"0.25\"} 1
+tick_seconds_bucket{le=\"1\"} 2
+tick_seconds_bucket{le=\"+Inf\"} 3
+tick_seconds_sum 2.625
+tick_seconds_count 3
+";
+        assert_eq!(registry.render(), expected);
+    }
+
+    #[test]
+    fn metered_codecs_count_by_kind() {
+        use shared::codec::WireCodec;
+
+        let registry = Arc::new(Registry::new());
+        let metrics = MessageMetrics::new(registry.clone(), "out");
+        let codec = Metered {
+            codec: WireCodec::default(),
+            metrics: &metrics,
+        };
+        let mut buf = Vec::new();
+        codec
+            .encode(&shared::Send::Ping { client_time: 1 }, &mut buf)
+            .unwrap();
+        codec
+            .encode(&shared::Send::Ping { client_time: 2 }, &mut buf)
+            .unwrap();
+
+        let ping = shared::Send::Ping { client_time: 0 }.kind();
+        let labels = [("direction", "out"), ("type", ping)];
+        let messages = registry.counter("lockstep_messages_total", "", &labels);
+        let bytes = registry.counter("lockstep_message_bytes_total", "", &labels);
+        assert_eq!(messages.get(), 2);
+        assert_eq!(bytes.get(), buf.len() as u64);
+
+        let too_many = shared::Send::RequestBodies {
+            frame_index: 0,
+            ids: (0..1000).collect(),
+        };
+        assert!(codec.encode(&too_many, &mut buf).is_err());
+        let labels = [("type", too_many.kind())];
+        let errors = registry.counter("lockstep_encode_errors_total", "", &labels);
+        assert_eq!(errors.get(), 1);
+        assert_eq!(messages.get(), 2);
+    }
+}
@@ -0,0 +1,376 @@
+use crate::metrics::{MessageMetrics, Metered};
+use crate::room::{RoomId, DEFAULT_ROOM};
+use serde::Serialize;
+use shared::codec::WireCodec;
+use shared::limits::{ControlLimiter, InputLimiter, InputLimits};
+use shared::packet::PacketEndpoint;
+use shared::redundant::{Deduplicator, RedundantQueue};
+use shared::validation::InputRules;
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+
+#[derive(Debug)]
+pub struct Player {
+    pub player_id: shared::PlayerId,
+    pub name: String,
+    pub limiter: InputLimiter,
+    pub control_limiter: ControlLimiter,
+}
+
+#[derive(Debug)]
+pub struct Spectator {
+    pub name: String,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct PeerCounts {
+    pub players: usize,
+    pub spectators: usize,
+}
+
+/// Who has joined a room, as the lobby reports it.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RoomPeers {
+    pub counts: PeerCounts,
+    pub player_ids: BTreeSet<shared::PlayerId>,
+}
+
+/// A remote address that has sent us at least one packet.
+#[derive(Debug, Default)]
+pub struct Peer {
+    pub endpoint: PacketEndpoint,
+    /// Messages waiting for the next flush.
+    pub outbox: Vec<shared::Recv>,
+    /// Messages repeated in every flush until the peer acknowledges them.
+    pub redundant: RedundantQueue<shared::Recv>,
+    /// Set once the `Hello`/`Welcome` handshake has completed.
+    pub player: Option<Player>,
+    /// Set instead of `player` once the `Spectate`/`Spectating` handshake has completed.
+    pub spectator: Option<Spectator>,
+    /// Set by `Enter`, otherwise the peer is in the default room.
+    pub entered: Option<RoomId>,
+}
+
+impl Peer {
+    /// True once either handshake has completed.
+    pub fn joined(&self) -> bool {
+        self.player.is_some() || self.spectator.is_some()
+    }
+
+    pub fn room_id(&self) -> RoomId {
+        self.entered.unwrap_or(DEFAULT_ROOM)
+    }
+}
+
+#[derive(Debug)]
+pub struct Peers {
+    peers: HashMap<SocketAddr, Peer>,
+    next_player_id: shared::PlayerId,
+    inputs_seen: Deduplicator,
+    controls_seen: Deduplicator,
+    input_limits: InputLimits,
+    input_rules: InputRules,
+    /// Peers to disconnect once their outbox has been flushed.
+    disconnected: Vec<SocketAddr>,
+}
+
+impl Peers {
+    pub fn new(input_limits: InputLimits, input_rules: InputRules) -> Self {
+        Self {
+            peers: Default::default(),
+            next_player_id: 0,
+            inputs_seen: Default::default(),
+            controls_seen: Default::default(),
+            input_limits,
+            input_rules,
+            disconnected: Vec::new(),
+        }
+    }
+
+    pub fn get_or_insert(&mut self, remote_addr: SocketAddr) -> &mut Peer {
+        self.peers.entry(remote_addr).or_default()
+    }
+
+    /// Returns the player id for the address, assigning a new one if the address hasn't
+    /// joined before. `Hello` may be resent so joining twice must be harmless. `tick_rate` is
+    /// the room's, for the player's limits.
+    pub fn join(
+        &mut self,
+        remote_addr: SocketAddr,
+        name: String,
+        tick_rate: u32,
+    ) -> shared::PlayerId {
+        let next_player_id = &mut self.next_player_id;
+        let peer = self.peers.entry(remote_addr).or_default();
+        if let Some(player) = &peer.player {
+            return player.player_id;
+        }
+
+        let player_id = *next_player_id;
+        *next_player_id += 1;
+        log::info!(
+            "player {} ({}) joined from {}",
+            player_id,
+            name,
+            remote_addr
+        );
+        peer.spectator = None;
+        peer.player = Some(Player {
+            player_id,
+            name,
+            limiter: InputLimiter::new(self.input_limits, tick_rate),
+            control_limiter: ControlLimiter::new(tick_rate),
+        });
+        player_id
+    }
+
+    /// Like `join` but for a spectator. An address that has joined as a player stays one.
+    pub fn spectate(&mut self, remote_addr: SocketAddr, name: String) {
+        let peer = self.peers.entry(remote_addr).or_default();
+        if peer.joined() {
+            return;
+        }
+        log::info!("spectator {} joined from {}", name, remote_addr);
+        peer.spectator = Some(Spectator { name });
+    }
+
+    /// Whether the address can still choose a room, which it can only do before either
+    /// handshake and only once.
+    pub fn can_enter(&self, remote_addr: &SocketAddr) -> bool {
+        self.peers
+            .get(remote_addr)
+            .is_none_or(|peer| !peer.joined() && peer.entered.is_none())
+    }
+
+    /// Puts the address in a room for its handshake. See `can_enter`.
+    pub fn enter(&mut self, remote_addr: SocketAddr, room_id: RoomId) {
+        if !self.can_enter(&remote_addr) {
+            return;
+        }
+        log::info!("{} entered room {}", remote_addr, room_id);
+        self.peers.entry(remote_addr).or_default().entered = Some(room_id);
+    }
+
+    pub fn joined(&self, remote_addr: &SocketAddr) -> bool {
+        self.peers.get(remote_addr).is_some_and(Peer::joined)
+    }
+
+    pub fn room_id(&self, remote_addr: &SocketAddr) -> RoomId {
+        self.peers
+            .get(remote_addr)
+            .map_or(DEFAULT_ROOM, Peer::room_id)
+    }
+
+    /// Every room with at least one player or spectator.
+    pub fn by_room(&self) -> HashMap<RoomId, RoomPeers> {
+        let mut rooms = HashMap::<RoomId, RoomPeers>::new();
+        for peer in self.peers.values() {
+            if let Some(player) = &peer.player {
+                let room = rooms.entry(peer.room_id()).or_default();
+                room.counts.players += 1;
+                room.player_ids.insert(player.player_id);
+            } else if peer.spectator.is_some() {
+                rooms.entry(peer.room_id()).or_default().counts.spectators += 1;
+            }
+        }
+        rooms
+    }
+
+    pub fn player(&self, remote_addr: &SocketAddr) -> Option<&Player> {
+        self.peers
+            .get(remote_addr)
+            .and_then(|peer| peer.player.as_ref())
+    }
+
+    /// Validates a new input against the latest state and accounts for it in the player's
+    /// limits. Invalid inputs don't count towards the limits.
+    pub fn check_input(
+        &mut self,
+        remote_addr: &SocketAddr,
+        latest: &shared::State,
+        event: &shared::AddBodyEvent,
+    ) -> Result<(), shared::InputRejected> {
+        self.input_rules.validate(event, latest)?;
+        match self
+            .peers
+            .get_mut(remote_addr)
+            .and_then(|peer| peer.player.as_mut())
+        {
+            Some(player) => player
+                .limiter
+                .check(latest.frame_index, event.mass().to_num()),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that players may send the control and accounts for it in the player's limits.
+    /// Admin controls don't count towards the limits.
+    pub fn check_control(
+        &mut self,
+        remote_addr: &SocketAddr,
+        frame_index: shared::FrameIndex,
+        control: &shared::control::Control,
+    ) -> Result<(), shared::ControlRejected> {
+        if control.is_admin_only() {
+            return Err(shared::ControlRejected::AdminOnly);
+        }
+        let limited = self
+            .peers
+            .get_mut(remote_addr)
+            .and_then(|peer| peer.player.as_mut())
+            .is_some_and(|player| !player.control_limiter.check(frame_index));
+        if limited {
+            return Err(shared::ControlRejected::RateLimited);
+        }
+        Ok(())
+    }
+
+    /// Updates the limits of every player in the room after an admin changes its tick rate.
+    pub fn set_tick_rate(&mut self, room_id: RoomId, tick_rate: u32) {
+        for peer in self.peers.values_mut() {
+            if peer.room_id() != room_id {
+                continue;
+            }
+            if let Some(player) = &mut peer.player {
+                player.limiter.set_tick_rate(tick_rate);
+                player.control_limiter.set_tick_rate(tick_rate);
+            }
+        }
+    }
+
+    /// Returns true the first time an input from a player is seen. Inputs are resent until
+    /// acknowledged so duplicates are expected.
+    pub fn is_new_input(
+        &mut self,
+        player_id: shared::PlayerId,
+        client_seq: shared::InputSequence,
+    ) -> bool {
+        self.inputs_seen.insert(player_id, client_seq)
+    }
+
+    /// Like `is_new_input`, for controls. A repeat scheduled again would apply twice.
+    pub fn is_new_control(
+        &mut self,
+        player_id: shared::PlayerId,
+        client_seq: shared::InputSequence,
+    ) -> bool {
+        self.controls_seen.insert(player_id, client_seq)
+    }
+
+    /// Queues a message for a single peer.
+    pub fn send(&mut self, remote_addr: &SocketAddr, message: shared::Recv) {
+        if let Some(peer) = self.peers.get_mut(remote_addr) {
+            peer.outbox.push(message);
+        }
+    }
+
+    /// Queues a message for every player and spectator in the room except the one at
+    /// `except`.
+    pub fn broadcast_except(
+        &mut self,
+        room_id: RoomId,
+        message: &shared::Recv,
+        except: Option<&SocketAddr>,
+    ) {
+        for (remote_addr, peer) in self.peers.iter_mut() {
+            if peer.joined() && peer.room_id() == room_id && Some(remote_addr) != except {
+                peer.outbox.push(message.clone());
+            }
+        }
+    }
+
+    pub fn broadcast(&mut self, room_id: RoomId, message: &shared::Recv) {
+        self.broadcast_except(room_id, message, None)
+    }
+
+    /// Like `send` but the message is repeated until the peer acknowledges it.
+    pub fn send_redundant(&mut self, remote_addr: &SocketAddr, message: shared::Recv) {
+        if let Some(peer) = self.peers.get_mut(remote_addr) {
+            peer.redundant.push(message);
+        }
+    }
+
+    /// True while a `FullState` or `StateDelta` is still on its way to the peer.
+    pub fn sending_state(&self, remote_addr: &SocketAddr) -> bool {
+        self.peers.get(remote_addr).map_or(false, |peer| {
+            peer.redundant.iter().any(|message| {
+                matches!(
+                    message,
+                    shared::Recv::FullState(_) | shared::Recv::StateDelta(_)
+                )
+            })
+        })
+    }
+
+    /// Like `broadcast_except` but the message is repeated until each peer acknowledges it.
+    pub fn broadcast_redundant_except(
+        &mut self,
+        room_id: RoomId,
+        message: &shared::Recv,
+        except: Option<&SocketAddr>,
+    ) {
+        for (remote_addr, peer) in self.peers.iter_mut() {
+            if peer.joined() && peer.room_id() == room_id && Some(remote_addr) != except {
+                peer.redundant.push(message.clone());
+            }
+        }
+    }
+
+    /// Packs every peer's outbox into datagrams, recording each message in `sent`.
+    pub fn flush(&mut self, sent: &MessageMetrics) -> Vec<(SocketAddr, Vec<u8>)> {
+        let codec = Metered {
+            codec: WireCodec::default(),
+            metrics: sent,
+        };
+        let mut datagrams = Vec::new();
+        for (remote_addr, peer) in self.peers.iter_mut() {
+            if peer.outbox.is_empty() && peer.redundant.is_empty() {
+                continue;
+            }
+            let outbox = std::mem::take(&mut peer.outbox);
+            let packed = peer.redundant.pack(&codec, &mut peer.endpoint, &outbox);
+            datagrams.extend(packed.into_iter().map(|d| (*remote_addr, d)));
+        }
+        datagrams
+    }
+
+    /// Tells a player in the room why they're being disconnected. The connection is closed
+    /// after the next flush, see `take_disconnected`.
+    pub fn disconnect(
+        &mut self,
+        room_id: RoomId,
+        player_id: shared::PlayerId,
+        reason: shared::ConnectionRejected,
+    ) {
+        let found = self.peers.iter_mut().find(|(_, peer)| {
+            peer.room_id() == room_id
+                && peer
+                    .player
+                    .as_ref()
+                    .is_some_and(|player| player.player_id == player_id)
+        });
+        if let Some((remote_addr, peer)) = found {
+            log::info!("disconnecting player {}: {}", player_id, reason);
+            peer.outbox.push(shared::Recv::Rejected(reason));
+            self.disconnected.push(*remote_addr);
+        }
+    }
+
+    pub fn take_disconnected(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.disconnected)
+    }
+
+    /// Forgets a peer that has disconnected and returns its room and player id if it was a
+    /// player.
+    pub fn remove(&mut self, remote_addr: &SocketAddr) -> Option<(RoomId, shared::PlayerId)> {
+        let peer = self.peers.remove(remote_addr)?;
+        if let Some(spectator) = &peer.spectator {
+            log::info!("spectator {} left", spectator.name);
+        }
+        let player = peer.player.as_ref()?;
+        log::info!("player {} ({}) left", player.player_id, player.name);
+        self.inputs_seen.remove_player(player.player_id);
+        self.controls_seen.remove_player(player.player_id);
+        Some((peer.room_id(), player.player_id))
+    }
+}
@@ -0,0 +1,385 @@
+//! A room is an independent simulation with its own state and tick task. The tick task
+//! schedules what the main loop sends it and reports back through a channel shared by every
+//! room, so each room's events reach the main loop in the order they happened.
+
+use crate::config::Scenario;
+use crate::metrics::{Counter, Gauge, Histogram, Registry, TICK_SECONDS_BUCKETS};
+use serde::Deserialize;
+use shared::control::{Control, ControlSequence, ScheduledControl};
+use shared::lockstep::{Lockstep, LockstepConfig, SyncMode, Tick};
+use shared::timestep::{FixedTimestep, TickStats};
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::sync::{mpsc, watch};
+
+pub type RoomId = u32;
+/// Clients that don't `Enter` a room play here. It's created from the server config.
+pub const DEFAULT_ROOM: RoomId = 0;
+/// The most ticks run back to back after falling behind. Any more are skipped, since clients
+/// would see the simulation jump ahead either way.
+const MAX_CATCH_UP_TICKS: u32 = 4;
+/// The most simulations that can be waiting for their frame. Any more are dropped.
+const MAX_SCHEDULED_LOADS: usize = 4;
+
+/// An input received from a client, or the same input once the tick task has decided which
+/// frame it will be applied on.
+#[derive(Debug)]
+pub struct ClientInput {
+    pub remote_addr: SocketAddr,
+    pub player_id: shared::PlayerId,
+    pub client_seq: shared::InputSequence,
+    pub input: shared::IndexedState<shared::AddBodyEvent>,
+}
+
+/// Something for the tick task to schedule, or news about players for strict lockstep.
+#[derive(Debug)]
+pub enum Request {
+    Input(ClientInput),
+    Control(Control),
+    /// Replaces the simulation on a frame scheduled like a control's. Only the server has the
+    /// simulation, clients resync to the result.
+    Load(shared::nbody::Simulation),
+    /// Disconnects a player, who strict lockstep then stops waiting on.
+    Kick(shared::PlayerId),
+    Join(shared::PlayerId),
+    Leave(shared::PlayerId),
+    InputsComplete {
+        player_id: shared::PlayerId,
+        frame_index: shared::FrameIndex,
+    },
+}
+
+/// A `Request` once the tick task has decided which frame it will be applied on.
+#[derive(Debug)]
+pub enum Scheduled {
+    Input(ClientInput),
+    Control(ScheduledControl),
+    /// The frame a `Load` will happen on.
+    Load(shared::FrameIndex),
+    Kicked(shared::PlayerId),
+    /// The player held up the simulation for too long and should be disconnected.
+    TimedOut {
+        player_id: shared::PlayerId,
+        frame_index: shared::FrameIndex,
+    },
+}
+
+#[derive(Debug)]
+pub enum Event {
+    Scheduled(Scheduled),
+    /// The state after each step.
+    Stepped(shared::State),
+}
+
+/// What `POST /rooms` takes.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RoomSettings {
+    pub name: String,
+    #[serde(default)]
+    pub scenario: Scenario,
+    #[serde(default)]
+    pub sync_mode: SyncMode,
+}
+
+/// Everything needed to talk to a room from outside its tick task.
+#[derive(Clone, Debug)]
+pub struct RoomHandle {
+    pub id: RoomId,
+    pub name: String,
+    pub sync_mode: SyncMode,
+    pub requests: mpsc::UnboundedSender<Request>,
+    /// The latest state, for the HTTP endpoints.
+    pub state: watch::Receiver<shared::State>,
+    pub tick_stats: watch::Receiver<TickStats>,
+}
+
+/// A room's series in the metrics registry, labelled with its id.
+struct RoomMetrics {
+    tick_seconds: Histogram,
+    skipped_ticks: Counter,
+    frame: Gauge,
+    bodies: Gauge,
+    late_inputs: Counter,
+}
+
+impl RoomMetrics {
+    fn new(registry: &Registry, id: RoomId) -> Self {
+        let id = id.to_string();
+        let labels = [("room", id.as_str())];
+        Self {
+            tick_seconds: registry.histogram(
+                "lockstep_tick_seconds",
+                "How long each step of the simulation took.",
+                &labels,
+                TICK_SECONDS_BUCKETS,
+            ),
+            skipped_ticks: registry.counter(
+                "lockstep_skipped_ticks_total",
+                "Ticks skipped after falling too far behind.",
+                &labels,
+            ),
+            frame: registry.gauge("lockstep_frame", "The current frame.", &labels),
+            bodies: registry.gauge("lockstep_bodies", "How many bodies there are.", &labels),
+            late_inputs: registry.counter(
+                "lockstep_late_inputs_total",
+                "Inputs that arrived after their frame and were moved to a later one.",
+                &labels,
+            ),
+        }
+    }
+}
+
+impl RoomHandle {
+    /// Starts the room's tick task, which runs for as long as the server does.
+    pub fn spawn(
+        id: RoomId,
+        settings: RoomSettings,
+        initial: shared::State,
+        tick_rate: u32,
+        lockstep: LockstepConfig,
+        events: mpsc::UnboundedSender<(RoomId, Event)>,
+        metrics: &Registry,
+    ) -> Self {
+        // a snapshot may have been saved after an admin changed its tick rate
+        let tick_rate = initial.playback.tick_rate.unwrap_or(tick_rate);
+        let (requests, request_recver) = mpsc::unbounded_channel();
+        let (state_sender, state) = watch::channel(initial.clone());
+        let (stats_sender, tick_stats) = watch::channel(TickStats::default());
+        let mut room = RoomState {
+            id,
+            metrics: RoomMetrics::new(metrics, id),
+            current: initial,
+            tick_rate,
+            next_control_seq: 0,
+            loads: Vec::new(),
+            lockstep: match settings.sync_mode {
+                SyncMode::Free => None,
+                SyncMode::Strict => Some(Lockstep::new(lockstep, tick_rate)),
+            },
+            request_recver,
+            events,
+            state_sender,
+        };
+        tokio::spawn(async move {
+            // tokio's clock rather than the system's, so that tests can pause and advance it
+            let epoch = tokio::time::Instant::now();
+            let mut timestep = FixedTimestep::new(tick_rate, MAX_CATCH_UP_TICKS, epoch.elapsed());
+            loop {
+                let deadline = epoch + timestep.next_deadline();
+                tokio::time::delay_until(deadline).await;
+                let skipped = timestep.stats().skipped;
+                for _ in 0..timestep.due(epoch.elapsed()) {
+                    let started = Instant::now();
+                    if let Err(err) = room.step() {
+                        log::error!("{}", err);
+                    }
+                    let elapsed = started.elapsed();
+                    timestep.record_step(elapsed);
+                    room.metrics.tick_seconds.observe(elapsed.as_secs_f64());
+                }
+                if room.tick_rate != timestep.tick_rate() {
+                    log::info!("room {} now runs at {} ticks/s", id, room.tick_rate);
+                    timestep.set_tick_rate(room.tick_rate);
+                }
+                let stats = timestep.stats();
+                if stats.skipped > skipped {
+                    room.metrics.skipped_ticks.inc_by(stats.skipped - skipped);
+                    log::warn!(
+                        "room {} fell behind and skipped {} ticks",
+                        id,
+                        stats.skipped - skipped
+                    );
+                }
+                if let Err(err) = stats_sender.broadcast(stats) {
+                    log::error!("{}", err);
+                }
+            }
+        });
+
+        Self {
+            id,
+            name: settings.name,
+            sync_mode: settings.sync_mode,
+            requests,
+            state,
+            tick_stats,
+        }
+    }
+}
+
+struct RoomState {
+    id: RoomId,
+    metrics: RoomMetrics,
+    current: shared::State,
+    tick_rate: u32,
+    next_control_seq: ControlSequence,
+    /// Simulations to swap in at the start of a frame, sorted by frame.
+    loads: Vec<(shared::FrameIndex, shared::nbody::Simulation)>,
+    /// Only set in strict lockstep.
+    lockstep: Option<Lockstep>,
+    request_recver: mpsc::UnboundedReceiver<Request>,
+    events: mpsc::UnboundedSender<(RoomId, Event)>,
+    state_sender: watch::Sender<shared::State>,
+}
+
+impl RoomState {
+    fn step(&mut self) -> Result<(), watch::error::SendError<shared::State>> {
+        while let Ok(request) = self.request_recver.try_recv() {
+            let scheduled = match request {
+                Request::Input(mut client_input) => {
+                    let requested = client_input.input.frame_index;
+                    client_input.input.frame_index = self.current.schedule(client_input.input);
+                    if client_input.input.frame_index != requested {
+                        self.metrics.late_inputs.inc();
+                    }
+                    Scheduled::Input(client_input)
+                }
+                Request::Control(control) => {
+                    // far enough ahead that clients have it before they simulate the frame
+                    let control = ScheduledControl {
+                        frame_index: self.current.frame_index + shared::INPUT_BUFFER_FRAMES,
+                        seq: self.next_control_seq,
+                        control,
+                    };
+                    if !self.current.schedule_control(control) {
+                        // the queue is full, so clients would drop it too
+                        continue;
+                    }
+                    self.next_control_seq = self.next_control_seq.wrapping_add(1);
+                    Scheduled::Control(control)
+                }
+                Request::Load(simulation) => {
+                    if self.loads.len() >= MAX_SCHEDULED_LOADS {
+                        log::warn!("room {} dropping a load, too many are scheduled", self.id);
+                        continue;
+                    }
+                    let frame_index = self.current.frame_index + shared::INPUT_BUFFER_FRAMES;
+                    self.loads.push((frame_index, simulation));
+                    Scheduled::Load(frame_index)
+                }
+                Request::Kick(player_id) => {
+                    if let Some(lockstep) = &mut self.lockstep {
+                        lockstep.leave(player_id);
+                    }
+                    Scheduled::Kicked(player_id)
+                }
+                Request::Join(player_id) => {
+                    if let Some(lockstep) = &mut self.lockstep {
+                        lockstep.join(player_id, self.current.frame_index);
+                    }
+                    continue;
+                }
+                Request::Leave(player_id) => {
+                    if let Some(lockstep) = &mut self.lockstep {
+                        lockstep.leave(player_id);
+                    }
+                    continue;
+                }
+                Request::InputsComplete {
+                    player_id,
+                    frame_index,
+                } => {
+                    if let Some(lockstep) = &mut self.lockstep {
+                        lockstep.complete(player_id, frame_index);
+                    }
+                    continue;
+                }
+            };
+            self.send(Event::Scheduled(scheduled));
+        }
+        if !self.lockstep_ready() {
+            return Ok(());
+        }
+        self.take_load();
+        self.current.step();
+        if let Some(tick_rate) = self.current.playback.tick_rate {
+            if tick_rate != self.tick_rate {
+                self.tick_rate = tick_rate;
+                if let Some(lockstep) = &mut self.lockstep {
+                    lockstep.set_tick_rate(tick_rate);
+                }
+            }
+        }
+        self.metrics.frame.set(self.current.frame_index as f64);
+        self.metrics
+            .bodies
+            .set(self.current.simulation.bodies.len() as f64);
+        self.send(Event::Stepped(self.current.clone()));
+        self.state_sender.broadcast(self.current.clone())
+    }
+
+    /// Swaps in the simulation due on the current frame, before its inputs. Clients stop
+    /// before this frame and resync to the result.
+    fn take_load(&mut self) {
+        let frame_index = self.current.frame_index;
+        let due = self
+            .loads
+            .iter()
+            .take_while(|(frame, _)| *frame <= frame_index)
+            .count();
+        if let Some((_, simulation)) = self.loads.drain(..due).last() {
+            log::info!(
+                "room {} loading a simulation with {} bodies on frame {}",
+                self.id,
+                simulation.bodies.len(),
+                frame_index
+            );
+            self.current.simulation = simulation;
+        }
+    }
+
+    fn send(&self, event: Event) {
+        if let Err(err) = self.events.send((self.id, event)) {
+            log::error!("room {} event send error: {}", self.id, err);
+        }
+    }
+
+    /// Whether the current frame can be simulated. Always true unless in strict lockstep.
+    fn lockstep_ready(&mut self) -> bool {
+        let frame_index = self.current.frame_index;
+        let lockstep = match &mut self.lockstep {
+            Some(lockstep) => lockstep,
+            None => return true,
+        };
+        let stall_ticks = lockstep.stall_ticks();
+        match lockstep.tick(frame_index) {
+            Tick::Advance { waited } => {
+                if waited >= stall_ticks {
+                    log::info!(
+                        "room {} resumed frame {} after stalling for {:.2}s",
+                        self.id,
+                        frame_index,
+                        waited as f64 / self.tick_rate as f64
+                    );
+                }
+                true
+            }
+            Tick::Wait {
+                waited,
+                waiting_on,
+                timed_out,
+            } => {
+                if waited == stall_ticks {
+                    log::warn!(
+                        "room {} stalled on frame {} waiting on players {:?}",
+                        self.id,
+                        frame_index,
+                        waiting_on
+                    );
+                }
+                for player_id in timed_out {
+                    log::warn!(
+                        "player {} timed out holding up frame {}",
+                        player_id,
+                        frame_index
+                    );
+                    self.send(Event::Scheduled(Scheduled::TimedOut {
+                        player_id,
+                        frame_index,
+                    }));
+                }
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! How datagrams get to and from clients.
+//!
+//! The main loop only sees `TransportEvent`s and sends datagrams to addresses, so it doesn't
+//! care whether a client is a browser on WebRTC or WebSockets, a native client on plain UDP or
+//! a test in the same process. Each transport reports a peer as connected before its first
+//! datagram and as disconnected once, whether it went away or was disconnected by the server.
+//! `SimulatedTransport` wraps any of them to put their peers behind a bad network and
+//! `MeteredTransport` to count their traffic for `/metrics`.
+
+mod memory;
+mod metered;
+mod netsim;
+mod udp;
+mod webrtc;
+mod websocket;
+
+pub use memory::{MemoryClient, MemoryConnector, MemoryTransport};
+pub use metered::MeteredTransport;
+pub use netsim::SimulatedTransport;
+pub use udp::UdpTransport;
+pub use webrtc::WebRtcTransport;
+pub use websocket::{WebSocketEndpoint, WebSocketTransport};
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+
+#[derive(Debug, PartialEq)]
+pub enum TransportEvent {
+    Connected(SocketAddr),
+    Received(SocketAddr, Vec<u8>),
+    Disconnected(SocketAddr),
+}
+
+#[async_trait]
+pub trait Transport: Send {
+    /// Waits for the next event. Errors only affect a single datagram, so receiving can carry
+    /// on after one. The returned future may be dropped before it completes without losing
+    /// events, so it can be raced against other work.
+    async fn recv(&mut self) -> io::Result<TransportEvent>;
+
+    async fn send(&mut self, remote_addr: &SocketAddr, datagram: &[u8]) -> io::Result<()>;
+
+    /// Closes the connection, after which `recv` reports the peer as disconnected.
+    async fn disconnect(&mut self, remote_addr: &SocketAddr) -> io::Result<()>;
+
+    /// The peers that are currently connected.
+    fn peers(&self) -> Vec<SocketAddr>;
+}
+
+#[async_trait]
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    async fn recv(&mut self) -> io::Result<TransportEvent> {
+        (**self).recv().await
+    }
+
+    async fn send(&mut self, remote_addr: &SocketAddr, datagram: &[u8]) -> io::Result<()> {
+        (**self).send(remote_addr, datagram).await
+    }
+
+    async fn disconnect(&mut self, remote_addr: &SocketAddr) -> io::Result<()> {
+        (**self).disconnect(remote_addr).await
+    }
+
+    fn peers(&self) -> Vec<SocketAddr> {
+        (**self).peers()
+    }
+}
+
+fn not_connected(remote_addr: &SocketAddr) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotConnected,
+        format!("{} is not connected", remote_addr),
+    )
+}
+
+/// Serves several transports at once, such as WebRTC for browsers and UDP for native clients.
+/// Their peers are told apart by address, so they shouldn't share one.
+pub struct Multiplex {
+    transports: Vec<Box<dyn Transport>>,
+    /// Which transport each connected peer is on.
+    owners: HashMap<SocketAddr, usize>,
+}
+
+impl Multiplex {
+    pub fn new(transports: Vec<Box<dyn Transport>>) -> Self {
+        assert!(!transports.is_empty());
+        Self {
+            transports,
+            owners: HashMap::new(),
+        }
+    }
+
+    fn owner(&mut self, remote_addr: &SocketAddr) -> io::Result<&mut Box<dyn Transport>> {
+        match self.owners.get(remote_addr) {
+            Some(&index) => Ok(&mut self.transports[index]),
+            None => Err(not_connected(remote_addr)),
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for Multiplex {
+    async fn recv(&mut self) -> io::Result<TransportEvent> {
+        let (event, index, _) =
+            futures::future::select_all(self.transports.iter_mut().map(|t| t.recv())).await;
+        match &event {
+            Ok(TransportEvent::Connected(remote_addr)) => {
+                self.owners.insert(*remote_addr, index);
+            }
+            Ok(TransportEvent::Disconnected(remote_addr)) => {
+                self.owners.remove(remote_addr);
+            }
+            _ => {}
+        }
+        event
+    }
+
+    async fn send(&mut self, remote_addr: &SocketAddr, datagram: &[u8]) -> io::Result<()> {
+        self.owner(remote_addr)?.send(remote_addr, datagram).await
+    }
+
+    async fn disconnect(&mut self, remote_addr: &SocketAddr) -> io::Result<()> {
+        self.owner(remote_addr)?.disconnect(remote_addr).await
+    }
+
+    fn peers(&self) -> Vec<SocketAddr> {
+        self.owners.keys().copied().collect()
+    }
+}
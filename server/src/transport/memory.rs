@@ -0,0 +1,156 @@
+use super::{not_connected, Transport, TransportEvent};
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+#[derive(Debug)]
+enum FromClient {
+    Connect(SocketAddr, mpsc::UnboundedSender<Vec<u8>>),
+    Datagram(SocketAddr, Vec<u8>),
+    Close(SocketAddr),
+}
+
+/// Channels between the server and clients in the same process, for tests. Nothing is lost,
+/// duplicated or reordered.
+pub struct MemoryTransport {
+    from_clients: mpsc::UnboundedReceiver<FromClient>,
+    to_clients: HashMap<SocketAddr, mpsc::UnboundedSender<Vec<u8>>>,
+    pending: VecDeque<TransportEvent>,
+}
+
+/// Creates clients of a `MemoryTransport`.
+#[derive(Clone)]
+pub struct MemoryConnector {
+    sender: mpsc::UnboundedSender<FromClient>,
+    next_port: Arc<AtomicU16>,
+}
+
+pub struct MemoryClient {
+    local_addr: SocketAddr,
+    sender: mpsc::UnboundedSender<FromClient>,
+    recver: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> (Self, MemoryConnector) {
+        let (sender, from_clients) = mpsc::unbounded_channel();
+        let transport = Self {
+            from_clients,
+            to_clients: HashMap::new(),
+            pending: VecDeque::new(),
+        };
+        let connector = MemoryConnector {
+            sender,
+            next_port: Arc::new(AtomicU16::new(1)),
+        };
+        (transport, connector)
+    }
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    async fn recv(&mut self) -> io::Result<TransportEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+            let message = match self.from_clients.recv().await {
+                Some(message) => message,
+                // every connector and client is gone, so nothing else will happen
+                None => futures::future::pending().await,
+            };
+            match message {
+                FromClient::Connect(remote_addr, sender) => {
+                    self.to_clients.insert(remote_addr, sender);
+                    return Ok(TransportEvent::Connected(remote_addr));
+                }
+                // a client keeps sending until it notices it's been disconnected
+                FromClient::Datagram(remote_addr, datagram) => {
+                    if self.to_clients.contains_key(&remote_addr) {
+                        return Ok(TransportEvent::Received(remote_addr, datagram));
+                    }
+                }
+                FromClient::Close(remote_addr) => {
+                    if self.to_clients.remove(&remote_addr).is_some() {
+                        return Ok(TransportEvent::Disconnected(remote_addr));
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send(&mut self, remote_addr: &SocketAddr, datagram: &[u8]) -> io::Result<()> {
+        match self.to_clients.get(remote_addr) {
+            // the client may have been dropped with its close still on the way
+            Some(sender) => sender
+                .send(datagram.to_vec())
+                .map_err(|_| not_connected(remote_addr)),
+            None => Err(not_connected(remote_addr)),
+        }
+    }
+
+    async fn disconnect(&mut self, remote_addr: &SocketAddr) -> io::Result<()> {
+        if self.to_clients.remove(remote_addr).is_none() {
+            return Err(not_connected(remote_addr));
+        }
+        self.pending
+            .push_back(TransportEvent::Disconnected(*remote_addr));
+        Ok(())
+    }
+
+    fn peers(&self) -> Vec<SocketAddr> {
+        self.to_clients.keys().copied().collect()
+    }
+}
+
+impl MemoryConnector {
+    /// Connects a new client with an address of its own.
+    pub fn connect(&self) -> MemoryClient {
+        let port = self.next_port.fetch_add(1, Ordering::Relaxed);
+        self.connect_as(SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    /// Connects a client that stands in for one at `local_addr`, which must be unique.
+    pub(super) fn connect_as(&self, local_addr: SocketAddr) -> MemoryClient {
+        let (to_client, recver) = mpsc::unbounded_channel();
+        // the transport only goes away with the server, after which nothing is delivered
+        let _ = self.sender.send(FromClient::Connect(local_addr, to_client));
+        MemoryClient {
+            local_addr,
+            sender: self.sender.clone(),
+            recver,
+        }
+    }
+}
+
+impl MemoryClient {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn send(&self, datagram: Vec<u8>) {
+        let _ = self
+            .sender
+            .send(FromClient::Datagram(self.local_addr, datagram));
+    }
+
+    /// Waits for the next datagram. Returns `None` once the server has disconnected us.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.recver.recv().await
+    }
+
+    /// Returns a datagram if one has already arrived.
+    pub fn try_recv(&mut self) -> Option<Vec<u8>> {
+        self.recver.try_recv().ok()
+    }
+}
+
+impl Drop for MemoryClient {
+    fn drop(&mut self) {
+        let _ = self.sender.send(FromClient::Close(self.local_addr));
+    }
+}
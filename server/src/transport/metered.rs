@@ -0,0 +1,105 @@
+use super::{Transport, TransportEvent};
+use crate::metrics::{Counter, Gauge, Registry};
+use async_trait::async_trait;
+use std::io;
+use std::net::SocketAddr;
+
+/// Counters for one direction.
+struct Traffic {
+    datagrams: Counter,
+    bytes: Counter,
+    errors: Counter,
+}
+
+impl Traffic {
+    fn new(registry: &Registry, name: &str, direction: &str) -> Self {
+        let labels = [("transport", name), ("direction", direction)];
+        Self {
+            datagrams: registry.counter(
+                "lockstep_transport_datagrams_total",
+                "Datagrams sent and received, by transport.",
+                &labels,
+            ),
+            bytes: registry.counter(
+                "lockstep_transport_bytes_total",
+                "Bytes sent and received in datagrams, by transport.",
+                &labels,
+            ),
+            errors: registry.counter(
+                "lockstep_transport_errors_total",
+                "Datagrams that couldn't be sent or received, by transport.",
+                &labels,
+            ),
+        }
+    }
+
+    fn record(&self, len: usize) {
+        self.datagrams.inc();
+        self.bytes.inc_by(len as u64);
+    }
+}
+
+/// Wraps another transport to count its datagrams, bytes and peers in the metrics registry,
+/// labelled with `name`.
+pub struct MeteredTransport<T> {
+    inner: T,
+    received: Traffic,
+    sent: Traffic,
+    connections: Counter,
+    peers: Gauge,
+}
+
+impl<T: Transport> MeteredTransport<T> {
+    pub fn new(inner: T, name: &str, registry: &Registry) -> Self {
+        let labels = [("transport", name)];
+        Self {
+            inner,
+            received: Traffic::new(registry, name, "in"),
+            sent: Traffic::new(registry, name, "out"),
+            connections: registry.counter(
+                "lockstep_transport_connections_total",
+                "Peers that have connected, by transport.",
+                &labels,
+            ),
+            peers: registry.gauge(
+                "lockstep_transport_peers",
+                "Peers that are connected, by transport.",
+                &labels,
+            ),
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Transport> Transport for MeteredTransport<T> {
+    async fn recv(&mut self) -> io::Result<TransportEvent> {
+        let event = self.inner.recv().await;
+        match &event {
+            Ok(TransportEvent::Connected(_)) => {
+                self.connections.inc();
+                self.peers.set(self.inner.peers().len() as f64);
+            }
+            Ok(TransportEvent::Received(_, datagram)) => self.received.record(datagram.len()),
+            Ok(TransportEvent::Disconnected(_)) => self.peers.set(self.inner.peers().len() as f64),
+            Err(_) => self.received.errors.inc(),
+        }
+        event
+    }
+
+    async fn send(&mut self, remote_addr: &SocketAddr, datagram: &[u8]) -> io::Result<()> {
+        let result = self.inner.send(remote_addr, datagram).await;
+        match &result {
+            Ok(()) => self.sent.record(datagram.len()),
+            Err(_) => self.sent.errors.inc(),
+        }
+        result
+    }
+
+    async fn disconnect(&mut self, remote_addr: &SocketAddr) -> io::Result<()> {
+        self.inner.disconnect(remote_addr).await
+    }
+
+    fn peers(&self) -> Vec<SocketAddr> {
+        self.inner.peers()
+    }
+}
@@ -0,0 +1,159 @@
+use super::{not_connected, Transport, TransportEvent};
+use async_trait::async_trait;
+use shared::netsim::{Link, LinkStats, NetworkConditions};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// A link each way for every peer.
+struct PeerLinks {
+    inbound: Link<Vec<u8>>,
+    outbound: Link<Vec<u8>>,
+}
+
+/// Wraps another transport to put every peer behind a simulated network, for reproducing
+/// loss, latency and reordering. Connections and disconnections aren't delayed. Each peer's
+/// links are seeded from `NetworkConditions::seed` and the order peers connect in, so a run
+/// with the same peers connecting in the same order makes the same choices.
+pub struct SimulatedTransport<T> {
+    inner: T,
+    conditions: NetworkConditions,
+    /// Link times are measured from here, on tokio's clock so that tests can control it.
+    epoch: tokio::time::Instant,
+    links: HashMap<SocketAddr, PeerLinks>,
+    connected: u64,
+}
+
+impl<T: Transport> SimulatedTransport<T> {
+    pub fn new(inner: T, conditions: NetworkConditions) -> Self {
+        Self {
+            inner,
+            conditions,
+            epoch: tokio::time::Instant::now(),
+            links: HashMap::new(),
+            connected: 0,
+        }
+    }
+
+    /// What each peer's links have done so far, inbound then outbound.
+    pub fn stats(&self) -> HashMap<SocketAddr, (LinkStats, LinkStats)> {
+        self.links
+            .iter()
+            .map(|(addr, links)| (*addr, (links.inbound.stats(), links.outbound.stats())))
+            .collect()
+    }
+
+    fn now(&self) -> Duration {
+        self.epoch.elapsed()
+    }
+
+    fn on_connected(&mut self, remote_addr: SocketAddr) {
+        let seed = self.conditions.seed.wrapping_add(self.connected * 2);
+        self.connected += 1;
+        self.links.insert(
+            remote_addr,
+            PeerLinks {
+                inbound: Link::new(self.conditions.with_seed(seed)),
+                outbound: Link::new(self.conditions.with_seed(seed.wrapping_add(1))),
+            },
+        );
+    }
+
+    /// Hands every outbound datagram that's due to the inner transport.
+    async fn send_due(&mut self) {
+        let now = self.now();
+        let mut due = Vec::new();
+        for (remote_addr, links) in self.links.iter_mut() {
+            while let Some(datagram) = links.outbound.poll(now) {
+                due.push((*remote_addr, datagram));
+            }
+        }
+        for (remote_addr, datagram) in due {
+            if let Err(err) = self.inner.send(&remote_addr, &datagram).await {
+                log::warn!(
+                    "could not send delayed datagram to {}: {}",
+                    remote_addr,
+                    err
+                );
+            }
+        }
+    }
+
+    fn take_due_inbound(&mut self) -> Option<TransportEvent> {
+        let now = self.now();
+        self.links.iter_mut().find_map(|(remote_addr, links)| {
+            let datagram = links.inbound.poll(now)?;
+            Some(TransportEvent::Received(*remote_addr, datagram))
+        })
+    }
+
+    fn next_due(&self) -> Option<Duration> {
+        self.links
+            .values()
+            .flat_map(|links| vec![links.inbound.next_due(), links.outbound.next_due()])
+            .flatten()
+            .min()
+    }
+}
+
+#[async_trait]
+impl<T: Transport> Transport for SimulatedTransport<T> {
+    async fn recv(&mut self) -> io::Result<TransportEvent> {
+        loop {
+            self.send_due().await;
+            if let Some(event) = self.take_due_inbound() {
+                return Ok(event);
+            }
+
+            let event = match self.next_due() {
+                Some(due) => {
+                    let wake = self.epoch + due;
+                    tokio::select! {
+                        event = self.inner.recv() => event?,
+                        _ = tokio::time::delay_until(wake) => continue,
+                    }
+                }
+                None => self.inner.recv().await?,
+            };
+            match event {
+                TransportEvent::Connected(remote_addr) => {
+                    self.on_connected(remote_addr);
+                    return Ok(event);
+                }
+                TransportEvent::Received(remote_addr, datagram) => {
+                    let now = self.now();
+                    // anything from a peer we don't know about is dropped like the inner
+                    // transport would
+                    if let Some(links) = self.links.get_mut(&remote_addr) {
+                        links.inbound.send(now, datagram.len(), datagram);
+                    }
+                }
+                TransportEvent::Disconnected(remote_addr) => {
+                    self.links.remove(&remote_addr);
+                    return Ok(event);
+                }
+            }
+        }
+    }
+
+    async fn send(&mut self, remote_addr: &SocketAddr, datagram: &[u8]) -> io::Result<()> {
+        let now = self.now();
+        let links = self
+            .links
+            .get_mut(remote_addr)
+            .ok_or_else(|| not_connected(remote_addr))?;
+        links.outbound.send(now, datagram.len(), datagram.to_vec());
+        self.send_due().await;
+        Ok(())
+    }
+
+    async fn disconnect(&mut self, remote_addr: &SocketAddr) -> io::Result<()> {
+        self.links.remove(remote_addr);
+        self.inner.disconnect(remote_addr).await
+    }
+
+    fn peers(&self) -> Vec<SocketAddr> {
+        self.inner.peers()
+    }
+}
@@ -0,0 +1,93 @@
+use super::{not_connected, Transport, TransportEvent};
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+/// Plain UDP for native clients. There's no handshake, so a peer connects with its first
+/// datagram and is disconnected once it has been quiet for `idle_timeout`. Clients ping every
+/// frame, so they're only quiet once they're gone.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    idle_timeout: Duration,
+    last_seen: HashMap<SocketAddr, Instant>,
+    pending: VecDeque<TransportEvent>,
+    buf: Vec<u8>,
+}
+
+impl UdpTransport {
+    pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub async fn bind(listen_addr: SocketAddr, idle_timeout: Duration) -> io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(listen_addr).await?,
+            idle_timeout,
+            last_seen: HashMap::new(),
+            pending: VecDeque::new(),
+            // big enough for the largest UDP datagram
+            buf: vec![0; 65536],
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+#[async_trait]
+impl Transport for UdpTransport {
+    async fn recv(&mut self) -> io::Result<TransportEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+            let idle_timeout = self.idle_timeout;
+            let idle = self
+                .last_seen
+                .iter()
+                .find(|(_, seen)| seen.elapsed() >= idle_timeout)
+                .map(|(addr, _)| *addr);
+            if let Some(idle) = idle {
+                self.last_seen.remove(&idle);
+                return Ok(TransportEvent::Disconnected(idle));
+            }
+
+            // wake up now and then to look for idle peers even if nothing arrives
+            let recv = self.socket.recv_from(&mut self.buf);
+            let (len, remote_addr) = match tokio::time::timeout(Duration::from_secs(1), recv).await
+            {
+                Ok(received) => received?,
+                Err(_) => continue,
+            };
+            let event = TransportEvent::Received(remote_addr, self.buf[..len].to_vec());
+            if self.last_seen.insert(remote_addr, Instant::now()).is_none() {
+                self.pending.push_back(event);
+                return Ok(TransportEvent::Connected(remote_addr));
+            }
+            return Ok(event);
+        }
+    }
+
+    async fn send(&mut self, remote_addr: &SocketAddr, datagram: &[u8]) -> io::Result<()> {
+        if !self.last_seen.contains_key(remote_addr) {
+            return Err(not_connected(remote_addr));
+        }
+        self.socket.send_to(datagram, remote_addr).await?;
+        Ok(())
+    }
+
+    async fn disconnect(&mut self, remote_addr: &SocketAddr) -> io::Result<()> {
+        if self.last_seen.remove(remote_addr).is_none() {
+            return Err(not_connected(remote_addr));
+        }
+        self.pending
+            .push_back(TransportEvent::Disconnected(*remote_addr));
+        Ok(())
+    }
+
+    fn peers(&self) -> Vec<SocketAddr> {
+        self.last_seen.keys().copied().collect()
+    }
+}
@@ -0,0 +1,72 @@
+use super::{Transport, TransportEvent};
+use async_trait::async_trait;
+use std::collections::{HashSet, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use webrtc_unreliable::{MessageType, Server as RtcServer, SessionEndpoint};
+
+/// WebRTC data channels for browsers. Sessions are set up over HTTP with the `SessionEndpoint`.
+pub struct WebRtcTransport {
+    server: RtcServer,
+    /// Peers that have been reported as connected.
+    known: HashSet<SocketAddr>,
+    pending: VecDeque<TransportEvent>,
+}
+
+impl WebRtcTransport {
+    pub async fn bind(listen_addr: SocketAddr, public_addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self {
+            server: RtcServer::new(listen_addr, public_addr).await?,
+            known: HashSet::new(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    pub fn session_endpoint(&self) -> SessionEndpoint {
+        self.server.session_endpoint()
+    }
+}
+
+#[async_trait]
+impl Transport for WebRtcTransport {
+    async fn recv(&mut self) -> io::Result<TransportEvent> {
+        if let Some(event) = self.pending.pop_front() {
+            return Ok(event);
+        }
+        // the server times out clients while receiving, this notices the ones it dropped
+        let server = &self.server;
+        if let Some(&gone) = self.known.iter().find(|addr| !server.is_connected(addr)) {
+            self.known.remove(&gone);
+            return Ok(TransportEvent::Disconnected(gone));
+        }
+
+        let received = self.server.recv().await?;
+        let remote_addr = received.remote_addr;
+        let event = TransportEvent::Received(remote_addr, received.message.as_ref().to_vec());
+        if self.known.insert(remote_addr) {
+            self.pending.push_back(event);
+            return Ok(TransportEvent::Connected(remote_addr));
+        }
+        Ok(event)
+    }
+
+    async fn send(&mut self, remote_addr: &SocketAddr, datagram: &[u8]) -> io::Result<()> {
+        self.server
+            .send(datagram, MessageType::Binary, remote_addr)
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn disconnect(&mut self, remote_addr: &SocketAddr) -> io::Result<()> {
+        self.server.disconnect(remote_addr).await?;
+        if self.known.remove(remote_addr) {
+            self.pending
+                .push_back(TransportEvent::Disconnected(*remote_addr));
+        }
+        Ok(())
+    }
+
+    fn peers(&self) -> Vec<SocketAddr> {
+        self.known.iter().copied().collect()
+    }
+}
@@ -0,0 +1,79 @@
+use super::{MemoryConnector, MemoryTransport, Transport, TransportEvent};
+use async_trait::async_trait;
+use futures::{SinkExt, StreamExt};
+use std::io;
+use std::net::SocketAddr;
+use warp::ws::{Message, WebSocket};
+
+/// WebSockets for browsers that can't open a WebRTC data channel. Each binary message carries
+/// one datagram, framed exactly as over WebRTC. Sockets are accepted over HTTP by the
+/// `WebSocketEndpoint`, which relays each one through in-process channels like
+/// `MemoryTransport`'s.
+pub struct WebSocketTransport {
+    inner: MemoryTransport,
+}
+
+/// Accepts WebSocket connections for a `WebSocketTransport`.
+#[derive(Clone)]
+pub struct WebSocketEndpoint {
+    connector: MemoryConnector,
+}
+
+impl WebSocketTransport {
+    pub fn new() -> (Self, WebSocketEndpoint) {
+        let (inner, connector) = MemoryTransport::new();
+        (Self { inner }, WebSocketEndpoint { connector })
+    }
+}
+
+impl WebSocketEndpoint {
+    /// Relays datagrams between the socket and the transport until either side closes.
+    pub async fn serve(self, socket: WebSocket, remote_addr: SocketAddr) {
+        let mut client = self.connector.connect_as(remote_addr);
+        let (mut sink, mut stream) = socket.split();
+        loop {
+            tokio::select! {
+                message = stream.next() => match message {
+                    Some(Ok(message)) if message.is_binary() => client.send(message.into_bytes()),
+                    Some(Ok(message)) if message.is_close() => break,
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => {
+                        log::debug!("websocket error from {}: {}", remote_addr, err);
+                        break;
+                    }
+                    None => break,
+                },
+                datagram = client.recv() => match datagram {
+                    Some(datagram) => {
+                        if let Err(err) = sink.send(Message::binary(datagram)).await {
+                            log::debug!("websocket error to {}: {}", remote_addr, err);
+                            break;
+                        }
+                    }
+                    // disconnected by the server
+                    None => break,
+                },
+            }
+        }
+        let _ = sink.close().await;
+    }
+}
+
+#[async_trait]
+impl Transport for WebSocketTransport {
+    async fn recv(&mut self) -> io::Result<TransportEvent> {
+        self.inner.recv().await
+    }
+
+    async fn send(&mut self, remote_addr: &SocketAddr, datagram: &[u8]) -> io::Result<()> {
+        self.inner.send(remote_addr, datagram).await
+    }
+
+    async fn disconnect(&mut self, remote_addr: &SocketAddr) -> io::Result<()> {
+        self.inner.disconnect(remote_addr).await
+    }
+
+    fn peers(&self) -> Vec<SocketAddr> {
+        self.inner.peers()
+    }
+}
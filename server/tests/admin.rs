@@ -0,0 +1,245 @@
+//! Changing a running room through the admin endpoints, with clients following along.
+
+mod harness;
+
+use client::session::SessionError;
+use harness::Harness;
+use server::admin;
+use server::config::{AppConfig, Scenario};
+use server::room::RoomSettings;
+use shared::lockstep::SyncMode;
+use shared::packet::MAX_PACKET_SIZE;
+use warp::http::StatusCode;
+
+const TOKEN: &str = "correct horse battery staple";
+
+async fn post(harness: &Harness, path: &str, token: Option<&str>, body: &[u8]) -> StatusCode {
+    let routes = admin::routes(harness.lobby(), Some(TOKEN.to_owned()));
+    let mut request = warp::test::request()
+        .method("POST")
+        .path(path)
+        .header("content-type", "application/json");
+    if let Some(token) = token {
+        request = request.header("authorization", format!("Bearer {}", token));
+    }
+    request.body(body).reply(&routes).await.status()
+}
+
+async fn command(harness: &Harness, json: &str) -> StatusCode {
+    post(harness, "/admin/rooms/0", Some(TOKEN), json.as_bytes()).await
+}
+
+#[tokio::test]
+async fn requests_need_the_token() {
+    let harness = Harness::with_scenario(Scenario::Orbits).await;
+    let pause = br#"{"command": "pause"}"#;
+    assert_eq!(
+        post(&harness, "/admin/rooms/0", None, pause).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        post(&harness, "/admin/rooms/0", Some("guess"), pause).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        post(&harness, "/admin/rooms/9", Some(TOKEN), pause).await,
+        StatusCode::NOT_FOUND
+    );
+
+    let disabled = admin::routes(harness.lobby(), None);
+    let response = warp::test::request()
+        .method("POST")
+        .path("/admin/rooms/0")
+        .header("authorization", format!("Bearer {}", TOKEN))
+        .body(&pause[..])
+        .reply(&disabled)
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn simulation_changes_reach_every_peer() {
+    let mut harness = Harness::with_scenario(Scenario::Orbits).await;
+    harness.connect().await;
+    harness.connect_with(|session| session.spectate(30)).await;
+    harness.run_frames(60).await;
+
+    let status = command(&harness, r#"{"command": "remove_body", "body_id": 1}"#).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    harness.run_frames(30).await;
+    let ids = harness
+        .server_state()
+        .simulation
+        .bodies
+        .iter()
+        .map(|body| body.id())
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec![0, 2]);
+
+    let status = command(&harness, r#"{"command": "reset", "scenario": "empty"}"#).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    harness.run_frames(30).await;
+    assert!(harness.server_state().simulation.bodies.is_empty());
+
+    let mut snapshot = shared::State::new();
+    snapshot
+        .simulation
+        .add_body(shared::nbody::Body::new_lossy(40., -20., 50.));
+    let path = "/admin/rooms/0/snapshot";
+    let status = post(&harness, path, Some(TOKEN), b"not a state").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let status = post(&harness, path, Some(TOKEN), &snapshot.encode()).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    harness.run_frames(120).await;
+
+    harness.assert_hashes_agree();
+    let server = harness.server_state();
+    assert_eq!(server.simulation.bodies.len(), 1);
+    let player = harness.client(0).state();
+    assert_eq!(player.frame_index, server.frame_index);
+    assert_eq!(player.hash(), server.hash());
+    // the spectator is behind but has seen every change
+    assert_eq!(harness.client(1).state().simulation.bodies.len(), 1);
+}
+
+#[tokio::test]
+async fn snapshots_larger_than_a_packet_reach_every_peer() {
+    let mut harness = Harness::with_scenario(Scenario::Orbits).await;
+    harness.connect().await;
+    harness.connect_with(|session| session.spectate(30)).await;
+    harness.run_frames(60).await;
+
+    let mut snapshot = shared::State::new();
+    for index in 0..300 {
+        let x = (index % 20) as f32 * 400. - 4000.;
+        let y = (index / 20) as f32 * 400. - 3000.;
+        snapshot
+            .simulation
+            .add_body(shared::nbody::Body::new_lossy(x, y, 5.));
+    }
+    let bytes = snapshot.encode();
+    assert!(bytes.len() > 10 * MAX_PACKET_SIZE);
+    let status = post(&harness, "/admin/rooms/0/snapshot", Some(TOKEN), &bytes).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    harness.run_frames(120).await;
+
+    harness.assert_hashes_agree();
+    let server = harness.server_state();
+    assert_eq!(server.simulation.bodies.len(), 300);
+    let player = harness.client(0).state();
+    assert_eq!(player.frame_index, server.frame_index);
+    assert_eq!(player.hash(), server.hash());
+    assert_eq!(harness.client(1).state().simulation.bodies.len(), 300);
+    // resyncing to the new simulation took many packets rather than one large one
+    assert!(harness.largest_datagram() <= MAX_PACKET_SIZE);
+}
+
+#[tokio::test]
+async fn peers_follow_a_new_tick_rate() {
+    let mut harness = Harness::new(AppConfig {
+        scenario: Scenario::Orbits,
+        tick_rate: 60,
+        ..AppConfig::default()
+    })
+    .await;
+    harness.connect().await;
+    harness.connect().await;
+    harness.run_frames(60).await;
+
+    // players can't change it themselves
+    harness.clients[0]
+        .session
+        .send_control(shared::control::Control::TickRate(10));
+    harness.run_frames(30).await;
+    assert_eq!(harness.server_state().playback.tick_rate, None);
+    assert_eq!(harness.client(0).rejected_controls(), 1);
+
+    let before = harness.server_state().frame_index;
+    let status = command(&harness, r#"{"command": "tick_rate", "tick_rate": 30}"#).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let status = command(&harness, r#"{"command": "tick_rate", "tick_rate": 0}"#).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    // four seconds of 60 ticks a second
+    harness.run_frames(240).await;
+
+    harness.assert_hashes_agree();
+    let server = harness.server_state();
+    assert_eq!(server.playback.tick_rate, Some(30));
+    let frames = server.frame_index - before;
+    assert!((120..140).contains(&frames), "ran {} frames", frames);
+    for index in 0..2 {
+        assert_eq!(harness.client(index).state().hash(), server.hash());
+    }
+}
+
+#[tokio::test]
+async fn player_limits_follow_a_new_tick_rate() {
+    use shared::control::Control;
+    use shared::limits::CONTROL_BURST;
+
+    let mut harness = Harness::new(AppConfig {
+        scenario: Scenario::Orbits,
+        tick_rate: 60,
+        ..AppConfig::default()
+    })
+    .await;
+    harness.connect().await;
+    harness.run_frames(60).await;
+    let status = command(&harness, r#"{"command": "tick_rate", "tick_rate": 30}"#).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    harness.run_frames(60).await;
+
+    for _ in 0..CONTROL_BURST {
+        harness.clients[0]
+            .session
+            .send_control(Control::Timescale(2));
+    }
+    // a second and a half earns three controls back at 30 frames a second, not one and a half
+    harness.run_frames(90).await;
+    for _ in 0..2 {
+        harness.clients[0]
+            .session
+            .send_control(Control::Timescale(2));
+    }
+    harness.run_frames(30).await;
+
+    harness.assert_hashes_agree();
+    assert_eq!(harness.client(0).rejected_controls(), 0);
+}
+
+#[tokio::test]
+async fn kicked_players_are_disconnected() {
+    let mut harness = Harness::with_scenario(Scenario::Orbits).await;
+    harness.connect().await;
+    let kicked = harness.connect().await;
+    harness.run_frames(30).await;
+
+    let player_id = harness.client(kicked).player_id().unwrap();
+    let json = format!(r#"{{"command": "kick", "player_id": {}}}"#, player_id);
+    assert_eq!(
+        command(&harness, r#"{"command": "kick", "player_id": 99}"#).await,
+        StatusCode::NOT_FOUND
+    );
+    let settings = RoomSettings {
+        name: "other".to_owned(),
+        scenario: Scenario::default(),
+        sync_mode: SyncMode::default(),
+    };
+    let lobby = harness.lobby();
+    let other_room = lobby.lock().unwrap().create(settings, Default::default());
+    let path = format!("/admin/rooms/{}", other_room.unwrap());
+    assert_eq!(
+        post(&harness, &path, Some(TOKEN), json.as_bytes()).await,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(command(&harness, &json).await, StatusCode::ACCEPTED);
+    harness.run_frames(30).await;
+
+    assert_eq!(
+        harness.rejection(kicked),
+        Some(&SessionError::Rejected(shared::ConnectionRejected::Kicked))
+    );
+    assert_eq!(harness.rejection(0), None);
+    let server = harness.server_state();
+    assert_eq!(harness.client(0).state().hash(), server.hash());
+}
@@ -0,0 +1,154 @@
+//! The server and clients together behind a simulated network, checking that peers stay in
+//! sync, or get back in sync, however badly it behaves.
+
+mod harness;
+
+use harness::{Harness, Peer};
+use server::config::{AppConfig, Scenario};
+use shared::netsim::NetworkConditions;
+
+async fn harness(network: NetworkConditions) -> Harness {
+    Harness::new(AppConfig {
+        scenario: Scenario::Orbits,
+        network,
+        ..AppConfig::default()
+    })
+    .await
+}
+
+fn lossy(seed: u64) -> NetworkConditions {
+    NetworkConditions {
+        latency: 0.05,
+        jitter: 0.03,
+        loss: 0.1,
+        duplicate: 0.02,
+        reorder: 0.05,
+        seed,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn idle_clients_agree_over_a_lossy_network() {
+    let mut harness = harness(lossy(1)).await;
+    for _ in 0..3 {
+        harness.connect().await;
+    }
+    harness.run_frames(600).await;
+
+    // without inputs there's nothing to mispredict, however many packets are lost
+    assert!(harness.assert_hashes_agree() > 0);
+    for index in 0..3 {
+        assert!(harness.client(index).player_id().is_some());
+        assert!(harness.client(index).hash_successes() > 0);
+    }
+}
+
+#[tokio::test]
+async fn inputs_converge_over_a_lossy_network() {
+    let mut harness = harness(lossy(2)).await;
+    for _ in 0..3 {
+        harness.connect().await;
+    }
+    for index in 0..3 {
+        for n in 0..4 {
+            let x = -1500. + 1000. * index as f32;
+            let event = shared::AddBodyEvent::new(x, 400. + 200. * n as f32, 10.);
+            harness.input(index, 60 + 40 * n, event);
+        }
+    }
+    harness.run_frames(900).await;
+
+    // the last inputs are sent by frame 180, then clients have a few seconds to resync
+    assert!(harness.assert_hashes_agree_from(600) > 0);
+    let everyone = [
+        Peer::Server,
+        Peer::Client(0),
+        Peer::Client(1),
+        Peer::Client(2),
+    ];
+    let late = harness.common_checkpoints(&everyone);
+    assert!(late.iter().any(|frame| *frame >= 600), "{:?}", late);
+    assert!(harness.server_state().simulation.bodies.len() > 3);
+}
+
+#[tokio::test]
+async fn late_joiners_resync_over_limited_bandwidth() {
+    let mut harness = harness(NetworkConditions {
+        latency: 0.03,
+        // a full state takes a few ticks to arrive
+        bandwidth: 20_000,
+        max_queue: 1.,
+        ..Default::default()
+    })
+    .await;
+    harness.connect().await;
+    harness.input(0, 30, shared::AddBodyEvent::new(800., 0., 20.));
+    harness.run_frames(300).await;
+
+    let late = harness.connect().await;
+    harness.run_frames(300).await;
+
+    assert!(harness.assert_hashes_agree_from(450) > 0);
+    assert!(harness.client(late).has_state());
+    let both = [Peer::Server, Peer::Client(0), Peer::Client(late)];
+    assert!(!harness.common_checkpoints(&both).is_empty());
+}
+
+#[tokio::test]
+async fn large_states_arrive_over_a_lossy_network() {
+    use shared::nbody::Body;
+
+    let mut initial = shared::State::new();
+    for n in 0..600 {
+        let (x, y) = ((n % 25) as f32 * 200., (n / 25) as f32 * 200.);
+        initial.simulation.add_body(Body::new_lossy(x, y, 1.));
+    }
+    // nothing needs simulating to check that the state arrives
+    initial.playback.paused = true;
+    let network = NetworkConditions {
+        loss: 0.25,
+        ..lossy(6)
+    };
+    let mut harness = Harness::with_state(
+        AppConfig {
+            network,
+            ..AppConfig::default()
+        },
+        initial,
+    )
+    .await;
+
+    // the state takes dozens of fragments, which almost never all arrive the first time
+    let client = harness.connect().await;
+    harness.run_frames(300).await;
+
+    assert!(harness.client(client).has_state());
+    assert!(harness.assert_hashes_agree() > 0);
+}
+
+#[tokio::test]
+async fn resent_controls_apply_once_over_a_lossy_network() {
+    use shared::control::Control;
+
+    let mut harness = harness(lossy(3)).await;
+    harness.connect().await;
+    harness.connect().await;
+    harness.run_frames(60).await;
+    harness.clients[0].session.send_control(Control::Pause);
+    harness.run_frames(120).await;
+
+    let mut stepped = harness.server_state().simulation;
+    for _ in 0..3 {
+        stepped.step();
+    }
+    // each control is resent in every packet until one of them is acknowledged
+    harness.clients[1].session.send_control(Control::Step(3));
+    harness.run_frames(180).await;
+
+    harness.assert_hashes_agree_from(240);
+    let server = harness.server_state();
+    assert!(server.playback.paused);
+    assert_eq!(server.playback.pending_steps, 0);
+    assert!(server.simulation == stepped);
+}
@@ -0,0 +1,311 @@
+//! Runs the server and any number of clients in one process, over the in-memory transport and
+//! on tokio's paused clock, so every test sees the same sequence of events however fast the
+//! machine is.
+//!
+//! Time only moves in `run_frames`, one server tick at a time. After each tick the server's
+//! tasks are left to settle, then every client receives what the server sent, adds the inputs
+//! scripted for it and simulates up to the server's frame, and the server settles again. The
+//! hashes that every peer had at each checkpoint frame are kept for `assert_hashes_agree`.
+//!
+//! `AppConfig::network` puts every client behind a simulated network, in both directions.
+
+#![allow(dead_code)]
+
+use client::session::{Session, SessionError};
+use server::app::App;
+use server::config::{AppConfig, Scenario};
+use server::lobby::SharedLobby;
+use server::metrics::SharedRegistry;
+use server::room::DEFAULT_ROOM;
+use server::transport::{
+    MemoryClient, MemoryConnector, MemoryTransport, MeteredTransport, SimulatedTransport,
+};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// How many times the runtime is yielded to after anything happens. Each yield lets every
+/// ready task run once, and a datagram takes a few hops between tasks to get anywhere.
+const SETTLE_YIELDS: usize = 32;
+
+/// Who a checkpoint hash came from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Peer {
+    Server,
+    Client(usize),
+}
+
+pub struct SimClient {
+    pub session: Session,
+    transport: MemoryClient,
+    /// Inputs still to send and the frame to send each one on, sorted by frame.
+    script: Vec<(shared::FrameIndex, shared::AddBodyEvent)>,
+    /// Set once the server disconnects the client, which stops it.
+    rejected: Option<SessionError>,
+    /// The in-memory transport takes datagrams of any size, unlike a real network.
+    largest_datagram: usize,
+}
+
+pub struct Harness {
+    pub config: AppConfig,
+    lobby: SharedLobby,
+    pub metrics: SharedRegistry,
+    connector: MemoryConnector,
+    pub clients: Vec<SimClient>,
+    epoch: tokio::time::Instant,
+    /// How many ticks `run_frames` has advanced the clock by.
+    ticks: u64,
+    checkpoint_interval: shared::FrameIndex,
+    checkpoints: BTreeMap<shared::FrameIndex, Vec<(Peer, u64)>>,
+}
+
+impl Harness {
+    /// Starts the server with `config`, without any clients. Pauses tokio's clock, so it must
+    /// be called from a test on the basic scheduler. Clients are behind `config.network`.
+    pub async fn new(config: AppConfig) -> Self {
+        let initial = config.scenario.state().expect("invalid scenario");
+        Self::with_state(config, initial).await
+    }
+
+    /// Like `new` but starting from `initial` rather than the config's scenario.
+    pub async fn with_state(config: AppConfig, initial: shared::State) -> Self {
+        tokio::time::pause();
+        let app = App::new(config.clone(), initial).expect("could not start the server");
+        let lobby = app.lobby();
+        let metrics = app.metrics();
+        let (transport, connector) = MemoryTransport::new();
+        let transport = MeteredTransport::new(transport, "memory", &metrics);
+        if config.network.is_perfect() {
+            tokio::spawn(app.run(transport));
+        } else {
+            tokio::spawn(app.run(SimulatedTransport::new(transport, config.network)));
+        }
+
+        let mut harness = Self {
+            config,
+            lobby,
+            metrics,
+            connector,
+            clients: Vec::new(),
+            epoch: tokio::time::Instant::now(),
+            ticks: 0,
+            checkpoint_interval: 10,
+            checkpoints: BTreeMap::new(),
+        };
+        harness.settle().await;
+        harness
+    }
+
+    /// The default config, apart from starting with `scenario`.
+    pub async fn with_scenario(scenario: Scenario) -> Self {
+        Self::new(AppConfig {
+            scenario,
+            ..AppConfig::default()
+        })
+        .await
+    }
+
+    /// Connects a client that joins as a player, returning its index.
+    pub async fn connect(&mut self) -> usize {
+        self.connect_with(|_| {}).await
+    }
+
+    /// Connects a client after `setup` has had a chance to configure its session, such as to
+    /// make it a spectator.
+    pub async fn connect_with<F: FnOnce(&mut Session)>(&mut self, setup: F) -> usize {
+        let mut session = Session::without_state();
+        session.set_client_name(format!("client{}", self.clients.len()));
+        session.set_checkpoint_interval(self.checkpoint_interval);
+        setup(&mut session);
+        self.clients.push(SimClient {
+            session,
+            transport: self.connector.connect(),
+            script: Vec::new(),
+            rejected: None,
+            largest_datagram: 0,
+        });
+        self.settle().await;
+        self.clients.len() - 1
+    }
+
+    /// Hashes are compared on every frame that's a multiple of `interval`. Only affects
+    /// clients connected after it's called.
+    pub fn set_checkpoint_interval(&mut self, interval: shared::FrameIndex) {
+        self.checkpoint_interval = interval;
+    }
+
+    /// Has the client add a body once it has simulated `frame`.
+    pub fn input(&mut self, client: usize, frame: shared::FrameIndex, event: shared::AddBodyEvent) {
+        let script = &mut self.clients[client].script;
+        script.push((frame, event));
+        script.sort_by_key(|(frame, _)| *frame);
+    }
+
+    /// Advances the clock one server tick at a time, for `frames` ticks.
+    pub async fn run_frames(&mut self, frames: u32) {
+        for _ in 0..frames {
+            self.ticks += 1;
+            // to the exact deadline, since whole periods would fall behind it by rounding
+            let nanos = self.ticks as u128 * 1_000_000_000 / self.config.tick_rate as u128;
+            let deadline = Duration::from_nanos(nanos as u64 + 1);
+            tokio::time::advance(deadline.saturating_sub(self.now())).await;
+            self.settle().await;
+            self.record_server_checkpoint();
+
+            let now = self.now();
+            for index in 0..self.clients.len() {
+                self.tick_client(index, now);
+            }
+            self.settle().await;
+        }
+    }
+
+    pub fn lobby(&self) -> SharedLobby {
+        self.lobby.clone()
+    }
+
+    /// Why the server disconnected the client, if it has.
+    pub fn rejection(&self, index: usize) -> Option<&SessionError> {
+        self.clients[index].rejected.as_ref()
+    }
+
+    /// The size of the largest datagram the server has sent to any client.
+    pub fn largest_datagram(&self) -> usize {
+        self.clients
+            .iter()
+            .map(|client| client.largest_datagram)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The time since the harness started, as the clients see it.
+    pub fn now(&self) -> Duration {
+        self.epoch.elapsed()
+    }
+
+    /// The default room's latest state.
+    pub fn server_state(&self) -> shared::State {
+        let lobby = self.lobby.lock().unwrap();
+        let room = lobby.room(DEFAULT_ROOM).unwrap();
+        let state = room.state.borrow().clone();
+        state
+    }
+
+    pub fn client(&self, index: usize) -> &Session {
+        &self.clients[index].session
+    }
+
+    /// Panics unless every peer that reached a checkpoint frame had the same hash on it, and
+    /// no client failed to match a hash from the server. Returns how many checkpoints were
+    /// compared between at least two peers.
+    pub fn assert_hashes_agree(&self) -> usize {
+        let compared = self.assert_hashes_agree_from(0);
+        for (index, client) in self.clients.iter().enumerate() {
+            assert_eq!(
+                client.session.hash_failures(),
+                0,
+                "client {} failed to match the server's hash: {:?}",
+                index,
+                client.session.desync_reports()
+            );
+        }
+        compared
+    }
+
+    /// Like `assert_hashes_agree` but only for checkpoints from `frame` on, and allowing for
+    /// clients that had to resync before then. On a lossy network a client can simulate a
+    /// frame before an input for it arrives, so agreement is only expected once it has had
+    /// time to resync.
+    /// Also panics if the server disconnected a client.
+    pub fn assert_hashes_agree_from(&self, frame: shared::FrameIndex) -> usize {
+        for (index, client) in self.clients.iter().enumerate() {
+            assert!(
+                client.rejected.is_none(),
+                "client {} was rejected: {:?}",
+                index,
+                client.rejected
+            );
+        }
+        let mut compared = 0;
+        for (frame, hashes) in self.checkpoints.range(frame..) {
+            let (first_peer, first_hash) = hashes[0];
+            for (peer, hash) in &hashes[1..] {
+                assert_eq!(
+                    *hash, first_hash,
+                    "{:?} and {:?} disagree on frame {}: {:?}",
+                    peer, first_peer, frame, hashes
+                );
+            }
+            if hashes.len() > 1 {
+                compared += 1;
+            }
+        }
+        compared
+    }
+
+    /// The checkpoint frames that every one of `peers` has a hash for.
+    pub fn common_checkpoints(&self, peers: &[Peer]) -> Vec<shared::FrameIndex> {
+        self.checkpoints
+            .iter()
+            .filter(|(_, hashes)| {
+                peers
+                    .iter()
+                    .all(|peer| hashes.iter().any(|(other, _)| other == peer))
+            })
+            .map(|(frame, _)| *frame)
+            .collect()
+    }
+
+    fn tick_client(&mut self, index: usize, now: Duration) {
+        let client = &mut self.clients[index];
+        if client.rejected.is_some() {
+            return;
+        }
+        while let Some(datagram) = client.transport.try_recv() {
+            client.largest_datagram = client.largest_datagram.max(datagram.len());
+            if let Err(err) = client.session.receive(&datagram, now) {
+                client.rejected = Some(err);
+                return;
+            }
+        }
+
+        if client.session.has_state() && client.session.player_id().is_some() {
+            let frame = client.session.state().frame_index;
+            let due = client
+                .script
+                .iter()
+                .take_while(|(at, _)| *at <= frame)
+                .count();
+            for (_, event) in client.script.drain(..due).collect::<Vec<_>>() {
+                client.session.add_body(event);
+            }
+        }
+        client.session.update(now);
+
+        for datagram in client.session.take_datagrams() {
+            client.transport.send(datagram);
+        }
+        for (frame, hash) in client.session.take_checkpoints() {
+            self.checkpoints
+                .entry(frame)
+                .or_default()
+                .push((Peer::Client(index), hash));
+        }
+    }
+
+    fn record_server_checkpoint(&mut self) {
+        let state = self.server_state();
+        if state.frame_index % self.checkpoint_interval != 0 {
+            return;
+        }
+        let hashes = self.checkpoints.entry(state.frame_index).or_default();
+        if !hashes.iter().any(|(peer, _)| *peer == Peer::Server) {
+            hashes.push((Peer::Server, state.hash()));
+        }
+    }
+
+    async fn settle(&self) {
+        for _ in 0..SETTLE_YIELDS {
+            tokio::task::yield_now().await;
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! The lobby's HTTP endpoints, against a running server.
+
+mod harness;
+
+use harness::Harness;
+use server::config::{AppConfig, Scenario};
+use server::lobby;
+use warp::http::StatusCode;
+
+const TOKEN: &str = "correct horse battery staple";
+
+async fn request(
+    harness: &Harness,
+    method: &str,
+    path: &str,
+    token: Option<&str>,
+    body: &str,
+) -> (StatusCode, String) {
+    let routes = lobby::routes(harness.lobby(), Some(TOKEN.to_owned()));
+    let mut request = warp::test::request()
+        .method(method)
+        .path(path)
+        .header("content-type", "application/json");
+    if let Some(token) = token {
+        request = request.header("authorization", format!("Bearer {}", token));
+    }
+    let response = request.body(body).reply(&routes).await;
+    let body = String::from_utf8_lossy(response.body()).into_owned();
+    (response.status(), body)
+}
+
+async fn create(harness: &Harness, body: &str) -> (StatusCode, String) {
+    request(harness, "POST", "/rooms", Some(TOKEN), body).await
+}
+
+#[tokio::test]
+async fn creating_rooms_needs_the_token() {
+    let harness = Harness::with_scenario(Scenario::Orbits).await;
+    let settings = r#"{"name": "second"}"#;
+    let (status, _) = request(&harness, "POST", "/rooms", None, settings).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = request(&harness, "POST", "/rooms", Some("guess"), settings).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let disabled = lobby::routes(harness.lobby(), None);
+    let response = warp::test::request()
+        .method("POST")
+        .path("/rooms")
+        .header("authorization", format!("Bearer {}", TOKEN))
+        .body(settings)
+        .reply(&disabled)
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let (status, body) = create(&harness, settings).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains(r#""id":1,"name":"second""#), "{}", body);
+    let (status, body) = request(&harness, "GET", "/rooms", None, "").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains(r#""id":0"#), "{}", body);
+    assert!(body.contains(r#""name":"second""#), "{}", body);
+}
+
+#[tokio::test]
+async fn invalid_rooms_are_refused() {
+    let harness = Harness::new(AppConfig {
+        max_rooms: 2,
+        ..AppConfig::default()
+    })
+    .await;
+    let (status, _) = create(&harness, r#"{"name": ""}"#).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let file = r#"{"name": "saved", "scenario": {"file": "/etc/passwd"}}"#;
+    let (status, _) = create(&harness, file).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, _) = create(&harness, r#"{"name": "second"}"#).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = create(&harness, r#"{"name": "third"}"#).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn rooms_hand_out_tickets_and_their_state() {
+    let mut harness = Harness::with_scenario(Scenario::Orbits).await;
+    harness.connect().await;
+    harness.run_frames(30).await;
+
+    let (status, body) = request(&harness, "POST", "/rooms/0/join", None, "").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains(r#""room_id":0,"ticket":""#), "{}", body);
+    let (status, _) = request(&harness, "POST", "/rooms/9/join", None, "").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let counts = r#"{"players":1,"spectators":0}"#;
+    for path in &["/rooms/0/status", "/status"] {
+        assert_eq!(
+            request(&harness, "GET", path, None, "").await,
+            (StatusCode::OK, counts.to_owned())
+        );
+    }
+    let (status, _) = request(&harness, "GET", "/rooms/9/status", None, "").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let routes = lobby::routes(harness.lobby(), None);
+    let response = warp::test::request()
+        .path("/rooms/0/state")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let (hash, state): (u64, shared::State) = bincode::deserialize(response.body()).unwrap();
+    assert_eq!(hash, state.hash());
+    assert_eq!(state.hash(), harness.server_state().hash());
+    let (status, _) = request(&harness, "GET", "/rooms/9/state", None, "").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
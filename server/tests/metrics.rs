@@ -0,0 +1,74 @@
+//! What the server reports at `/metrics` while clients play.
+
+mod harness;
+
+use harness::Harness;
+use server::config::Scenario;
+use server::metrics::Registry;
+
+fn counter(registry: &Registry, name: &str, labels: &[(&str, &str)]) -> u64 {
+    registry.counter(name, "", labels).get()
+}
+
+fn gauge(registry: &Registry, name: &str, labels: &[(&str, &str)]) -> f64 {
+    registry.gauge(name, "", labels).get()
+}
+
+#[tokio::test]
+async fn metrics_follow_the_room_and_its_traffic() {
+    let mut harness = Harness::with_scenario(Scenario::Orbits).await;
+    harness.connect().await;
+    harness.connect_with(|session| session.spectate(30)).await;
+    harness.input(0, 30, shared::AddBodyEvent::new(800., 0., 20.));
+    harness.run_frames(120).await;
+
+    let registry = &harness.metrics;
+    let server = harness.server_state();
+    let room = [("room", "0")];
+    assert_eq!(
+        gauge(registry, "lockstep_frame", &room),
+        server.frame_index as f64
+    );
+    assert_eq!(
+        gauge(registry, "lockstep_bodies", &room),
+        server.simulation.bodies.len() as f64
+    );
+    let ticks = registry
+        .histogram("lockstep_tick_seconds", "", &room, &[])
+        .count();
+    assert!(ticks >= 100, "only {} ticks recorded", ticks);
+
+    let players = [("room", "0"), ("role", "player")];
+    let spectators = [("room", "0"), ("role", "spectator")];
+    assert_eq!(gauge(registry, "lockstep_peers", &players), 1.);
+    assert_eq!(gauge(registry, "lockstep_peers", &spectators), 1.);
+    let memory = [("transport", "memory")];
+    assert_eq!(gauge(registry, "lockstep_transport_peers", &memory), 2.);
+    assert_eq!(
+        counter(registry, "lockstep_transport_connections_total", &memory),
+        2
+    );
+
+    let received = |kind| [("direction", "in"), ("type", kind)];
+    let sent = |kind| [("direction", "out"), ("type", kind)];
+    assert!(counter(registry, "lockstep_messages_total", &received("Hello")) >= 1);
+    assert!(counter(registry, "lockstep_messages_total", &received("InputState")) >= 1);
+    assert!(counter(registry, "lockstep_messages_total", &sent("StateHash")) >= 100);
+    // every datagram has a header on top of its messages
+    let hash_bytes = counter(registry, "lockstep_message_bytes_total", &sent("StateHash"));
+    let datagram_bytes = counter(
+        registry,
+        "lockstep_transport_bytes_total",
+        &[("transport", "memory"), ("direction", "out")],
+    );
+    assert!(datagram_bytes > hash_bytes);
+    assert_eq!(counter(registry, "lockstep_decode_errors_total", &[]), 0);
+
+    let rendered = registry.render();
+    assert!(rendered.contains("# TYPE lockstep_tick_seconds histogram\n"));
+    assert!(rendered.contains("lockstep_tick_seconds_bucket{room=\"0\",le=\"+Inf\"}"));
+    assert!(rendered.contains(&format!(
+        "lockstep_frame{{room=\"0\"}} {}\n",
+        server.frame_index
+    )));
+}
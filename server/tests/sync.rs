@@ -0,0 +1,175 @@
+//! The server and clients together, checking that every peer stays in sync.
+
+mod harness;
+
+use harness::{Harness, Peer};
+use server::config::Scenario;
+
+#[tokio::test]
+async fn idle_clients_agree_with_the_server() {
+    let mut harness = Harness::with_scenario(Scenario::Orbits).await;
+    for _ in 0..3 {
+        harness.connect().await;
+    }
+    harness.run_frames(300).await;
+
+    assert!(harness.assert_hashes_agree() > 0);
+    let everyone = [
+        Peer::Server,
+        Peer::Client(0),
+        Peer::Client(1),
+        Peer::Client(2),
+    ];
+    assert!(!harness.common_checkpoints(&everyone).is_empty());
+    for index in 0..3 {
+        assert!(harness.client(index).player_id().is_some());
+        assert!(harness.client(index).hash_successes() > 0);
+    }
+}
+
+#[tokio::test]
+async fn scripted_inputs_reach_every_peer() {
+    let mut harness = Harness::with_scenario(Scenario::Empty).await;
+    for _ in 0..4 {
+        harness.connect().await;
+    }
+    for index in 0..4 {
+        let x = 200. * index as f32 - 300.;
+        let frame = 60 + 15 * index as shared::FrameIndex;
+        harness.input(index, frame, shared::AddBodyEvent::new(x, 500., 10.));
+    }
+    harness.run_frames(300).await;
+
+    assert!(harness.assert_hashes_agree() > 0);
+    // bodies may have merged since, but not all of them
+    let server = harness.server_state();
+    assert!(server.simulation.bodies.len() > 1);
+    for index in 0..4 {
+        let state = harness.client(index).state();
+        assert_eq!(state.frame_index, server.frame_index);
+        assert_eq!(state.hash(), server.hash());
+    }
+}
+
+#[tokio::test]
+async fn inputs_on_the_same_frame_agree() {
+    let mut harness = Harness::with_scenario(Scenario::Orbits).await;
+    for _ in 0..3 {
+        harness.connect().await;
+    }
+    for index in 0..3 {
+        let event =
+            shared::AddBodyEvent::new_with_velocity(-1000., 300. * index as f32, 50., 1., 0.);
+        harness.input(index, 100, event);
+    }
+    harness.run_frames(400).await;
+
+    assert!(harness.assert_hashes_agree() > 0);
+    assert!(harness.server_state().simulation.bodies.len() > 3);
+}
+
+#[tokio::test]
+async fn late_joiners_resync_and_agree() {
+    let mut harness = Harness::with_scenario(Scenario::Orbits).await;
+    harness.connect().await;
+    harness.input(0, 30, shared::AddBodyEvent::new(800., 0., 20.));
+    harness.run_frames(200).await;
+
+    let late = harness.connect().await;
+    harness.run_frames(200).await;
+
+    assert!(harness.assert_hashes_agree() > 0);
+    assert!(harness.client(late).has_state());
+    let both = [Peer::Server, Peer::Client(0), Peer::Client(late)];
+    assert!(!harness.common_checkpoints(&both).is_empty());
+    assert_eq!(
+        harness.client(late).state().hash(),
+        harness.server_state().hash()
+    );
+}
+
+#[tokio::test]
+async fn spectators_agree_behind_the_players() {
+    let mut harness = Harness::with_scenario(Scenario::Orbits).await;
+    harness.connect().await;
+    let spectator = harness.connect_with(|session| session.spectate(30)).await;
+    harness.input(0, 50, shared::AddBodyEvent::new(-800., 0., 20.));
+    harness.run_frames(300).await;
+
+    assert!(harness.assert_hashes_agree() > 0);
+    assert!(harness.client(spectator).spectating());
+    let behind = harness.server_state().frame_index - harness.client(spectator).state().frame_index;
+    assert!(behind >= 30, "spectator is only {} frames behind", behind);
+}
+
+#[tokio::test]
+async fn spectators_start_behind_the_server() {
+    let mut harness = Harness::with_scenario(Scenario::Orbits).await;
+    harness.connect().await;
+    // scheduled after the state the spectator starts from, and before it joins
+    harness.input(0, 80, shared::AddBodyEvent::new(-800., 0., 20.));
+    harness.run_frames(100).await;
+
+    let spectator = harness.connect_with(|session| session.spectate(30)).await;
+    harness.run_frames(10).await;
+    assert!(harness.client(spectator).has_state());
+    let behind = harness.server_state().frame_index - harness.client(spectator).state().frame_index;
+    assert!(
+        (30..40).contains(&behind),
+        "spectator is {} frames behind",
+        behind
+    );
+
+    harness.run_frames(100).await;
+    assert!(harness.assert_hashes_agree() > 0);
+    let both = [Peer::Server, Peer::Client(spectator)];
+    assert!(!harness.common_checkpoints(&both).is_empty());
+    assert_eq!(harness.client(spectator).hash_failures(), 0);
+}
+
+#[tokio::test]
+async fn pausing_stops_every_peer_on_the_same_frame() {
+    let mut harness = Harness::with_scenario(Scenario::Orbits).await;
+    harness.connect().await;
+    harness.connect().await;
+    harness.run_frames(60).await;
+
+    harness.clients[0]
+        .session
+        .send_control(shared::control::Control::Pause);
+    harness.run_frames(120).await;
+
+    let paused = harness.server_state().simulation;
+    harness.run_frames(60).await;
+
+    harness.assert_hashes_agree();
+    let server = harness.server_state();
+    assert!(server.playback.paused);
+    assert!(server.simulation == paused);
+    for index in 0..2 {
+        let state = harness.client(index).state();
+        assert!(state.playback.paused);
+        assert!(state.simulation == paused);
+    }
+}
+
+#[tokio::test]
+async fn controls_past_the_burst_are_rejected() {
+    use shared::control::Control;
+    use shared::limits::CONTROL_BURST;
+
+    let mut harness = Harness::with_scenario(Scenario::Orbits).await;
+    harness.connect().await;
+    harness.run_frames(60).await;
+
+    for _ in 0..CONTROL_BURST + 3 {
+        harness.clients[0]
+            .session
+            .send_control(Control::Timescale(2));
+    }
+    harness.run_frames(60).await;
+
+    harness.assert_hashes_agree();
+    assert_eq!(harness.client(0).rejected_controls(), 3);
+    assert_eq!(harness.server_state().playback.timescale, 2);
+}
@@ -0,0 +1,415 @@
+//! Estimating the server's clock and frame from ping/pong exchanges.
+//!
+//! Every pong gives four timestamps in the style of NTP: when the ping was sent and the pong
+//! received on the local clock, and the server's clock and frame when it answered. Assuming
+//! the two legs took equally long, the server's clock was ahead of ours by
+//! `server_time - (sent + received) / 2`, give or take half the round trip. Exchanges with the
+//! shortest round trips are the least affected by queuing so only those are fitted, with a
+//! line whose slope is the drift between the two clocks.
+//!
+//! Nothing here reads a clock. Times are passed in as durations since an arbitrary local
+//! epoch, which keeps it usable from wasm and deterministic under test.
+
+use super::FrameIndex;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// How many recent exchanges are kept.
+const SAMPLES: usize = 64;
+/// Fitting a drift over a shorter span than this is mostly fitting noise.
+const MIN_DRIFT_SPAN: f64 = 2.;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Sample {
+    /// Local time halfway between sending and receiving, in seconds.
+    local: f64,
+    round_trip: f64,
+    /// `server_time - local`, in seconds.
+    offset: f64,
+    server_time: f64,
+    server_frame: FrameIndex,
+}
+
+/// A prediction of the server's current frame. The true frame is expected to be within
+/// `earliest..=latest`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FramePrediction {
+    pub frame: FrameIndex,
+    pub earliest: FrameIndex,
+    pub latest: FrameIndex,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Fit {
+    /// Offset at `center`, in seconds.
+    offset: f64,
+    center: f64,
+    /// Seconds the server gains per local second.
+    drift: f64,
+    /// Half width of the offset's confidence interval, in seconds.
+    uncertainty: f64,
+}
+
+impl Fit {
+    fn offset_at(&self, local: f64) -> f64 {
+        self.offset + self.drift * (local - self.center)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ClockSync {
+    tick_rate: u32,
+    /// The server time of the latest sample when the tick rate last changed. Frames counted
+    /// across the change say nothing about the new rate.
+    rate_changed_at: f64,
+    samples: VecDeque<Sample>,
+    fit: Option<Fit>,
+}
+
+impl ClockSync {
+    pub fn new(tick_rate: u32) -> Self {
+        Self {
+            tick_rate,
+            rate_changed_at: f64::MIN,
+            samples: VecDeque::with_capacity(SAMPLES),
+            fit: None,
+        }
+    }
+
+    pub fn set_tick_rate(&mut self, tick_rate: u32) {
+        if tick_rate != self.tick_rate {
+            if let Some(latest) = self.samples.back() {
+                self.rate_changed_at = latest.server_time;
+            }
+        }
+        self.tick_rate = tick_rate;
+    }
+
+    /// Records an exchange. `sent` and `received` are on the local clock, `server_time` and
+    /// `server_frame` are what the server reported when it answered.
+    pub fn on_pong(
+        &mut self,
+        sent: Duration,
+        server_time: Duration,
+        server_frame: FrameIndex,
+        received: Duration,
+    ) {
+        if received < sent {
+            return;
+        }
+        let sent = sent.as_secs_f64();
+        let received = received.as_secs_f64();
+        let local = (sent + received) / 2.;
+        let server_time = server_time.as_secs_f64();
+        if self.samples.len() == SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample {
+            local,
+            round_trip: received - sent,
+            offset: server_time - local,
+            server_time,
+            server_frame,
+        });
+        self.fit = self.fit();
+    }
+
+    /// The smallest round trip among recent exchanges.
+    pub fn round_trip(&self) -> Option<Duration> {
+        self.samples
+            .iter()
+            .map(|sample| sample.round_trip)
+            .fold(None, |min: Option<f64>, rtt| {
+                Some(min.map_or(rtt, |min| min.min(rtt)))
+            })
+            .map(Duration::from_secs_f64)
+    }
+
+    /// How far the server's clock is ahead of the local clock at `local`, in seconds, and the
+    /// half width of the confidence interval around it.
+    pub fn offset(&self, local: Duration) -> Option<(f64, f64)> {
+        self.fit
+            .map(|fit| (fit.offset_at(local.as_secs_f64()), fit.uncertainty))
+    }
+
+    /// Seconds the server's clock gains per local second.
+    pub fn drift(&self) -> Option<f64> {
+        self.fit.map(|fit| fit.drift)
+    }
+
+    /// The server's clock at `local`, in seconds.
+    pub fn server_time(&self, local: Duration) -> Option<f64> {
+        self.offset(local)
+            .map(|(offset, _)| local.as_secs_f64() + offset)
+    }
+
+    pub fn predict_frame(&self, local: Duration) -> Option<FramePrediction> {
+        let fit = self.fit?;
+        let latest = self
+            .samples
+            .iter()
+            .max_by(|a, b| a.server_time.partial_cmp(&b.server_time).unwrap())?;
+        let frame_rate = self.frame_rate();
+
+        let server_now = local.as_secs_f64() + fit.offset_at(local.as_secs_f64());
+        let center = latest.server_frame as f64 + (server_now - latest.server_time) * frame_rate;
+        let spread = fit.uncertainty * frame_rate;
+        let clamp = |frame: f64| frame.max(0.).min(FrameIndex::MAX as f64) as FrameIndex;
+        Some(FramePrediction {
+            frame: clamp(center.floor()),
+            earliest: clamp((center - spread).floor()),
+            // the reported frame may have been up to a tick old when the pong was sent
+            latest: clamp((center + spread + 1.).ceil()),
+        })
+    }
+
+    /// Frames per server second, measured if enough time has been observed.
+    fn frame_rate(&self) -> f64 {
+        let nominal = self.tick_rate as f64;
+        let since_change = self
+            .samples
+            .iter()
+            .find(|sample| sample.server_time > self.rate_changed_at);
+        let (first, last) = match (since_change, self.samples.back()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return nominal,
+        };
+        let elapsed = last.server_time - first.server_time;
+        if elapsed < MIN_DRIFT_SPAN {
+            return nominal;
+        }
+        let frames = last.server_frame.wrapping_sub(first.server_frame) as f64;
+        let measured = frames / elapsed;
+        // a stalled or restarted server shouldn't throw the estimate off completely
+        if (measured - nominal).abs() > nominal / 2. {
+            nominal
+        } else {
+            measured
+        }
+    }
+
+    fn fit(&self) -> Option<Fit> {
+        let mut best = self.samples.iter().collect::<Vec<_>>();
+        best.sort_by(|a, b| a.round_trip.partial_cmp(&b.round_trip).unwrap());
+        best.truncate(self.samples.len().div_ceil(2).max(1));
+        let first = best.first()?;
+
+        let n = best.len() as f64;
+        let center = best.iter().map(|s| s.local).sum::<f64>() / n;
+        let mean_offset = best.iter().map(|s| s.offset).sum::<f64>() / n;
+        let span = best.iter().map(|s| s.local).fold(f64::MIN, f64::max)
+            - best.iter().map(|s| s.local).fold(f64::MAX, f64::min);
+
+        let drift = if span < MIN_DRIFT_SPAN {
+            0.
+        } else {
+            let covariance = best
+                .iter()
+                .map(|s| (s.local - center) * (s.offset - mean_offset))
+                .sum::<f64>();
+            let variance = best.iter().map(|s| (s.local - center).powi(2)).sum::<f64>();
+            covariance / variance
+        };
+
+        let fit = Fit {
+            offset: mean_offset,
+            center,
+            drift,
+            uncertainty: 0.,
+        };
+        let residual = (best
+            .iter()
+            .map(|s| (s.offset - fit.offset_at(s.local)).powi(2))
+            .sum::<f64>()
+            / n)
+            .sqrt();
+        Some(Fit {
+            uncertainty: first.round_trip / 2. + 2. * residual,
+            ..fit
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK_RATE: u32 = 60;
+
+    /// A local clock and a server clock that runs `drift` faster and started `offset` ahead,
+    /// connected by a network with deterministic jitter.
+    struct FakeClock {
+        local: f64,
+        offset: f64,
+        drift: f64,
+        base_latency: f64,
+        jitter: f64,
+        seed: u64,
+    }
+
+    impl FakeClock {
+        fn new(offset: f64, drift: f64) -> Self {
+            Self {
+                local: 100.,
+                offset,
+                drift,
+                base_latency: 0.02,
+                jitter: 0.03,
+                seed: 1,
+            }
+        }
+
+        fn server_time(&self, local: f64) -> f64 {
+            local * (1. + self.drift) + self.offset
+        }
+
+        fn server_frame(&self, local: f64) -> FrameIndex {
+            (self.server_time(local) * TICK_RATE as f64) as FrameIndex
+        }
+
+        fn latency(&mut self) -> f64 {
+            self.seed = self
+                .seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let random = (self.seed >> 33) as f64 / (1u64 << 31) as f64;
+            self.base_latency + self.jitter * random * random
+        }
+
+        /// Pings the server and advances local time until the next ping.
+        fn exchange(&mut self, sync: &mut ClockSync, interval: f64) {
+            let sent = self.local;
+            let arrived = sent + self.latency();
+            let received = arrived + self.latency();
+            sync.on_pong(
+                Duration::from_secs_f64(sent),
+                Duration::from_secs_f64(self.server_time(arrived)),
+                self.server_frame(arrived),
+                Duration::from_secs_f64(received),
+            );
+            self.local += interval;
+        }
+    }
+
+    #[test]
+    fn nothing_is_predicted_without_samples() {
+        let sync = ClockSync::new(TICK_RATE);
+        assert_eq!(sync.predict_frame(Duration::from_secs(1)), None);
+        assert_eq!(sync.round_trip(), None);
+    }
+
+    #[test]
+    fn single_exchange() {
+        let mut clock = FakeClock::new(5., 0.);
+        clock.jitter = 0.;
+        let mut sync = ClockSync::new(TICK_RATE);
+        clock.exchange(&mut sync, 0.);
+
+        let (offset, uncertainty) = sync.offset(Duration::from_secs_f64(clock.local)).unwrap();
+        assert!((offset - 5.).abs() < 1e-6, "{}", offset);
+        assert!((uncertainty - 0.02).abs() < 1e-6, "{}", uncertainty);
+        assert_eq!(sync.round_trip(), Some(Duration::from_secs_f64(0.04)));
+    }
+
+    #[test]
+    fn estimates_offset_and_drift() {
+        let mut clock = FakeClock::new(-37.5, 200e-6);
+        let mut sync = ClockSync::new(TICK_RATE);
+        for _ in 0..SAMPLES {
+            clock.exchange(&mut sync, 0.25);
+        }
+
+        let now = Duration::from_secs_f64(clock.local);
+        let (offset, uncertainty) = sync.offset(now).unwrap();
+        let actual = clock.server_time(clock.local) - clock.local;
+        assert!((offset - actual).abs() <= uncertainty);
+        assert!(uncertainty < 0.03, "{}", uncertainty);
+
+        let drift = sync.drift().unwrap();
+        assert!((drift - 200e-6).abs() < 100e-6, "{}", drift);
+    }
+
+    #[test]
+    fn predicted_frame_interval_contains_the_server_frame() {
+        let mut clock = FakeClock::new(12., -150e-6);
+        let mut sync = ClockSync::new(TICK_RATE);
+        for i in 0..200 {
+            clock.exchange(&mut sync, 0.1);
+            if i < 10 {
+                continue;
+            }
+            // predict a little way ahead of the last exchange as the client does between pongs
+            let local = clock.local + 0.05;
+            let prediction = sync.predict_frame(Duration::from_secs_f64(local)).unwrap();
+            let actual = clock.server_frame(local);
+            assert!(
+                prediction.earliest <= actual && actual <= prediction.latest,
+                "{:?} {}",
+                prediction,
+                actual
+            );
+            assert!(
+                prediction.latest - prediction.earliest <= 6,
+                "{:?}",
+                prediction
+            );
+        }
+    }
+
+    #[test]
+    fn frames_before_a_tick_rate_change_are_not_measured() {
+        let mut clock = FakeClock::new(0., 0.);
+        clock.jitter = 0.;
+        let mut sync = ClockSync::new(TICK_RATE);
+        for _ in 0..20 {
+            clock.exchange(&mut sync, 0.25);
+        }
+        assert!((sync.frame_rate() - TICK_RATE as f64).abs() < 1.);
+
+        // the server halves its rate from here, so frames stop lining up with `server_frame`
+        sync.set_tick_rate(TICK_RATE / 2);
+        let base = clock.server_frame(clock.local);
+        let start = clock.local;
+        for _ in 0..20 {
+            let sent = clock.local;
+            let arrived = sent + 0.02;
+            let frame = base + ((arrived - start) * (TICK_RATE / 2) as f64) as FrameIndex;
+            sync.on_pong(
+                Duration::from_secs_f64(sent),
+                Duration::from_secs_f64(clock.server_time(arrived)),
+                frame,
+                Duration::from_secs_f64(arrived + 0.02),
+            );
+            clock.local += 0.25;
+        }
+        let rate = sync.frame_rate();
+        assert!((rate - (TICK_RATE / 2) as f64).abs() < 1., "{}", rate);
+    }
+
+    #[test]
+    fn slow_exchanges_are_ignored() {
+        let mut clock = FakeClock::new(3., 0.);
+        clock.jitter = 0.;
+        let mut sync = ClockSync::new(TICK_RATE);
+        for i in 0..20 {
+            // every other pong is delayed on the way back only, which would skew the offset
+            if i % 2 == 0 {
+                clock.base_latency = 0.01;
+                clock.exchange(&mut sync, 0.5);
+            } else {
+                let sent = clock.local;
+                let arrived = sent + 0.01;
+                sync.on_pong(
+                    Duration::from_secs_f64(sent),
+                    Duration::from_secs_f64(clock.server_time(arrived)),
+                    clock.server_frame(arrived),
+                    Duration::from_secs_f64(arrived + 0.5),
+                );
+                clock.local += 0.5;
+            }
+        }
+
+        let (offset, _) = sync.offset(Duration::from_secs_f64(clock.local)).unwrap();
+        assert!((offset - 3.).abs() < 1e-3, "{}", offset);
+    }
+}
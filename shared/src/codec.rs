@@ -0,0 +1,1453 @@
+//! Encoding of individual messages on the wire.
+//!
+//! A `Codec` turns one message into bytes and back. `Bincode` and `Postcard` work for anything
+//! serde can handle; `BitPacked` is written by hand for `Send` and `Recv` and packs small
+//! integers as varints and fixed point numbers by how much of them is actually used.
+//! `Budgeted` wraps any of them and rejects messages larger than their type allows, in both
+//! directions.
+
+use super::control::{self, Control, Playback, ScheduledControl};
+use super::hash_tree::{self, HashTree};
+use super::lockstep::SyncMode;
+use super::snapshot::StateDelta;
+use super::validation::InputRules;
+use super::{
+    AddBodyEvent, ConnectionRejected, ControlRejected, EncodingError, IndexedState, InputBuffer,
+    InputRejected, Recv, Send, State, MAX_BODIES, MAX_CLIENT_NAME_LEN, MAX_PENDING_INPUTS,
+};
+use nbody::{Body, BodyId, Float, Point2D, Vector2D};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// The codec used by the client and server.
+pub type WireCodec = Budgeted<BitPacked>;
+
+/// No message may be larger than this, whatever its type. It's the budget of `FullState`.
+/// Larger messages are split across packets and lost if any part is, so this stays small.
+pub const MAX_MESSAGE_SIZE: usize = 1 << 16;
+
+#[derive(Debug)]
+pub enum CodecError {
+    Bincode(bincode::Error),
+    Postcard(postcard::Error),
+    Truncated,
+    InvalidTag(u64),
+    Invalid(&'static str),
+    /// A collection is longer than any valid message would contain.
+    TooMany {
+        what: &'static str,
+        len: usize,
+        max: usize,
+    },
+    State(EncodingError),
+    TooLarge {
+        kind: &'static str,
+        size: usize,
+        max: usize,
+    },
+}
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::Bincode(err) => write!(f, "bincode: {}", err),
+            CodecError::Postcard(err) => write!(f, "postcard: {}", err),
+            CodecError::Truncated => write!(f, "message is truncated"),
+            CodecError::InvalidTag(tag) => write!(f, "invalid variant tag {}", tag),
+            CodecError::Invalid(what) => write!(f, "invalid {}", what),
+            CodecError::TooMany { what, len, max } => {
+                write!(f, "{} {} is over the limit of {}", len, what, max)
+            }
+            CodecError::State(err) => write!(f, "{}", err),
+            CodecError::TooLarge { kind, size, max } => write!(
+                f,
+                "{} message is {} bytes which is over its budget of {}",
+                kind, size, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+pub trait Codec<T> {
+    /// Appends the encoded message to `buf`.
+    fn encode(&self, message: &T, buf: &mut Vec<u8>) -> Result<(), CodecError>;
+
+    /// Decodes one message from the front of `bytes`, returning it with the bytes after it.
+    fn decode<'a>(&self, bytes: &'a [u8]) -> Result<(T, &'a [u8]), CodecError>;
+}
+
+/// A message type with a limit on how large each kind of message may be once encoded.
+pub trait Message {
+    fn kind(&self) -> &'static str;
+    fn max_encoded_size(&self) -> usize;
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Bincode;
+
+/// The same format as `bincode::serialize` but refusing to read more than `MAX_MESSAGE_SIZE`.
+pub(crate) fn bincode_options() -> impl bincode::Options {
+    use bincode::Options;
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_MESSAGE_SIZE as u64)
+}
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for Bincode {
+    fn encode(&self, message: &T, buf: &mut Vec<u8>) -> Result<(), CodecError> {
+        use bincode::Options;
+        bincode_options()
+            .serialize_into(buf, message)
+            .map_err(CodecError::Bincode)
+    }
+
+    fn decode<'a>(&self, mut bytes: &'a [u8]) -> Result<(T, &'a [u8]), CodecError> {
+        use bincode::Options;
+        let message = bincode_options()
+            .deserialize_from(&mut bytes)
+            .map_err(CodecError::Bincode)?;
+        Ok((message, bytes))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Postcard;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for Postcard {
+    fn encode(&self, message: &T, buf: &mut Vec<u8>) -> Result<(), CodecError> {
+        let encoded = postcard::to_allocvec(message).map_err(CodecError::Postcard)?;
+        buf.extend_from_slice(&encoded);
+        Ok(())
+    }
+
+    fn decode<'a>(&self, bytes: &'a [u8]) -> Result<(T, &'a [u8]), CodecError> {
+        postcard::take_from_bytes(bytes).map_err(CodecError::Postcard)
+    }
+}
+
+/// Enforces `Message::max_encoded_size` around another codec.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Budgeted<C>(pub C);
+
+fn check_budget<T: Message>(message: &T, size: usize) -> Result<(), CodecError> {
+    let max = message.max_encoded_size();
+    if size > max {
+        Err(CodecError::TooLarge {
+            kind: message.kind(),
+            size,
+            max,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+impl<T: Message, C: Codec<T>> Codec<T> for Budgeted<C> {
+    fn encode(&self, message: &T, buf: &mut Vec<u8>) -> Result<(), CodecError> {
+        let start = buf.len();
+        self.0.encode(message, buf)?;
+        let result = check_budget(message, buf.len() - start);
+        if result.is_err() {
+            buf.truncate(start);
+        }
+        result
+    }
+
+    fn decode<'a>(&self, bytes: &'a [u8]) -> Result<(T, &'a [u8]), CodecError> {
+        let (message, rest) = self.0.decode(bytes)?;
+        check_budget(&message, bytes.len() - rest.len())?;
+        Ok((message, rest))
+    }
+}
+
+impl Message for Send {
+    fn kind(&self) -> &'static str {
+        self.budget().0
+    }
+
+    fn max_encoded_size(&self) -> usize {
+        self.budget().1
+    }
+}
+
+impl Send {
+    fn budget(&self) -> (&'static str, usize) {
+        match self {
+            Send::Hello { .. } => ("Hello", 96),
+            Send::Ping { .. } => ("Ping", 16),
+            Send::InputState { .. } => ("InputState", 64),
+            Send::RequestState { .. } => ("RequestState", 16),
+            Send::RequestHashTree { .. } => ("RequestHashTree", 16),
+            Send::RequestBodyHashes { .. } => ("RequestBodyHashes", 96),
+            Send::RequestBodies { .. } => ("RequestBodies", 128),
+            Send::Control { .. } => ("Control", 32),
+            Send::Spectate { .. } => ("Spectate", 96),
+            Send::InputsComplete { .. } => ("InputsComplete", 16),
+            Send::Enter { .. } => ("Enter", 16),
+        }
+    }
+}
+
+impl Message for Recv {
+    fn kind(&self) -> &'static str {
+        self.budget().0
+    }
+
+    fn max_encoded_size(&self) -> usize {
+        self.budget().1
+    }
+}
+
+impl Recv {
+    fn budget(&self) -> (&'static str, usize) {
+        match self {
+            Recv::Welcome { .. } => ("Welcome", 96),
+            Recv::Rejected(_) => ("Rejected", 16),
+            Recv::Pong { .. } => ("Pong", 32),
+            Recv::StateHash(_) => ("StateHash", 32),
+            Recv::InputState { .. } => ("InputState", 64),
+            Recv::FullState(_) => ("FullState", MAX_MESSAGE_SIZE),
+            Recv::StateDelta(_) => ("StateDelta", MAX_MESSAGE_SIZE),
+            Recv::InputAck { .. } => ("InputAck", 16),
+            Recv::HashTree(_) => ("HashTree", 256),
+            Recv::BodyHashes { .. } => ("BodyHashes", 1 << 14),
+            Recv::Bodies { .. } => ("Bodies", 1024),
+            Recv::FrameUnavailable(_) => ("FrameUnavailable", 16),
+            Recv::Control(_) => ("Control", 32),
+            Recv::InputRejected { .. } => ("InputRejected", 16),
+            Recv::Spectating { .. } => ("Spectating", 32),
+            Recv::Load { .. } => ("Load", 16),
+            Recv::ControlRejected { .. } => ("ControlRejected", 16),
+        }
+    }
+}
+
+/// Writes values least significant bit first. Each message is padded to a whole byte.
+struct BitWriter<'a> {
+    buf: &'a mut Vec<u8>,
+    used: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(buf: &'a mut Vec<u8>) -> Self {
+        Self { buf, used: 0 }
+    }
+
+    fn bits(&mut self, mut value: u64, mut count: u32) {
+        while count > 0 {
+            if self.used == 0 {
+                self.buf.push(0);
+            }
+            let take = (8 - self.used).min(count);
+            let chunk = (value & ((1 << take) - 1)) as u8;
+            *self.buf.last_mut().unwrap() |= chunk << self.used;
+            value = value.checked_shr(take).unwrap_or(0);
+            count -= take;
+            self.used = (self.used + take) % 8;
+        }
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.bits(value as u64, 1)
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        loop {
+            let group = value & 0x7f;
+            value >>= 7;
+            self.bits(group, 7);
+            self.bool(value != 0);
+            if value == 0 {
+                break;
+            }
+        }
+    }
+
+    fn len(&mut self, len: usize) {
+        self.varint(len as u64)
+    }
+
+    /// Zero and whole numbers are common, e.g. positions from clicks, so they're shortened.
+    fn float(&mut self, value: Float) {
+        let bits = value.to_bits();
+        if bits == 0 {
+            self.bits(0, 2);
+        } else if bits & 0xffff_ffff == 0 {
+            self.bits(1, 2);
+            let whole = bits >> 32;
+            self.varint(((whole << 1) ^ (whole >> 63)) as u64);
+        } else {
+            self.bits(2, 2);
+            self.bits(bits as u64, 64);
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.len(bytes.len());
+        for byte in bytes {
+            self.bits(*byte as u64, 8);
+        }
+    }
+
+    fn event(&mut self, event: &AddBodyEvent) {
+        for value in event.sort_key().iter() {
+            self.float(*value);
+        }
+    }
+
+    fn input(&mut self, input: &IndexedState<AddBodyEvent>) {
+        self.varint(input.frame_index as u64);
+        self.event(&input.state);
+    }
+
+    fn rules(&mut self, rules: &InputRules) {
+        for value in &[
+            rules.min_mass,
+            rules.max_mass,
+            rules.max_distance,
+            rules.max_speed,
+            rules.min_spacing,
+        ] {
+            self.bits(value.to_bits(), 64);
+        }
+    }
+
+    fn body(&mut self, body: &Body) {
+        self.varint(body.id());
+        self.bool(body.collided());
+        self.float(body.position.x);
+        self.float(body.position.y);
+        self.float(body.velocity.x);
+        self.float(body.velocity.y);
+        self.float(body.acceleration.x);
+        self.float(body.acceleration.y);
+        self.float(body.mass);
+    }
+
+    fn ids(&mut self, ids: &[BodyId]) {
+        self.len(ids.len());
+        for id in ids {
+            self.varint(*id);
+        }
+    }
+
+    fn control(&mut self, control: &Control) {
+        match control {
+            Control::Pause => self.varint(0),
+            Control::Resume => self.varint(1),
+            Control::Step(steps) => {
+                self.varint(2);
+                self.varint(*steps as u64);
+            }
+            Control::Timescale(timescale) => {
+                self.varint(3);
+                self.varint(*timescale as u64);
+            }
+            Control::RemoveBody(id) => {
+                self.varint(4);
+                self.varint(*id);
+            }
+            Control::TickRate(tick_rate) => {
+                self.varint(5);
+                self.varint(*tick_rate as u64);
+            }
+        }
+    }
+
+    fn scheduled_control(&mut self, control: &ScheduledControl) {
+        self.varint(control.frame_index as u64);
+        self.varint(control.seq as u64);
+        self.control(&control.control);
+    }
+
+    fn playback(&mut self, playback: &Playback) {
+        self.bool(playback.paused);
+        self.varint(playback.timescale as u64);
+        self.varint(playback.pending_steps as u64);
+        self.len(playback.scheduled().len());
+        for control in playback.scheduled() {
+            self.scheduled_control(control);
+        }
+        self.varint(playback.tick_rate.unwrap_or(0) as u64);
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    /// The bytes after the current one.
+    fn rest(&self) -> &'a [u8] {
+        &self.bytes[self.position.div_ceil(8)..]
+    }
+
+    fn remaining_bits(&self) -> usize {
+        self.bytes.len() * 8 - self.position
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u64, CodecError> {
+        let mut value = 0;
+        let mut read = 0;
+        while read < count {
+            let byte = *self
+                .bytes
+                .get(self.position / 8)
+                .ok_or(CodecError::Truncated)?;
+            let offset = (self.position % 8) as u32;
+            let take = (8 - offset).min(count - read);
+            let chunk = (byte >> offset) as u64 & ((1 << take) - 1);
+            value |= chunk << read;
+            read += take;
+            self.position += take as usize;
+        }
+        Ok(value)
+    }
+
+    fn bool(&mut self) -> Result<bool, CodecError> {
+        Ok(self.bits(1)? == 1)
+    }
+
+    fn varint(&mut self) -> Result<u64, CodecError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            value |= self.bits(7)? << shift;
+            if !self.bool()? {
+                return Ok(value);
+            }
+        }
+        Err(CodecError::Invalid("varint"))
+    }
+
+    fn u32(&mut self) -> Result<u32, CodecError> {
+        use std::convert::TryFrom;
+        u32::try_from(self.varint()?).map_err(|_| CodecError::Invalid("32 bit integer"))
+    }
+
+    /// A collection length of at most `max`. Every item takes at least one bit so anything
+    /// longer than the remaining bits is rejected before it can be allocated.
+    fn len(&mut self, what: &'static str, max: usize) -> Result<usize, CodecError> {
+        let len = self.varint()?;
+        if len > max as u64 {
+            return Err(CodecError::TooMany {
+                what,
+                len: len.min(usize::MAX as u64) as usize,
+                max,
+            });
+        }
+        if len > self.remaining_bits() as u64 {
+            return Err(CodecError::Truncated);
+        }
+        Ok(len as usize)
+    }
+
+    fn float(&mut self) -> Result<Float, CodecError> {
+        let bits = match self.bits(2)? {
+            0 => 0,
+            1 => {
+                let zigzag = self.varint()?;
+                let whole = (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64);
+                whole
+                    .checked_mul(1 << 32)
+                    .ok_or(CodecError::Invalid("fixed point number"))?
+            }
+            2 => self.bits(64)? as i64,
+            tag => return Err(CodecError::InvalidTag(tag)),
+        };
+        Ok(Float::from_bits(bits))
+    }
+
+    fn bytes(&mut self, what: &'static str, max: usize) -> Result<Vec<u8>, CodecError> {
+        let len = self.len(what, max)?;
+        (0..len).map(|_| Ok(self.bits(8)? as u8)).collect()
+    }
+
+    fn string(&mut self, what: &'static str, max: usize) -> Result<String, CodecError> {
+        String::from_utf8(self.bytes(what, max)?).map_err(|_| CodecError::Invalid("utf-8"))
+    }
+
+    fn event(&mut self) -> Result<AddBodyEvent, CodecError> {
+        Ok(AddBodyEvent {
+            position_x: self.float()?,
+            position_y: self.float()?,
+            velocity_x: self.float()?,
+            velocity_y: self.float()?,
+            mass: self.float()?,
+        })
+    }
+
+    fn input(&mut self) -> Result<IndexedState<AddBodyEvent>, CodecError> {
+        Ok(IndexedState {
+            frame_index: self.u32()?,
+            state: self.event()?,
+        })
+    }
+
+    fn rules(&mut self) -> Result<InputRules, CodecError> {
+        let mut value = || Ok::<_, CodecError>(f64::from_bits(self.bits(64)?));
+        Ok(InputRules {
+            min_mass: value()?,
+            max_mass: value()?,
+            max_distance: value()?,
+            max_speed: value()?,
+            min_spacing: value()?,
+        })
+    }
+
+    fn body(&mut self) -> Result<Body, CodecError> {
+        let id = self.varint()?;
+        let collided = self.bool()?;
+        let position = Point2D::new(self.float()?, self.float()?);
+        let velocity = Vector2D::new(self.float()?, self.float()?);
+        let acceleration = Vector2D::new(self.float()?, self.float()?);
+        let mass = self.float()?;
+        Ok(Body::from_parts(
+            id,
+            collided,
+            position,
+            velocity,
+            acceleration,
+            mass,
+        ))
+    }
+
+    fn ids(&mut self, max: usize) -> Result<Vec<BodyId>, CodecError> {
+        let len = self.len("body ids", max)?;
+        (0..len).map(|_| self.varint()).collect()
+    }
+
+    fn buckets(&mut self) -> Result<Vec<u32>, CodecError> {
+        let len = self.len("buckets", hash_tree::BUCKETS as usize)?;
+        (0..len).map(|_| self.u32()).collect()
+    }
+
+    fn bodies(&mut self, max: usize) -> Result<Vec<Body>, CodecError> {
+        let len = self.len("bodies", max)?;
+        (0..len).map(|_| self.body()).collect()
+    }
+
+    fn control(&mut self) -> Result<Control, CodecError> {
+        Ok(match self.varint()? {
+            0 => Control::Pause,
+            1 => Control::Resume,
+            2 => Control::Step(self.u32()?),
+            3 => Control::Timescale(self.u32()?),
+            4 => Control::RemoveBody(self.varint()?),
+            5 => Control::TickRate(self.u32()?),
+            tag => return Err(CodecError::InvalidTag(tag)),
+        })
+    }
+
+    fn scheduled_control(&mut self) -> Result<ScheduledControl, CodecError> {
+        Ok(ScheduledControl {
+            frame_index: self.u32()?,
+            seq: self.u32()?,
+            control: self.control()?,
+        })
+    }
+
+    fn playback(&mut self) -> Result<Playback, CodecError> {
+        let mut playback = Playback::default();
+        playback.paused = self.bool()?;
+        playback.timescale = self.u32()?;
+        playback.pending_steps = self.u32()?;
+        for _ in 0..self.len("scheduled controls", control::MAX_SCHEDULED)? {
+            playback.schedule(self.scheduled_control()?);
+        }
+        playback.tick_rate = Some(self.u32()?).filter(|tick_rate| *tick_rate != 0);
+        Ok(playback)
+    }
+}
+
+/// Hand written packing of `Send` and `Recv`. Variant tags are explicit so the order of the
+/// enums doesn't matter here, but the tags of `Hello`, `Welcome` and `Rejected` must never
+/// change.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BitPacked;
+
+impl Codec<Send> for BitPacked {
+    fn encode(&self, message: &Send, buf: &mut Vec<u8>) -> Result<(), CodecError> {
+        let mut w = BitWriter::new(buf);
+        match message {
+            Send::Hello {
+                protocol_version,
+                client_name,
+            } => {
+                w.varint(0);
+                w.varint(*protocol_version as u64);
+                w.bytes(client_name.as_bytes());
+            }
+            Send::Ping { client_time } => {
+                w.varint(1);
+                w.varint(*client_time);
+            }
+            Send::InputState { client_seq, input } => {
+                w.varint(2);
+                w.varint(*client_seq as u64);
+                w.input(input);
+            }
+            Send::RequestState { baseline, delay } => {
+                w.varint(3);
+                w.bool(baseline.is_some());
+                if let Some(baseline) = baseline {
+                    w.varint(*baseline as u64);
+                }
+                w.varint(*delay as u64);
+            }
+            Send::RequestHashTree { frame_index } => {
+                w.varint(4);
+                w.varint(*frame_index as u64);
+            }
+            Send::RequestBodyHashes {
+                frame_index,
+                buckets,
+            } => {
+                w.varint(5);
+                w.varint(*frame_index as u64);
+                w.len(buckets.len());
+                for bucket in buckets {
+                    w.varint(*bucket as u64);
+                }
+            }
+            Send::RequestBodies { frame_index, ids } => {
+                w.varint(6);
+                w.varint(*frame_index as u64);
+                w.ids(ids);
+            }
+            Send::Control {
+                client_seq,
+                control,
+            } => {
+                w.varint(7);
+                w.varint(*client_seq as u64);
+                w.control(control);
+            }
+            Send::Spectate {
+                protocol_version,
+                client_name,
+            } => {
+                w.varint(8);
+                w.varint(*protocol_version as u64);
+                w.bytes(client_name.as_bytes());
+            }
+            Send::InputsComplete { frame_index } => {
+                w.varint(9);
+                w.varint(*frame_index as u64);
+            }
+            Send::Enter { ticket } => {
+                w.varint(10);
+                w.bits(*ticket, 64);
+            }
+        }
+        Ok(())
+    }
+
+    fn decode<'a>(&self, bytes: &'a [u8]) -> Result<(Send, &'a [u8]), CodecError> {
+        let mut r = BitReader::new(bytes);
+        let message = match r.varint()? {
+            0 => Send::Hello {
+                protocol_version: r.u32()?,
+                client_name: r.string("name bytes", MAX_CLIENT_NAME_LEN)?,
+            },
+            1 => Send::Ping {
+                client_time: r.varint()?,
+            },
+            2 => Send::InputState {
+                client_seq: r.u32()?,
+                input: r.input()?,
+            },
+            3 => Send::RequestState {
+                baseline: if r.bool()? { Some(r.u32()?) } else { None },
+                delay: r.u32()?,
+            },
+            4 => Send::RequestHashTree {
+                frame_index: r.u32()?,
+            },
+            5 => Send::RequestBodyHashes {
+                frame_index: r.u32()?,
+                buckets: r.buckets()?,
+            },
+            6 => Send::RequestBodies {
+                frame_index: r.u32()?,
+                ids: r.ids(hash_tree::BODIES_PER_REQUEST)?,
+            },
+            7 => Send::Control {
+                client_seq: r.u32()?,
+                control: r.control()?,
+            },
+            8 => Send::Spectate {
+                protocol_version: r.u32()?,
+                client_name: r.string("name bytes", MAX_CLIENT_NAME_LEN)?,
+            },
+            9 => Send::InputsComplete {
+                frame_index: r.u32()?,
+            },
+            10 => Send::Enter {
+                ticket: r.bits(64)?,
+            },
+            tag => return Err(CodecError::InvalidTag(tag)),
+        };
+        Ok((message, r.rest()))
+    }
+}
+
+impl Codec<Recv> for BitPacked {
+    fn encode(&self, message: &Recv, buf: &mut Vec<u8>) -> Result<(), CodecError> {
+        let mut w = BitWriter::new(buf);
+        match message {
+            Recv::Welcome {
+                player_id,
+                tick_rate,
+                input_delay,
+                server_frame,
+                sync_mode,
+                input_rules,
+            } => {
+                w.varint(0);
+                w.varint(*player_id as u64);
+                w.varint(*tick_rate as u64);
+                w.varint(*input_delay as u64);
+                w.varint(*server_frame as u64);
+                w.varint(match sync_mode {
+                    SyncMode::Free => 0,
+                    SyncMode::Strict => 1,
+                });
+                w.rules(input_rules);
+            }
+            Recv::Rejected(ConnectionRejected::IncompatibleVersion { server_version }) => {
+                w.varint(1);
+                w.varint(0);
+                w.varint(*server_version as u64);
+            }
+            Recv::Rejected(ConnectionRejected::TimedOut { frame_index }) => {
+                w.varint(1);
+                w.varint(1);
+                w.varint(*frame_index as u64);
+            }
+            Recv::Rejected(ConnectionRejected::InvalidTicket) => {
+                w.varint(1);
+                w.varint(2);
+            }
+            Recv::Rejected(ConnectionRejected::Kicked) => {
+                w.varint(1);
+                w.varint(3);
+            }
+            Recv::Pong {
+                client_time,
+                server_time,
+                server_frame,
+            } => {
+                w.varint(2);
+                w.varint(*client_time);
+                w.varint(*server_time);
+                w.varint(*server_frame as u64);
+            }
+            Recv::StateHash(IndexedState { frame_index, state }) => {
+                w.varint(3);
+                w.varint(*frame_index as u64);
+                w.bits(*state, 64);
+            }
+            Recv::InputState {
+                player_id,
+                client_seq,
+                input,
+            } => {
+                w.varint(4);
+                w.varint(*player_id as u64);
+                w.varint(*client_seq as u64);
+                w.input(input);
+            }
+            Recv::FullState(state) => {
+                w.varint(5);
+                w.bytes(&state.encode());
+            }
+            Recv::StateDelta(delta) => {
+                w.varint(6);
+                w.varint(delta.baseline_frame as u64);
+                w.varint(delta.frame_index as u64);
+                w.varint(delta.next_body_id);
+                w.ids(&delta.removed);
+                w.len(delta.added.len());
+                for body in delta.added.iter() {
+                    w.body(body);
+                }
+                w.bytes(&delta.changed);
+                w.len(delta.input_buffer.iter().count());
+                for input in delta.input_buffer.iter() {
+                    w.input(input);
+                }
+                w.playback(&delta.playback);
+                w.bits(delta.hash, 64);
+            }
+            Recv::InputAck {
+                client_seq,
+                applied_frame,
+            } => {
+                w.varint(7);
+                w.varint(*client_seq as u64);
+                w.varint(*applied_frame as u64);
+            }
+            Recv::HashTree(tree) => {
+                w.varint(8);
+                w.varint(tree.frame_index as u64);
+                w.bits(tree.root, 64);
+                w.bits(tree.frame, 64);
+                w.bits(tree.config, 64);
+                w.len(tree.buckets.len());
+                for bucket in tree.buckets.iter() {
+                    w.bits(*bucket, 64);
+                }
+            }
+            Recv::BodyHashes {
+                frame_index,
+                buckets,
+                hashes,
+            } => {
+                w.varint(9);
+                w.varint(*frame_index as u64);
+                w.len(buckets.len());
+                for bucket in buckets {
+                    w.varint(*bucket as u64);
+                }
+                w.len(hashes.len());
+                for (id, hash) in hashes {
+                    w.varint(*id);
+                    w.bits(*hash, 64);
+                }
+            }
+            Recv::Bodies {
+                frame_index,
+                ids,
+                bodies,
+            } => {
+                w.varint(10);
+                w.varint(*frame_index as u64);
+                w.ids(ids);
+                w.len(bodies.len());
+                for body in bodies {
+                    w.body(body);
+                }
+            }
+            Recv::FrameUnavailable(frame_index) => {
+                w.varint(11);
+                w.varint(*frame_index as u64);
+            }
+            Recv::Control(control) => {
+                w.varint(12);
+                w.scheduled_control(control);
+            }
+            Recv::InputRejected { client_seq, reason } => {
+                w.varint(13);
+                w.varint(*client_seq as u64);
+                w.varint(match reason {
+                    InputRejected::TooManyPerFrame => 0,
+                    InputRejected::RateLimited => 1,
+                    InputRejected::MassBudgetExceeded => 2,
+                    InputRejected::MassOutOfRange => 3,
+                    InputRejected::TooFarFromCenter => 4,
+                    InputRejected::TooFast => 5,
+                    InputRejected::TooClose => 6,
+                });
+            }
+            Recv::Spectating {
+                tick_rate,
+                server_frame,
+            } => {
+                w.varint(14);
+                w.varint(*tick_rate as u64);
+                w.varint(*server_frame as u64);
+            }
+            Recv::Load { frame_index } => {
+                w.varint(15);
+                w.varint(*frame_index as u64);
+            }
+            Recv::ControlRejected { client_seq, reason } => {
+                w.varint(16);
+                w.varint(*client_seq as u64);
+                w.varint(match reason {
+                    ControlRejected::RateLimited => 0,
+                    ControlRejected::AdminOnly => 1,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn decode<'a>(&self, bytes: &'a [u8]) -> Result<(Recv, &'a [u8]), CodecError> {
+        let mut r = BitReader::new(bytes);
+        let message = match r.varint()? {
+            0 => Recv::Welcome {
+                player_id: r.u32()?,
+                tick_rate: r.u32()?,
+                input_delay: r.u32()?,
+                server_frame: r.u32()?,
+                sync_mode: match r.varint()? {
+                    0 => SyncMode::Free,
+                    1 => SyncMode::Strict,
+                    tag => return Err(CodecError::InvalidTag(tag)),
+                },
+                input_rules: r.rules()?,
+            },
+            1 => match r.varint()? {
+                0 => Recv::Rejected(ConnectionRejected::IncompatibleVersion {
+                    server_version: r.u32()?,
+                }),
+                1 => Recv::Rejected(ConnectionRejected::TimedOut {
+                    frame_index: r.u32()?,
+                }),
+                2 => Recv::Rejected(ConnectionRejected::InvalidTicket),
+                3 => Recv::Rejected(ConnectionRejected::Kicked),
+                tag => return Err(CodecError::InvalidTag(tag)),
+            },
+            2 => Recv::Pong {
+                client_time: r.varint()?,
+                server_time: r.varint()?,
+                server_frame: r.u32()?,
+            },
+            3 => Recv::StateHash(IndexedState {
+                frame_index: r.u32()?,
+                state: r.bits(64)?,
+            }),
+            4 => Recv::InputState {
+                player_id: r.u32()?,
+                client_seq: r.u32()?,
+                input: r.input()?,
+            },
+            5 => Recv::FullState(
+                State::decode(&r.bytes("state bytes", MAX_MESSAGE_SIZE)?)
+                    .map_err(CodecError::State)?,
+            ),
+            6 => {
+                let baseline_frame = r.u32()?;
+                let frame_index = r.u32()?;
+                let next_body_id = r.varint()?;
+                let removed = r.ids(MAX_BODIES)?;
+                let added = r.bodies(MAX_BODIES)?;
+                let changed = r.bytes("changed bytes", MAX_MESSAGE_SIZE)?;
+                let mut input_buffer = InputBuffer::default();
+                for _ in 0..r.len("pending inputs", MAX_PENDING_INPUTS)? {
+                    input_buffer.push(r.input()?);
+                }
+                Recv::StateDelta(StateDelta {
+                    baseline_frame,
+                    frame_index,
+                    next_body_id,
+                    removed,
+                    added,
+                    changed,
+                    input_buffer,
+                    playback: r.playback()?,
+                    hash: r.bits(64)?,
+                })
+            }
+            7 => Recv::InputAck {
+                client_seq: r.u32()?,
+                applied_frame: r.u32()?,
+            },
+            8 => {
+                let frame_index = r.u32()?;
+                let root = r.bits(64)?;
+                let frame = r.bits(64)?;
+                let config = r.bits(64)?;
+                let buckets = (0..r.len("buckets", hash_tree::BUCKETS as usize)?)
+                    .map(|_| r.bits(64))
+                    .collect::<Result<_, _>>()?;
+                Recv::HashTree(HashTree {
+                    frame_index,
+                    root,
+                    frame,
+                    config,
+                    buckets,
+                })
+            }
+            9 => {
+                let frame_index = r.u32()?;
+                let buckets = r.buckets()?;
+                let hashes = (0..r.len("body hashes", MAX_BODIES)?)
+                    .map(|_| Ok((r.varint()?, r.bits(64)?)))
+                    .collect::<Result<_, _>>()?;
+                Recv::BodyHashes {
+                    frame_index,
+                    buckets,
+                    hashes,
+                }
+            }
+            10 => {
+                let frame_index = r.u32()?;
+                let ids = r.ids(hash_tree::BODIES_PER_REQUEST)?;
+                let bodies = r.bodies(hash_tree::BODIES_PER_REQUEST)?;
+                Recv::Bodies {
+                    frame_index,
+                    ids,
+                    bodies,
+                }
+            }
+            11 => Recv::FrameUnavailable(r.u32()?),
+            12 => Recv::Control(r.scheduled_control()?),
+            13 => Recv::InputRejected {
+                client_seq: r.u32()?,
+                reason: match r.varint()? {
+                    0 => InputRejected::TooManyPerFrame,
+                    1 => InputRejected::RateLimited,
+                    2 => InputRejected::MassBudgetExceeded,
+                    3 => InputRejected::MassOutOfRange,
+                    4 => InputRejected::TooFarFromCenter,
+                    5 => InputRejected::TooFast,
+                    6 => InputRejected::TooClose,
+                    tag => return Err(CodecError::InvalidTag(tag)),
+                },
+            },
+            14 => Recv::Spectating {
+                tick_rate: r.u32()?,
+                server_frame: r.u32()?,
+            },
+            15 => Recv::Load {
+                frame_index: r.u32()?,
+            },
+            16 => Recv::ControlRejected {
+                client_seq: r.u32()?,
+                reason: match r.varint()? {
+                    0 => ControlRejected::RateLimited,
+                    1 => ControlRejected::AdminOnly,
+                    tag => return Err(CodecError::InvalidTag(tag)),
+                },
+            },
+            tag => return Err(CodecError::InvalidTag(tag)),
+        };
+        Ok((message, r.rest()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(frame_index: crate::FrameIndex) -> IndexedState<AddBodyEvent> {
+        IndexedState {
+            frame_index,
+            state: AddBodyEvent::new_with_velocity(312., 148., 802.6582, 0.25, -1.5),
+        }
+    }
+
+    fn state() -> State {
+        let mut state = State::new();
+        for i in 0..20 {
+            state.schedule(IndexedState {
+                frame_index: 0,
+                state: AddBodyEvent::new(i as f32 * 40., 0., 2.),
+            });
+        }
+        for _ in 0..3 {
+            state.step();
+        }
+        state.schedule(input(10));
+        state
+    }
+
+    fn sends() -> Vec<Send> {
+        vec![
+            Send::Hello {
+                protocol_version: crate::PROTOCOL_VERSION,
+                client_name: "name".to_owned(),
+            },
+            Send::Ping {
+                client_time: 12_345_678,
+            },
+            Send::InputState {
+                client_seq: 7,
+                input: input(100_007),
+            },
+            Send::RequestState {
+                baseline: None,
+                delay: 0,
+            },
+            Send::RequestState {
+                baseline: Some(99_000),
+                delay: 30,
+            },
+            Send::RequestHashTree { frame_index: 5 },
+            Send::RequestBodyHashes {
+                frame_index: 5,
+                buckets: vec![0, 3, 15],
+            },
+            Send::RequestBodies {
+                frame_index: 5,
+                ids: vec![1, 1 << 40],
+            },
+            Send::Control {
+                client_seq: 0,
+                control: Control::Pause,
+            },
+            Send::Control {
+                client_seq: 1,
+                control: Control::Step(600),
+            },
+            Send::Control {
+                client_seq: 2,
+                control: Control::Timescale(16),
+            },
+            Send::Control {
+                client_seq: 3,
+                control: Control::RemoveBody(1 << 40),
+            },
+            Send::Control {
+                client_seq: u32::MAX,
+                control: Control::TickRate(30),
+            },
+            Send::Spectate {
+                protocol_version: crate::PROTOCOL_VERSION,
+                client_name: "watcher".to_owned(),
+            },
+            Send::InputsComplete {
+                frame_index: 100_006,
+            },
+            Send::Enter {
+                ticket: 0xdead_beef_f00d_cafe,
+            },
+        ]
+    }
+
+    fn recvs() -> Vec<Recv> {
+        let baseline = state();
+        let mut target = baseline.clone();
+        let control = ScheduledControl {
+            frame_index: 100_010,
+            seq: 4,
+            control: Control::Timescale(2),
+        };
+        target.schedule_control(control);
+        target.playback.paused = true;
+        target.playback.tick_rate = Some(30);
+        target.step();
+        let body = target.simulation.bodies[0];
+        vec![
+            Recv::Welcome {
+                player_id: 3,
+                tick_rate: 60,
+                input_delay: 7,
+                server_frame: 100_000,
+                sync_mode: SyncMode::Strict,
+                input_rules: InputRules {
+                    max_speed: 2.5,
+                    ..Default::default()
+                },
+            },
+            Recv::Rejected(ConnectionRejected::IncompatibleVersion { server_version: 9 }),
+            Recv::Rejected(ConnectionRejected::TimedOut {
+                frame_index: 100_000,
+            }),
+            Recv::Rejected(ConnectionRejected::InvalidTicket),
+            Recv::Rejected(ConnectionRejected::Kicked),
+            Recv::Pong {
+                client_time: 12_345_678,
+                server_time: 98_765_432_100,
+                server_frame: 100_000,
+            },
+            Recv::StateHash(IndexedState {
+                frame_index: 100_000,
+                state: u64::MAX - 3,
+            }),
+            Recv::InputState {
+                player_id: 1,
+                client_seq: 7,
+                input: input(100_007),
+            },
+            Recv::FullState(target.clone()),
+            Recv::StateDelta(StateDelta::encode(&baseline, &target)),
+            Recv::InputAck {
+                client_seq: 7,
+                applied_frame: 100_008,
+            },
+            Recv::HashTree(HashTree::new(&target)),
+            Recv::BodyHashes {
+                frame_index: 4,
+                buckets: vec![2],
+                hashes: vec![(2, 12345), (18, u64::MAX)],
+            },
+            Recv::Bodies {
+                frame_index: 4,
+                ids: vec![body.id()],
+                bodies: vec![body],
+            },
+            Recv::FrameUnavailable(4),
+            Recv::Control(control),
+            Recv::Control(ScheduledControl {
+                control: Control::Resume,
+                ..control
+            }),
+            Recv::InputRejected {
+                client_seq: 7,
+                reason: InputRejected::MassBudgetExceeded,
+            },
+            Recv::InputRejected {
+                client_seq: 8,
+                reason: InputRejected::TooClose,
+            },
+            Recv::Spectating {
+                tick_rate: 60,
+                server_frame: 100_000,
+            },
+            Recv::Load {
+                frame_index: 100_012,
+            },
+            Recv::ControlRejected {
+                client_seq: 9,
+                reason: ControlRejected::RateLimited,
+            },
+            Recv::ControlRejected {
+                client_seq: 10,
+                reason: ControlRejected::AdminOnly,
+            },
+        ]
+    }
+
+    fn encode<T, C: Codec<T>>(codec: &C, message: &T) -> Vec<u8> {
+        let mut buf = Vec::new();
+        codec.encode(message, &mut buf).unwrap();
+        buf
+    }
+
+    /// Decodes the encoded messages back to back and checks that they re-encode identically.
+    fn round_trip<T, C: Codec<T>>(codec: &C, messages: &[T]) {
+        let encoded = messages
+            .iter()
+            .map(|message| encode(codec, message))
+            .collect::<Vec<_>>();
+        let mut rest = &encoded.concat()[..];
+        for expected in encoded.iter() {
+            let (message, next) = codec.decode(rest).unwrap();
+            assert_eq!(&encode(codec, &message), expected);
+            rest = next;
+        }
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn every_codec_round_trips() {
+        round_trip(&WireCodec::default(), &sends());
+        round_trip(&WireCodec::default(), &recvs());
+        round_trip(&Budgeted(Bincode), &sends());
+        round_trip(&Budgeted(Bincode), &recvs());
+        round_trip(&Budgeted(Postcard), &sends());
+        round_trip(&Budgeted(Postcard), &recvs());
+    }
+
+    #[test]
+    fn floats() {
+        let values = [
+            Float::from_bits(0),
+            Float::from_num(1),
+            Float::from_num(-1),
+            Float::from_num(i32::MIN),
+            Float::from_num(0.1),
+            Float::from_bits(i64::MIN),
+            Float::from_bits(i64::MAX),
+        ];
+        let mut buf = Vec::new();
+        let mut w = BitWriter::new(&mut buf);
+        for value in values.iter() {
+            w.float(*value);
+        }
+        let mut r = BitReader::new(&buf);
+        for value in values.iter() {
+            assert_eq!(r.float().unwrap(), *value);
+        }
+    }
+
+    #[test]
+    fn typical_traffic_sizes() {
+        // what a client and the server exchange every frame once connected
+        let sends = vec![
+            Send::Ping {
+                client_time: 905_123_456,
+            },
+            Send::InputState {
+                client_seq: 12,
+                input: IndexedState {
+                    frame_index: 54_328,
+                    state: AddBodyEvent::new_with_velocity(640., 360., 1000., 1.5, -0.25),
+                },
+            },
+        ];
+        let recvs = vec![
+            Recv::Pong {
+                client_time: 905_123_456,
+                server_time: 905_320_000,
+                server_frame: 54_321,
+            },
+            Recv::StateHash(IndexedState {
+                frame_index: 54_322,
+                state: 0x1234_5678_9abc_def0,
+            }),
+            Recv::InputAck {
+                client_seq: 12,
+                applied_frame: 54_328,
+            },
+        ];
+
+        fn total<T, C: Codec<T>>(codec: &C, messages: &[T]) -> usize {
+            messages
+                .iter()
+                .map(|message| encode(codec, message).len())
+                .sum()
+        }
+        let bincode = total(&Bincode, &sends) + total(&Bincode, &recvs);
+        let postcard = total(&Postcard, &sends) + total(&Postcard, &recvs);
+        let bit_packed = total(&BitPacked, &sends) + total(&BitPacked, &recvs);
+        assert!(postcard < bincode, "{} {}", postcard, bincode);
+        assert!(bit_packed < postcard, "{} {}", bit_packed, postcard);
+
+        let full = Recv::FullState(state());
+        assert!(encode(&BitPacked, &full).len() <= encode(&Bincode, &full).len());
+    }
+
+    #[test]
+    fn budgets_fit_the_largest_codec() {
+        for message in sends() {
+            assert!(encode(&Bincode, &message).len() <= message.max_encoded_size());
+        }
+        for message in recvs() {
+            assert!(encode(&Bincode, &message).len() <= message.max_encoded_size());
+        }
+    }
+
+    #[test]
+    fn the_largest_state_fits_its_budget() {
+        let mut state = State::new();
+        for i in 0..MAX_BODIES {
+            state
+                .simulation
+                .add_body(Body::new_lossy(i as f32 * 10., 0., 1.));
+        }
+        let mut buf = Vec::new();
+        WireCodec::default()
+            .encode(&Recv::FullState(state), &mut buf)
+            .unwrap();
+    }
+
+    #[test]
+    fn budgets_are_enforced() {
+        let hello = Send::Hello {
+            protocol_version: crate::PROTOCOL_VERSION,
+            client_name: "x".repeat(200),
+        };
+        let mut buf = vec![1, 2, 3];
+        assert!(matches!(
+            WireCodec::default().encode(&hello, &mut buf),
+            Err(CodecError::TooLarge { kind: "Hello", .. })
+        ));
+        assert_eq!(buf, vec![1, 2, 3]);
+
+        // the name limit is hit before the budget
+        let oversized = encode(&BitPacked, &hello);
+        assert!(matches!(
+            Codec::<Send>::decode(&WireCodec::default(), &oversized),
+            Err(CodecError::TooMany {
+                what: "name bytes",
+                len: 200,
+                max: MAX_CLIENT_NAME_LEN,
+            })
+        ));
+        assert!(matches!(
+            Codec::<Send>::decode(&Budgeted(Bincode), &encode(&Bincode, &hello)),
+            Err(CodecError::TooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn lengths_are_bounded() {
+        let mut buf = Vec::new();
+        let mut w = BitWriter::new(&mut buf);
+        w.varint(6);
+        w.varint(0);
+        w.varint(u32::MAX as u64);
+        assert!(matches!(
+            Codec::<Send>::decode(&BitPacked, &buf),
+            Err(CodecError::TooMany {
+                what: "body ids",
+                max: hash_tree::BODIES_PER_REQUEST,
+                ..
+            })
+        ));
+
+        // within the limit but longer than what's left
+        let mut buf = Vec::new();
+        let mut w = BitWriter::new(&mut buf);
+        w.varint(5);
+        w.varint(1000);
+        assert!(matches!(
+            Codec::<Recv>::decode(&BitPacked, &buf),
+            Err(CodecError::Truncated)
+        ));
+
+        let mut state = State::new();
+        for i in 0..MAX_BODIES + 1 {
+            state
+                .simulation
+                .add_body(Body::new_lossy(i as f32 * 10., 0., 1.));
+        }
+        assert!(matches!(
+            State::decode(&state.encode()),
+            Err(EncodingError::TooMany { what: "bodies", .. })
+        ));
+    }
+
+    /// Truncates and corrupts an encoded message at evenly spaced positions.
+    fn mutations(encoded: &[u8]) -> Vec<Vec<u8>> {
+        let step = (encoded.len() / 64).max(1);
+        let mut mutations = Vec::new();
+        for i in (0..encoded.len()).step_by(step) {
+            mutations.push(encoded[..i].to_vec());
+            for value in [0x00, 0x7f, 0x80, 0xff].iter() {
+                let mut mutated = encoded.to_vec();
+                mutated[i] = *value;
+                mutations.push(mutated);
+            }
+        }
+        mutations
+    }
+
+    fn decode_mutations<T, C: Codec<T>>(codec: &C, messages: &[T], mut check: impl FnMut(T)) {
+        for message in messages {
+            for mutated in mutations(&encode(codec, message)) {
+                let mut rest = &mutated[..];
+                while let Ok((message, next)) = codec.decode(rest) {
+                    check(message);
+                    if next.is_empty() {
+                        break;
+                    }
+                    rest = next;
+                }
+            }
+        }
+    }
+
+    /// A deterministic stand-in for the fuzz targets: corrupt input may fail to decode but
+    /// must never panic, and whatever does decode must be safe to use.
+    #[test]
+    fn corrupt_messages_are_rejected_without_panicking() {
+        let baseline = state();
+        let check_send = |message: Send| {
+            encode(&BitPacked, &message);
+        };
+        let check_recv = |message: Recv| {
+            encode(&BitPacked, &message);
+            if let Recv::StateDelta(delta) = message {
+                let _ = delta.apply(&baseline);
+            }
+        };
+        decode_mutations(&WireCodec::default(), &sends(), check_send);
+        decode_mutations(&WireCodec::default(), &recvs(), check_recv);
+        decode_mutations(&Budgeted(Bincode), &sends(), check_send);
+        decode_mutations(&Budgeted(Bincode), &recvs(), check_recv);
+        decode_mutations(&Budgeted(Postcard), &sends(), check_send);
+        decode_mutations(&Budgeted(Postcard), &recvs(), check_recv);
+    }
+}
@@ -0,0 +1,273 @@
+//! Pausing, stepping and speeding up the simulation for everyone at once, and the admin's
+//! changes to it.
+//!
+//! Controls are scheduled on a frame by the server like inputs are, so every peer applies
+//! them on the same frame. Frames keep counting while paused: the frame index is network time
+//! and `Playback` decides how many simulation steps each frame runs. Replacing the whole
+//! simulation, to reset it or load a snapshot, is scheduled on a frame too but only happens on
+//! the server, see `Recv::Load`.
+
+use super::FrameIndex;
+use nbody::BodyId;
+use serde::{Deserialize, Serialize};
+
+/// Assigned by the server. Orders controls on the same frame and identifies repeats.
+pub type ControlSequence = u32;
+
+pub const MAX_TIMESCALE: u32 = 16;
+/// The most frames a single `Step` can queue up.
+pub const MAX_STEPS: u32 = 10 * 60;
+/// The most controls that can be waiting for their frame. Any more are dropped.
+pub const MAX_SCHEDULED: usize = 256;
+pub const MIN_TICK_RATE: u32 = 1;
+pub const MAX_TICK_RATE: u32 = 240;
+
+#[derive(Copy, Clone, Debug, Hash, Serialize, Deserialize, Eq, PartialEq)]
+pub enum Control {
+    Pause,
+    Resume,
+    /// Runs this many simulation steps, one per frame, while paused.
+    Step(u32),
+    /// Runs this many simulation steps every frame while not paused.
+    Timescale(u32),
+    /// Removes a body, if it still exists.
+    RemoveBody(BodyId),
+    /// Runs this many frames a second from now on. Frames are network time, so this changes
+    /// how often peers simulate and exchange frames rather than what happens on each one.
+    TickRate(u32),
+}
+
+impl Control {
+    /// Whether only the admin API may schedule it. Players can pause, step and speed up the
+    /// simulation but not change it.
+    pub fn is_admin_only(&self) -> bool {
+        matches!(self, Control::RemoveBody(_) | Control::TickRate(_))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Hash, Serialize, Deserialize, Eq, PartialEq)]
+pub struct ScheduledControl {
+    pub frame_index: FrameIndex,
+    pub seq: ControlSequence,
+    pub control: Control,
+}
+
+#[derive(Clone, Debug, Hash, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Playback {
+    pub paused: bool,
+    pub timescale: u32,
+    /// Steps still to run from `Step` controls.
+    pub pending_steps: u32,
+    /// Set by `TickRate`. Until then the server's configured tick rate applies.
+    pub tick_rate: Option<u32>,
+    /// Sorted by frame then sequence.
+    scheduled: Vec<ScheduledControl>,
+}
+
+impl Default for Playback {
+    fn default() -> Self {
+        Self {
+            paused: false,
+            timescale: 1,
+            pending_steps: 0,
+            tick_rate: None,
+            scheduled: Vec::new(),
+        }
+    }
+}
+
+impl Playback {
+    /// Queues a control. Returns false if it was already queued or the queue is full.
+    pub fn schedule(&mut self, control: ScheduledControl) -> bool {
+        if self.scheduled.len() >= MAX_SCHEDULED {
+            log::warn!("dropping {:?}, too many controls scheduled", control);
+            return false;
+        }
+        let key = |c: &ScheduledControl| (c.frame_index, c.seq);
+        match self.scheduled.binary_search_by_key(&key(&control), key) {
+            Ok(_) => false,
+            Err(index) => {
+                self.scheduled.insert(index, control);
+                true
+            }
+        }
+    }
+
+    pub fn scheduled(&self) -> &[ScheduledControl] {
+        &self.scheduled
+    }
+
+    /// Applies a control to the playback. `RemoveBody` doesn't affect it, see
+    /// `State::step`.
+    fn apply(&mut self, control: Control) {
+        log::debug!("applying {:?}", control);
+        match control {
+            Control::Pause => self.paused = true,
+            Control::Resume => {
+                self.paused = false;
+                self.pending_steps = 0;
+            }
+            Control::Step(steps) => {
+                if self.paused {
+                    self.pending_steps = self.pending_steps.saturating_add(steps).min(MAX_STEPS);
+                }
+            }
+            Control::Timescale(timescale) => self.timescale = timescale.clamp(1, MAX_TIMESCALE),
+            Control::RemoveBody(_) => {}
+            Control::TickRate(tick_rate) => {
+                self.tick_rate = Some(tick_rate.clamp(MIN_TICK_RATE, MAX_TICK_RATE))
+            }
+        }
+    }
+
+    /// Applies the controls due on `frame_index` and returns them, so that the ones that
+    /// change the simulation can be applied to it too.
+    pub fn apply_due(&mut self, frame_index: FrameIndex) -> Vec<Control> {
+        let due = self
+            .scheduled
+            .iter()
+            .take_while(|control| control.frame_index <= frame_index)
+            .count();
+        let mut applied = Vec::with_capacity(due);
+        for control in self.scheduled.drain(..due).collect::<Vec<_>>() {
+            if control.frame_index < frame_index {
+                log::warn!(
+                    "missed control for frame {}. current frame: {}",
+                    control.frame_index,
+                    frame_index
+                );
+            }
+            self.apply(control.control);
+            applied.push(control.control);
+        }
+        applied
+    }
+
+    /// Applies the controls due on `frame_index` and returns how many simulation steps the
+    /// frame should run.
+    pub fn advance(&mut self, frame_index: FrameIndex) -> u32 {
+        self.apply_due(frame_index);
+        self.steps()
+    }
+
+    /// How many simulation steps the current frame should run, once its controls have been
+    /// applied. Uses up a pending step while paused.
+    pub fn steps(&mut self) -> u32 {
+        if !self.paused {
+            self.timescale
+        } else if self.pending_steps > 0 {
+            self.pending_steps -= 1;
+            1
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AddBodyEvent, IndexedState, State};
+
+    fn control(
+        frame_index: FrameIndex,
+        seq: ControlSequence,
+        control: Control,
+    ) -> ScheduledControl {
+        ScheduledControl {
+            frame_index,
+            seq,
+            control,
+        }
+    }
+
+    #[test]
+    fn playback() {
+        let mut playback = Playback::default();
+        assert!(playback.schedule(control(2, 1, Control::Step(2))));
+        assert!(playback.schedule(control(2, 0, Control::Pause)));
+        assert!(playback.schedule(control(6, 2, Control::Timescale(3))));
+        assert!(playback.schedule(control(7, 3, Control::Resume)));
+        assert!(!playback.schedule(control(2, 1, Control::Step(2))));
+
+        let steps = (0..9)
+            .map(|frame| playback.advance(frame))
+            .collect::<Vec<_>>();
+        assert_eq!(steps, vec![1, 1, 1, 1, 0, 0, 0, 3, 3]);
+        assert!(playback.scheduled().is_empty());
+    }
+
+    #[test]
+    fn limits() {
+        let mut playback = Playback::default();
+        playback.schedule(control(0, 0, Control::Timescale(1000)));
+        playback.schedule(control(0, 1, Control::Step(5)));
+        playback.schedule(control(1, 2, Control::Timescale(0)));
+        assert_eq!(playback.advance(0), MAX_TIMESCALE);
+        assert_eq!(playback.pending_steps, 0);
+        assert_eq!(playback.advance(1), 1);
+    }
+
+    #[test]
+    fn admin_controls() {
+        let mut state = State::new();
+        for x in 0..3 {
+            state
+                .simulation
+                .add_body(nbody::Body::new_lossy(x as f32 * 100., 0., 1.));
+        }
+        state.schedule_control(control(1, 0, Control::RemoveBody(1)));
+        state.schedule_control(control(1, 1, Control::RemoveBody(7)));
+        state.schedule_control(control(2, 2, Control::TickRate(1000)));
+        state.step();
+        assert_eq!(state.simulation.bodies.len(), 3);
+        state.step();
+        let ids = state.simulation.bodies.iter().map(|body| body.id());
+        assert_eq!(ids.collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(state.playback.tick_rate, None);
+        state.step();
+        assert_eq!(state.playback.tick_rate, Some(MAX_TICK_RATE));
+    }
+
+    #[test]
+    fn peers_agree_on_paused_and_sped_up_states() {
+        let controls = [
+            control(3, 0, Control::Timescale(4)),
+            control(10, 1, Control::Pause),
+            control(12, 2, Control::Step(3)),
+            control(20, 3, Control::Resume),
+        ];
+        let input = IndexedState {
+            frame_index: 14,
+            state: AddBodyEvent::new(50., 0., 5.),
+        };
+
+        let mut a = State::new();
+        a.simulation.add_body(nbody::Body::new_lossy(0., 0., 100.));
+        let mut b = a.clone();
+        for control in controls.iter() {
+            assert!(a.schedule_control(*control));
+        }
+        a.schedule(input);
+        b.schedule(input);
+        for control in controls.iter().rev() {
+            assert!(b.schedule_control(*control));
+        }
+
+        let mut paused_positions = Vec::new();
+        for _ in 0..25 {
+            a.step();
+            b.step();
+            assert_eq!(a.hash(), b.hash());
+            // paused with the steps used up
+            if (15..20).contains(&(a.frame_index - 1)) {
+                paused_positions.push(a.simulation.bodies[0].position);
+            }
+        }
+        assert!(paused_positions.windows(2).all(|w| w[0] == w[1]));
+        assert!(!paused_positions.is_empty());
+
+        // too late to apply
+        assert!(!a.schedule_control(control(3, 9, Control::Pause)));
+    }
+}
@@ -0,0 +1,712 @@
+//! The canonical binary encoding of `State`.
+//!
+//! Every peer must produce the same bytes for the same state, so the layout is spelled out
+//! field by field rather than left to serde: little endian integers, fixed point numbers as
+//! their raw bits, bodies in simulation order and pending inputs sorted. `State::hash` is
+//! computed over these bytes so anything that can affect future frames is covered by it.
+//!
+//! Encoded states start with `MAGIC` and a version. Older versions are decoded by their own
+//! function and upgraded in memory, so add a new `decode_vN` rather than changing an old one.
+//! Bytes without the magic are the bincode encoding of the serde derives that predated this
+//! format.
+
+use super::control::{Control, Playback, ScheduledControl, MAX_SCHEDULED};
+use super::{
+    AddBodyEvent, FrameIndex, IndexedState, InputBuffer, State, MAX_BODIES, MAX_PENDING_INPUTS,
+};
+use nbody::{Body, BodyId, Float, Point2D, Vector2D};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+pub const MAGIC: [u8; 4] = *b"NBST";
+pub const STATE_ENCODING_VERSION: u16 = 4;
+
+const BODY_SIZE: usize = 8 + 1 + 7 * 8;
+const INPUT_SIZE: usize = 4 + 5 * 8;
+const CONTROL_SIZE_V2: usize = 4 + 4 + 1 + 4;
+const CONTROL_SIZE: usize = 4 + 4 + 1 + 8;
+/// A version 3 load with an empty simulation.
+const LOAD_SIZE_V3: usize = 4 + 4 + 8 + 4;
+/// Version 2 predates the admin controls, so its tags stop at `Timescale`'s.
+const MAX_CONTROL_TAG_V2: u8 = 3;
+
+#[derive(Debug)]
+pub enum EncodingError {
+    Truncated,
+    TrailingBytes(usize),
+    UnsupportedVersion(u16),
+    /// The state was produced by a build with different simulation constants.
+    ParameterMismatch {
+        name: &'static str,
+        expected: Float,
+        actual: Float,
+    },
+    InvalidFlag(u8),
+    /// A collection is longer than any valid state would contain.
+    TooMany {
+        what: &'static str,
+        count: usize,
+        max: usize,
+    },
+    Legacy(bincode::Error),
+}
+
+impl std::fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncodingError::Truncated => write!(f, "encoded state is truncated"),
+            EncodingError::TrailingBytes(len) => {
+                write!(f, "encoded state has {} unexpected trailing bytes", len)
+            }
+            EncodingError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported state encoding version {}. latest: {}",
+                version, STATE_ENCODING_VERSION
+            ),
+            EncodingError::ParameterMismatch {
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "simulation parameter {} is {} but this build uses {}",
+                name, actual, expected
+            ),
+            EncodingError::InvalidFlag(flag) => write!(f, "invalid flag {}", flag),
+            EncodingError::TooMany { what, count, max } => {
+                write!(f, "{} {} is over the limit of {}", count, what, max)
+            }
+            EncodingError::Legacy(err) => write!(f, "could not decode legacy state: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for EncodingError {}
+
+/// The simulation constants that a state is only valid under, in encoding order.
+fn parameters() -> [(&'static str, Float); 3] {
+    [
+        ("gravity", nbody::GRAVITY),
+        ("density", nbody::DENSITY),
+        ("tick", nbody::TICK),
+    ]
+}
+
+fn write_float(buf: &mut Vec<u8>, value: Float) {
+    buf.extend_from_slice(&value.to_bits().to_le_bytes());
+}
+
+fn write_input(buf: &mut Vec<u8>, input: &IndexedState<AddBodyEvent>) {
+    buf.extend_from_slice(&input.frame_index.to_le_bytes());
+    for value in input.state.sort_key().iter() {
+        write_float(buf, *value);
+    }
+}
+
+fn write_simulation(buf: &mut Vec<u8>, simulation: &nbody::Simulation) {
+    let bodies = &simulation.bodies;
+    buf.extend_from_slice(&simulation.next_id().to_le_bytes());
+    buf.extend_from_slice(&(bodies.len() as u32).to_le_bytes());
+    for body in bodies.iter() {
+        buf.extend_from_slice(&body.id().to_le_bytes());
+        buf.push(body.collided() as u8);
+        write_float(buf, body.position.x);
+        write_float(buf, body.position.y);
+        write_float(buf, body.velocity.x);
+        write_float(buf, body.velocity.y);
+        write_float(buf, body.acceleration.x);
+        write_float(buf, body.acceleration.y);
+        write_float(buf, body.mass);
+    }
+}
+
+fn write_control(buf: &mut Vec<u8>, control: &ScheduledControl) {
+    let (tag, arg) = match control.control {
+        Control::Pause => (0u8, 0),
+        Control::Resume => (1, 0),
+        Control::Step(steps) => (2, steps as u64),
+        Control::Timescale(timescale) => (3, timescale as u64),
+        Control::RemoveBody(id) => (4, id),
+        Control::TickRate(tick_rate) => (5, tick_rate as u64),
+    };
+    buf.extend_from_slice(&control.frame_index.to_le_bytes());
+    buf.extend_from_slice(&control.seq.to_le_bytes());
+    buf.push(tag);
+    buf.extend_from_slice(&arg.to_le_bytes());
+}
+
+/// Controls with a `u32` argument are stored as their `u64` since version 3, so anything larger
+/// can't have come from `write_control`.
+fn control_from(tag: u8, arg: u64) -> Result<Control, EncodingError> {
+    let small = || u32::try_from(arg).map_err(|_| EncodingError::InvalidFlag(tag));
+    Ok(match tag {
+        0 => Control::Pause,
+        1 => Control::Resume,
+        2 => Control::Step(small()?),
+        3 => Control::Timescale(small()?),
+        4 => Control::RemoveBody(arg),
+        5 => Control::TickRate(small()?),
+        tag => return Err(EncodingError::InvalidFlag(tag)),
+    })
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], EncodingError> {
+        if self.0.len() < len {
+            return Err(EncodingError::Truncated);
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, EncodingError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, EncodingError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn u32(&mut self) -> Result<u32, EncodingError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, EncodingError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn bool(&mut self) -> Result<bool, EncodingError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            flag => Err(EncodingError::InvalidFlag(flag)),
+        }
+    }
+
+    fn float(&mut self) -> Result<Float, EncodingError> {
+        Ok(Float::from_bits(self.u64()? as i64))
+    }
+
+    /// Reads a count of at most `max` items that each take at least `item_size` bytes,
+    /// rejecting counts that the remaining bytes couldn't possibly hold.
+    fn count(
+        &mut self,
+        what: &'static str,
+        max: usize,
+        item_size: usize,
+    ) -> Result<usize, EncodingError> {
+        let count = self.u32()? as usize;
+        if count > max {
+            return Err(EncodingError::TooMany { what, count, max });
+        }
+        if count.saturating_mul(item_size) > self.0.len() {
+            return Err(EncodingError::Truncated);
+        }
+        Ok(count)
+    }
+
+    fn finish(self) -> Result<(), EncodingError> {
+        match self.0.len() {
+            0 => Ok(()),
+            len => Err(EncodingError::TrailingBytes(len)),
+        }
+    }
+}
+
+fn read_simulation(reader: &mut Reader) -> Result<nbody::Simulation, EncodingError> {
+    let mut simulation = nbody::Simulation::new();
+    simulation.set_next_id(reader.u64()?);
+    for _ in 0..reader.count("bodies", MAX_BODIES, BODY_SIZE)? {
+        let id = reader.u64()?;
+        let collided = reader.bool()?;
+        let position = Point2D::new(reader.float()?, reader.float()?);
+        let velocity = Vector2D::new(reader.float()?, reader.float()?);
+        let acceleration = Vector2D::new(reader.float()?, reader.float()?);
+        let mass = reader.float()?;
+        simulation.bodies.push(Body::from_parts(
+            id,
+            collided,
+            position,
+            velocity,
+            acceleration,
+            mass,
+        ));
+    }
+    Ok(simulation)
+}
+
+fn decode_v1(reader: &mut Reader) -> Result<State, EncodingError> {
+    let frame_index = reader.u32()?;
+    for (name, expected) in parameters().iter() {
+        let actual = reader.float()?;
+        if actual != *expected {
+            return Err(EncodingError::ParameterMismatch {
+                name,
+                expected: *expected,
+                actual,
+            });
+        }
+    }
+
+    let simulation = read_simulation(reader)?;
+    let mut input_buffer = InputBuffer::default();
+    for _ in 0..reader.count("pending inputs", MAX_PENDING_INPUTS, INPUT_SIZE)? {
+        let frame_index = reader.u32()?;
+        let state = AddBodyEvent {
+            position_x: reader.float()?,
+            position_y: reader.float()?,
+            velocity_x: reader.float()?,
+            velocity_y: reader.float()?,
+            mass: reader.float()?,
+        };
+        input_buffer.push(IndexedState { frame_index, state });
+    }
+
+    Ok(State {
+        simulation,
+        frame_index,
+        input_buffer,
+        playback: Playback::default(),
+    })
+}
+
+/// Version 1 followed by the playback state.
+fn decode_v2(reader: &mut Reader) -> Result<State, EncodingError> {
+    let mut state = decode_v1(reader)?;
+    let playback = &mut state.playback;
+    playback.paused = reader.bool()?;
+    playback.timescale = reader.u32()?;
+    playback.pending_steps = reader.u32()?;
+    for _ in 0..reader.count("scheduled controls", MAX_SCHEDULED, CONTROL_SIZE_V2)? {
+        let frame_index = reader.u32()?;
+        let seq = reader.u32()?;
+        let tag = reader.u8()?;
+        if tag > MAX_CONTROL_TAG_V2 {
+            return Err(EncodingError::InvalidFlag(tag));
+        }
+        let control = control_from(tag, reader.u32()? as u64)?;
+        playback.schedule(ScheduledControl {
+            frame_index,
+            seq,
+            control,
+        });
+    }
+    Ok(state)
+}
+
+/// Version 4 followed by the loads scheduled on the frames to come. Loads are no longer part
+/// of the state, so only states without any can be upgraded.
+fn decode_v3(reader: &mut Reader) -> Result<State, EncodingError> {
+    let state = decode_v4(reader)?;
+    reader.count("scheduled loads", 0, LOAD_SIZE_V3)?;
+    Ok(state)
+}
+
+/// Version 1 followed by the playback state, with `u64` control arguments and the tick rate.
+fn decode_v4(reader: &mut Reader) -> Result<State, EncodingError> {
+    let mut state = decode_v1(reader)?;
+    let playback = &mut state.playback;
+    playback.paused = reader.bool()?;
+    playback.timescale = reader.u32()?;
+    playback.pending_steps = reader.u32()?;
+    for _ in 0..reader.count("scheduled controls", MAX_SCHEDULED, CONTROL_SIZE)? {
+        let frame_index = reader.u32()?;
+        let seq = reader.u32()?;
+        let tag = reader.u8()?;
+        let control = control_from(tag, reader.u64()?)?;
+        playback.schedule(ScheduledControl {
+            frame_index,
+            seq,
+            control,
+        });
+    }
+    playback.tick_rate = Some(reader.u32()?).filter(|tick_rate| *tick_rate != 0);
+    Ok(state)
+}
+
+/// The serde layout of `State` before the canonical encoding existed. Bodies had no
+/// serialized id so they're numbered in order.
+#[derive(Serialize, Deserialize)]
+struct LegacyBody {
+    collided: bool,
+    position: Point2D,
+    velocity: Vector2D,
+    acceleration: Vector2D,
+    mass: Float,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LegacySimulation {
+    bodies: Vec<LegacyBody>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LegacyState {
+    simulation: LegacySimulation,
+    frame_index: FrameIndex,
+    input_buffer: InputBuffer,
+}
+
+fn decode_legacy(bytes: &[u8]) -> Result<State, EncodingError> {
+    use bincode::Options;
+    let legacy = super::codec::bincode_options()
+        .deserialize::<LegacyState>(bytes)
+        .map_err(EncodingError::Legacy)?;
+    if legacy.simulation.bodies.len() > MAX_BODIES {
+        return Err(EncodingError::TooMany {
+            what: "bodies",
+            count: legacy.simulation.bodies.len(),
+            max: MAX_BODIES,
+        });
+    }
+    let mut simulation = nbody::Simulation::new();
+    for (id, body) in legacy.simulation.bodies.into_iter().enumerate() {
+        simulation.bodies.push(Body::from_parts(
+            id as BodyId,
+            body.collided,
+            body.position,
+            body.velocity,
+            body.acceleration,
+            body.mass,
+        ));
+    }
+    simulation.set_next_id(simulation.bodies.len() as BodyId);
+    Ok(State {
+        simulation,
+        frame_index: legacy.frame_index,
+        input_buffer: legacy.input_buffer,
+        playback: Playback::default(),
+    })
+}
+
+impl State {
+    /// Encodes the state in the latest canonical version.
+    pub fn encode(&self) -> Vec<u8> {
+        let bodies = &self.simulation.bodies;
+        let mut inputs = self.input_buffer.iter().collect::<Vec<_>>();
+        inputs.sort_unstable_by_key(|input| (input.frame_index, input.state.sort_key()));
+
+        let mut buf = Vec::with_capacity(64 + bodies.len() * BODY_SIZE + inputs.len() * INPUT_SIZE);
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&STATE_ENCODING_VERSION.to_le_bytes());
+        buf.extend_from_slice(&self.frame_index.to_le_bytes());
+        for (_, value) in parameters().iter() {
+            write_float(&mut buf, *value);
+        }
+
+        write_simulation(&mut buf, &self.simulation);
+
+        buf.extend_from_slice(&(inputs.len() as u32).to_le_bytes());
+        for input in inputs {
+            write_input(&mut buf, input);
+        }
+
+        let playback = &self.playback;
+        buf.push(playback.paused as u8);
+        buf.extend_from_slice(&playback.timescale.to_le_bytes());
+        buf.extend_from_slice(&playback.pending_steps.to_le_bytes());
+        buf.extend_from_slice(&(playback.scheduled().len() as u32).to_le_bytes());
+        for control in playback.scheduled() {
+            write_control(&mut buf, control);
+        }
+        buf.extend_from_slice(&playback.tick_rate.unwrap_or(0).to_le_bytes());
+        buf
+    }
+
+    /// Decodes any version of the encoding, upgrading older versions to the current `State`.
+    pub fn decode(bytes: &[u8]) -> Result<State, EncodingError> {
+        if !bytes.starts_with(&MAGIC) {
+            return decode_legacy(bytes);
+        }
+
+        let mut reader = Reader(&bytes[MAGIC.len()..]);
+        let state = match reader.u16()? {
+            1 => decode_v1(&mut reader)?,
+            2 => decode_v2(&mut reader)?,
+            3 => decode_v3(&mut reader)?,
+            4 => decode_v4(&mut reader)?,
+            version => return Err(EncodingError::UnsupportedVersion(version)),
+        };
+        reader.finish()?;
+        Ok(state)
+    }
+}
+
+impl Serialize for State {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.encode())
+    }
+}
+
+impl<'de> Deserialize<'de> for State {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = serde_bytes::ByteBuf::deserialize(deserializer)?;
+        State::decode(&bytes).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> State {
+        let mut state = State::new();
+        for i in 0..20 {
+            state.schedule(IndexedState {
+                frame_index: i % 3,
+                state: AddBodyEvent::new(i as f32 * 50., 0., 3.),
+            });
+        }
+        for _ in 0..5 {
+            state.step();
+        }
+        state.schedule(IndexedState {
+            frame_index: 9,
+            state: AddBodyEvent::new(1., 2., 3.),
+        });
+        state
+    }
+
+    #[test]
+    fn round_trip() {
+        let state = state();
+        let encoded = state.encode();
+        assert!(encoded.starts_with(&MAGIC));
+
+        let decoded = State::decode(&encoded).unwrap();
+        assert_eq!(decoded.encode(), encoded);
+        assert_eq!(decoded.hash(), state.hash());
+
+        let wire = bincode::serialize(&state).unwrap();
+        let decoded = bincode::deserialize::<State>(&wire).unwrap();
+        assert_eq!(decoded.encode(), encoded);
+    }
+
+    #[test]
+    fn playback_round_trips_and_version_1_still_decodes() {
+        let mut playing = state();
+        playing.schedule_control(ScheduledControl {
+            frame_index: 7,
+            seq: 2,
+            control: Control::Timescale(3),
+        });
+        playing.schedule_control(ScheduledControl {
+            frame_index: 8,
+            seq: 3,
+            control: Control::RemoveBody(1 << 40),
+        });
+        playing.playback.paused = true;
+        playing.playback.pending_steps = 4;
+        playing.playback.tick_rate = Some(30);
+        let decoded = State::decode(&playing.encode()).unwrap();
+        assert_eq!(decoded.playback, playing.playback);
+        assert_eq!(decoded.hash(), playing.hash());
+
+        // version 3 also had the scheduled loads, which are only upgraded if there are none
+        let state = state();
+        let mut v3 = state.encode();
+        v3.extend_from_slice(&0u32.to_le_bytes());
+        v3[4..6].copy_from_slice(&3u16.to_le_bytes());
+        let decoded = State::decode(&v3).unwrap();
+        assert_eq!(decoded.encode(), state.encode());
+        let len = v3.len();
+        v3[len - 4..].copy_from_slice(&1u32.to_le_bytes());
+        assert!(matches!(
+            State::decode(&v3),
+            Err(EncodingError::TooMany {
+                what: "scheduled loads",
+                ..
+            })
+        ));
+
+        // version 2 had no tick rate, and smaller control arguments
+        let mut v2 = state.encode();
+        v2.truncate(v2.len() - 4);
+        v2[4..6].copy_from_slice(&2u16.to_le_bytes());
+        let decoded = State::decode(&v2).unwrap();
+        assert_eq!(decoded.encode(), state.encode());
+
+        // version 2 appended the playback to version 1, which had none
+        let mut v1 = v2;
+        v1.truncate(v1.len() - (1 + 4 + 4 + 4));
+        v1[4..6].copy_from_slice(&1u16.to_le_bytes());
+        let decoded = State::decode(&v1).unwrap();
+        assert_eq!(decoded.encode(), state.encode());
+    }
+
+    #[test]
+    fn version_2_controls_still_decode() {
+        let mut state = state();
+        let controls = [
+            ScheduledControl {
+                frame_index: 7,
+                seq: 2,
+                control: Control::Timescale(3),
+            },
+            ScheduledControl {
+                frame_index: 8,
+                seq: 3,
+                control: Control::Step(5),
+            },
+        ];
+        for control in controls.iter() {
+            assert!(state.schedule_control(*control));
+        }
+        state.playback.paused = true;
+        state.playback.pending_steps = 4;
+
+        // the version 1 part is unchanged, then the playback with `u32` control arguments
+        let encoded = state.encode();
+        let playback = 1 + 4 + 4 + 4 + controls.len() * CONTROL_SIZE + 4;
+        let mut v2 = encoded[..encoded.len() - playback].to_vec();
+        v2[4..6].copy_from_slice(&2u16.to_le_bytes());
+        v2.push(1);
+        v2.extend_from_slice(&1u32.to_le_bytes());
+        v2.extend_from_slice(&4u32.to_le_bytes());
+        v2.extend_from_slice(&(controls.len() as u32).to_le_bytes());
+        let first_tag = v2.len() + 4 + 4;
+        for (control, (tag, arg)) in controls.iter().zip([(3u8, 3u32), (2, 5)].iter()) {
+            v2.extend_from_slice(&control.frame_index.to_le_bytes());
+            v2.extend_from_slice(&control.seq.to_le_bytes());
+            v2.push(*tag);
+            v2.extend_from_slice(&arg.to_le_bytes());
+        }
+        let decoded = State::decode(&v2).unwrap();
+        assert_eq!(decoded.playback, state.playback);
+        assert_eq!(decoded.hash(), state.hash());
+
+        // the admin controls came later
+        v2[first_tag] = MAX_CONTROL_TAG_V2 + 1;
+        assert!(matches!(
+            State::decode(&v2),
+            Err(EncodingError::InvalidFlag(_))
+        ));
+    }
+
+    #[test]
+    fn hash_covers_pending_inputs_and_ids() {
+        let state = state();
+
+        let mut with_input = state.clone();
+        with_input.schedule(IndexedState {
+            frame_index: 20,
+            state: AddBodyEvent::new(0., 0., 1.),
+        });
+        assert_ne!(with_input.hash(), state.hash());
+
+        let mut renumbered = state.clone();
+        renumbered.simulation.set_next_id(1000);
+        let body = renumbered.simulation.bodies.remove(0);
+        renumbered.simulation.add_body(body);
+        let mut reordered = state.clone();
+        let body = reordered.simulation.bodies.remove(0);
+        reordered.simulation.bodies.push(body);
+        assert_ne!(renumbered.hash(), reordered.hash());
+    }
+
+    #[test]
+    fn pending_input_order_does_not_matter() {
+        let inputs = (0..5)
+            .map(|i| IndexedState {
+                frame_index: 3,
+                state: AddBodyEvent::new(i as f32, 0., 1.),
+            })
+            .collect::<Vec<_>>();
+        let mut a = State::new();
+        let mut b = State::new();
+        for input in inputs.iter() {
+            a.schedule(*input);
+        }
+        for input in inputs.iter().rev() {
+            b.schedule(*input);
+        }
+        assert_eq!(a.encode(), b.encode());
+    }
+
+    #[test]
+    fn legacy_states_are_upgraded() {
+        let state = state();
+        let legacy = LegacyState {
+            simulation: LegacySimulation {
+                bodies: state
+                    .simulation
+                    .bodies
+                    .iter()
+                    .map(|body| LegacyBody {
+                        collided: body.collided(),
+                        position: body.position,
+                        velocity: body.velocity,
+                        acceleration: body.acceleration,
+                        mass: body.mass,
+                    })
+                    .collect(),
+            },
+            frame_index: state.frame_index,
+            input_buffer: state.input_buffer.clone(),
+        };
+
+        let decoded = State::decode(&bincode::serialize(&legacy).unwrap()).unwrap();
+        assert_eq!(decoded.frame_index, state.frame_index);
+        assert_eq!(
+            decoded.simulation.bodies.len(),
+            state.simulation.bodies.len()
+        );
+        assert_eq!(
+            decoded.simulation.next_id(),
+            decoded.simulation.bodies.len() as BodyId
+        );
+        assert_eq!(decoded.input_buffer.iter().count(), 1);
+        for (decoded, body) in decoded
+            .simulation
+            .bodies
+            .iter()
+            .zip(state.simulation.bodies.iter())
+        {
+            assert_eq!(decoded.position, body.position);
+            assert_eq!(decoded.mass, body.mass);
+        }
+    }
+
+    #[test]
+    fn invalid_encodings_are_rejected() {
+        let encoded = state().encode();
+
+        let mut future = encoded.clone();
+        future[4..6].copy_from_slice(&(STATE_ENCODING_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            State::decode(&future),
+            Err(EncodingError::UnsupportedVersion(_))
+        ));
+
+        let mut other_gravity = encoded.clone();
+        other_gravity[10..18].copy_from_slice(&0i64.to_le_bytes());
+        assert!(matches!(
+            State::decode(&other_gravity),
+            Err(EncodingError::ParameterMismatch {
+                name: "gravity",
+                ..
+            })
+        ));
+
+        assert!(matches!(
+            State::decode(&encoded[..encoded.len() - 1]),
+            Err(EncodingError::Truncated)
+        ));
+
+        let mut trailing = encoded;
+        trailing.push(0);
+        assert!(matches!(
+            State::decode(&trailing),
+            Err(EncodingError::TrailingBytes(1))
+        ));
+    }
+}
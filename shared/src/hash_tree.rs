@@ -0,0 +1,554 @@
+//! Hierarchical hashing of `State` for finding out exactly where two peers diverged.
+//!
+//! The root hash covers the frame index, a config hash (simulation constants, the next body
+//! id, pending inputs and playback) and a fixed number of body buckets. Each bucket hashes the bodies
+//! whose id falls into it, and each body hash covers all of its fields. Peers that disagree on
+//! the root compare buckets, then the body hashes in the differing buckets, then the fields of
+//! the differing bodies, so only a small part of the state ever needs to be sent.
+
+use super::{FrameIndex, Recv, Send, State};
+use nbody::{Body, BodyId};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
+
+pub const BUCKETS: u32 = 16;
+/// Keeps each request and response comfortably inside a single packet.
+pub const BUCKETS_PER_REQUEST: usize = 4;
+pub const BODIES_PER_REQUEST: usize = 8;
+
+fn hasher() -> twox_hash::XxHash64 {
+    twox_hash::XxHash64::with_seed(0)
+}
+
+pub fn bucket_of(id: BodyId) -> u32 {
+    (id % BUCKETS as BodyId) as u32
+}
+
+pub fn body_hash(body: &Body) -> u64 {
+    let mut hasher = hasher();
+    body.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn frame_hash(frame_index: FrameIndex) -> u64 {
+    let mut hasher = hasher();
+    frame_index.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn config_hash(state: &State) -> u64 {
+    let mut hasher = hasher();
+    nbody::GRAVITY.hash(&mut hasher);
+    nbody::DENSITY.hash(&mut hasher);
+    nbody::TICK.hash(&mut hasher);
+    state.simulation.next_id().hash(&mut hasher);
+
+    // the buffer is a heap so its iteration order isn't shared between peers
+    let mut inputs = state
+        .input_buffer
+        .iter()
+        .map(|input| {
+            let mut hasher = self::hasher();
+            input.hash(&mut hasher);
+            (input.frame_index, hasher.finish())
+        })
+        .collect::<Vec<_>>();
+    inputs.sort_unstable();
+    inputs.hash(&mut hasher);
+    state.playback.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Body hashes for the given buckets, sorted by id.
+pub fn body_hashes(state: &State, buckets: &[u32]) -> Vec<(BodyId, u64)> {
+    let mut hashes = state
+        .simulation
+        .bodies
+        .iter()
+        .filter(|body| buckets.contains(&bucket_of(body.id())))
+        .map(|body| (body.id(), body_hash(body)))
+        .collect::<Vec<_>>();
+    hashes.sort_unstable();
+    hashes
+}
+
+/// The bodies with the given ids, in id order. Unknown ids are skipped.
+pub fn bodies(state: &State, ids: &[BodyId]) -> Vec<Body> {
+    let mut bodies = state
+        .simulation
+        .bodies
+        .iter()
+        .filter(|body| ids.contains(&body.id()))
+        .copied()
+        .collect::<Vec<_>>();
+    bodies.sort_unstable_by_key(Body::id);
+    bodies
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct HashTree {
+    pub frame_index: FrameIndex,
+    pub root: u64,
+    pub frame: u64,
+    pub config: u64,
+    pub buckets: Vec<u64>,
+}
+
+impl HashTree {
+    pub fn new(state: &State) -> Self {
+        let mut buckets = (0..BUCKETS).map(|_| Vec::new()).collect::<Vec<_>>();
+        for body in state.simulation.bodies.iter() {
+            buckets[bucket_of(body.id()) as usize].push((body.id(), body_hash(body)));
+        }
+        let buckets = buckets
+            .into_iter()
+            .map(|mut bodies| {
+                bodies.sort_unstable();
+                let mut hasher = hasher();
+                bodies.hash(&mut hasher);
+                hasher.finish()
+            })
+            .collect::<Vec<_>>();
+
+        let frame = frame_hash(state.frame_index);
+        let config = config_hash(state);
+        let mut hasher = hasher();
+        frame.hash(&mut hasher);
+        config.hash(&mut hasher);
+        buckets.hash(&mut hasher);
+        Self {
+            frame_index: state.frame_index,
+            root: hasher.finish(),
+            frame,
+            config,
+            buckets,
+        }
+    }
+
+    pub fn differing_buckets(&self, other: &HashTree) -> Vec<u32> {
+        (0..BUCKETS)
+            .filter(|bucket| {
+                self.buckets.get(*bucket as usize) != other.buckets.get(*bucket as usize)
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct FieldDivergence {
+    pub field: String,
+    pub local: String,
+    pub remote: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct BodyDivergence {
+    pub id: BodyId,
+    pub fields: Vec<FieldDivergence>,
+}
+
+impl BodyDivergence {
+    fn new(local: &Body, remote: &Body) -> Self {
+        let fields = vec![
+            ("position.x", local.position.x, remote.position.x),
+            ("position.y", local.position.y, remote.position.y),
+            ("velocity.x", local.velocity.x, remote.velocity.x),
+            ("velocity.y", local.velocity.y, remote.velocity.y),
+            (
+                "acceleration.x",
+                local.acceleration.x,
+                remote.acceleration.x,
+            ),
+            (
+                "acceleration.y",
+                local.acceleration.y,
+                remote.acceleration.y,
+            ),
+            ("mass", local.mass, remote.mass),
+        ];
+        Self {
+            id: local.id(),
+            fields: fields
+                .into_iter()
+                .filter(|(_, local, remote)| local != remote)
+                .map(|(field, local, remote)| FieldDivergence {
+                    field: field.to_owned(),
+                    local: local.to_string(),
+                    remote: remote.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Everything a diagnosis found out about how a local state differs from the remote one.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct DesyncReport {
+    pub frame_index: FrameIndex,
+    pub local_root: u64,
+    pub remote_root: Option<u64>,
+    pub frame_mismatch: bool,
+    pub config_mismatch: bool,
+    pub buckets: Vec<u32>,
+    /// Bodies the remote has that we don't.
+    pub missing: Vec<BodyId>,
+    /// Bodies we have that the remote doesn't.
+    pub extra: Vec<BodyId>,
+    pub bodies: Vec<BodyDivergence>,
+    /// False if the remote could no longer provide the frame.
+    pub complete: bool,
+}
+
+impl std::fmt::Display for DesyncReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "desync at frame {}: local root {:016x}, remote root ",
+            self.frame_index, self.local_root
+        )?;
+        match self.remote_root {
+            Some(root) => writeln!(f, "{:016x}", root)?,
+            None => writeln!(f, "unknown")?,
+        }
+        if !self.complete {
+            writeln!(f, "  incomplete: the remote no longer has this frame")?;
+        }
+        if self.frame_mismatch {
+            writeln!(f, "  frame index differs")?;
+        }
+        if self.config_mismatch {
+            writeln!(
+                f,
+                "  config differs (constants, next body id, pending inputs or playback)"
+            )?;
+        }
+        if !self.buckets.is_empty() {
+            writeln!(f, "  differing buckets: {:?}", self.buckets)?;
+        }
+        if !self.missing.is_empty() {
+            writeln!(f, "  missing bodies: {:?}", self.missing)?;
+        }
+        if !self.extra.is_empty() {
+            writeln!(f, "  extra bodies: {:?}", self.extra)?;
+        }
+        for body in self.bodies.iter() {
+            writeln!(f, "  body {}:", body.id)?;
+            for field in body.fields.iter() {
+                writeln!(
+                    f,
+                    "    {}: local {} remote {}",
+                    field.field, field.local, field.remote
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Stage {
+    HashTree,
+    BodyHashes(Vec<u32>),
+    Bodies(Vec<BodyId>),
+    Done,
+}
+
+/// Drives the drill down against a remote from a copy of the local state at the frame that
+/// failed to match. It doesn't do any IO: send `request` until `on_message` accepts a
+/// response, and repeat until `report` returns something.
+#[derive(Clone, Debug)]
+pub struct Diagnosis {
+    local: State,
+    stage: Stage,
+    report: DesyncReport,
+}
+
+impl Diagnosis {
+    pub fn new(local: State) -> Self {
+        let report = DesyncReport {
+            frame_index: local.frame_index,
+            local_root: HashTree::new(&local).root,
+            ..Default::default()
+        };
+        Self {
+            local,
+            stage: Stage::HashTree,
+            report,
+        }
+    }
+
+    pub fn frame_index(&self) -> FrameIndex {
+        self.local.frame_index
+    }
+
+    /// The request that the next expected response answers. `None` once finished.
+    pub fn request(&self) -> Option<Send> {
+        let frame_index = self.frame_index();
+        match &self.stage {
+            Stage::HashTree => Some(Send::RequestHashTree { frame_index }),
+            Stage::BodyHashes(buckets) => Some(Send::RequestBodyHashes {
+                frame_index,
+                buckets: buckets.iter().take(BUCKETS_PER_REQUEST).copied().collect(),
+            }),
+            Stage::Bodies(ids) => Some(Send::RequestBodies {
+                frame_index,
+                ids: ids.iter().take(BODIES_PER_REQUEST).copied().collect(),
+            }),
+            Stage::Done => None,
+        }
+    }
+
+    /// Advances the diagnosis with a response from the remote. Returns false if the message
+    /// isn't the response being waited on, e.g. a duplicate or one for another frame.
+    pub fn on_message(&mut self, message: &Recv) -> bool {
+        let stage = std::mem::replace(&mut self.stage, Stage::Done);
+        let (stage, accepted) = match (stage, message) {
+            (_, Recv::FrameUnavailable(frame_index)) if *frame_index == self.frame_index() => {
+                (Stage::Done, true)
+            }
+            (Stage::HashTree, Recv::HashTree(remote))
+                if remote.frame_index == self.frame_index() =>
+            {
+                (self.on_hash_tree(remote), true)
+            }
+            (
+                Stage::BodyHashes(buckets),
+                Recv::BodyHashes {
+                    frame_index,
+                    buckets: answered,
+                    hashes,
+                },
+            ) if *frame_index == self.frame_index() && buckets.starts_with(answered) => {
+                (self.on_body_hashes(buckets, answered, hashes), true)
+            }
+            (
+                Stage::Bodies(ids),
+                Recv::Bodies {
+                    frame_index,
+                    ids: answered,
+                    bodies,
+                },
+            ) if *frame_index == self.frame_index() && ids.starts_with(answered) => {
+                (self.on_bodies(ids, answered, bodies), true)
+            }
+            (stage, _) => (stage, false),
+        };
+        self.stage = stage;
+        accepted
+    }
+
+    fn on_hash_tree(&mut self, remote: &HashTree) -> Stage {
+        let local = HashTree::new(&self.local);
+        self.report.remote_root = Some(remote.root);
+        self.report.frame_mismatch = local.frame != remote.frame;
+        self.report.config_mismatch = local.config != remote.config;
+        self.report.buckets = local.differing_buckets(remote);
+        self.next_body_hashes(self.report.buckets.clone())
+    }
+
+    fn next_body_hashes(&mut self, buckets: Vec<u32>) -> Stage {
+        if buckets.is_empty() {
+            let ids = self
+                .report
+                .bodies
+                .iter()
+                .map(|body| body.id)
+                .collect::<Vec<_>>();
+            self.report.bodies.clear();
+            self.next_bodies(ids)
+        } else {
+            Stage::BodyHashes(buckets)
+        }
+    }
+
+    fn on_body_hashes(
+        &mut self,
+        mut buckets: Vec<u32>,
+        answered: &[u32],
+        remote: &[(BodyId, u64)],
+    ) -> Stage {
+        let local = body_hashes(&self.local, answered)
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+        let remote = remote.iter().copied().collect::<BTreeMap<_, _>>();
+        let ids = local.keys().chain(remote.keys()).collect::<BTreeSet<_>>();
+        for id in ids {
+            match (local.get(id), remote.get(id)) {
+                (Some(_), None) => self.report.extra.push(*id),
+                (None, Some(_)) => self.report.missing.push(*id),
+                (Some(local), Some(remote)) if local != remote => {
+                    // collected here and replaced with the real divergence once fetched
+                    self.report.bodies.push(BodyDivergence {
+                        id: *id,
+                        fields: Vec::new(),
+                    })
+                }
+                _ => {}
+            }
+        }
+        buckets.drain(..answered.len());
+        self.next_body_hashes(buckets)
+    }
+
+    fn next_bodies(&mut self, ids: Vec<BodyId>) -> Stage {
+        if ids.is_empty() {
+            self.report.complete = true;
+            Stage::Done
+        } else {
+            Stage::Bodies(ids)
+        }
+    }
+
+    fn on_bodies(&mut self, mut ids: Vec<BodyId>, answered: &[BodyId], remote: &[Body]) -> Stage {
+        let local = bodies(&self.local, answered);
+        for remote in remote.iter() {
+            if let Some(local) = local.iter().find(|body| body.id() == remote.id()) {
+                self.report.bodies.push(BodyDivergence::new(local, remote));
+            }
+        }
+        ids.drain(..answered.len());
+        self.next_bodies(ids)
+    }
+
+    /// The finished report. Incomplete if the remote couldn't provide the frame.
+    pub fn report(&self) -> Option<&DesyncReport> {
+        match self.stage {
+            Stage::Done => Some(&self.report),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AddBodyEvent, IndexedState};
+
+    fn state() -> State {
+        let mut state = State::new();
+        for i in 0..40 {
+            state.schedule(IndexedState {
+                frame_index: 0,
+                state: AddBodyEvent::new(i as f32 * 100., (i % 7) as f32 * 100., 5.),
+            });
+        }
+        for _ in 0..10 {
+            state.step();
+        }
+        state
+    }
+
+    /// Answers requests the way the server does.
+    fn respond(remote: &State, request: Send) -> Recv {
+        match request {
+            Send::RequestHashTree { .. } => Recv::HashTree(HashTree::new(remote)),
+            Send::RequestBodyHashes {
+                frame_index,
+                buckets,
+            } => Recv::BodyHashes {
+                frame_index,
+                hashes: body_hashes(remote, &buckets),
+                buckets,
+            },
+            Send::RequestBodies { frame_index, ids } => Recv::Bodies {
+                frame_index,
+                bodies: bodies(remote, &ids),
+                ids,
+            },
+            request => panic!("unexpected request {:?}", request),
+        }
+    }
+
+    fn diagnose(local: State, remote: &State) -> DesyncReport {
+        let mut diagnosis = Diagnosis::new(local);
+        while let Some(request) = diagnosis.request() {
+            assert!(diagnosis.on_message(&respond(remote, request)));
+        }
+        diagnosis.report().unwrap().clone()
+    }
+
+    #[test]
+    fn identical_states() {
+        let state = state();
+        assert_eq!(HashTree::new(&state), HashTree::new(&state.clone()));
+
+        let report = diagnose(state.clone(), &state);
+        assert!(report.complete);
+        assert_eq!(report.remote_root, Some(report.local_root));
+        assert!(report.buckets.is_empty());
+        assert!(report.bodies.is_empty());
+    }
+
+    #[test]
+    fn finds_differing_body_and_field() {
+        let remote = state();
+        let mut local = remote.clone();
+        let id = local.simulation.bodies[3].id();
+        local.simulation.bodies[3].velocity.y += nbody::Float::from_bits(1);
+
+        let report = diagnose(local, &remote);
+        assert!(report.complete);
+        assert!(!report.config_mismatch);
+        assert_eq!(report.buckets, vec![bucket_of(id)]);
+        assert_eq!(report.bodies.len(), 1);
+        assert_eq!(report.bodies[0].id, id);
+        assert_eq!(report.bodies[0].fields.len(), 1);
+        assert_eq!(report.bodies[0].fields[0].field, "velocity.y");
+    }
+
+    #[test]
+    fn finds_missing_extra_and_many_changed_bodies() {
+        let remote = state();
+        let mut local = remote.clone();
+        let removed = local.simulation.bodies.remove(0).id();
+        for body in local.simulation.bodies.iter_mut() {
+            body.mass += nbody::Float::from_num(1);
+        }
+        local
+            .simulation
+            .add_body(nbody::Body::new_lossy(0., 0., 1.));
+        let added = local.simulation.bodies.last().unwrap().id();
+
+        let report = diagnose(local, &remote);
+        assert!(report.complete);
+        assert!(report.config_mismatch);
+        assert_eq!(report.buckets.len(), BUCKETS as usize);
+        assert_eq!(report.missing, vec![removed]);
+        assert_eq!(report.extra, vec![added]);
+        assert_eq!(report.bodies.len(), remote.simulation.bodies.len() - 1);
+        assert!(report
+            .bodies
+            .iter()
+            .all(|body| body.fields.iter().any(|field| field.field == "mass")));
+    }
+
+    #[test]
+    fn pending_input_order_does_not_matter() {
+        let mut a = State::new();
+        let mut b = State::new();
+        let first = IndexedState {
+            frame_index: 5,
+            state: AddBodyEvent::new(1., 2., 3.),
+        };
+        let second = IndexedState {
+            frame_index: 5,
+            state: AddBodyEvent::new(4., 5., 6.),
+        };
+        a.schedule(first);
+        a.schedule(second);
+        b.schedule(second);
+        b.schedule(first);
+        assert_eq!(HashTree::new(&a), HashTree::new(&b));
+    }
+
+    #[test]
+    fn unavailable_frame_ends_incomplete() {
+        let mut diagnosis = Diagnosis::new(state());
+        assert!(!diagnosis.on_message(&Recv::FrameUnavailable(0)));
+        assert!(diagnosis.on_message(&Recv::FrameUnavailable(10)));
+        let report = diagnosis.report().unwrap();
+        assert!(!report.complete);
+        assert_eq!(report.remote_root, None);
+    }
+}
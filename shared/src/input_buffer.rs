@@ -3,22 +3,29 @@ use serde::{Deserialize, Serialize};
 pub const INPUT_BUFFER_FRAMES: super::FrameIndex = 7;
 type Input = super::IndexedState<super::AddBodyEvent>;
 
+/// Ordered so that the earliest frame sits at the top of the (max) heap. Inputs on the same
+/// frame are ordered by their contents so that every peer applies them in the same order no
+/// matter when they arrived.
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 struct OrderedInput(Input);
 impl std::cmp::PartialEq for OrderedInput {
     fn eq(&self, other: &Self) -> bool {
-        self.0.frame_index.eq(&other.0.frame_index)
+        self.cmp(other) == std::cmp::Ordering::Equal
     }
 }
 impl std::cmp::Eq for OrderedInput {}
 impl std::cmp::PartialOrd for OrderedInput {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.0.frame_index.partial_cmp(&other.0.frame_index)
+        Some(self.cmp(other))
     }
 }
 impl std::cmp::Ord for OrderedInput {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.0.frame_index.cmp(&other.0.frame_index)
+        other
+            .0
+            .frame_index
+            .cmp(&self.0.frame_index)
+            .then_with(|| other.0.state.sort_key().cmp(&self.0.state.sort_key()))
     }
 }
 impl std::hash::Hash for OrderedInput {
@@ -48,4 +55,96 @@ impl InputBuffer {
             }
         }
     }
+
+    /// Visits every buffered input in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &Input> {
+        self.0.iter().map(|input| &input.0)
+    }
+
+    /// Moves a buffered input to a different frame. Returns false if the input wasn't found.
+    pub fn reschedule(&mut self, input: &Input, frame_index: super::FrameIndex) -> bool {
+        let mut inputs = std::mem::take(&mut self.0).into_vec();
+        let found = match inputs.iter_mut().find(|other| other.0 == *input) {
+            None => false,
+            Some(other) => {
+                other.0.frame_index = frame_index;
+                true
+            }
+        };
+        self.0 = inputs.into();
+        found
+    }
+
+    /// Drops a buffered input. Returns false if the input wasn't found.
+    pub fn remove(&mut self, input: &Input) -> bool {
+        let mut inputs = std::mem::take(&mut self.0).into_vec();
+        let len = inputs.len();
+        inputs.retain(|other| other.0 != *input);
+        let found = inputs.len() != len;
+        self.0 = inputs.into();
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(frame_index: super::super::FrameIndex, mass: f32) -> Input {
+        Input {
+            frame_index,
+            state: super::super::AddBodyEvent::new(0., 0., mass),
+        }
+    }
+
+    #[test]
+    fn pops_earliest_first() {
+        let mut buffer = InputBuffer::default();
+        buffer.push(input(10, 1.));
+        buffer.push(input(5, 2.));
+
+        assert_eq!(buffer.next(4), None);
+        assert_eq!(buffer.next(5), Some(input(5, 2.)));
+        assert_eq!(buffer.next(9), None);
+        assert_eq!(buffer.next(10), Some(input(10, 1.)));
+    }
+
+    #[test]
+    fn reschedule() {
+        let mut buffer = InputBuffer::default();
+        buffer.push(input(5, 1.));
+        buffer.push(input(6, 2.));
+
+        assert!(buffer.reschedule(&input(5, 1.), 8));
+        assert!(!buffer.reschedule(&input(5, 1.), 9));
+        assert_eq!(buffer.next(7), Some(input(6, 2.)));
+        assert_eq!(buffer.next(7), None);
+        assert_eq!(buffer.next(8), Some(input(8, 1.)));
+    }
+
+    #[test]
+    fn remove() {
+        let mut buffer = InputBuffer::default();
+        buffer.push(input(5, 1.));
+        buffer.push(input(5, 2.));
+
+        assert!(buffer.remove(&input(5, 1.)));
+        assert!(!buffer.remove(&input(5, 1.)));
+        assert_eq!(buffer.next(5), Some(input(5, 2.)));
+        assert_eq!(buffer.next(5), None);
+    }
+
+    #[test]
+    fn same_frame_order_is_independent_of_arrival() {
+        let mut a = InputBuffer::default();
+        let mut b = InputBuffer::default();
+        for mass in 1..10 {
+            a.push(input(5, mass as f32));
+            b.push(input(5, 10. - mass as f32));
+        }
+
+        for _ in 1..10 {
+            assert_eq!(a.next(5), b.next(5));
+        }
+    }
 }
@@ -1,25 +1,246 @@
 pub extern crate nbody;
 
+pub mod clock;
+pub mod codec;
+pub mod control;
+mod encoding;
+pub mod hash_tree;
 mod input_buffer;
+pub mod limits;
+pub mod lockstep;
+pub mod netsim;
+pub mod packet;
+pub mod redundant;
+pub mod snapshot;
+pub mod timestep;
+pub mod validation;
+pub use encoding::{EncodingError, STATE_ENCODING_VERSION};
 pub use input_buffer::*;
 
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 
 pub type FrameIndex = u32;
+/// Chosen by the sending client to match an `InputAck` to the input it sent.
+pub type InputSequence = u32;
+/// Assigned by the server when a client's `Hello` is accepted.
+pub type PlayerId = u32;
+/// Handed out by the server's lobby for joining a room. See `Send::Enter`.
+pub type RoomTicket = u64;
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+/// Bumped once per release whose `Send` or `Recv` would break a peer from the previous one,
+/// however many changes went into it, so that only peers from different releases are rejected.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Longer names are truncated by the client and don't fit the `Hello` size budget.
+pub const MAX_CLIENT_NAME_LEN: usize = 64;
+/// The most bodies a decoded state or delta may hold. A full state with this many bodies and
+/// no pending inputs still fits the `FullState` size budget. Inputs that would add more are
+/// dropped when they're applied.
+pub const MAX_BODIES: usize = 900;
+/// The most pending inputs a decoded state or delta may hold.
+pub const MAX_PENDING_INPUTS: usize = 1 << 12;
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub enum Send {
-    Ping(FrameIndex),
-    InputState(IndexedState<AddBodyEvent>),
+    /// Must remain the first variant so that any version of the server can decode it.
+    Hello {
+        protocol_version: u32,
+        client_name: String,
+    },
+    /// `client_time` is in microseconds on the client's clock and is echoed in the `Pong`.
+    Ping { client_time: u64 },
+    InputState {
+        client_seq: InputSequence,
+        input: IndexedState<AddBodyEvent>,
+    },
+    /// Asks for the server's state `delay` frames before its latest, delta encoded against
+    /// `baseline` if the server still has that frame. Without a baseline, or if it's too old,
+    /// a `FullState` is sent. Only spectators ask for a delay, so they can carry on behind the
+    /// server instead of waiting for it to get `delay` frames ahead again.
+    RequestState {
+        baseline: Option<FrameIndex>,
+        delay: FrameIndex,
+    },
+    /// The following drill down into a frame whose hash didn't match. See `hash_tree`.
+    RequestHashTree { frame_index: FrameIndex },
+    RequestBodyHashes {
+        frame_index: FrameIndex,
+        buckets: Vec<u32>,
+    },
+    RequestBodies {
+        frame_index: FrameIndex,
+        ids: Vec<nbody::BodyId>,
+    },
+    /// Asks the server to schedule a control for everyone. See `control`. Resent until
+    /// acknowledged like `InputState`, so the server drops repeats of a `client_seq`. Controls
+    /// are numbered separately from inputs.
+    Control {
+        client_seq: InputSequence,
+        control: control::Control,
+    },
+    /// The handshake for spectators, used instead of `Hello`. Spectators receive everything
+    /// players do but can't send inputs or controls.
+    Spectate {
+        protocol_version: u32,
+        client_name: String,
+    },
+    /// Every input for frames up to and including `frame_index` has been sent. Only used in
+    /// strict lockstep, where it's sent every frame. See `lockstep`.
+    InputsComplete { frame_index: FrameIndex },
+    /// Puts the connection in the room the ticket was issued for. Sent before the handshake,
+    /// which without it happens in the server's default room.
+    Enter { ticket: RoomTicket },
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Recv {
-    Pong(FrameIndex),
+    /// Must remain the first variant so that any version of the client can decode it.
+    Welcome {
+        player_id: PlayerId,
+        tick_rate: u32,
+        input_delay: FrameIndex,
+        server_frame: FrameIndex,
+        sync_mode: lockstep::SyncMode,
+        /// The server's, so that inputs it would reject are never sent.
+        input_rules: validation::InputRules,
+    },
+    /// Must remain the second variant so that any version of the client can decode it.
+    Rejected(ConnectionRejected),
+    /// `server_time` is in microseconds since the server started and `server_frame` is the
+    /// latest frame it had simulated at that time. See `clock`.
+    Pong {
+        client_time: u64,
+        server_time: u64,
+        server_frame: FrameIndex,
+    },
     StateHash(IndexedState<u64>),
-    InputState(IndexedState<AddBodyEvent>),
+    /// Another player's input. `client_seq` is the sequence chosen by that player so the pair
+    /// can be used to discard duplicates.
+    InputState {
+        player_id: PlayerId,
+        client_seq: InputSequence,
+        input: IndexedState<AddBodyEvent>,
+    },
     FullState(State),
+    StateDelta(snapshot::StateDelta),
+    /// The frame the server actually scheduled an input for. This may be later than the frame
+    /// requested by the client if the input arrived after the server had passed that frame.
+    InputAck {
+        client_seq: InputSequence,
+        applied_frame: FrameIndex,
+    },
+    HashTree(hash_tree::HashTree),
+    BodyHashes {
+        frame_index: FrameIndex,
+        buckets: Vec<u32>,
+        hashes: Vec<(nbody::BodyId, u64)>,
+    },
+    Bodies {
+        frame_index: FrameIndex,
+        ids: Vec<nbody::BodyId>,
+        bodies: Vec<nbody::Body>,
+    },
+    /// The requested frame is no longer, or not yet, in the server's history.
+    FrameUnavailable(FrameIndex),
+    /// A control every peer applies on its frame. Sent redundantly, repeats are ignored.
+    Control(control::ScheduledControl),
+    /// The server dropped one of our inputs instead of scheduling it, so it should be removed
+    /// from our prediction.
+    InputRejected {
+        client_seq: InputSequence,
+        reason: InputRejected,
+    },
+    /// The reply to an accepted `Spectate`.
+    Spectating {
+        tick_rate: u32,
+        server_frame: FrameIndex,
+    },
+    /// The server swaps in a new simulation at the start of this frame. Peers stop before
+    /// simulating it and resync once the server has, rather than being sent the simulation.
+    /// Sent redundantly like `Control`.
+    Load {
+        frame_index: FrameIndex,
+    },
+    /// Like `InputRejected`, for one of our controls that the server won't schedule.
+    ControlRejected {
+        client_seq: InputSequence,
+        reason: ControlRejected,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub enum ConnectionRejected {
+    IncompatibleVersion {
+        server_version: u32,
+    },
+    /// Sent before disconnecting a player that held up a strict lockstep server for too long.
+    TimedOut {
+        frame_index: FrameIndex,
+    },
+    /// The ticket in `Enter` was never issued, has expired or was used by someone else.
+    InvalidTicket,
+    /// Disconnected by the server's admin.
+    Kicked,
+}
+
+impl std::fmt::Display for ConnectionRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionRejected::IncompatibleVersion { server_version } => write!(
+                f,
+                "incompatible protocol version. client: {}, server: {}",
+                PROTOCOL_VERSION, server_version
+            ),
+            ConnectionRejected::TimedOut { frame_index } => write!(
+                f,
+                "disconnected for holding up frame {} for too long",
+                frame_index
+            ),
+            ConnectionRejected::InvalidTicket => write!(f, "invalid or expired room ticket"),
+            ConnectionRejected::Kicked => write!(f, "kicked by an admin"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub enum InputRejected {
+    TooManyPerFrame,
+    RateLimited,
+    MassBudgetExceeded,
+    MassOutOfRange,
+    TooFarFromCenter,
+    TooFast,
+    TooClose,
+}
+
+impl std::fmt::Display for InputRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputRejected::TooManyPerFrame => write!(f, "too many inputs in one frame"),
+            InputRejected::RateLimited => write!(f, "inputs sent too quickly"),
+            InputRejected::MassBudgetExceeded => write!(f, "too much mass added recently"),
+            InputRejected::MassOutOfRange => write!(f, "mass out of range"),
+            InputRejected::TooFarFromCenter => write!(f, "too far from the center"),
+            InputRejected::TooFast => write!(f, "too fast"),
+            InputRejected::TooClose => write!(f, "too close to another body"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub enum ControlRejected {
+    RateLimited,
+    /// Only the admin API may schedule it, see `Control::is_admin_only`.
+    AdminOnly,
+}
+
+impl std::fmt::Display for ControlRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlRejected::RateLimited => write!(f, "controls sent too quickly"),
+            ControlRejected::AdminOnly => write!(f, "only an admin can do that"),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, Default, Hash, Serialize, Deserialize, Eq, PartialEq)]
@@ -57,6 +278,29 @@ impl AddBodyEvent {
             mass: nbody::Float::from_num(mass),
         }
     }
+
+    pub fn mass(&self) -> nbody::Float {
+        self.mass
+    }
+
+    /// The body this event adds, before the simulation assigns its id.
+    pub fn body(&self) -> nbody::Body {
+        let mut body = nbody::Body::new(self.position_x, self.position_y, self.mass);
+        body.velocity.x = self.velocity_x;
+        body.velocity.y = self.velocity_y;
+        body
+    }
+
+    /// A total order over events, used wherever peers must agree on an order.
+    fn sort_key(&self) -> [nbody::Float; 5] {
+        [
+            self.position_x,
+            self.position_y,
+            self.velocity_x,
+            self.velocity_y,
+            self.mass,
+        ]
+    }
 }
 
 #[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, Hash, Eq, PartialEq)]
@@ -65,17 +309,18 @@ pub struct IndexedState<T> {
     pub state: T,
 }
 
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+/// Serialized with the canonical encoding in `encoding`.
+#[derive(Clone, Debug, Default)]
 pub struct State {
     pub simulation: nbody::Simulation,
     pub frame_index: FrameIndex,
     pub input_buffer: InputBuffer,
+    pub playback: control::Playback,
 }
 
 impl std::hash::Hash for State {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.simulation.hash(state);
-        self.frame_index.hash(state);
+        state.write(&self.encode());
     }
 }
 
@@ -85,21 +330,49 @@ impl State {
             simulation: Default::default(),
             frame_index: 0,
             input_buffer: Default::default(),
+            playback: Default::default(),
         }
     }
 
+    /// Checksum of the canonical encoding.
     pub fn hash(&self) -> u64 {
         let mut hasher = twox_hash::XxHash64::with_seed(0);
         std::hash::Hash::hash(&self, &mut hasher);
         std::hash::Hasher::finish(&hasher)
     }
 
+    /// Queues an input, moving it to the current frame if that frame has already passed.
+    /// Returns the frame that the input will be applied on.
+    pub fn schedule(&mut self, mut input: IndexedState<AddBodyEvent>) -> FrameIndex {
+        if input.frame_index < self.frame_index {
+            log::debug!(
+                "rescheduling late input from frame {} to {}",
+                input.frame_index,
+                self.frame_index
+            );
+            input.frame_index = self.frame_index;
+        }
+        self.input_buffer.push(input);
+        input.frame_index
+    }
+
+    /// Queues a control scheduled by the server. Returns false if it's a repeat or its frame
+    /// has already passed, which is also how repeats of applied controls are recognised.
+    pub fn schedule_control(&mut self, control: control::ScheduledControl) -> bool {
+        control.frame_index >= self.frame_index && self.playback.schedule(control)
+    }
+
     fn handle_event(&mut self, event: AddBodyEvent) {
         log::trace!("handle_event @ {}: {:?}", self.frame_index, event);
-        let mut body = nbody::Body::new(event.position_x, event.position_y, event.mass);
-        body.velocity.x = event.velocity_x;
-        body.velocity.y = event.velocity_y;
-        self.simulation.add_body(body)
+        if self.simulation.bodies.len() >= MAX_BODIES {
+            log::debug!(
+                "dropping input on frame {}, there are already {} bodies",
+                self.frame_index,
+                MAX_BODIES
+            );
+            return;
+        }
+        self.simulation.add_body(event.body())
     }
 
     pub fn step(&mut self) {
@@ -114,7 +387,14 @@ impl State {
                 Ordering::Greater => break,
             }
         }
-        self.simulation.step();
+        for control in self.playback.apply_due(self.frame_index) {
+            if let control::Control::RemoveBody(id) = control {
+                self.simulation.remove_body(id);
+            }
+        }
+        for _ in 0..self.playback.steps() {
+            self.simulation.step();
+        }
         self.frame_index += 1;
     }
 }
@@ -132,17 +412,97 @@ mod tests {
         }
     }
 
+    #[test]
+    fn inputs_stop_adding_bodies_at_the_limit() {
+        let mut state = State::new();
+        for index in 0..MAX_BODIES {
+            state
+                .simulation
+                .add_body(nbody::Body::new_lossy(index as f32 * 100., 0., 1.));
+        }
+        state.schedule(IndexedState {
+            frame_index: 0,
+            state: AddBodyEvent::new(0., 5000., 1.),
+        });
+        state.step();
+        assert_eq!(state.simulation.bodies.len(), MAX_BODIES);
+    }
+
     #[test]
     fn serde_sanity() {
         let send_control = vec![
-            Send::Ping(6),
-            Send::InputState(IndexedState {
-                frame_index: 541093,
-                state: AddBodyEvent::new(272., 335., 802.6582641602),
-            }),
+            Send::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                client_name: "test".to_owned(),
+            },
+            Send::Ping { client_time: 6 },
+            Send::InputState {
+                client_seq: 3,
+                input: IndexedState {
+                    frame_index: 541093,
+                    state: AddBodyEvent::new(272., 335., 802.6582641602),
+                },
+            },
+            Send::RequestBodyHashes {
+                frame_index: 12,
+                buckets: vec![1, 5],
+            },
         ];
         let bin = bincode::serialize(&send_control).unwrap();
         let send: Vec<Send> = bincode::deserialize(&bin).unwrap();
         assert_eq!(send_control, send);
     }
+
+    #[test]
+    fn handshake_is_version_independent() {
+        use codec::{Codec, WireCodec};
+
+        // an older or newer peer must still be able to read the handshake messages
+        let mut hello = Vec::new();
+        WireCodec::default()
+            .encode(
+                &Send::Hello {
+                    protocol_version: PROTOCOL_VERSION + 1,
+                    client_name: "future".to_owned(),
+                },
+                &mut hello,
+            )
+            .unwrap();
+        assert_eq!(hello[0], 0);
+        assert_eq!(hello[1], PROTOCOL_VERSION as u8 + 1);
+
+        let mut rejected = Vec::new();
+        WireCodec::default()
+            .encode(
+                &Recv::Rejected(ConnectionRejected::IncompatibleVersion { server_version: 0 }),
+                &mut rejected,
+            )
+            .unwrap();
+        assert_eq!(rejected[0], 1);
+    }
+
+    #[test]
+    fn late_inputs_are_rescheduled() {
+        let mut state = State::new();
+        for _ in 0..10 {
+            state.step();
+        }
+
+        let late = IndexedState {
+            frame_index: 5,
+            state: AddBodyEvent::new(0., 0., 1.),
+        };
+        let early = IndexedState {
+            frame_index: 12,
+            state: AddBodyEvent::new(1000., 0., 1.),
+        };
+        assert_eq!(state.schedule(late), 10);
+        assert_eq!(state.schedule(early), 12);
+
+        state.step();
+        assert_eq!(state.simulation.bodies.len(), 1);
+        state.step();
+        state.step();
+        assert_eq!(state.simulation.bodies.len(), 2);
+    }
 }
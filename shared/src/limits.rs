@@ -0,0 +1,256 @@
+//! Per-player accounting of inputs on the server.
+//!
+//! Each player gets an `InputLimiter` that checks three things before an input is scheduled:
+//! how many inputs arrived on the current frame, a token bucket that bounds the sustained
+//! rate while allowing short bursts, and the total mass added over a sliding window. Time is
+//! measured in server frames so the limiter never reads a clock. Rejected inputs cost nothing.
+//!
+//! Controls get a `ControlLimiter`, a smaller token bucket of its own. Every peer in the room
+//! applies every control, so one player flooding them would fill the queue for everyone.
+
+use super::{FrameIndex, InputRejected};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct InputLimits {
+    /// Inputs accepted from one player on a single frame.
+    pub per_frame: u32,
+    /// Inputs that can be sent at once after a quiet period.
+    pub burst: u32,
+    /// Inputs regained per second once the burst is used up.
+    pub per_second: f64,
+    /// Total mass one player can add within `mass_window`.
+    pub mass_budget: f64,
+    /// In seconds.
+    pub mass_window: f64,
+}
+
+impl Default for InputLimits {
+    fn default() -> Self {
+        Self {
+            per_frame: 2,
+            burst: 10,
+            per_second: 4.,
+            mass_budget: 250_000.,
+            mass_window: 10.,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InputLimiter {
+    limits: InputLimits,
+    tick_rate: u32,
+    frame_index: FrameIndex,
+    this_frame: u32,
+    tokens: f64,
+    /// Accepted masses by the frame they arrived on, oldest first.
+    spent: VecDeque<(FrameIndex, f64)>,
+}
+
+impl InputLimiter {
+    pub fn new(limits: InputLimits, tick_rate: u32) -> Self {
+        Self {
+            limits,
+            tick_rate,
+            frame_index: 0,
+            this_frame: 0,
+            tokens: limits.burst as f64,
+            spent: VecDeque::new(),
+        }
+    }
+
+    /// The limits are per second, so they follow the room's tick rate when an admin changes it.
+    pub fn set_tick_rate(&mut self, tick_rate: u32) {
+        self.tick_rate = tick_rate;
+    }
+
+    /// Accounts for an input of `mass` arriving while the server is on `frame_index`, or says
+    /// why it can't be accepted.
+    pub fn check(&mut self, frame_index: FrameIndex, mass: f64) -> Result<(), InputRejected> {
+        let elapsed = frame_index.saturating_sub(self.frame_index);
+        if elapsed > 0 {
+            self.frame_index = frame_index;
+            self.this_frame = 0;
+            self.tokens = (self.tokens
+                + elapsed as f64 * self.limits.per_second / self.tick_rate as f64)
+                .min(self.limits.burst as f64);
+        }
+
+        let window = (self.limits.mass_window * self.tick_rate as f64) as FrameIndex;
+        while let Some((spent_at, _)) = self.spent.front() {
+            if spent_at.saturating_add(window) > frame_index {
+                break;
+            }
+            self.spent.pop_front();
+        }
+
+        if self.this_frame >= self.limits.per_frame {
+            return Err(InputRejected::TooManyPerFrame);
+        }
+        if self.tokens < 1. {
+            return Err(InputRejected::RateLimited);
+        }
+        let spent = self.spent.iter().map(|(_, mass)| mass).sum::<f64>();
+        if spent + mass > self.limits.mass_budget {
+            return Err(InputRejected::MassBudgetExceeded);
+        }
+
+        self.this_frame += 1;
+        self.tokens -= 1.;
+        self.spent.push_back((frame_index, mass));
+        Ok(())
+    }
+}
+
+/// Controls that can be sent at once after a quiet period.
+pub const CONTROL_BURST: u32 = 8;
+/// Controls regained per second once the burst is used up.
+pub const CONTROLS_PER_SECOND: f64 = 2.;
+
+#[derive(Clone, Debug)]
+pub struct ControlLimiter {
+    tick_rate: u32,
+    frame_index: FrameIndex,
+    tokens: f64,
+}
+
+impl ControlLimiter {
+    pub fn new(tick_rate: u32) -> Self {
+        Self {
+            tick_rate,
+            frame_index: 0,
+            tokens: CONTROL_BURST as f64,
+        }
+    }
+
+    /// See `InputLimiter::set_tick_rate`.
+    pub fn set_tick_rate(&mut self, tick_rate: u32) {
+        self.tick_rate = tick_rate;
+    }
+
+    /// Accounts for a control arriving while the server is on `frame_index`. Returns false if
+    /// the player has sent too many.
+    pub fn check(&mut self, frame_index: FrameIndex) -> bool {
+        let elapsed = frame_index.saturating_sub(self.frame_index);
+        self.frame_index = self.frame_index.max(frame_index);
+        self.tokens = (self.tokens + elapsed as f64 * CONTROLS_PER_SECOND / self.tick_rate as f64)
+            .min(CONTROL_BURST as f64);
+        if self.tokens < 1. {
+            return false;
+        }
+        self.tokens -= 1.;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK_RATE: u32 = 60;
+
+    fn limits() -> InputLimits {
+        InputLimits {
+            per_frame: 2,
+            burst: 4,
+            // a quarter of a token per frame
+            per_second: 15.,
+            mass_budget: 100.,
+            mass_window: 1.,
+        }
+    }
+
+    fn limiter() -> InputLimiter {
+        InputLimiter::new(limits(), TICK_RATE)
+    }
+
+    #[test]
+    fn per_frame() {
+        let mut limiter = limiter();
+        assert_eq!(limiter.check(5, 1.), Ok(()));
+        assert_eq!(limiter.check(5, 1.), Ok(()));
+        assert_eq!(limiter.check(5, 1.), Err(InputRejected::TooManyPerFrame));
+        assert_eq!(limiter.check(6, 1.), Ok(()));
+    }
+
+    #[test]
+    fn token_bucket() {
+        let limits = InputLimits {
+            per_frame: 100,
+            ..limits()
+        };
+        let mut limiter = InputLimiter::new(limits, TICK_RATE);
+        for _ in 0..4 {
+            assert_eq!(limiter.check(0, 1.), Ok(()));
+        }
+        assert_eq!(limiter.check(0, 1.), Err(InputRejected::RateLimited));
+        assert_eq!(limiter.check(3, 1.), Err(InputRejected::RateLimited));
+        assert_eq!(limiter.check(4, 1.), Ok(()));
+        assert_eq!(limiter.check(5, 1.), Err(InputRejected::RateLimited));
+
+        // a flood only ever gets the burst through
+        let mut limiter = InputLimiter::new(limits, TICK_RATE);
+        let accepted = (0..1000).filter(|_| limiter.check(100, 1.).is_ok()).count();
+        assert_eq!(accepted, 4);
+    }
+
+    #[test]
+    fn mass_budget() {
+        let mut limiter = limiter();
+        assert_eq!(limiter.check(0, 60.), Ok(()));
+        assert_eq!(
+            limiter.check(1, 50.),
+            Err(InputRejected::MassBudgetExceeded)
+        );
+        assert_eq!(limiter.check(1, 40.), Ok(()));
+        assert_eq!(
+            limiter.check(59, 1.),
+            Err(InputRejected::MassBudgetExceeded)
+        );
+        // the first input leaves the one second window
+        assert_eq!(limiter.check(60, 50.), Ok(()));
+    }
+
+    #[test]
+    fn controls() {
+        let mut limiter = ControlLimiter::new(TICK_RATE);
+        let accepted = (0..100).filter(|_| limiter.check(0)).count();
+        assert_eq!(accepted, CONTROL_BURST as usize);
+        // a token every half second
+        assert!(!limiter.check(15));
+        assert!(limiter.check(31));
+        assert!(!limiter.check(31));
+    }
+
+    #[test]
+    fn limits_follow_the_tick_rate() {
+        let limits = InputLimits {
+            per_frame: 100,
+            ..limits()
+        };
+        let mut limiter = InputLimiter::new(limits, TICK_RATE);
+        for _ in 0..4 {
+            assert_eq!(limiter.check(0, 1.), Ok(()));
+        }
+        // half a token per frame, and a window half as many frames long
+        limiter.set_tick_rate(TICK_RATE / 2);
+        assert_eq!(limiter.check(1, 1.), Err(InputRejected::RateLimited));
+        assert_eq!(limiter.check(2, 1.), Ok(()));
+        assert_eq!(
+            limiter.check(29, 96.),
+            Err(InputRejected::MassBudgetExceeded)
+        );
+        assert_eq!(limiter.check(30, 96.), Ok(()));
+
+        let mut limiter = ControlLimiter::new(TICK_RATE);
+        let accepted = (0..100).filter(|_| limiter.check(0)).count();
+        assert_eq!(accepted, CONTROL_BURST as usize);
+        // a token every fifteen frames
+        limiter.set_tick_rate(TICK_RATE / 2);
+        assert!(!limiter.check(14));
+        assert!(limiter.check(16));
+    }
+}
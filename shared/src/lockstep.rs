@@ -0,0 +1,276 @@
+//! Strict lockstep, where the server only simulates a frame once every player has finished
+//! sending inputs for it.
+//!
+//! In `SyncMode::Free` the server ticks on its own and late inputs are moved to a later frame.
+//! In `SyncMode::Strict` each player keeps sending `InputsComplete` with the last frame it
+//! will never send another input for, which is the explicit empty input for frames without
+//! any. `Lockstep` tracks this on the server, notices when the simulation is stalled waiting
+//! on players and reports the ones that held it up for too long so they can be disconnected.
+//! Like `InputLimiter`, time is counted in server ticks rather than read from a clock.
+
+use super::{FrameIndex, PlayerId, INPUT_BUFFER_FRAMES};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMode {
+    #[default]
+    Free,
+    Strict,
+}
+
+impl std::str::FromStr for SyncMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "free" => Ok(SyncMode::Free),
+            "strict" => Ok(SyncMode::Strict),
+            _ => Err(format!(
+                "unknown sync mode {:?}, expected free or strict",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct LockstepConfig {
+    /// In seconds. Waiting this long on a single frame is reported as a stall.
+    pub stall_after: f64,
+    /// In seconds. A player that holds up the simulation for this long is disconnected.
+    pub player_timeout: f64,
+}
+
+impl Default for LockstepConfig {
+    fn default() -> Self {
+        Self {
+            stall_after: 0.25,
+            player_timeout: 10.,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Tick {
+    /// Every player has completed the frame, after `waited` ticks.
+    Advance { waited: u32 },
+    /// The frame can't be simulated yet. Players in `timed_out` have been removed and aren't
+    /// included in `waiting_on`.
+    Wait {
+        waited: u32,
+        waiting_on: Vec<PlayerId>,
+        timed_out: Vec<PlayerId>,
+    },
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Progress {
+    /// No more inputs will be sent for this frame or any before it.
+    completed: FrameIndex,
+    /// Ticks this player has held up the simulation for since it last kept up.
+    waited: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Lockstep {
+    config: LockstepConfig,
+    tick_rate: u32,
+    players: BTreeMap<PlayerId, Progress>,
+    /// Ticks spent waiting on the current frame.
+    waited: u32,
+}
+
+impl Lockstep {
+    pub fn new(config: LockstepConfig, tick_rate: u32) -> Self {
+        Self {
+            config,
+            tick_rate,
+            players: BTreeMap::new(),
+            waited: 0,
+        }
+    }
+
+    /// Stall and time out limits are in seconds, so they follow the room's tick rate.
+    pub fn set_tick_rate(&mut self, tick_rate: u32) {
+        self.tick_rate = tick_rate;
+    }
+
+    /// Starts waiting on a player that joined while the server was on `frame_index`. Nothing
+    /// from the player can be scheduled before the input delay has passed, so the frames
+    /// before that are already complete. Joining again keeps the player's progress.
+    pub fn join(&mut self, player_id: PlayerId, frame_index: FrameIndex) {
+        self.players.entry(player_id).or_insert(Progress {
+            completed: frame_index + INPUT_BUFFER_FRAMES - 1,
+            waited: 0,
+        });
+    }
+
+    pub fn leave(&mut self, player_id: PlayerId) {
+        self.players.remove(&player_id);
+    }
+
+    /// Records that the player has sent every input for frames up to `frame_index`. These
+    /// are resent every frame so they may arrive out of order.
+    pub fn complete(&mut self, player_id: PlayerId, frame_index: FrameIndex) {
+        if let Some(progress) = self.players.get_mut(&player_id) {
+            progress.completed = progress.completed.max(frame_index);
+        }
+    }
+
+    /// The players that haven't completed `frame_index` yet.
+    pub fn waiting_on(&self, frame_index: FrameIndex) -> Vec<PlayerId> {
+        self.players
+            .iter()
+            .filter(|(_, progress)| progress.completed < frame_index)
+            .map(|(player_id, _)| *player_id)
+            .collect()
+    }
+
+    /// How many ticks spent waiting on one frame count as a stall.
+    pub fn stall_ticks(&self) -> u32 {
+        self.seconds_to_ticks(self.config.stall_after)
+    }
+
+    /// Called on every server tick before simulating `frame_index`.
+    pub fn tick(&mut self, frame_index: FrameIndex) -> Tick {
+        let timeout = self.seconds_to_ticks(self.config.player_timeout);
+        let mut timed_out = Vec::new();
+        for (player_id, progress) in self.players.iter_mut() {
+            if progress.completed >= frame_index {
+                progress.waited = 0;
+                continue;
+            }
+            progress.waited += 1;
+            if progress.waited >= timeout {
+                timed_out.push(*player_id);
+            }
+        }
+        for player_id in timed_out.iter() {
+            self.players.remove(player_id);
+        }
+
+        let waiting_on = self.waiting_on(frame_index);
+        if waiting_on.is_empty() && timed_out.is_empty() {
+            Tick::Advance {
+                waited: std::mem::take(&mut self.waited),
+            }
+        } else {
+            self.waited += 1;
+            Tick::Wait {
+                waited: self.waited,
+                waiting_on,
+                timed_out,
+            }
+        }
+    }
+
+    fn seconds_to_ticks(&self, seconds: f64) -> u32 {
+        ((seconds * self.tick_rate as f64).ceil() as u32).max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK_RATE: u32 = 10;
+
+    fn lockstep() -> Lockstep {
+        let config = LockstepConfig {
+            stall_after: 0.2,
+            player_timeout: 1.,
+        };
+        Lockstep::new(config, TICK_RATE)
+    }
+
+    #[test]
+    fn waits_for_every_player() {
+        let mut lockstep = lockstep();
+        assert_eq!(lockstep.tick(0), Tick::Advance { waited: 0 });
+
+        lockstep.join(1, 0);
+        lockstep.join(2, 0);
+        // the input delay is already complete
+        for frame_index in 0..INPUT_BUFFER_FRAMES {
+            assert_eq!(lockstep.tick(frame_index), Tick::Advance { waited: 0 });
+        }
+
+        let frame_index = INPUT_BUFFER_FRAMES;
+        lockstep.complete(1, frame_index + 5);
+        assert_eq!(
+            lockstep.tick(frame_index),
+            Tick::Wait {
+                waited: 1,
+                waiting_on: vec![2],
+                timed_out: vec![],
+            }
+        );
+        assert_eq!(
+            lockstep.tick(frame_index),
+            Tick::Wait {
+                waited: 2,
+                waiting_on: vec![2],
+                timed_out: vec![],
+            }
+        );
+        // an older completion arriving late changes nothing
+        lockstep.complete(2, frame_index);
+        lockstep.complete(2, frame_index - 1);
+        assert_eq!(lockstep.tick(frame_index), Tick::Advance { waited: 2 });
+        assert_eq!(
+            lockstep.tick(frame_index + 1),
+            Tick::Wait {
+                waited: 1,
+                waiting_on: vec![2],
+                timed_out: vec![],
+            }
+        );
+
+        lockstep.leave(2);
+        assert_eq!(lockstep.tick(frame_index + 1), Tick::Advance { waited: 1 });
+        assert_eq!(lockstep.stall_ticks(), 2);
+    }
+
+    #[test]
+    fn players_time_out() {
+        let mut lockstep = lockstep();
+        lockstep.join(1, 0);
+        lockstep.join(2, 0);
+        lockstep.join(3, 0);
+        let frame_index = INPUT_BUFFER_FRAMES;
+        lockstep.complete(1, frame_index);
+
+        for waited in 1..TICK_RATE {
+            let waiting_on = if waited <= 5 { vec![2, 3] } else { vec![2] };
+            assert_eq!(
+                lockstep.tick(frame_index),
+                Tick::Wait {
+                    waited,
+                    waiting_on,
+                    timed_out: vec![],
+                }
+            );
+            // player 3 catches up before its timeout
+            if waited == 5 {
+                lockstep.complete(3, frame_index);
+            }
+        }
+        assert_eq!(
+            lockstep.tick(frame_index),
+            Tick::Wait {
+                waited: TICK_RATE,
+                waiting_on: vec![],
+                timed_out: vec![2],
+            }
+        );
+        assert_eq!(
+            lockstep.tick(frame_index),
+            Tick::Advance { waited: TICK_RATE }
+        );
+        assert!(lockstep.waiting_on(frame_index + 1).contains(&1));
+        assert!(!lockstep.waiting_on(frame_index + 1).contains(&2));
+    }
+}
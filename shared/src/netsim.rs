@@ -0,0 +1,323 @@
+//! Simulated network conditions, for reproducing what players see on bad connections.
+//!
+//! A `Link` carries datagrams in one direction. Each one that's sent is dropped, delayed,
+//! duplicated or held back according to its `NetworkConditions`, then comes out of `poll` once
+//! it's due. A bandwidth cap makes datagrams wait for the ones ahead of them, and drops them
+//! once too many are waiting. Every choice comes from a seeded generator, so the same seed and
+//! the same sends give the same deliveries.
+//!
+//! Like `clock`, nothing here reads a clock. Times are durations since an arbitrary epoch.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct NetworkConditions {
+    /// The least time a datagram takes, in seconds.
+    pub latency: f64,
+    /// Up to this much more, in seconds, chosen at random for each datagram. Enough jitter
+    /// reorders datagrams by itself.
+    pub jitter: f64,
+    /// The chance of dropping each datagram, from 0 to 1.
+    pub loss: f64,
+    /// The chance of delivering a datagram twice.
+    pub duplicate: f64,
+    /// The chance of holding a datagram back by `reorder_delay` so that later ones overtake it.
+    pub reorder: f64,
+    /// In seconds.
+    pub reorder_delay: f64,
+    /// In bytes per second. Unlimited if 0.
+    pub bandwidth: u64,
+    /// Datagrams are dropped once the ones waiting for bandwidth would take longer than this
+    /// to send, in seconds.
+    pub max_queue: f64,
+    pub seed: u64,
+}
+
+impl Default for NetworkConditions {
+    /// A perfect network.
+    fn default() -> Self {
+        Self {
+            latency: 0.,
+            jitter: 0.,
+            loss: 0.,
+            duplicate: 0.,
+            reorder: 0.,
+            reorder_delay: 0.05,
+            bandwidth: 0,
+            max_queue: 0.5,
+            seed: 0,
+        }
+    }
+}
+
+impl NetworkConditions {
+    /// Whether datagrams get through unchanged and straight away.
+    pub fn is_perfect(&self) -> bool {
+        self.latency == 0.
+            && self.jitter == 0.
+            && self.loss == 0.
+            && self.duplicate == 0.
+            && self.reorder == 0.
+            && self.bandwidth == 0
+    }
+
+    /// Why the conditions don't make sense, if they don't.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let probabilities = [self.loss, self.duplicate, self.reorder];
+        if !probabilities.iter().all(|p| (0. ..=1.).contains(p)) {
+            return Err("loss, duplicate and reorder must be between 0 and 1");
+        }
+        let times = [
+            self.latency,
+            self.jitter,
+            self.reorder_delay,
+            self.max_queue,
+        ];
+        if !times.iter().all(|t| t.is_finite() && *t >= 0.) {
+            return Err("latency, jitter, reorder_delay and max_queue can't be negative");
+        }
+        Ok(())
+    }
+
+    /// The same conditions with a different seed, for links that shouldn't make the same
+    /// choices as each other.
+    pub fn with_seed(self, seed: u64) -> Self {
+        Self { seed, ..self }
+    }
+}
+
+/// What a link has done with the datagrams sent over it.
+#[derive(Copy, Clone, Debug, Default, Serialize, PartialEq)]
+pub struct LinkStats {
+    pub sent: u64,
+    pub delivered: u64,
+    pub lost: u64,
+    /// Dropped because too many were waiting for bandwidth.
+    pub overflowed: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+/// SplitMix64. Not for anything that needs to be unpredictable.
+#[derive(Clone, Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0. && self.next_f64() < probability
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Link<T> {
+    conditions: NetworkConditions,
+    rng: Rng,
+    /// When the datagrams already waiting for bandwidth will have been sent.
+    busy_until: Duration,
+    /// By when each is due, then the order they were sent in.
+    in_flight: BTreeMap<(Duration, u64), T>,
+    next_seq: u64,
+    stats: LinkStats,
+}
+
+impl<T: Clone> Link<T> {
+    pub fn new(conditions: NetworkConditions) -> Self {
+        Self {
+            conditions,
+            rng: Rng(conditions.seed),
+            busy_until: Duration::default(),
+            in_flight: BTreeMap::new(),
+            next_seq: 0,
+            stats: LinkStats::default(),
+        }
+    }
+
+    /// Sends a datagram that's `len` bytes long.
+    pub fn send(&mut self, now: Duration, len: usize, datagram: T) {
+        let conditions = self.conditions;
+        self.stats.sent += 1;
+        if self.rng.chance(conditions.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+
+        let departs = if conditions.bandwidth > 0 {
+            let starts = self.busy_until.max(now);
+            if starts - now > Duration::from_secs_f64(conditions.max_queue) {
+                self.stats.overflowed += 1;
+                return;
+            }
+            let transmit = len as f64 / conditions.bandwidth as f64;
+            self.busy_until = starts + Duration::from_secs_f64(transmit);
+            self.busy_until
+        } else {
+            now
+        };
+
+        let copies = if self.rng.chance(conditions.duplicate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut delay = conditions.latency + conditions.jitter * self.rng.next_f64();
+            if self.rng.chance(conditions.reorder) {
+                self.stats.reordered += 1;
+                delay += conditions.reorder_delay;
+            }
+            let due = departs + Duration::from_secs_f64(delay);
+            self.in_flight
+                .insert((due, self.next_seq), datagram.clone());
+            self.next_seq += 1;
+        }
+    }
+
+    /// The next datagram that's due by `now`, in the order they're due.
+    pub fn poll(&mut self, now: Duration) -> Option<T> {
+        let key = *self.in_flight.keys().next()?;
+        if key.0 > now {
+            return None;
+        }
+        self.stats.delivered += 1;
+        self.in_flight.remove(&key)
+    }
+
+    /// When the next datagram is due, if any are on their way.
+    pub fn next_due(&self) -> Option<Duration> {
+        self.in_flight.keys().next().map(|(due, _)| *due)
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    pub fn conditions(&self) -> &NetworkConditions {
+        &self.conditions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn drain(link: &mut Link<u32>, now: Duration) -> Vec<u32> {
+        std::iter::from_fn(|| link.poll(now)).collect()
+    }
+
+    #[test]
+    fn perfect_links_deliver_in_order_straight_away() {
+        let mut link = Link::new(NetworkConditions::default());
+        for i in 0..10 {
+            link.send(ms(5), 100, i);
+        }
+        assert_eq!(drain(&mut link, ms(5)), (0..10).collect::<Vec<_>>());
+        assert_eq!(link.next_due(), None);
+    }
+
+    #[test]
+    fn latency_delays_until_due() {
+        let mut link = Link::new(NetworkConditions {
+            latency: 0.1,
+            ..Default::default()
+        });
+        link.send(ms(0), 100, 1);
+        link.send(ms(10), 100, 2);
+        assert_eq!(link.next_due(), Some(ms(100)));
+        assert!(drain(&mut link, ms(99)).is_empty());
+        assert_eq!(drain(&mut link, ms(100)), vec![1]);
+        assert_eq!(drain(&mut link, ms(200)), vec![2]);
+    }
+
+    #[test]
+    fn same_seed_same_deliveries() {
+        let conditions = NetworkConditions {
+            latency: 0.02,
+            jitter: 0.05,
+            loss: 0.1,
+            duplicate: 0.05,
+            reorder: 0.05,
+            seed: 7,
+            ..Default::default()
+        };
+        let run = |conditions| {
+            let mut link = Link::new(conditions);
+            let mut delivered = Vec::new();
+            for i in 0..1000 {
+                link.send(ms(i as u64), 100, i);
+                delivered.extend(drain(&mut link, ms(i as u64)));
+            }
+            delivered.extend(drain(&mut link, ms(10_000)));
+            delivered
+        };
+        assert_eq!(run(conditions), run(conditions));
+        assert_ne!(run(conditions), run(conditions.with_seed(8)));
+    }
+
+    #[test]
+    fn loss_duplication_and_reordering_happen_about_as_often_as_asked() {
+        let mut link = Link::new(NetworkConditions {
+            loss: 0.2,
+            duplicate: 0.1,
+            reorder: 0.1,
+            reorder_delay: 0.01,
+            seed: 1,
+            ..Default::default()
+        });
+        let mut delivered = Vec::new();
+        for i in 0..10_000 {
+            link.send(ms(i as u64), 100, i);
+            delivered.extend(drain(&mut link, ms(i as u64)));
+        }
+        delivered.extend(drain(&mut link, ms(100_000)));
+
+        let stats = link.stats();
+        assert!((1800..2200).contains(&stats.lost), "{:?}", stats);
+        assert!((700..900).contains(&stats.duplicated), "{:?}", stats);
+        assert_eq!(stats.delivered as usize, delivered.len());
+        assert_eq!(
+            delivered.len() as u64,
+            10_000 - stats.lost + stats.duplicated
+        );
+        assert!(delivered.windows(2).any(|pair| pair[0] > pair[1]));
+    }
+
+    #[test]
+    fn bandwidth_spaces_datagrams_and_overflows() {
+        let mut link = Link::new(NetworkConditions {
+            // one 100 byte datagram every 10ms
+            bandwidth: 10_000,
+            max_queue: 0.05,
+            ..Default::default()
+        });
+        for i in 0..10 {
+            link.send(ms(0), 100, i);
+        }
+        // the first starts straight away and five more fit in the queue
+        assert_eq!(link.stats().overflowed, 4);
+        assert!(drain(&mut link, ms(9)).is_empty());
+        assert_eq!(drain(&mut link, ms(10)), vec![0]);
+        assert_eq!(drain(&mut link, ms(35)), vec![1, 2]);
+        assert_eq!(drain(&mut link, ms(60)), vec![3, 4, 5]);
+    }
+}
@@ -0,0 +1,684 @@
+use super::codec::{Codec, CodecError, MAX_MESSAGE_SIZE};
+use std::collections::VecDeque;
+
+pub type PacketSequence = u16;
+
+/// Datagrams larger than this risk fragmentation so messages are split across packets to stay
+/// under it. A single message larger than this is split into fragments that each have their
+/// own packet, and is only received once every fragment has arrived.
+pub const MAX_PACKET_SIZE: usize = 1200;
+
+/// How many packets before `ack` are covered by `ack_bits`.
+const ACK_BITS: u16 = 32;
+/// Sent packets that are still unacknowledged after this many newer sends are considered lost.
+const MAX_IN_FLIGHT: usize = 1024;
+const MAX_HEADER_SIZE: usize = 15;
+/// The most message bytes a single fragment carries.
+pub(crate) const FRAGMENT_SIZE: usize = MAX_PACKET_SIZE - MAX_HEADER_SIZE;
+/// Enough fragments for the largest message any codec accepts.
+const MAX_FRAGMENTS: usize = (MAX_MESSAGE_SIZE + FRAGMENT_SIZE - 1) / FRAGMENT_SIZE;
+/// Messages missing fragments are given up on once this many newer ones have started arriving.
+/// As many completed messages are remembered, so that a resent fragment of one is ignored.
+const MAX_REASSEMBLING: usize = 4;
+
+/// The layout of the header is fixed and independent of the message codec so that `Hello`
+/// and `Rejected` can always be read.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct PacketHeader {
+    pub sequence: PacketSequence,
+    /// The most recent sequence received from the remote, if any.
+    pub ack: Option<PacketSequence>,
+    /// Bit `n` is set if `ack - 1 - n` was also received.
+    pub ack_bits: u32,
+    /// Set if the packet carries part of a message too large for one packet.
+    pub fragment: Option<Fragment>,
+}
+
+/// The part of a fragmented message that a packet carries.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Fragment {
+    /// Numbers the fragmented messages from one endpoint, wrapping around.
+    pub message: u16,
+    pub index: u16,
+    pub count: u16,
+}
+
+#[derive(Clone, Debug)]
+struct Reassembly {
+    message: u16,
+    parts: Vec<Option<Vec<u8>>>,
+    missing: usize,
+}
+
+impl PacketHeader {
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.sequence.to_le_bytes());
+        let flags = self.ack.is_some() as u8 | (self.fragment.is_some() as u8) << 1;
+        buf.push(flags);
+        if let Some(ack) = self.ack {
+            buf.extend_from_slice(&ack.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.ack_bits.to_le_bytes());
+        if let Some(fragment) = self.fragment {
+            buf.extend_from_slice(&fragment.message.to_le_bytes());
+            buf.extend_from_slice(&fragment.index.to_le_bytes());
+            buf.extend_from_slice(&fragment.count.to_le_bytes());
+        }
+    }
+
+    /// Reads a header from the front of `bytes`, returning it with the bytes after it.
+    pub fn read(bytes: &[u8]) -> Result<(Self, &[u8]), CodecError> {
+        fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], CodecError> {
+            if bytes.len() < len {
+                return Err(CodecError::Truncated);
+            }
+            let (head, tail) = bytes.split_at(len);
+            *bytes = tail;
+            Ok(head)
+        }
+
+        fn take_u16(bytes: &mut &[u8]) -> Result<u16, CodecError> {
+            let value = take(bytes, 2)?;
+            Ok(u16::from_le_bytes([value[0], value[1]]))
+        }
+
+        let mut bytes = bytes;
+        let sequence = take_u16(&mut bytes)?;
+        let flags = take(&mut bytes, 1)?[0];
+        if flags > 0b11 {
+            return Err(CodecError::InvalidTag(flags as u64));
+        }
+        let ack = if flags & 1 != 0 {
+            Some(take_u16(&mut bytes)?)
+        } else {
+            None
+        };
+        let ack_bits = take(&mut bytes, 4)?;
+        let ack_bits = u32::from_le_bytes([ack_bits[0], ack_bits[1], ack_bits[2], ack_bits[3]]);
+        let fragment = if flags & 0b10 != 0 {
+            Some(Fragment {
+                message: take_u16(&mut bytes)?,
+                index: take_u16(&mut bytes)?,
+                count: take_u16(&mut bytes)?,
+            })
+        } else {
+            None
+        };
+        Ok((
+            Self {
+                sequence,
+                ack,
+                ack_bits,
+                fragment,
+            },
+            bytes,
+        ))
+    }
+}
+
+/// The local sequences that a received header confirmed or gave up on.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Acks {
+    pub acked: Vec<PacketSequence>,
+    pub lost: Vec<PacketSequence>,
+}
+
+/// Returns true if `s1` is more recent than `s2`, accounting for wrap around.
+pub fn sequence_greater_than(s1: PacketSequence, s2: PacketSequence) -> bool {
+    const HALF: PacketSequence = PacketSequence::MAX / 2 + 1;
+    ((s1 > s2) && (s1 - s2 <= HALF)) || ((s1 < s2) && (s2 - s1 > HALF))
+}
+
+fn decode_all<T, C: Codec<T>>(codec: &C, mut bytes: &[u8]) -> Result<Vec<T>, CodecError> {
+    let mut messages = Vec::new();
+    while !bytes.is_empty() {
+        let (message, rest) = codec.decode(bytes)?;
+        messages.push(message);
+        bytes = rest;
+    }
+    Ok(messages)
+}
+
+fn header_acks(header: &PacketHeader, ack: PacketSequence, sequence: PacketSequence) -> bool {
+    if sequence == ack {
+        return true;
+    }
+    if !sequence_greater_than(ack, sequence) {
+        return false;
+    }
+    let distance = ack.wrapping_sub(sequence);
+    distance <= ACK_BITS && header.ack_bits & (1 << (distance - 1)) != 0
+}
+
+/// Encodes messages with `codec`, one buffer per message.
+pub fn encode<T, C: Codec<T>>(codec: &C, messages: &[T]) -> Result<Vec<Vec<u8>>, CodecError> {
+    messages
+        .iter()
+        .map(|message| {
+            let mut buf = Vec::new();
+            codec.encode(message, &mut buf).map(|_| buf)
+        })
+        .collect()
+}
+
+/// Splits encoded messages into groups that each fit in a packet under `MAX_PACKET_SIZE`,
+/// preserving order. A message too large for a packet is put in a group of its own. Always
+/// returns at least one (possibly empty) group.
+pub fn batch(messages: Vec<Vec<u8>>) -> Vec<Vec<Vec<u8>>> {
+    let mut batches = vec![Vec::new()];
+    let mut batch_size = MAX_HEADER_SIZE;
+    for message in messages {
+        let size = message.len();
+        let batch = batches.last_mut().unwrap();
+        if !batch.is_empty() && batch_size + size > MAX_PACKET_SIZE {
+            batches.push(vec![message]);
+            batch_size = MAX_HEADER_SIZE + size;
+        } else {
+            batch.push(message);
+            batch_size += size;
+        }
+    }
+    batches
+}
+
+fn datagram(header: &PacketHeader, bytes: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(MAX_HEADER_SIZE + bytes.len());
+    header.write(&mut datagram);
+    datagram.extend_from_slice(bytes);
+    datagram
+}
+
+/// Sequence numbering and acknowledgement for one side of a connection. It only deals with
+/// bytes so it can sit on top of any datagram transport.
+#[derive(Clone, Debug, Default)]
+pub struct PacketEndpoint {
+    local_sequence: PacketSequence,
+    remote_sequence: Option<PacketSequence>,
+    received_bits: u32,
+    in_flight: VecDeque<PacketSequence>,
+    next_fragmented: u16,
+    reassembling: VecDeque<Reassembly>,
+    /// The most recent fragmented messages to arrive whole.
+    reassembled: VecDeque<u16>,
+}
+
+impl PacketEndpoint {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Builds the header for the next outgoing packet.
+    pub fn next_header(&mut self) -> PacketHeader {
+        let header = PacketHeader {
+            sequence: self.local_sequence,
+            ack: self.remote_sequence,
+            ack_bits: self.received_bits,
+            fragment: None,
+        };
+        self.local_sequence = self.local_sequence.wrapping_add(1);
+        if self.in_flight.len() == MAX_IN_FLIGHT {
+            self.in_flight.pop_front();
+        }
+        self.in_flight.push_back(header.sequence);
+        header
+    }
+
+    /// Records a received header. Returns `None` if the packet is a duplicate or too old to
+    /// track, in which case its messages should be dropped.
+    pub fn receive(&mut self, header: &PacketHeader) -> Option<Acks> {
+        match self.remote_sequence {
+            None => {
+                self.remote_sequence = Some(header.sequence);
+                self.received_bits = 0;
+            }
+            Some(remote) if header.sequence == remote => return None,
+            Some(remote) if sequence_greater_than(header.sequence, remote) => {
+                let shift = header.sequence.wrapping_sub(remote);
+                self.received_bits = if shift > ACK_BITS {
+                    0
+                } else {
+                    self.received_bits.checked_shl(shift as u32).unwrap_or(0) | 1 << (shift - 1)
+                };
+                self.remote_sequence = Some(header.sequence);
+            }
+            Some(remote) => {
+                let distance = remote.wrapping_sub(header.sequence);
+                if distance > ACK_BITS {
+                    return None;
+                }
+                let bit = 1 << (distance - 1);
+                if self.received_bits & bit != 0 {
+                    return None;
+                }
+                self.received_bits |= bit;
+            }
+        }
+
+        let mut acks = Acks::default();
+        let ack = match header.ack {
+            None => return Some(acks),
+            Some(ack) => ack,
+        };
+        let oldest_trackable = ack.wrapping_sub(ACK_BITS);
+        self.in_flight.retain(|sequence| {
+            if header_acks(header, ack, *sequence) {
+                acks.acked.push(*sequence);
+                false
+            } else if sequence_greater_than(oldest_trackable, *sequence) {
+                acks.lost.push(*sequence);
+                false
+            } else {
+                true
+            }
+        });
+        Some(acks)
+    }
+
+    /// Packs messages into as few datagrams as possible. At least one datagram is always
+    /// produced so that acknowledgements keep flowing even without messages.
+    pub fn pack<T, C: Codec<T>>(
+        &mut self,
+        codec: &C,
+        messages: &[T],
+    ) -> Result<Vec<Vec<u8>>, CodecError> {
+        Ok(batch(encode(codec, messages)?)
+            .into_iter()
+            .flat_map(|messages| self.write(&messages))
+            .map(|(_, datagram)| datagram)
+            .collect())
+    }
+
+    /// Frames already encoded messages into a datagram and returns it along with the sequence
+    /// it was sent with. Messages too large for one packet are split into fragments, one
+    /// datagram each.
+    pub fn write(&mut self, messages: &[Vec<u8>]) -> Vec<(PacketSequence, Vec<u8>)> {
+        let bytes = messages.concat();
+        if bytes.len() <= FRAGMENT_SIZE {
+            let header = self.next_header();
+            return vec![(header.sequence, datagram(&header, &bytes))];
+        }
+
+        let message = self.next_fragmented_message();
+        let chunks = bytes.chunks(FRAGMENT_SIZE);
+        let count = chunks.len() as u16;
+        chunks
+            .enumerate()
+            .map(|(index, chunk)| {
+                let fragment = Fragment {
+                    message,
+                    index: index as u16,
+                    count,
+                };
+                self.write_fragment(fragment, chunk)
+            })
+            .collect()
+    }
+
+    /// Numbers a message for splitting into fragments of at most `FRAGMENT_SIZE` bytes.
+    pub fn next_fragmented_message(&mut self) -> u16 {
+        let message = self.next_fragmented;
+        self.next_fragmented = self.next_fragmented.wrapping_add(1);
+        message
+    }
+
+    /// Frames one fragment of a message numbered by `next_fragmented_message`. A fragment
+    /// that's lost can be written again, and the receiver keeps the others until it arrives.
+    pub fn write_fragment(
+        &mut self,
+        fragment: Fragment,
+        bytes: &[u8],
+    ) -> (PacketSequence, Vec<u8>) {
+        let mut header = self.next_header();
+        header.fragment = Some(fragment);
+        (header.sequence, datagram(&header, bytes))
+    }
+
+    /// Decodes a datagram. Duplicate and stale packets decode to `None`. Nothing is recorded
+    /// for a datagram that fails to decode, except that a fragment is recorded as soon as it
+    /// arrives and its message is only decoded once the last fragment has.
+    pub fn unpack<T, C: Codec<T>>(
+        &mut self,
+        codec: &C,
+        datagram: &[u8],
+    ) -> Result<Option<(Vec<T>, Acks)>, CodecError> {
+        let (header, rest) = PacketHeader::read(datagram)?;
+        let fragment = match header.fragment {
+            None => {
+                let messages = decode_all(codec, rest)?;
+                return Ok(self.receive(&header).map(|acks| (messages, acks)));
+            }
+            Some(fragment) => fragment,
+        };
+        if fragment.count as usize > MAX_FRAGMENTS {
+            return Err(CodecError::TooMany {
+                what: "fragments",
+                len: fragment.count as usize,
+                max: MAX_FRAGMENTS,
+            });
+        }
+        if fragment.index >= fragment.count {
+            return Err(CodecError::Invalid("fragment index"));
+        }
+        let acks = match self.receive(&header) {
+            Some(acks) => acks,
+            None => return Ok(None),
+        };
+        let messages = match self.reassemble(fragment, rest)? {
+            Some(bytes) => decode_all(codec, &bytes)?,
+            None => Vec::new(),
+        };
+        Ok(Some((messages, acks)))
+    }
+
+    /// Keeps a fragment, returning the whole message once every fragment has arrived.
+    fn reassemble(
+        &mut self,
+        fragment: Fragment,
+        bytes: &[u8],
+    ) -> Result<Option<Vec<u8>>, CodecError> {
+        if self.reassembled.contains(&fragment.message) {
+            return Ok(None);
+        }
+        let count = fragment.count as usize;
+        let position = self
+            .reassembling
+            .iter()
+            .position(|reassembly| reassembly.message == fragment.message);
+        let position = match position {
+            Some(position) => position,
+            None => {
+                if self.reassembling.len() == MAX_REASSEMBLING {
+                    self.reassembling.pop_front();
+                }
+                self.reassembling.push_back(Reassembly {
+                    message: fragment.message,
+                    parts: vec![None; count],
+                    missing: count,
+                });
+                self.reassembling.len() - 1
+            }
+        };
+
+        let reassembly = &mut self.reassembling[position];
+        if reassembly.parts.len() != count {
+            return Err(CodecError::Invalid("fragment count"));
+        }
+        let part = &mut reassembly.parts[fragment.index as usize];
+        if part.is_none() {
+            *part = Some(bytes.to_vec());
+            reassembly.missing -= 1;
+        }
+        if reassembly.missing > 0 {
+            return Ok(None);
+        }
+        let reassembly = self.reassembling.remove(position).unwrap();
+        if self.reassembled.len() == MAX_REASSEMBLING {
+            self.reassembled.pop_front();
+        }
+        self.reassembled.push_back(reassembly.message);
+        Ok(Some(
+            reassembly.parts.into_iter().flatten().flatten().collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Bincode;
+
+    #[test]
+    fn sequence_wrap_around() {
+        assert!(sequence_greater_than(1, 0));
+        assert!(!sequence_greater_than(0, 1));
+        assert!(sequence_greater_than(0, PacketSequence::MAX));
+        assert!(sequence_greater_than(10, PacketSequence::MAX - 10));
+        assert!(!sequence_greater_than(PacketSequence::MAX - 10, 10));
+    }
+
+    #[test]
+    fn ack_bits() {
+        let mut sender = PacketEndpoint::new();
+        let mut receiver = PacketEndpoint::new();
+
+        let headers = (0..5).map(|_| sender.next_header()).collect::<Vec<_>>();
+        for index in [0, 2, 4].iter() {
+            assert!(receiver.receive(&headers[*index]).is_some());
+        }
+
+        let reply = receiver.next_header();
+        assert_eq!(reply.ack, Some(4));
+        assert_eq!(reply.ack_bits, 0b1010);
+
+        let acks = sender.receive(&reply).unwrap();
+        assert_eq!(acks.acked, vec![0, 2, 4]);
+        assert!(acks.lost.is_empty());
+    }
+
+    #[test]
+    fn nothing_is_acked_before_receiving() {
+        let mut a = PacketEndpoint::new();
+        let mut b = PacketEndpoint::new();
+
+        a.next_header();
+        let acks = a.receive(&b.next_header()).unwrap();
+        assert_eq!(acks, Acks::default());
+    }
+
+    #[test]
+    fn duplicates_and_stale_packets_are_dropped() {
+        let mut sender = PacketEndpoint::new();
+        let mut receiver = PacketEndpoint::new();
+
+        let first = sender.next_header();
+        for _ in 0..ACK_BITS {
+            sender.next_header();
+        }
+        let last = sender.next_header();
+
+        assert!(receiver.receive(&last).is_some());
+        assert!(receiver.receive(&last).is_none());
+        assert!(receiver.receive(&first).is_none());
+    }
+
+    #[test]
+    fn unacknowledged_packets_are_lost() {
+        let mut sender = PacketEndpoint::new();
+        let mut receiver = PacketEndpoint::new();
+
+        let first = sender.next_header();
+        let mut last = first;
+        for _ in 0..ACK_BITS + 1 {
+            last = sender.next_header();
+        }
+        receiver.receive(&last).unwrap();
+
+        let acks = sender.receive(&receiver.next_header()).unwrap();
+        assert_eq!(acks.acked, vec![last.sequence]);
+        assert_eq!(acks.lost, vec![first.sequence]);
+    }
+
+    #[test]
+    fn wrapping_sequences() {
+        let mut sender = PacketEndpoint {
+            local_sequence: PacketSequence::MAX - 1,
+            ..Default::default()
+        };
+        let mut receiver = PacketEndpoint::new();
+
+        let headers = (0..4).map(|_| sender.next_header()).collect::<Vec<_>>();
+        for header in headers.iter() {
+            assert!(receiver.receive(header).is_some());
+        }
+        let reply = receiver.next_header();
+        assert_eq!(reply.ack, Some(1));
+        assert_eq!(reply.ack_bits, 0b111);
+        assert_eq!(sender.receive(&reply).unwrap().acked.len(), 4);
+    }
+
+    #[test]
+    fn batching() {
+        let mut sender = PacketEndpoint::new();
+        let mut receiver = PacketEndpoint::new();
+
+        let messages = (0..1000u32).collect::<Vec<_>>();
+        let datagrams = sender.pack(&Bincode, &messages).unwrap();
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|d| d.len() <= MAX_PACKET_SIZE));
+
+        let mut received = Vec::new();
+        for datagram in datagrams.iter() {
+            let (messages, _) = receiver
+                .unpack::<u32, _>(&Bincode, datagram)
+                .unwrap()
+                .unwrap();
+            received.extend(messages);
+        }
+        assert_eq!(received, messages);
+
+        assert_eq!(sender.pack::<u32, _>(&Bincode, &[]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn header_layout() {
+        for header in [
+            PacketHeader::default(),
+            PacketHeader {
+                sequence: 513,
+                ack: Some(PacketSequence::MAX),
+                ack_bits: 0xdead_beef,
+                fragment: None,
+            },
+            PacketHeader {
+                sequence: 7,
+                ack: None,
+                ack_bits: 0,
+                fragment: Some(Fragment {
+                    message: 3,
+                    index: 55,
+                    count: 56,
+                }),
+            },
+        ]
+        .iter()
+        {
+            let mut buf = Vec::new();
+            header.write(&mut buf);
+            assert!(buf.len() <= MAX_HEADER_SIZE);
+            buf.push(42);
+            assert_eq!(PacketHeader::read(&buf).unwrap(), (*header, &[42][..]));
+        }
+        assert!(PacketHeader::read(&[0, 0, 1, 0]).is_err());
+        assert!(PacketHeader::read(&[0, 0, 4, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn large_messages_are_fragmented() {
+        let mut sender = PacketEndpoint::new();
+        let mut receiver = PacketEndpoint::new();
+
+        let large = vec![7u8; 10 * MAX_PACKET_SIZE];
+        let messages = vec![vec![1u8, 2], large.clone(), vec![3u8; 5]];
+        let datagrams = sender.pack(&Bincode, &messages).unwrap();
+        assert!(datagrams.len() > 10);
+        assert!(datagrams.iter().all(|d| d.len() <= MAX_PACKET_SIZE));
+
+        // fragments can arrive in any order, and the message once all of them have
+        let mut received = Vec::new();
+        let (last, rest) = datagrams.split_last().unwrap();
+        for datagram in rest.iter().rev().chain(std::iter::once(last)) {
+            let (messages, _) = receiver
+                .unpack::<Vec<u8>, _>(&Bincode, datagram)
+                .unwrap()
+                .unwrap();
+            received.extend(messages);
+        }
+        received.sort_by_key(Vec::len);
+        assert_eq!(received, vec![vec![1, 2], vec![3; 5], large]);
+    }
+
+    #[test]
+    fn lost_fragments_lose_the_message() {
+        let mut sender = PacketEndpoint::new();
+        let mut receiver = PacketEndpoint::new();
+
+        let large = vec![vec![7u8; 3 * MAX_PACKET_SIZE]];
+        let mut datagrams = sender.pack(&Bincode, &large).unwrap();
+        datagrams.remove(1);
+        for datagram in datagrams.iter() {
+            let (messages, _) = receiver
+                .unpack::<Vec<u8>, _>(&Bincode, datagram)
+                .unwrap()
+                .unwrap();
+            assert!(messages.is_empty());
+        }
+
+        // the next fragmented message doesn't pick up the missing part of the last one
+        let datagrams = sender.pack(&Bincode, &large).unwrap();
+        let mut received = Vec::new();
+        for datagram in datagrams.iter() {
+            let (messages, _) = receiver
+                .unpack::<Vec<u8>, _>(&Bincode, datagram)
+                .unwrap()
+                .unwrap();
+            received.extend(messages);
+        }
+        assert_eq!(received, large);
+
+        let mut forged = Vec::new();
+        PacketHeader {
+            sequence: 100,
+            fragment: Some(Fragment {
+                message: 9,
+                index: 0,
+                count: u16::MAX,
+            }),
+            ..Default::default()
+        }
+        .write(&mut forged);
+        assert!(matches!(
+            receiver.unpack::<Vec<u8>, _>(&Bincode, &forged),
+            Err(CodecError::TooMany { .. })
+        ));
+    }
+
+    #[test]
+    fn resent_fragments_complete_the_message_once() {
+        let mut sender = PacketEndpoint::new();
+        let mut receiver = PacketEndpoint::new();
+
+        let mut bytes = Vec::new();
+        Bincode
+            .encode(&vec![7u8; 3 * MAX_PACKET_SIZE], &mut bytes)
+            .unwrap();
+        let message = sender.next_fragmented_message();
+        let count = bytes.chunks(FRAGMENT_SIZE).len() as u16;
+        let fragments = bytes
+            .chunks(FRAGMENT_SIZE)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let index = index as u16;
+                (
+                    Fragment {
+                        message,
+                        index,
+                        count,
+                    },
+                    chunk,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // the others arrive again once the first completes the message
+        let mut received = Vec::new();
+        for (fragment, chunk) in fragments.iter().skip(1).chain(fragments.iter()) {
+            let (_, datagram) = sender.write_fragment(*fragment, chunk);
+            let (messages, _) = receiver
+                .unpack::<Vec<u8>, _>(&Bincode, &datagram)
+                .unwrap()
+                .unwrap();
+            received.extend(messages);
+        }
+        assert_eq!(received, vec![vec![7u8; 3 * MAX_PACKET_SIZE]]);
+    }
+}
@@ -0,0 +1,410 @@
+use super::codec::Codec;
+use super::packet::{self, Acks, Fragment, PacketEndpoint, PacketSequence, FRAGMENT_SIZE};
+use std::collections::{BTreeSet, HashMap};
+
+/// How many recent sequences are remembered per player when deduplicating.
+const DEDUPLICATION_WINDOW: usize = 1024;
+
+#[derive(Clone, Debug)]
+struct Pending<T> {
+    message: T,
+    /// The packets that have carried this message so far.
+    packets: Vec<PacketSequence>,
+    /// Set once the message turns out too large for one packet. Only holds the fragments that
+    /// haven't been acknowledged yet.
+    fragments: Option<Vec<PendingFragment>>,
+}
+
+#[derive(Clone, Debug)]
+struct PendingFragment {
+    fragment: Fragment,
+    bytes: Vec<u8>,
+    /// The packet carrying this fragment, until it is lost.
+    packet: Option<PacketSequence>,
+}
+
+/// Messages that are repeated in every outgoing packet until one of the packets carrying them
+/// is acknowledged. Used for anything that must survive the unreliable channel. Messages too
+/// large for one packet are split into fragments once, and a fragment is only sent again when
+/// the packet carrying it is lost, so the message is gone once every fragment is acknowledged.
+#[derive(Clone, Debug)]
+pub struct RedundantQueue<T> {
+    pending: Vec<Pending<T>>,
+}
+
+impl<T> Default for RedundantQueue<T> {
+    fn default() -> Self {
+        Self {
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<T> RedundantQueue<T> {
+    pub fn push(&mut self, message: T) {
+        self.pending.push(Pending {
+            message,
+            packets: Vec::new(),
+            fragments: None,
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.pending.iter().map(|pending| &pending.message)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Drops messages that were carried by an acknowledged packet, or whose fragments all were.
+    pub fn acknowledge(&mut self, acks: &Acks) {
+        for fragments in self
+            .pending
+            .iter_mut()
+            .flat_map(|pending| &mut pending.fragments)
+        {
+            fragments.retain(|fragment| {
+                !matches!(fragment.packet, Some(sequence) if acks.acked.contains(&sequence))
+            });
+            for fragment in fragments.iter_mut() {
+                if matches!(fragment.packet, Some(sequence) if acks.lost.contains(&sequence)) {
+                    fragment.packet = None;
+                }
+            }
+        }
+        self.pending.retain(|pending| match &pending.fragments {
+            Some(fragments) => !fragments.is_empty(),
+            None => !pending
+                .packets
+                .iter()
+                .any(|sequence| acks.acked.contains(sequence)),
+        });
+        for pending in self.pending.iter_mut() {
+            pending
+                .packets
+                .retain(|sequence| !acks.lost.contains(sequence));
+        }
+    }
+
+    /// Like `PacketEndpoint::pack` but with every unacknowledged message sent ahead of
+    /// `messages`, apart from fragments that are still in flight. A message that can't be
+    /// encoded is logged and dropped, so that it doesn't hold up the others.
+    pub fn pack<C: Codec<T>>(
+        &mut self,
+        codec: &C,
+        endpoint: &mut PacketEndpoint,
+        messages: &[T],
+    ) -> Vec<Vec<u8>> {
+        let mut datagrams = Vec::new();
+        let mut all = Vec::with_capacity(self.pending.len() + messages.len());
+        // the index in `pending` of each message in `all`, if it's pending
+        let mut owners = Vec::with_capacity(all.capacity());
+        for mut pending in std::mem::take(&mut self.pending) {
+            if pending.fragments.is_none() {
+                let bytes = match encode(codec, &pending.message) {
+                    Some(bytes) => bytes,
+                    None => continue,
+                };
+                if bytes.len() <= FRAGMENT_SIZE {
+                    owners.push(Some(self.pending.len()));
+                    all.push(bytes);
+                    self.pending.push(pending);
+                    continue;
+                }
+                pending.fragments = Some(fragment(endpoint, &bytes));
+            }
+            let unsent = pending
+                .fragments
+                .iter_mut()
+                .flatten()
+                .filter(|fragment| fragment.packet.is_none());
+            for fragment in unsent {
+                let (sequence, datagram) =
+                    endpoint.write_fragment(fragment.fragment, &fragment.bytes);
+                fragment.packet = Some(sequence);
+                datagrams.push(datagram);
+            }
+            self.pending.push(pending);
+        }
+        for message in messages {
+            if let Some(bytes) = encode(codec, message) {
+                owners.push(None);
+                all.push(bytes);
+            }
+        }
+
+        let mut owners = owners.into_iter();
+        for batch in packet::batch(all) {
+            let carried = owners
+                .by_ref()
+                .take(batch.len())
+                .flatten()
+                .collect::<Vec<_>>();
+            for (sequence, datagram) in endpoint.write(&batch) {
+                for index in carried.iter() {
+                    self.pending[*index].packets.push(sequence);
+                }
+                datagrams.push(datagram);
+            }
+        }
+        datagrams
+    }
+}
+
+fn encode<T, C: Codec<T>>(codec: &C, message: &T) -> Option<Vec<u8>> {
+    let mut buf = Vec::new();
+    match codec.encode(message, &mut buf) {
+        Ok(()) => Some(buf),
+        Err(err) => {
+            log::error!("dropping a message that could not be encoded: {}", err);
+            None
+        }
+    }
+}
+
+fn fragment(endpoint: &mut PacketEndpoint, bytes: &[u8]) -> Vec<PendingFragment> {
+    let message = endpoint.next_fragmented_message();
+    let chunks = bytes.chunks(FRAGMENT_SIZE);
+    let count = chunks.len() as u16;
+    chunks
+        .enumerate()
+        .map(|(index, chunk)| PendingFragment {
+            fragment: Fragment {
+                message,
+                index: index as u16,
+                count,
+            },
+            bytes: chunk.to_vec(),
+            packet: None,
+        })
+        .collect()
+}
+
+/// Filters out inputs that have already been received, keyed by player and sequence.
+#[derive(Clone, Debug, Default)]
+pub struct Deduplicator {
+    seen: HashMap<super::PlayerId, BTreeSet<super::InputSequence>>,
+}
+
+impl Deduplicator {
+    /// Returns true the first time a player's sequence is seen.
+    pub fn insert(&mut self, player_id: super::PlayerId, sequence: super::InputSequence) -> bool {
+        let seen = self.seen.entry(player_id).or_default();
+        if let Some(oldest) = seen.iter().next() {
+            if seen.len() >= DEDUPLICATION_WINDOW && sequence < *oldest {
+                return false;
+            }
+        }
+        let inserted = seen.insert(sequence);
+        if seen.len() > DEDUPLICATION_WINDOW {
+            let oldest = *seen.iter().next().unwrap();
+            seen.remove(&oldest);
+        }
+        inserted
+    }
+
+    pub fn remove_player(&mut self, player_id: super::PlayerId) {
+        self.seen.remove(&player_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{Bincode, CodecError};
+
+    /// Bincode, apart from refusing to encode anything over 100.
+    struct UpTo100;
+
+    impl Codec<u32> for UpTo100 {
+        fn encode(&self, message: &u32, buf: &mut Vec<u8>) -> Result<(), CodecError> {
+            if *message > 100 {
+                return Err(CodecError::Invalid("message"));
+            }
+            Bincode.encode(message, buf)
+        }
+
+        fn decode<'a>(&self, bytes: &'a [u8]) -> Result<(u32, &'a [u8]), CodecError> {
+            Bincode.decode(bytes)
+        }
+    }
+
+    /// Deterministic stand-in for a lossy network.
+    struct Lossy(u64);
+
+    impl Lossy {
+        fn drops(&mut self, loss: u64) -> bool {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 33) % 100 < loss
+        }
+    }
+
+    #[test]
+    fn deduplication() {
+        let mut dedup = Deduplicator::default();
+        assert!(dedup.insert(0, 0));
+        assert!(dedup.insert(1, 0));
+        assert!(!dedup.insert(0, 0));
+        assert!(dedup.insert(0, 1));
+
+        for sequence in 2..DEDUPLICATION_WINDOW as u32 + 10 {
+            assert!(dedup.insert(0, sequence));
+        }
+        assert!(!dedup.insert(0, 0));
+    }
+
+    #[test]
+    fn acknowledged_messages_stop_repeating() {
+        let mut sender = PacketEndpoint::new();
+        let mut receiver = PacketEndpoint::new();
+        let mut queue = RedundantQueue::default();
+
+        queue.push(1u32);
+        let first = queue.pack(&Bincode, &mut sender, &[]);
+        queue.push(2u32);
+        let second = queue.pack(&Bincode, &mut sender, &[]);
+
+        let (messages, _) = receiver
+            .unpack::<u32, _>(&Bincode, &second[0])
+            .unwrap()
+            .unwrap();
+        assert_eq!(messages, vec![1, 2]);
+        assert!(receiver
+            .unpack::<u32, _>(&Bincode, &first[0])
+            .unwrap()
+            .is_some());
+
+        let reply = receiver.pack::<u32, _>(&Bincode, &[]).unwrap();
+        let (_, acks) = sender
+            .unpack::<u32, _>(&Bincode, &reply[0])
+            .unwrap()
+            .unwrap();
+        queue.acknowledge(&acks);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn messages_that_cant_be_encoded_are_dropped_alone() {
+        let mut sender = PacketEndpoint::new();
+        let mut receiver = PacketEndpoint::new();
+        let mut queue = RedundantQueue::default();
+
+        queue.push(1u32);
+        queue.push(1000);
+        let datagrams = queue.pack(&UpTo100, &mut sender, &[2, 2000, 3]);
+        assert_eq!(queue.len(), 1);
+        let (messages, _) = receiver
+            .unpack::<u32, _>(&UpTo100, &datagrams[0])
+            .unwrap()
+            .unwrap();
+        assert_eq!(messages, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn only_lost_fragments_are_resent() {
+        let mut sender = PacketEndpoint::new();
+        let mut receiver = PacketEndpoint::new();
+        let mut queue = RedundantQueue::default();
+
+        let large = (0..1000u32).collect::<Vec<_>>();
+        queue.push(large.clone());
+        let datagrams = queue.pack(&Bincode, &mut sender, &[]);
+        assert!(datagrams.len() > 2);
+        for (index, datagram) in datagrams.iter().enumerate() {
+            if index == 1 {
+                continue;
+            }
+            let (messages, _) = receiver
+                .unpack::<Vec<u32>, _>(&Bincode, datagram)
+                .unwrap()
+                .unwrap();
+            assert!(messages.is_empty());
+        }
+
+        // the other fragments are in flight, so nothing but acks goes out until the loss shows
+        for _ in 0..33 {
+            let datagrams = queue.pack(&Bincode, &mut sender, &[]);
+            assert_eq!(datagrams.len(), 1);
+            receiver
+                .unpack::<Vec<u32>, _>(&Bincode, &datagrams[0])
+                .unwrap()
+                .unwrap();
+        }
+        let reply = receiver.pack::<Vec<u32>, _>(&Bincode, &[]).unwrap();
+        let (_, acks) = sender
+            .unpack::<Vec<u32>, _>(&Bincode, &reply[0])
+            .unwrap()
+            .unwrap();
+        queue.acknowledge(&acks);
+        assert_eq!(queue.len(), 1);
+
+        let datagrams = queue.pack(&Bincode, &mut sender, &[]);
+        assert_eq!(datagrams.len(), 2);
+        let (messages, _) = receiver
+            .unpack::<Vec<u32>, _>(&Bincode, &datagrams[0])
+            .unwrap()
+            .unwrap();
+        assert_eq!(messages, vec![large]);
+
+        let reply = receiver.pack::<Vec<u32>, _>(&Bincode, &[]).unwrap();
+        let (_, acks) = sender
+            .unpack::<Vec<u32>, _>(&Bincode, &reply[0])
+            .unwrap()
+            .unwrap();
+        queue.acknowledge(&acks);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn every_input_arrives_despite_loss() {
+        const INPUTS: u32 = 200;
+        const LOSS_PERCENT: u64 = 40;
+
+        let mut network = Lossy(7);
+        let mut client = PacketEndpoint::new();
+        let mut server = PacketEndpoint::new();
+        let mut queue = RedundantQueue::default();
+        let mut dedup = Deduplicator::default();
+        let mut received = Vec::new();
+
+        for tick in 0..INPUTS * 4 {
+            if tick < INPUTS {
+                queue.push(tick);
+            }
+
+            for datagram in queue.pack(&Bincode, &mut client, &[]) {
+                if network.drops(LOSS_PERCENT) {
+                    continue;
+                }
+                if let Some((messages, _)) = server.unpack::<u32, _>(&Bincode, &datagram).unwrap() {
+                    for message in messages {
+                        if dedup.insert(0, message) {
+                            received.push(message);
+                        }
+                    }
+                }
+            }
+
+            for datagram in server.pack::<u32, _>(&Bincode, &[]).unwrap() {
+                if network.drops(LOSS_PERCENT) {
+                    continue;
+                }
+                if let Some((_, acks)) = client.unpack::<u32, _>(&Bincode, &datagram).unwrap() {
+                    queue.acknowledge(&acks);
+                }
+            }
+        }
+
+        received.sort_unstable();
+        assert_eq!(received, (0..INPUTS).collect::<Vec<_>>());
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,571 @@
+//! Delta compression of `State` against a baseline frame the receiver already has.
+//!
+//! Body fields are fixed point so every difference is an exact number of the smallest
+//! representable step. Rather than sending the raw difference, each changed field is sent as
+//! the residual from a prediction made with one integration step per elapsed frame. For a one
+//! frame gap only the acceleration residuals are non-zero and the rest are elided.
+
+use super::control::{Playback, ScheduledControl};
+use super::{AddBodyEvent, FrameIndex, IndexedState, InputBuffer, State};
+use nbody::{Body, BodyId, Float};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+const ACCELERATION_X: u8 = 1 << 0;
+const ACCELERATION_Y: u8 = 1 << 1;
+const VELOCITY_X: u8 = 1 << 2;
+const VELOCITY_Y: u8 = 1 << 3;
+const POSITION_X: u8 = 1 << 4;
+const POSITION_Y: u8 = 1 << 5;
+const MASS: u8 = 1 << 6;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DeltaError {
+    WrongBaseline {
+        expected: FrameIndex,
+        actual: FrameIndex,
+    },
+    UnknownBody(BodyId),
+    Truncated,
+    HashMismatch {
+        expected: u64,
+        actual: u64,
+    },
+}
+
+impl std::fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeltaError::WrongBaseline { expected, actual } => write!(
+                f,
+                "delta expects baseline frame {} but was given {}",
+                expected, actual
+            ),
+            DeltaError::UnknownBody(id) => write!(f, "delta changes unknown body {}", id),
+            DeltaError::Truncated => write!(f, "delta body changes are truncated"),
+            DeltaError::HashMismatch { expected, actual } => write!(
+                f,
+                "applied delta hashes to {} instead of {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DeltaError {}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateDelta {
+    pub baseline_frame: FrameIndex,
+    pub frame_index: FrameIndex,
+    pub next_body_id: BodyId,
+    pub removed: Vec<BodyId>,
+    pub added: Vec<Body>,
+    /// Packed changes to bodies that exist in both states. See `write_body_delta`.
+    pub changed: Vec<u8>,
+    pub input_buffer: InputBuffer,
+    pub playback: Playback,
+    /// `State::hash` of the target, checked after the delta is applied.
+    pub hash: u64,
+}
+
+impl StateDelta {
+    pub fn encode(baseline: &State, target: &State) -> Self {
+        let gap = target.frame_index.wrapping_sub(baseline.frame_index) as i64;
+        let target_ids = target
+            .simulation
+            .bodies
+            .iter()
+            .map(Body::id)
+            .collect::<HashSet<_>>();
+        let baseline_bodies = baseline
+            .simulation
+            .bodies
+            .iter()
+            .map(|body| (body.id(), body))
+            .collect::<HashMap<_, _>>();
+
+        let mut removed = baseline
+            .simulation
+            .bodies
+            .iter()
+            .map(Body::id)
+            .filter(|id| !target_ids.contains(id))
+            .collect::<Vec<_>>();
+        let mut added = Vec::new();
+        let mut changed = Vec::new();
+        let mut previous_id = 0;
+        for body in target.simulation.bodies.iter() {
+            match baseline_bodies.get(&body.id()) {
+                None => added.push(*body),
+                Some(base) => {
+                    if write_body_delta(&mut changed, previous_id, base, body, gap) {
+                        previous_id = body.id();
+                    }
+                }
+            }
+        }
+
+        // bodies are applied as baseline survivors followed by additions. that matches the
+        // order the simulation produces but anything else falls back to sending every body.
+        let survivors = baseline
+            .simulation
+            .bodies
+            .iter()
+            .map(Body::id)
+            .filter(|id| target_ids.contains(id));
+        let order = survivors.chain(added.iter().map(Body::id));
+        if !order.eq(target.simulation.bodies.iter().map(Body::id)) {
+            removed = baseline.simulation.bodies.iter().map(Body::id).collect();
+            added = target.simulation.bodies.clone();
+            changed.clear();
+        }
+
+        Self {
+            baseline_frame: baseline.frame_index,
+            frame_index: target.frame_index,
+            next_body_id: target.simulation.next_id(),
+            removed,
+            added,
+            changed,
+            input_buffer: target.input_buffer.clone(),
+            playback: target.playback.clone(),
+            hash: target.hash(),
+        }
+    }
+
+    pub fn apply(&self, baseline: &State) -> Result<State, DeltaError> {
+        if baseline.frame_index != self.baseline_frame {
+            return Err(DeltaError::WrongBaseline {
+                expected: self.baseline_frame,
+                actual: baseline.frame_index,
+            });
+        }
+        let gap = self.frame_index.wrapping_sub(self.baseline_frame) as i64;
+
+        let mut state = baseline.clone();
+        state.frame_index = self.frame_index;
+        state.input_buffer = self.input_buffer.clone();
+        state.playback = self.playback.clone();
+        state.simulation.set_next_id(self.next_body_id);
+        state
+            .simulation
+            .bodies
+            .retain(|body| !self.removed.contains(&body.id()));
+
+        let indices = state
+            .simulation
+            .bodies
+            .iter()
+            .enumerate()
+            .map(|(index, body)| (body.id(), index))
+            .collect::<HashMap<_, _>>();
+        let mut changed = &self.changed[..];
+        let mut previous_id = 0;
+        while !changed.is_empty() {
+            let id = read_body_id(&mut changed, previous_id)?;
+            let index = *indices.get(&id).ok_or(DeltaError::UnknownBody(id))?;
+            read_body_delta(&mut changed, &mut state.simulation.bodies[index], gap)?;
+            previous_id = id;
+        }
+        state.simulation.bodies.extend(self.added.iter().copied());
+
+        let actual = state.hash();
+        if actual == self.hash {
+            Ok(state)
+        } else {
+            Err(DeltaError::HashMismatch {
+                expected: self.hash,
+                actual,
+            })
+        }
+    }
+}
+
+/// Something scheduled after a snapshot was taken, so missing from it.
+#[derive(Copy, Clone, Debug)]
+enum Scheduled {
+    Input(IndexedState<AddBodyEvent>),
+    Control(ScheduledControl),
+}
+
+/// Recent states kept so that deltas can be encoded against whichever one a client has.
+#[derive(Clone, Debug)]
+pub struct SnapshotHistory {
+    /// Each state with what was scheduled before the next one was taken.
+    states: VecDeque<(State, Vec<Scheduled>)>,
+    capacity: usize,
+}
+
+impl SnapshotHistory {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            states: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, state: State) {
+        if self.states.len() == self.capacity {
+            self.states.pop_front();
+        }
+        self.states.push_back((state, Vec::new()));
+    }
+
+    /// Remembers an input scheduled since the latest state was taken, for `caught_up`.
+    pub fn scheduled_input(&mut self, input: IndexedState<AddBodyEvent>) {
+        if let Some((_, scheduled)) = self.states.back_mut() {
+            scheduled.push(Scheduled::Input(input));
+        }
+    }
+
+    /// Like `scheduled_input`, for a control.
+    pub fn scheduled_control(&mut self, control: ScheduledControl) {
+        if let Some((_, scheduled)) = self.states.back_mut() {
+            scheduled.push(Scheduled::Control(control));
+        }
+    }
+
+    pub fn get(&self, frame_index: FrameIndex) -> Option<&State> {
+        self.position(frame_index)
+            .map(|index| &self.states[index].0)
+    }
+
+    /// The state on `frame_index` with every input and control scheduled since it was taken,
+    /// which is everything a peer needs to carry on from that frame.
+    pub fn caught_up(&self, frame_index: FrameIndex) -> Option<State> {
+        let index = self.position(frame_index)?;
+        let mut state = self.states[index].0.clone();
+        for (_, scheduled) in self.states.range(index..) {
+            for scheduled in scheduled {
+                match *scheduled {
+                    Scheduled::Input(input) => state.input_buffer.push(input),
+                    Scheduled::Control(control) => {
+                        state.schedule_control(control);
+                    }
+                }
+            }
+        }
+        Some(state)
+    }
+
+    pub fn latest(&self) -> Option<&State> {
+        self.states.back().map(|(state, _)| state)
+    }
+
+    fn position(&self, frame_index: FrameIndex) -> Option<usize> {
+        self.states
+            .iter()
+            .rposition(|(state, _)| state.frame_index == frame_index)
+    }
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn read_varint(buf: &mut &[u8]) -> Result<u64, DeltaError> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = buf.split_first().ok_or(DeltaError::Truncated)?;
+        *buf = rest;
+        v |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(DeltaError::Truncated)
+}
+
+fn read_body_id(buf: &mut &[u8], previous_id: BodyId) -> Result<BodyId, DeltaError> {
+    let delta = unzigzag(read_varint(buf)?);
+    Ok(previous_id.wrapping_add(delta as u64))
+}
+
+/// The fields of a body in the order they're predicted and packed, paired with their flag.
+/// Later fields are predicted from the target value of earlier ones.
+fn predictions(base: &Body, target: &Body, gap: i64) -> [(u8, i64, i64); 7] {
+    let predict = |base: Float, rate: Float| {
+        base.to_bits()
+            .wrapping_add(rate.to_bits().wrapping_mul(gap))
+    };
+    [
+        (
+            ACCELERATION_X,
+            base.acceleration.x.to_bits(),
+            target.acceleration.x.to_bits(),
+        ),
+        (
+            ACCELERATION_Y,
+            base.acceleration.y.to_bits(),
+            target.acceleration.y.to_bits(),
+        ),
+        (
+            VELOCITY_X,
+            predict(base.velocity.x, target.acceleration.x),
+            target.velocity.x.to_bits(),
+        ),
+        (
+            VELOCITY_Y,
+            predict(base.velocity.y, target.acceleration.y),
+            target.velocity.y.to_bits(),
+        ),
+        (
+            POSITION_X,
+            predict(base.position.x, target.velocity.x),
+            target.position.x.to_bits(),
+        ),
+        (
+            POSITION_Y,
+            predict(base.position.y, target.velocity.y),
+            target.position.y.to_bits(),
+        ),
+        (MASS, base.mass.to_bits(), target.mass.to_bits()),
+    ]
+}
+
+/// Appends `id, flags, residuals...` for a body that differs from its baseline. Returns false
+/// if the body is unchanged and nothing was written.
+fn write_body_delta(
+    buf: &mut Vec<u8>,
+    previous_id: BodyId,
+    base: &Body,
+    target: &Body,
+    gap: i64,
+) -> bool {
+    let unchanged = base.position == target.position
+        && base.velocity == target.velocity
+        && base.acceleration == target.acceleration
+        && base.mass == target.mass;
+    if unchanged {
+        return false;
+    }
+
+    let fields = predictions(base, target, gap);
+    let flags = fields
+        .iter()
+        .filter(|(_, predicted, actual)| predicted != actual)
+        .fold(0, |flags, (flag, _, _)| flags | flag);
+
+    write_varint(buf, zigzag(target.id().wrapping_sub(previous_id) as i64));
+    buf.push(flags);
+    for (_, predicted, actual) in fields.iter() {
+        if predicted != actual {
+            write_varint(buf, zigzag(actual.wrapping_sub(*predicted)));
+        }
+    }
+    true
+}
+
+fn read_body_delta(buf: &mut &[u8], body: &mut Body, gap: i64) -> Result<(), DeltaError> {
+    let (flags, rest) = buf.split_first().ok_or(DeltaError::Truncated)?;
+    let flags = *flags;
+    *buf = rest;
+
+    let mut read = |flag: u8, predicted: i64| -> Result<Float, DeltaError> {
+        let residual = if flags & flag == 0 {
+            0
+        } else {
+            unzigzag(read_varint(buf)?)
+        };
+        Ok(Float::from_bits(predicted.wrapping_add(residual)))
+    };
+    let predict = |base: Float, rate: Float| {
+        base.to_bits()
+            .wrapping_add(rate.to_bits().wrapping_mul(gap))
+    };
+
+    body.acceleration.x = read(ACCELERATION_X, body.acceleration.x.to_bits())?;
+    body.acceleration.y = read(ACCELERATION_Y, body.acceleration.y.to_bits())?;
+    body.velocity.x = read(VELOCITY_X, predict(body.velocity.x, body.acceleration.x))?;
+    body.velocity.y = read(VELOCITY_Y, predict(body.velocity.y, body.acceleration.y))?;
+    body.position.x = read(POSITION_X, predict(body.position.x, body.velocity.x))?;
+    body.position.y = read(POSITION_Y, predict(body.position.y, body.velocity.y))?;
+    body.mass = read(MASS, body.mass.to_bits())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AddBodyEvent, IndexedState};
+
+    fn disk(count: usize) -> State {
+        let mut state = State::new();
+        for i in 0..count {
+            let angle = i as f32 / count as f32 * 2. * std::f32::consts::PI;
+            let radius = 200. + (i % 7) as f32 * 30.;
+            let mut body = Body::new_lossy(radius * angle.cos(), radius * angle.sin(), 1.);
+            body.velocity.x = Float::from_num(angle.sin() * -0.5);
+            body.velocity.y = Float::from_num(angle.cos() * 0.5);
+            state.simulation.add_body(body);
+        }
+        state.simulation.add_body(Body::new_lossy(0., 0., 10000.));
+        state
+    }
+
+    fn round_trip(baseline: &State, target: &State) -> StateDelta {
+        let delta = StateDelta::encode(baseline, target);
+        let applied = delta.apply(baseline).unwrap();
+        assert_eq!(applied.hash(), target.hash());
+        assert_eq!(applied.frame_index, target.frame_index);
+        assert_eq!(
+            applied
+                .simulation
+                .bodies
+                .iter()
+                .map(Body::id)
+                .collect::<Vec<_>>(),
+            target
+                .simulation
+                .bodies
+                .iter()
+                .map(Body::id)
+                .collect::<Vec<_>>()
+        );
+        delta
+    }
+
+    #[test]
+    fn varints() {
+        for v in [0, 1, -1, 63, -64, 1 << 40, i64::MAX, i64::MIN].iter() {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, zigzag(*v));
+            assert_eq!(unzigzag(read_varint(&mut &buf[..]).unwrap()), *v);
+        }
+        assert_eq!(read_varint(&mut &[0x80][..]), Err(DeltaError::Truncated));
+    }
+
+    #[test]
+    fn single_frame_delta_is_small() {
+        let baseline = disk(100);
+        let mut target = baseline.clone();
+        target.step();
+
+        let delta = round_trip(&baseline, &target);
+        let delta_size = bincode::serialized_size(&delta).unwrap();
+        let full_size = bincode::serialized_size(&target).unwrap();
+        assert!(
+            delta_size * 3 < full_size,
+            "delta: {}, full: {}",
+            delta_size,
+            full_size
+        );
+    }
+
+    #[test]
+    fn multi_frame_delta_with_added_and_removed_bodies() {
+        // the last two bodies collide on the first step
+        let mut baseline = disk(50);
+        baseline
+            .simulation
+            .add_body(Body::new_lossy(500., 500., 10.));
+        baseline
+            .simulation
+            .add_body(Body::new_lossy(501., 500., 10.));
+
+        let mut target = baseline.clone();
+        target.schedule(IndexedState {
+            frame_index: target.frame_index + 3,
+            state: AddBodyEvent::new(-800., 0., 5.),
+        });
+        target.schedule(IndexedState {
+            frame_index: target.frame_index + 100,
+            state: AddBodyEvent::new(800., 0., 5.),
+        });
+        for _ in 0..30 {
+            target.step();
+        }
+
+        let delta = round_trip(&baseline, &target);
+        assert!(!delta.added.is_empty());
+        assert!(!delta.removed.is_empty());
+    }
+
+    #[test]
+    fn wrong_baseline_is_rejected() {
+        let baseline = disk(10);
+        let mut target = baseline.clone();
+        target.step();
+        let delta = StateDelta::encode(&baseline, &target);
+
+        assert_eq!(
+            delta.apply(&target).unwrap_err(),
+            DeltaError::WrongBaseline {
+                expected: 0,
+                actual: 1
+            }
+        );
+
+        let mut diverged = baseline.clone();
+        diverged.simulation.bodies[0].mass = Float::from_num(2);
+        assert!(matches!(
+            delta.apply(&diverged),
+            Err(DeltaError::HashMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn history() {
+        let mut history = SnapshotHistory::with_capacity(2);
+        let mut state = State::new();
+        for _ in 0..3 {
+            history.push(state.clone());
+            state.step();
+        }
+        assert!(history.get(0).is_none());
+        assert_eq!(history.get(1).unwrap().frame_index, 1);
+        assert_eq!(history.latest().unwrap().frame_index, 2);
+    }
+
+    #[test]
+    fn caught_up_states_have_everything_scheduled_since() {
+        let mut history = SnapshotHistory::with_capacity(8);
+        let mut server = State::new();
+        history.push(server.clone());
+        for frame_index in 0..4 {
+            // scheduled for the next frame, like a late input
+            let input = IndexedState {
+                frame_index: frame_index + 1,
+                state: AddBodyEvent::new(100. * frame_index as f32, 0., 10.),
+            };
+            server.input_buffer.push(input);
+            history.scheduled_input(input);
+            server.step();
+            history.push(server.clone());
+        }
+        let control = ScheduledControl {
+            frame_index: 6,
+            seq: 0,
+            control: crate::control::Control::Pause,
+        };
+        server.schedule_control(control);
+        history.scheduled_control(control);
+
+        let mut caught_up = history.caught_up(2).unwrap();
+        let mut stale = history.get(2).unwrap().clone();
+        while caught_up.frame_index < 8 {
+            caught_up.step();
+            stale.step();
+        }
+        while server.frame_index < 8 {
+            server.step();
+        }
+        assert_eq!(caught_up.hash(), server.hash());
+        assert!(caught_up.playback.paused);
+        assert_ne!(stale.hash(), server.hash());
+        assert!(history.caught_up(9).is_none());
+    }
+}
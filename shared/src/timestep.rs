@@ -0,0 +1,190 @@
+//! A fixed timestep keyed to absolute deadlines.
+//!
+//! Tick `n` is due `n / tick_rate` seconds after the start, however long earlier ticks took,
+//! so time spent simulating and waking up doesn't accumulate into drift. A tick that starts
+//! late is followed by the ones that became due in the meantime, back to back, but only up to
+//! `max_catch_up` of them at once. Past that the server has fallen too far behind to catch up
+//! without stalling, so the missed ticks are skipped and the schedule restarts from the latest.
+//!
+//! Like `clock`, nothing here reads a clock. Times are durations since an arbitrary epoch.
+
+use serde::Serialize;
+use std::time::Duration;
+
+/// Counters for how well the server keeps to its tick rate, since it started.
+#[derive(Copy, Clone, Debug, Default, Serialize, PartialEq)]
+pub struct TickStats {
+    pub ticks: u64,
+    /// Steps that took longer than a tick.
+    pub overruns: u64,
+    /// Ticks run back to back because they were already late.
+    pub caught_up: u64,
+    /// Ticks dropped because catching up would have taken too many.
+    pub skipped: u64,
+    /// The longest step, in seconds.
+    pub max_step: f64,
+    /// The latest a tick started after its deadline, in seconds.
+    pub max_lateness: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct FixedTimestep {
+    tick_rate: u32,
+    max_catch_up: u32,
+    /// When the tick `ticks` after it is due.
+    origin: Duration,
+    ticks: u64,
+    stats: TickStats,
+}
+
+impl FixedTimestep {
+    pub fn new(tick_rate: u32, max_catch_up: u32, start: Duration) -> Self {
+        assert!(tick_rate > 0);
+        Self {
+            tick_rate,
+            max_catch_up: max_catch_up.max(1),
+            origin: start,
+            ticks: 0,
+            stats: TickStats::default(),
+        }
+    }
+
+    pub fn tick_rate(&self) -> u32 {
+        self.tick_rate
+    }
+
+    /// Changes the tick rate from the next tick on. That tick keeps its deadline and the ones
+    /// after it are spaced by the new rate.
+    pub fn set_tick_rate(&mut self, tick_rate: u32) {
+        assert!(tick_rate > 0);
+        self.origin = self.next_deadline();
+        self.ticks = 0;
+        self.tick_rate = tick_rate;
+    }
+
+    pub fn period(&self) -> Duration {
+        Duration::from_nanos(1_000_000_000 / self.tick_rate as u64)
+    }
+
+    /// Computed from the tick count rather than summed, so rounding doesn't add up.
+    fn deadline(&self, ticks: u64) -> Duration {
+        let nanos = ticks as u128 * 1_000_000_000 / self.tick_rate as u128;
+        self.origin + Duration::from_nanos(nanos as u64)
+    }
+
+    pub fn next_deadline(&self) -> Duration {
+        self.deadline(self.ticks)
+    }
+
+    /// Returns how many ticks should be run now, which is zero before the next deadline and at
+    /// most `max_catch_up`.
+    pub fn due(&mut self, now: Duration) -> u32 {
+        let first = self.next_deadline();
+        if now < first {
+            return 0;
+        }
+        let lateness = (now - first).as_secs_f64();
+        self.stats.max_lateness = self.stats.max_lateness.max(lateness);
+
+        // every tick up to and including the last deadline that has passed, which includes
+        // the next one even where its deadline was rounded down
+        let elapsed = (now - self.origin).as_nanos();
+        let reached = ((elapsed * self.tick_rate as u128 / 1_000_000_000) as u64).max(self.ticks);
+        let mut due = reached + 1 - self.ticks;
+        if due > self.max_catch_up as u64 {
+            let skipped = due - self.max_catch_up as u64;
+            self.origin = self.deadline(self.ticks + skipped);
+            self.ticks = 0;
+            self.stats.skipped += skipped;
+            due = self.max_catch_up as u64;
+        }
+        self.ticks += due;
+        self.stats.ticks += due;
+        self.stats.caught_up += due - 1;
+        due as u32
+    }
+
+    /// Accounts for how long a tick took to run.
+    pub fn record_step(&mut self, took: Duration) {
+        if took > self.period() {
+            self.stats.overruns += 1;
+        }
+        self.stats.max_step = self.stats.max_step.max(took.as_secs_f64());
+    }
+
+    pub fn stats(&self) -> TickStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn deadlines_do_not_drift() {
+        let start = ms(500);
+        let mut timestep = FixedTimestep::new(60, 4, start);
+        assert_eq!(timestep.due(start - ms(1)), 0);
+        // waking up a little late every time doesn't push later deadlines back
+        for _ in 0..600 {
+            let deadline = timestep.next_deadline();
+            assert_eq!(timestep.due(deadline + ms(3)), 1);
+            assert_eq!(timestep.due(deadline + ms(3)), 0);
+        }
+        assert_eq!(timestep.next_deadline(), start + Duration::from_secs(10));
+        let stats = timestep.stats();
+        assert_eq!(stats.ticks, 600);
+        assert_eq!((stats.caught_up, stats.skipped), (0, 0));
+        assert!((stats.max_lateness - 0.003).abs() < 1e-9);
+    }
+
+    #[test]
+    fn late_ticks_catch_up_within_the_limit() {
+        let mut timestep = FixedTimestep::new(100, 4, Duration::ZERO);
+        assert_eq!(timestep.due(Duration::ZERO), 1);
+
+        // ticks 1 to 3 are due by 35ms
+        assert_eq!(timestep.due(ms(35)), 3);
+        assert_eq!(timestep.next_deadline(), ms(40));
+        assert_eq!(timestep.stats().caught_up, 2);
+
+        // ticks 4 to 13 are due by 130ms, only 4 run and the schedule restarts from the last
+        assert_eq!(timestep.due(ms(130)), 4);
+        assert_eq!(timestep.stats().skipped, 6);
+        assert_eq!(timestep.next_deadline(), ms(140));
+        assert_eq!(timestep.due(ms(139)), 0);
+        assert_eq!(timestep.due(ms(140)), 1);
+        assert_eq!(timestep.stats().ticks, 9);
+    }
+
+    #[test]
+    fn tick_rate_changes_from_the_next_tick() {
+        let mut timestep = FixedTimestep::new(100, 4, Duration::ZERO);
+        assert_eq!(timestep.due(Duration::ZERO), 1);
+        assert_eq!(timestep.due(ms(10)), 1);
+        timestep.set_tick_rate(20);
+        assert_eq!(timestep.tick_rate(), 20);
+        assert_eq!(timestep.next_deadline(), ms(20));
+        assert_eq!(timestep.due(ms(20)), 1);
+        assert_eq!(timestep.next_deadline(), ms(70));
+        assert_eq!(timestep.due(ms(69)), 0);
+        assert_eq!(timestep.due(ms(120)), 2);
+        assert_eq!(timestep.stats().ticks, 5);
+    }
+
+    #[test]
+    fn slow_steps_are_overruns() {
+        let mut timestep = FixedTimestep::new(50, 4, Duration::ZERO);
+        timestep.record_step(ms(5));
+        timestep.record_step(ms(20));
+        timestep.record_step(ms(35));
+        let stats = timestep.stats();
+        assert_eq!(stats.overruns, 1);
+        assert!((stats.max_step - 0.035).abs() < 1e-9);
+    }
+}
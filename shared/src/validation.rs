@@ -0,0 +1,127 @@
+//! Rules that every added body must satisfy.
+//!
+//! The server checks inputs against its latest state before scheduling them and clients run
+//! the same checks before sending, so an input that would be rejected never shows up in the
+//! local prediction. The input is applied a few frames after it's checked, so spacing is only
+//! guaranteed against the bodies as they were when it arrived.
+
+use super::{AddBodyEvent, InputRejected, State};
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct InputRules {
+    pub min_mass: f64,
+    pub max_mass: f64,
+    /// How far from the origin a body may be added.
+    pub max_distance: f64,
+    /// In distance per tick.
+    pub max_speed: f64,
+    /// The smallest gap between the surface of a new body and any existing one.
+    pub min_spacing: f64,
+}
+
+impl Default for InputRules {
+    fn default() -> Self {
+        Self {
+            min_mass: 1.,
+            max_mass: 100_000.,
+            max_distance: 10_000.,
+            max_speed: 20.,
+            min_spacing: 1.,
+        }
+    }
+}
+
+impl InputRules {
+    pub fn validate(&self, event: &AddBodyEvent, state: &State) -> Result<(), InputRejected> {
+        let mass = event.mass.to_num::<f64>();
+        if mass < self.min_mass || mass > self.max_mass {
+            return Err(InputRejected::MassOutOfRange);
+        }
+
+        let x = event.position_x.to_num::<f64>();
+        let y = event.position_y.to_num::<f64>();
+        if x.hypot(y) > self.max_distance {
+            return Err(InputRejected::TooFarFromCenter);
+        }
+
+        let speed = event
+            .velocity_x
+            .to_num::<f64>()
+            .hypot(event.velocity_y.to_num::<f64>());
+        if speed > self.max_speed {
+            return Err(InputRejected::TooFast);
+        }
+
+        let radius = event.body().radius().to_num::<f64>();
+        let too_close = state.simulation.bodies.iter().any(|body| {
+            let distance =
+                (body.position.x.to_num::<f64>() - x).hypot(body.position.y.to_num::<f64>() - y);
+            distance < radius + body.radius().to_num::<f64>() + self.min_spacing
+        });
+        if too_close {
+            return Err(InputRejected::TooClose);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> State {
+        let mut state = State::new();
+        state
+            .simulation
+            .add_body(nbody::Body::new_lossy(100., 0., 1000.));
+        state
+    }
+
+    #[test]
+    fn valid_inputs_pass() {
+        let rules = InputRules::default();
+        let event = AddBodyEvent::new_with_velocity(-100., 50., 10., 1.5, -2.);
+        assert_eq!(rules.validate(&event, &state()), Ok(()));
+    }
+
+    #[test]
+    fn rejections() {
+        let rules = InputRules::default();
+        let state = state();
+        let cases = [
+            (AddBodyEvent::new(0., 0., 0.), InputRejected::MassOutOfRange),
+            (
+                AddBodyEvent::new(0., 0., -5.),
+                InputRejected::MassOutOfRange,
+            ),
+            (
+                AddBodyEvent::new(0., 0., 1e9),
+                InputRejected::MassOutOfRange,
+            ),
+            (
+                AddBodyEvent::new(8000., 8000., 1.),
+                InputRejected::TooFarFromCenter,
+            ),
+            (
+                AddBodyEvent::new(-2e9, 0., 1.),
+                InputRejected::TooFarFromCenter,
+            ),
+            (
+                AddBodyEvent::new_with_velocity(0., 0., 1., 15., 15.),
+                InputRejected::TooFast,
+            ),
+            // the existing body has a radius of about 6.2
+            (AddBodyEvent::new(107., 0., 1.), InputRejected::TooClose),
+            (AddBodyEvent::new(100., 0., 1.), InputRejected::TooClose),
+        ];
+        for (event, reason) in cases.iter() {
+            assert_eq!(rules.validate(event, &state), Err(*reason), "{:?}", event);
+        }
+        assert_eq!(
+            rules.validate(&AddBodyEvent::new(110., 0., 1.), &state),
+            Ok(())
+        );
+    }
+}
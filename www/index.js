@@ -6,15 +6,36 @@ async function run() {
       urls: ['stun:stun.l.google.com:19302'],
     }],
   });
-  const channel = await Connection.connect(peer).catch(err => console.error(err));
+  // ?transport=websocket skips WebRTC, which is otherwise only given up on if it fails
+  const forceWebSocket = new URLSearchParams(window.location.search).get('transport') === 'websocket';
+  const channel = await (forceWebSocket ? Connection.connect_websocket() : Connection.open(peer))
+    .catch(err => console.error(err));
   if (!channel) {
     return;
   }
 
-  const state_buffer = await fetch('state')
+  const params = new URLSearchParams(window.location.search);
+  // e.g. ?room=3 plays in a room created with POST /rooms instead of the default one
+  const room = params.get('room');
+  const join = room === null
+    ? null
+    : await fetch(`rooms/${room}/join`, { method: 'POST' }).then(r => r.json());
+  const state_buffer = await fetch(join ? `rooms/${join.room_id}/state` : 'state')
     	.then((r) => r.arrayBuffer())
     	.then((e) => new Uint8Array(e));
   const state = State.from_raw(state_buffer, channel);
+  if (join) {
+    state.set_ticket(join.ticket);
+  }
+  const name = params.get('name');
+  if (name) {
+    state.set_client_name(name);
+  }
+  // e.g. ?spectate=120 watches two seconds behind the server
+  const spectate = params.get('spectate');
+  if (spectate !== null) {
+    state.spectate(parseInt(spectate) || 0);
+  }
 
   const shader_2d = await fetch("resources/shader.glsl").then(r => r.text());
   const shader_instanced = await fetch("resources/instanced.glsl").then(r => r.text());
@@ -81,6 +102,18 @@ async function run() {
     }
   })
 
+  window.addEventListener('keydown', (event) => {
+    if (event.key === ' ') {
+      state.paused() ? state.resume() : state.pause();
+    } else if (event.key === '.') {
+      state.step_frames(1);
+    } else if (event.key === '+' || event.key === '=') {
+      state.set_timescale(state.timescale() + 1);
+    } else if (event.key === '-') {
+      state.set_timescale(Math.max(state.timescale() - 1, 1));
+    }
+  });
+
   let prev_t = performance.now();
   const loop = (t) => {
     state.step();
@@ -96,11 +129,17 @@ async function run() {
 
     overlay_ctx.fillText(`FPS: ${state.latency_secs()}`, 0, (++textIndex * fontSize));
     overlay_ctx.fillText(`FRAME: ${state.current_frame()}`, 0, (++textIndex * fontSize));
-    overlay_ctx.fillText(`TARGET: ${state.target_frame()}`, 0, (++textIndex * fontSize));
+    overlay_ctx.fillText(`TARGET: ${state.target_frame()} ± ${state.target_frame_error()}`, 0, (++textIndex * fontSize));
+    overlay_ctx.fillText(`SPEED: ${state.paused() ? 'PAUSED' : `${state.timescale()}x`}`, 0, (++textIndex * fontSize));
     overlay_ctx.fillText(`PKT LOSS: ${state.packet_loss()}`, 0, (++textIndex * fontSize));
     overlay_ctx.fillText(`HASH SUC: ${state.hash_successes()}`, 0, (++textIndex * fontSize));
     overlay_ctx.fillText(`HASH FAIL: ${state.hash_failures()}`, 0, (++textIndex * fontSize));
     overlay_ctx.fillText(`BODIES: ${bodies.length}`, 0, (++textIndex * fontSize));
+    overlay_ctx.fillText(state.spectating() ? 'SPECTATING' : `PLAYER: ${state.player_id()}`, 0, (++textIndex * fontSize));
+    const rejection = state.last_rejection();
+    if (rejection) {
+      overlay_ctx.fillText(`REJECTED: ${rejection}`, 0, (++textIndex * fontSize));
+    }
 
     if (leftMouseDown) {
       overlay_ctx.strokeStyle = 'blue';
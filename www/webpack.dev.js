@@ -12,9 +12,13 @@ module.exports = merge(common, {
     devServer: {
     // contentBase: './dist',
     proxy: [{
-      context: ['/new_rtc_session', '/state', '/hash'],
+      context: ['/new_rtc_session', '/state', '/hash', '/rooms', '/status'],
       target: 'http://localhost:3030',
       changeOrigin: true,
+    }, {
+      context: ['/ws'],
+      target: 'ws://localhost:3030',
+      ws: true,
     }]
 },
 });